@@ -6,11 +6,13 @@ use futures::Stream;
 use crate::stream_adapter::{StrictOrLazyLineBuilder, StrictOrLazyLines};
 use config::{self, Config, DbPath, K8sTrackingConf};
 use env_logger::Env;
+use fs::rule::RuleDef;
 use fs::tail;
 use futures::StreamExt;
 use http::batch::TimedRequestBatcherStreamExt;
 use http::client::{Client, ClientError, SendStatus};
 use http::retry::{retry, RetryItem};
+use http::types::body::LineBuilder;
 
 #[cfg(feature = "libjournald")]
 use journald::libjournald::source::create_source;
@@ -18,14 +20,25 @@ use journald::libjournald::source::create_source;
 use journald::journalctl::create_journalctl_source;
 
 use k8s::event_source::K8sEventStream;
-use k8s::lease::{get_available_lease, K8S_STARTUP_LEASE_LABEL, K8S_STARTUP_LEASE_RETRY_ATTEMPTS};
+use k8s::lease::{
+    claim_lease, get_available_lease, ClaimedLease, K8S_STARTUP_LEASE_LABEL,
+    K8S_STARTUP_LEASE_RETRY_ATTEMPTS,
+};
+use k8s::resource_source::K8sResourceStream;
 
 use k8s::create_k8s_client_default_from_env;
 use k8s::middleware::K8sMetadata;
 use kube::Client as Kube_Client;
 use metrics::Metrics;
+use middleware::dir_meta::DirMetaRules;
+use middleware::extract_rules::ExtractRules;
+use middleware::field_rules::FieldRules;
+use middleware::level_detection::LevelDetection;
 use middleware::line_rules::LineRules;
+use middleware::line_script::LineScript;
 use middleware::meta_rules::{MetaRules, MetaRulesConfig};
+use middleware::metric_rules::MetricRules;
+use middleware::wasm_plugin::WasmPlugin;
 use middleware::Executor;
 
 use config::env_vars;
@@ -33,6 +46,7 @@ use pin_utils::pin_mut;
 use state::{AgentState, FileId, SpanVec};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::signal::*;
 use tokio::sync::Mutex;
 use tokio::time::Duration;
@@ -96,7 +110,7 @@ async fn _main() {
     dep_audit::get_auditable_dependency_list()
         .map_or_else(|e| trace!("{}", e), |d| trace!("{}", d));
 
-    let config = match Config::new(std::env::args_os()) {
+    let mut config = match Config::new(std::env::args_os()) {
         Ok(v) => v,
         Err(e) => {
             error!("config error: {}", e);
@@ -132,6 +146,7 @@ async fn _main() {
     let handles = offset_state
         .as_ref()
         .map(|os| (os.write_handle(), os.flush_handle()));
+    let offsets_handle = offset_state.as_ref().map(|os| os.write_handle());
 
     let user_agent = config.http.template.user_agent.clone();
     let (retry, retry_stream) = retry(
@@ -155,24 +170,37 @@ async fn _main() {
     }
 
     let mut executor = Executor::new();
+    let mut pending_lines = None;
 
-    let mut k8s_claimed_lease: Option<String> = None;
-    let k8s_event_stream = match create_k8s_client_default_from_env(user_agent) {
+    let (k8s_event_stream, k8s_resource_stream) = match create_k8s_client_default_from_env(
+        user_agent,
+    ) {
         Ok(k8s_client) => {
             info!("K8s Config Startup Option: {:?}", &config.startup.option);
-            check_startup_lease_status(
-                Some(&config.startup.option),
-                &mut k8s_claimed_lease,
-                k8s_client.clone(),
-            )
-            .await;
+            let k8s_claimed_lease =
+                check_startup_lease_status(Some(&config.startup.option), k8s_client.clone()).await;
             if config.log.use_k8s_enrichment == K8sTrackingConf::Always
                 && std::env::var_os("KUBERNETES_SERVICE_HOST").is_some()
             {
                 let node_name = std::env::var("NODE_NAME").ok();
-                match K8sMetadata::new(k8s_client.clone(), node_name.as_deref()).await {
+                match K8sMetadata::new(k8s_client.clone(), node_name.as_deref(), &config.log).await
+                {
                     Ok((driver, v)) => {
+                        let v = match v.with_selection(&config.log.k8s_selection) {
+                            Ok(v) => v,
+                            Err(e) => {
+                                error!("k8s pod selection is invalid: {}", e);
+                                std::process::exit(1);
+                            }
+                        };
+                        // Avoid tailing the files of the pods that are not selected
+                        let excluded = RuleDef::fn_rule(v.excluded_path_filter());
+                        for dir in config.log.dir_rules.iter_mut() {
+                            dir.rules.add_exclusion(excluded.clone());
+                        }
+                        config.log.rules.add_exclusion(excluded);
                         tokio::spawn(driver);
+                        pending_lines = Some(v.pending_lines());
                         executor.register(v);
                         info!("Registered k8s metadata middleware");
                     }
@@ -192,12 +220,11 @@ async fn _main() {
                 K8sTrackingConf::Always => {
                     let pod_name = std::env::var("POD_NAME").ok();
                     let namespace = std::env::var("NAMESPACE").ok();
-                    let pod_label = std::env::var("POD_APP_LABEL").ok();
-                    match (pod_name, namespace, pod_label) {
-                        (Some(pod_name), Some(namespace), Some(pod_label)) => Some(
-                            K8sEventStream::new(k8s_client.clone(), pod_name, namespace, pod_label),
-                        ),
-                        (pn, n, pl) => {
+                    match (pod_name, namespace) {
+                        (Some(pod_name), Some(namespace)) => {
+                            Some(K8sEventStream::new(k8s_client.clone(), pod_name, namespace))
+                        }
+                        (pn, n) => {
                             if pn.is_none() {
                                 warn!("Kubernetes event logging is configured, but POD_NAME env is not set")
                             }
@@ -206,9 +233,6 @@ async fn _main() {
                                     "Kubernetes event logging is configured, but NAMESPACE env is not set"
                                 )
                             }
-                            if pl.is_none() {
-                                warn!("Kubernetes event logging is configured, but POD_APP_LABEL env is not set")
-                            }
                             warn!("Kubernetes event logging disabled");
                             None
                         }
@@ -216,26 +240,42 @@ async fn _main() {
                 }
             };
 
-            match k8s_claimed_lease.as_ref() {
-                Some(lease) => {
-                    info!("Releasing lease: {:?}", lease);
-                    let k8s_lease_api = k8s::lease::get_k8s_lease_api(
-                        &std::env::var("NAMESPACE").unwrap(),
+            let k8s_resource_stream = if config.log.k8s_watch.is_empty() {
+                None
+            } else {
+                match (std::env::var("POD_NAME"), std::env::var("NAMESPACE")) {
+                    (Ok(pod_name), Ok(namespace)) => Some(K8sResourceStream::new(
                         k8s_client.clone(),
-                    )
-                    .await;
-                    k8s::lease::release_lease(lease, &k8s_lease_api).await;
+                        pod_name,
+                        namespace,
+                        config.log.k8s_watch.clone(),
+                    )),
+                    _ => {
+                        warn!("Kubernetes resource watching is configured, but POD_NAME or NAMESPACE env is not set");
+                        None
+                    }
+                }
+            };
+
+            match k8s_claimed_lease {
+                Some(lease) => {
+                    info!("Releasing lease: {:?}", lease.name());
+                    let name = lease.name().to_string();
+                    if let Err(e) = lease.release().await {
+                        // The lease is taken over once it expires
+                        warn!("Unable to release lease {}: {}", name, e);
+                    }
                 }
                 None => {
                     info!("No K8s lease claimed during startup.");
                 }
             }
 
-            k8s_event_stream
+            (k8s_event_stream, k8s_resource_stream)
         }
         Err(e) => {
             warn!("Unable to initialize kubernetes client: {}", e);
-            None
+            (None, None)
         }
     };
 
@@ -243,7 +283,13 @@ async fn _main() {
         &config.log.line_exclusion_regex,
         &config.log.line_inclusion_regex,
         &config.log.line_redact_regex,
-    ) {
+    )
+    .and_then(|r| {
+        r.with_redact_rules(
+            &config.log.redact_rules,
+            config.log.redact_hash_key.as_deref(),
+        )
+    }) {
         Ok(v) => executor.register(v),
         Err(e) => {
             error!("line regex is invalid: {}", e);
@@ -259,11 +305,89 @@ async fn _main() {
         }
     };
 
+    // Runs before the extraction, the extract rules can be limited to the per directory apps
+    let dir_meta = DirMetaRules::new(&config.log.dir_meta);
+    if !dir_meta.is_empty() {
+        executor.register(dir_meta);
+    }
+
+    // Runs after the app overrides, the minimum levels by app match the app that is sent.
+    // The journald lines only get a level from their priority when the levels are used
+    let level_detection = LevelDetection::new(&config.log.level);
+    let journald_levels = level_detection.is_active();
+    if journald_levels {
+        executor.register(level_detection);
+    }
+
+    if !config.log.line_extract_rules.is_empty() {
+        match ExtractRules::new(&config.log.line_extract_rules) {
+            Ok(v) => executor.register(v),
+            Err(e) => {
+                error!("line extract rules are invalid: {}", e);
+                std::process::exit(1);
+            }
+        };
+    }
+
+    // Runs after the enrichment and extraction to cover all the metadata of the lines
+    match FieldRules::new(
+        &config.log.field_rules,
+        config.log.redact_hash_key.as_deref(),
+    ) {
+        Ok(v) if !v.is_empty() => executor.register(v),
+        Ok(_) => {}
+        Err(e) => {
+            error!("field rules are invalid: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(script_config) = &config.log.script {
+        match LineScript::new(script_config) {
+            Ok(v) => {
+                executor.register(v);
+                info!("Registered line script middleware");
+            }
+            Err(e) => {
+                error!("line script is invalid: {}", e);
+                std::process::exit(1);
+            }
+        };
+    }
+
+    for path in &config.log.wasm_plugins {
+        match WasmPlugin::new(path, config.log.wasm_fuel, config.log.wasm_max_memory) {
+            Ok(v) => {
+                executor.register(v);
+                info!("Registered wasm plugin {}", path.display());
+            }
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        };
+    }
+
+    match MetricRules::new(&config.log.metric_rules, prometheus::default_registry()) {
+        Ok(v) if v.is_empty() => {}
+        Ok(v) => {
+            if config.log.metrics_port.is_none() {
+                warn!("metric rules are defined but the metrics endpoint is not enabled");
+            }
+            executor.register(v);
+            info!("Registered metric rules middleware");
+        }
+        Err(e) => {
+            error!("metric rules are invalid: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     executor.init();
 
     #[cfg(feature = "libjournald")]
     let (journalctl_source, journald_source) = if config.journald.paths.is_empty() {
-        let journalctl_source = create_journalctl_source()
+        let journalctl_source = create_journalctl_source(journald_levels)
             .map(|s| s.map(StrictOrLazyLineBuilder::Strict))
             .map_err(|e| {
                 info!("Journalctl source was not initialized");
@@ -273,12 +397,15 @@ async fn _main() {
     } else {
         (
             None,
-            Some(create_source(&config.journald.paths).map(StrictOrLazyLineBuilder::Strict)),
+            Some(
+                create_source(&config.journald.paths, journald_levels)
+                    .map(StrictOrLazyLineBuilder::Strict),
+            ),
         )
     };
 
     #[cfg(not(feature = "libjournald"))]
-    let journalctl_source = create_journalctl_source()
+    let journalctl_source = create_journalctl_source(journald_levels)
         .map(|s| s.map(StrictOrLazyLineBuilder::Strict))
         .map_err(|e| warn!("Error initializing journalctl source: {}", e))
         .ok();
@@ -289,26 +416,47 @@ async fn _main() {
 
     let ds_source_params = (
         config.log.dirs.clone(),
+        config.log.files.clone(),
         config.log.rules.clone(),
+        config.log.dir_rules.clone(),
         config.log.lookback.clone(),
         initial_offsets.clone(),
+        config.log.watch.clone(),
+        config.log.ignore_older,
+        config.log.open_files.clone(),
+        config.log.read.clone(),
+        offsets_handle,
     );
 
     let fs_source = tail::RestartingTailer::new(
         ds_source_params,
-        |item| match item {
-            Err(fs::cache::Error::WatchOverflow) => {
-                warn!("overflowed kernel queue, restarting stream");
-                true
-            }
-            _ => false,
-        },
+        // The overflows of the inotify queue are recovered by rescanning the tracked directories
+        |_| false,
         |params| {
             let watched_dirs = params.0.clone();
-            let rules = params.1.clone();
-            let lookback = params.2.clone();
-            let offsets = params.3.clone();
-            let tailer = tail::Tailer::new(watched_dirs, rules, lookback, offsets);
+            let file_targets = params.1.clone();
+            let rules = params.2.clone();
+            let dir_rules = params.3.clone();
+            let lookback = params.4.clone();
+            let offsets = params.5.clone();
+            let watch_config = params.6.clone();
+            let ignore_older = params.7;
+            let open_files_config = params.8.clone();
+            let read_config = params.9.clone();
+            let offsets_handle = params.10.clone();
+            let tailer = tail::Tailer::new(
+                watched_dirs,
+                file_targets,
+                rules,
+                dir_rules,
+                lookback,
+                ignore_older,
+                offsets,
+                watch_config,
+                open_files_config,
+                read_config,
+                offsets_handle,
+            );
             async move { tail::process(tailer).expect("except Failed to create FS Tailer") }
         },
     )
@@ -330,25 +478,38 @@ async fn _main() {
         }
     });
 
+    // The checkpoint of the k8s events advances once their batches are sent
+    let event_checkpoints = k8s_event_stream.as_ref().map(K8sEventStream::checkpoints);
     let k8s_event_source: Option<_> = if let Some(fut) = k8s_event_stream.map(|e| e.event_stream())
     {
         Some(
             fut.await
                 .expect("Failed to create stream")
-                .map(StrictOrLazyLineBuilder::Strict),
+                .map(|(line, checkpoint)| StrictOrLazyLineBuilder::K8sEvent(line, checkpoint)),
         )
     } else {
         None
     };
 
+    let k8s_resource_source: Option<_> = match k8s_resource_stream {
+        Some(s) => Some(
+            s.resource_stream()
+                .await
+                .map(StrictOrLazyLineBuilder::Strict),
+        ),
+        None => None,
+    };
+
     pin_mut!(fs_source);
     pin_mut!(k8s_event_source);
+    pin_mut!(k8s_resource_source);
     pin_mut!(journalctl_source);
 
     #[cfg(feature = "libjournald")]
     pin_mut!(journald_source);
 
     let mut k8s_event_source: Option<std::pin::Pin<&mut _>> = k8s_event_source.as_pin_mut();
+    let mut k8s_resource_source: Option<std::pin::Pin<&mut _>> = k8s_resource_source.as_pin_mut();
     let mut journalctl_source: Option<std::pin::Pin<&mut _>> = journalctl_source.as_pin_mut();
 
     #[cfg(feature = "libjournald")]
@@ -379,23 +540,42 @@ async fn _main() {
         sources.push(k)
     };
 
-    let lines_stream = sources.map(|line| match line {
-        StrictOrLazyLineBuilder::Strict(mut line) => {
-            if executor.process(&mut line).is_some() {
-                match line.build() {
-                    Ok(line) => Some(StrictOrLazyLines::Strict(line)),
-                    Err(e) => {
-                        error!("Couldn't build line from linebuilder {:?}", e);
-                        None
-                    }
+    if let Some(k) = k8s_resource_source.as_mut() {
+        info!("Enabling k8s_resource_source");
+        sources.push(k)
+    };
+
+    // The first lines of the new pods wait for their metadata before the middlewares
+    let sources = match pending_lines {
+        Some(pending_lines) => futures::future::Either::Left(
+            pending_lines.hold(sources, StrictOrLazyLineBuilder::line_buffer_mut),
+        ),
+        None => futures::future::Either::Right(sources),
+    };
+
+    let build_strict = |mut line: LineBuilder| {
+        if executor.process(&mut line).is_some() {
+            match line.build() {
+                Ok(line) => Some(StrictOrLazyLines::Strict(line)),
+                Err(e) => {
+                    error!("Couldn't build line from linebuilder {:?}", e);
+                    None
                 }
-            } else {
-                None
             }
+        } else {
+            None
+        }
+    };
+
+    // The lines of the k8s events keep the checkpoint of their event until they are batched
+    let lines_stream = sources.map(|line| match line {
+        StrictOrLazyLineBuilder::Strict(line) => build_strict(line).map(|line| (line, None)),
+        StrictOrLazyLineBuilder::K8sEvent(line, checkpoint) => {
+            build_strict(line).map(|line| (line, checkpoint))
         }
         StrictOrLazyLineBuilder::Lazy(mut line) => {
             if executor.process(&mut line).is_some() {
-                Some(StrictOrLazyLines::Lazy(line))
+                Some((StrictOrLazyLines::Lazy(line), None))
             } else {
                 None
             }
@@ -404,9 +584,20 @@ async fn _main() {
 
     let body_offsets_stream = lines_stream
         .filter_map(|l| async { l })
+        // The batches are emitted before the next line is pulled, so the events added here
+        // are the ones in the next batch
+        .map(|(line, event)| {
+            if let (Some(checkpoints), Some(event)) = (event_checkpoints.as_ref(), event) {
+                checkpoints.add(event);
+            }
+            line
+        })
         // TODO: paramaterise the flush frequency
         .timed_request_batches(config.http.body_size, Duration::from_millis(250))
-        .map(|b| async { b })
+        .map(|b| {
+            let events = event_checkpoints.as_ref().map(|c| c.batch());
+            async { (b, events) }
+        })
         .buffered(10);
 
     async fn handle_client_error<T>(
@@ -454,14 +645,20 @@ async fn _main() {
     let lines_client = client.clone();
     let lines_driver = body_offsets_stream.for_each_concurrent(None, {
         let shutdown_tx = shutdown_tx.clone();
-        move |body_offsets| {
+        move |(body_offsets, events)| {
             let client = lines_client.clone();
             let shutdown_tx = shutdown_tx.clone();
             async {
                 tokio::spawn(async move {
                     match body_offsets {
                         Ok((body, offsets)) => match client.send(body, Some(offsets)).await {
-                            Ok(s) => handle_send_status(s),
+                            Ok(s) => {
+                                // Sent or stored for retry, the events are not sent again
+                                if let Some(events) = events {
+                                    events.acknowledge();
+                                }
+                                handle_send_status(s)
+                            }
                             Err(e) => handle_client_error(e, shutdown_tx).await,
                         },
                         Err(e) => error!("Couldn't batch lines {:?}", e),
@@ -539,9 +736,8 @@ async fn _main() {
 
 async fn check_startup_lease_status(
     start_option: Option<&str>,
-    claimed_lease_ref: &mut Option<String>,
     client: Kube_Client,
-) {
+) -> Option<ClaimedLease> {
     let max_attempts = match start_option {
         Some("attempt") => {
             info!("Getting agent-startup-lease (making limited attempts)");
@@ -556,34 +752,60 @@ async fn check_startup_lease_status(
                 "Kubernetes cluster initialised, K8s startup lease set to: {:?}",
                 start_option
             );
-            return;
+            return None;
         }
     };
 
-    let k8s_lease_api =
-        k8s::lease::get_k8s_lease_api(&std::env::var("NAMESPACE").unwrap(), client).await;
+    let (namespace, pod_name) = match (std::env::var("NAMESPACE"), std::env::var("POD_NAME")) {
+        (Ok(namespace), Ok(pod_name)) => (namespace, pod_name),
+        _ => {
+            warn!("K8s startup lease is configured, but NAMESPACE or POD_NAME env is not set");
+            return None;
+        }
+    };
+    let k8s_lease_api = k8s::lease::get_k8s_lease_api(&namespace, client).await;
+    let start = Instant::now();
+    let mut claimed_lease = None;
     let mut attempts = 0;
     while (max_attempts == -1) || (attempts < max_attempts) {
         info!("Attempting connection: {}", attempts);
         match get_available_lease(K8S_STARTUP_LEASE_LABEL, &k8s_lease_api).await {
-            Some(available_lease) => {
+            Ok(Some(available_lease)) => {
                 info!("Lease available: {:?}", available_lease);
-                k8s::lease::claim_lease(
-                    available_lease,
-                    std::env::var("POD_NAME").unwrap(),
-                    &k8s_lease_api,
-                    claimed_lease_ref,
-                )
-                .await;
-                break;
-            }
-            None => {
-                attempts += 1;
-                info!("No lease availabe at this time. Waiting 1 second...");
-                tokio::time::sleep(Duration::from_millis(1000)).await;
+                match claim_lease(&available_lease, &pod_name, &k8s_lease_api).await {
+                    Ok(Some(duration)) => {
+                        claimed_lease = Some(ClaimedLease::new(
+                            available_lease,
+                            pod_name,
+                            k8s_lease_api,
+                            duration,
+                        ));
+                        break;
+                    }
+                    Ok(None) => info!("Lease {} was claimed by another agent", available_lease),
+                    Err(e) => warn!("Unable to claim lease {}: {}", available_lease, e),
+                }
             }
+            Ok(None) => info!("No lease availabe at this time. Waiting 1 second..."),
+            Err(e) => warn!("Unable to get the startup leases: {}", e),
         };
+        attempts += 1;
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+    }
+
+    Metrics::k8s().add_startup_lease_wait(start);
+    match claimed_lease.as_ref() {
+        Some(lease) => info!(
+            "Claimed lease {} after waiting {:?}",
+            lease.name(),
+            start.elapsed()
+        ),
+        None => warn!(
+            "No startup lease claimed after waiting {:?}, starting anyway",
+            start.elapsed()
+        ),
     }
+    claimed_lease
 }
 
 #[cfg(unix)]
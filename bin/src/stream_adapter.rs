@@ -1,17 +1,30 @@
 use async_trait::async_trait;
 
 use fs::cache::tailed_file::LazyLineSerializer;
-use http::types::body::{Line, LineBuilder, LineMeta};
+use http::types::body::{Line, LineBufferMut, LineBuilder, LineMeta};
 use http::types::serialize::{
     IngestLineSerialize, IngestLineSerializeError, SerializeI64, SerializeMap, SerializeStr,
     SerializeUtf8, SerializeValue,
 };
+use k8s::leader::Checkpoint;
 use state::GetOffset;
 use std::collections::HashMap;
 
 pub(crate) enum StrictOrLazyLineBuilder {
     Strict(LineBuilder),
     Lazy(LazyLineSerializer),
+    /// The line of a k8s event, along with the checkpoint of the event
+    K8sEvent(LineBuilder, Option<Checkpoint>),
+}
+
+impl StrictOrLazyLineBuilder {
+    pub(crate) fn line_buffer_mut(&mut self) -> &mut dyn LineBufferMut {
+        match self {
+            StrictOrLazyLineBuilder::Strict(line) => line,
+            StrictOrLazyLineBuilder::Lazy(line) => line,
+            StrictOrLazyLineBuilder::K8sEvent(line, _) => line,
+        }
+    }
 }
 
 #[allow(clippy::large_enum_variant)]
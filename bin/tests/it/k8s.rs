@@ -328,6 +328,19 @@ async fn create_agent_ds(
                     "create",
                     "watch"
                 ]
+            },
+            {
+                "apiGroups": [
+                    "coordination.k8s.io"
+                ],
+                "resources": [
+                    "leases"
+                ],
+                "verbs": [
+                    "get",
+                    "create",
+                    "update"
+                ]
             }
         ]
     }))
@@ -1054,9 +1067,8 @@ async fn test_k8s_events_logged() {
 async fn test_k8s_startup_lease_functions() {
     let lease_name = "agent-startup-lease";
     let namespace = "default";
-    let pod_name = "agent-pod-name".to_string();
+    let pod_name = "agent-pod-name";
     let lease_label = "process=logdna-agent-startup";
-    let mut claimed_lease_name: Option<String> = None;
     let client = Client::try_default().await.unwrap();
     let lease_client: Api<Lease> = Api::namespaced(client.clone(), namespace);
     let lp = ListParams::default().labels(lease_label);
@@ -1065,20 +1077,38 @@ async fn test_k8s_startup_lease_functions() {
     let lease_list = lease_client.list(&lp).await;
     assert!(lease_list.as_ref().unwrap().iter().count() > 0);
 
-    k8s::lease::release_lease("agent-startup-lease-1", &lease_client).await;
-    let available_lease = k8s::lease::get_available_lease(lease_label, &lease_client).await;
+    k8s::lease::release_lease("agent-startup-lease-1", &lease_client)
+        .await
+        .unwrap();
+    let available_lease = k8s::lease::get_available_lease(lease_label, &lease_client)
+        .await
+        .unwrap();
     assert_eq!(available_lease.as_ref().unwrap(), "agent-startup-lease-1");
-    k8s::lease::claim_lease(
-        available_lease.unwrap(),
-        pod_name,
-        &lease_client,
-        &mut claimed_lease_name,
-    )
-    .await;
-    let available_lease = k8s::lease::get_available_lease(lease_label, &lease_client).await;
+    let claimed_lease_name = available_lease.unwrap();
+    let duration = k8s::lease::claim_lease(&claimed_lease_name, pod_name, &lease_client)
+        .await
+        .unwrap();
+    assert!(duration.is_some());
+    // The lease can't be claimed twice
+    let duration = k8s::lease::claim_lease(&claimed_lease_name, "other-pod", &lease_client)
+        .await
+        .unwrap();
+    assert!(duration.is_none());
+    let available_lease = k8s::lease::get_available_lease(lease_label, &lease_client)
+        .await
+        .unwrap();
     assert_eq!(available_lease.as_ref(), None);
-    k8s::lease::release_lease(&claimed_lease_name.unwrap(), &lease_client).await;
-    let available_lease = k8s::lease::get_available_lease(lease_label, &lease_client).await;
+    assert!(
+        k8s::lease::renew_lease(&claimed_lease_name, pod_name, &lease_client)
+            .await
+            .unwrap()
+    );
+    k8s::lease::release_lease(&claimed_lease_name, &lease_client)
+        .await
+        .unwrap();
+    let available_lease = k8s::lease::get_available_lease(lease_label, &lease_client)
+        .await
+        .unwrap();
     assert_eq!(available_lease.as_ref().unwrap(), "agent-startup-lease-1");
 }
 
@@ -1185,9 +1215,12 @@ async fn test_k8s_startup_leases_always_start() {
 
         tokio::time::sleep(tokio::time::Duration::from_millis(5000)).await;
         log::info!("RELEASE AGENT STARTUP LEASE...");
-        k8s::lease::release_lease("agent-startup-lease-1", &agent_lease_api).await;
-        let available_lease =
-            k8s::lease::get_available_lease(agent_lease_label, &agent_lease_api).await;
+        k8s::lease::release_lease("agent-startup-lease-1", &agent_lease_api)
+            .await
+            .unwrap();
+        let available_lease = k8s::lease::get_available_lease(agent_lease_label, &agent_lease_api)
+            .await
+            .unwrap();
         assert_eq!(available_lease.as_ref().unwrap(), "agent-startup-lease-1");
         tokio::time::sleep(tokio::time::Duration::from_millis(5000)).await;
 
@@ -77,6 +77,11 @@ pub struct ArgumentOptions {
     #[structopt(long = "logdir", short = "d", env = env_vars::LOG_DIRS)]
     log_dirs: Vec<String>,
 
+    /// Adds log files to tail, either paths or glob patterns matched by path component,
+    /// e.g. /srv/*/logs/**/*.log
+    #[structopt(long = "logfile", env = env_vars::LOG_FILES)]
+    log_files: Vec<String>,
+
     /// List of glob patterns to exclude files from monitoring, to add to the default set of
     /// exclusion rules.
     #[structopt(long = "exclude", env = env_vars::EXCLUSION_RULES)]
@@ -100,11 +105,15 @@ pub struct ArgumentOptions {
     #[structopt(long, env = env_vars::JOURNALD_PATHS)]
     journald_paths: Vec<String>,
 
-    /// The lookback strategy on startup ("smallfiles", "start" or "none").
-    /// Defaults to "smallfiles".
+    /// The lookback strategy on startup ("smallfiles", "start", "none" or a duration like
+    /// "2h"). Defaults to "smallfiles".
     #[structopt(long, env = env_vars::LOOKBACK)]
     lookback: Option<Lookback>,
 
+    /// Files not modified for longer than this duration (e.g. "7d") are not tailed.
+    #[structopt(long, env = env_vars::IGNORE_OLDER)]
+    ignore_older: Option<String>,
+
     /// List of tags metadata to attach to lines forwarded from this agent
     #[structopt(long, short, env = env_vars::TAGS)]
     tags: Vec<String>,
@@ -121,6 +130,47 @@ pub struct ArgumentOptions {
     #[structopt(long, env = env_vars::LOG_K8S_EVENTS)]
     log_k8s_events: Option<K8sTrackingConf>,
 
+    /// List of namespaces (globs are supported) of the pods to collect the logs from, the logs of
+    /// the pods in other namespaces are not tailed.
+    #[structopt(long, env = env_vars::K8S_INCLUDE_NAMESPACES)]
+    k8s_include_namespaces: Vec<String>,
+
+    /// List of namespaces (globs are supported) of the pods to ignore the logs from.
+    #[structopt(long, env = env_vars::K8S_EXCLUDE_NAMESPACES)]
+    k8s_exclude_namespaces: Vec<String>,
+
+    /// Label selector requirements (e.g. "app=api", "tier in (web,api)") the pods must match
+    /// for their logs to be collected.
+    #[structopt(long, env = env_vars::K8S_INCLUDE_LABELS)]
+    k8s_include_labels: Vec<String>,
+
+    /// Label selector requirements that exclude the logs of the matching pods.
+    #[structopt(long, env = env_vars::K8S_EXCLUDE_LABELS)]
+    k8s_exclude_labels: Vec<String>,
+
+    /// List of container names (globs are supported) to collect the logs from.
+    #[structopt(long, env = env_vars::K8S_INCLUDE_CONTAINERS)]
+    k8s_include_containers: Vec<String>,
+
+    /// List of container names (globs are supported) to ignore the logs from.
+    #[structopt(long, env = env_vars::K8S_EXCLUDE_CONTAINERS)]
+    k8s_exclude_containers: Vec<String>,
+
+    /// Determines whether the agent should watch the ReplicaSets and Jobs to add the workload
+    /// (e.g. Deployment or CronJob) of the pods to their lines. Defaults to false.
+    #[structopt(long, env = env_vars::K8S_WORKLOAD_ENRICHMENT)]
+    k8s_workload_enrichment: Option<bool>,
+
+    /// List of node label keys (globs are supported) to add to every line, the labels are
+    /// read from the node set in the NODE_NAME env var. Defaults to the topology labels.
+    #[structopt(long, env = env_vars::K8S_NODE_LABELS)]
+    k8s_node_labels: Vec<String>,
+
+    /// Maximum time in milliseconds to hold the lines of a pod that is not known yet while
+    /// its metadata is fetched. Use 0 to ship those lines without waiting. Defaults to 1000.
+    #[structopt(long, env = env_vars::K8S_METADATA_WAIT_MS)]
+    k8s_metadata_wait_ms: Option<u64>,
+
     /// Determine wheather or not to look for available K8s startup leases before attempting
     /// to start the agent; used to throttle startup on very large K8s clusters.
     /// Defaults to "off".
@@ -152,6 +202,119 @@ pub struct ArgumentOptions {
     #[structopt(long, env = env_vars::REDACT)]
     line_redact: Vec<String>,
 
+    /// List of built-in detectors used to mask sensitive information in the log lines:
+    /// credit_card, email, ipv4, ipv6, jwt, aws_key and bearer_token. The replacement strategy
+    /// can be set after the detector name, e.g. "email:hash", it defaults to "constant".
+    #[structopt(long, env = env_vars::REDACT_DETECTORS)]
+    redact_detectors: Vec<String>,
+
+    /// The secret key used by the "hash" and "format_preserving_hash" redaction strategies.
+    #[structopt(long, env = env_vars::REDACT_HASH_KEY)]
+    redact_hash_key: Option<String>,
+
+    /// List of metadata fields to redact, as "target:key_glob" where target is annotations,
+    /// labels or meta (dot separated path of the nested fields),
+    /// e.g. "annotations:kubectl.kubernetes.io/last-applied-configuration".
+    #[structopt(long, env = env_vars::REDACT_FIELDS)]
+    redact_fields: Vec<String>,
+
+    /// List of metadata fields to remove from the log lines, in the same format as the redacted
+    /// fields.
+    #[structopt(long, env = env_vars::DROP_FIELDS)]
+    drop_fields: Vec<String>,
+
+    /// List of regex patterns with named capture groups, the captured values are added to the
+    /// meta of the matching log lines.
+    #[structopt(long, env = env_vars::LINE_EXTRACT)]
+    line_extract: Vec<String>,
+
+    /// Path to a Rhai script that is run for each log line, allowing custom transforms
+    /// of the line and its metadata. The script is reloaded when the file changes.
+    #[structopt(long, env = env_vars::SCRIPT_PATH)]
+    script_path: Option<String>,
+
+    /// The maximum number of operations the line script can perform per line.
+    /// Defaults to 100000.
+    #[structopt(long, env = env_vars::SCRIPT_MAX_OPERATIONS)]
+    script_max_operations: Option<u64>,
+
+    /// The maximum size, in bytes, of the strings created by the line script.
+    /// Defaults to 1048576 (1 MB).
+    #[structopt(long, env = env_vars::SCRIPT_MAX_STRING_SIZE)]
+    script_max_string_size: Option<usize>,
+
+    /// List of paths to WebAssembly plugin modules that are run for each log line, in order.
+    #[structopt(long = "wasm-plugin", env = env_vars::WASM_PLUGINS)]
+    wasm_plugins: Vec<String>,
+
+    /// The amount of fuel (roughly the number of instructions) a WebAssembly plugin can use to
+    /// process a line. Defaults to 1000000.
+    #[structopt(long, env = env_vars::WASM_FUEL)]
+    wasm_fuel: Option<u64>,
+
+    /// The maximum size, in bytes, of the memory of a WebAssembly plugin. Defaults to 67108864.
+    #[structopt(long, env = env_vars::WASM_MAX_MEMORY)]
+    wasm_max_memory: Option<usize>,
+
+    /// Determines whether to detect the level of the log lines from their content, e.g.
+    /// `ERROR`, `[warn]`, `level=info` or a JSON `severity` field. Defaults to "false".
+    #[structopt(long, env = env_vars::LEVEL_DETECTION)]
+    level_detection: Option<bool>,
+
+    /// When level detection is enabled, use the `ERROR` level for the container lines written
+    /// to stderr that have no other level information. Defaults to "false".
+    #[structopt(long, env = env_vars::LEVEL_STDERR_AS_ERROR)]
+    level_stderr_as_error: Option<bool>,
+
+    /// List of minimum levels, the lines with a lower level are not sent. Each value is either
+    /// a level applied to all the apps (e.g. "info") or a level for a specific app
+    /// (e.g. "nginx:warn").
+    #[structopt(long, env = env_vars::MIN_LEVEL)]
+    min_level: Vec<String>,
+
+    /// List of directories watched by periodically polling their files instead of using
+    /// inotify, e.g. for overlay filesystems. The directories on network filesystems (NFS, CIFS,
+    /// FUSE) are always polled.
+    #[structopt(long = "poll-dir", env = env_vars::POLL_DIRS)]
+    poll_dirs: Vec<String>,
+
+    /// The interval, in milliseconds, between two polls of the polled directories.
+    /// Defaults to 1000 ms.
+    #[structopt(long, env = env_vars::POLL_INTERVAL_MS)]
+    poll_interval_ms: Option<u64>,
+
+    /// The time, in milliseconds, after which the handle of a file without new lines is closed,
+    /// it is reopened on its next write. The files are kept open when unset or 0.
+    #[structopt(long, env = env_vars::FILE_IDLE_TIMEOUT_MS)]
+    file_idle_timeout_ms: Option<u64>,
+
+    /// The maximum number of files kept open, the least recently read files are closed first.
+    /// Unlimited by default.
+    #[structopt(long, env = env_vars::MAX_OPEN_FILES)]
+    max_open_files: Option<usize>,
+
+    /// The maximum number of bytes read again from the end of a truncated file, e.g. rotated
+    /// with copytruncate. The whole file is read again by default.
+    #[structopt(long, env = env_vars::TRUNCATE_REREAD_LIMIT)]
+    truncate_reread_limit: Option<u64>,
+
+    /// List of encodings of the files matching a glob pattern, e.g.
+    /// "/var/log/legacy/*.log:latin1". Used for the files without a byte order mark, the other
+    /// files are read as UTF-8.
+    #[structopt(long, env = env_vars::FILE_ENCODINGS)]
+    file_encodings: Vec<String>,
+
+    /// The maximum length in bytes of the lines read from the files, the longer lines are
+    /// handled according to `long_lines`. Unlimited by default.
+    #[structopt(long, env = env_vars::MAX_LINE_LENGTH)]
+    max_line_length: Option<usize>,
+
+    /// What is done with the lines longer than `max_line_length`: "truncate" sends their
+    /// start flagged with `truncated` in their meta, "split" sends them in chunks and "skip"
+    /// drops them. Defaults to "truncate".
+    #[structopt(long, env = env_vars::LONG_LINES)]
+    long_lines: Option<String>,
+
     /// Show the current agent settings from the configuration sources (default config file
     /// and environment variables).
     #[structopt(short = "l", long = "list")]
@@ -261,6 +424,13 @@ impl ArgumentOptions {
                 .for_each(|v| raw.log.dirs.push(PathBuf::from(v)));
         }
 
+        if !self.log_files.is_empty() {
+            let files = raw.log.files.get_or_insert(Vec::new());
+            with_csv(self.log_files)
+                .iter()
+                .for_each(|v| files.push(v.clone()));
+        }
+
         if let Some(ref p) = self.db_path {
             raw.log.db_path = Some(p.into())
         }
@@ -291,6 +461,10 @@ impl ArgumentOptions {
             raw.log.lookback = self.lookback.map(|v| v.to_string());
         }
 
+        if self.ignore_older.is_some() {
+            raw.log.ignore_older = self.ignore_older;
+        }
+
         if self.use_k8s_enrichment.is_some() {
             raw.log.use_k8s_enrichment = self.use_k8s_enrichment.map(|v| v.to_string());
         }
@@ -299,6 +473,63 @@ impl ArgumentOptions {
             raw.log.log_k8s_events = self.log_k8s_events.map(|v| v.to_string());
         }
 
+        if !self.k8s_include_namespaces.is_empty() {
+            let values = raw.log.k8s_include_namespaces.get_or_insert(Vec::new());
+            with_csv(self.k8s_include_namespaces)
+                .iter()
+                .for_each(|v| values.push(v.clone()));
+        }
+
+        if !self.k8s_exclude_namespaces.is_empty() {
+            let values = raw.log.k8s_exclude_namespaces.get_or_insert(Vec::new());
+            with_csv(self.k8s_exclude_namespaces)
+                .iter()
+                .for_each(|v| values.push(v.clone()));
+        }
+
+        if !self.k8s_include_labels.is_empty() {
+            let values = raw.log.k8s_include_labels.get_or_insert(Vec::new());
+            with_csv(self.k8s_include_labels)
+                .iter()
+                .for_each(|v| values.push(v.clone()));
+        }
+
+        if !self.k8s_exclude_labels.is_empty() {
+            let values = raw.log.k8s_exclude_labels.get_or_insert(Vec::new());
+            with_csv(self.k8s_exclude_labels)
+                .iter()
+                .for_each(|v| values.push(v.clone()));
+        }
+
+        if !self.k8s_include_containers.is_empty() {
+            let values = raw.log.k8s_include_containers.get_or_insert(Vec::new());
+            with_csv(self.k8s_include_containers)
+                .iter()
+                .for_each(|v| values.push(v.clone()));
+        }
+
+        if !self.k8s_exclude_containers.is_empty() {
+            let values = raw.log.k8s_exclude_containers.get_or_insert(Vec::new());
+            with_csv(self.k8s_exclude_containers)
+                .iter()
+                .for_each(|v| values.push(v.clone()));
+        }
+
+        if self.k8s_workload_enrichment.is_some() {
+            raw.log.k8s_workload_enrichment = self.k8s_workload_enrichment;
+        }
+
+        if !self.k8s_node_labels.is_empty() {
+            let values = raw.log.k8s_node_labels.get_or_insert(Vec::new());
+            with_csv(self.k8s_node_labels)
+                .iter()
+                .for_each(|v| values.push(v.clone()));
+        }
+
+        if self.k8s_metadata_wait_ms.is_some() {
+            raw.log.k8s_metadata_wait_ms = self.k8s_metadata_wait_ms;
+        }
+
         if self.k8s_startup_lease.is_some() {
             raw.startup.option = self.k8s_startup_lease;
         }
@@ -328,6 +559,118 @@ impl ArgumentOptions {
                 .for_each(|v| regex.push(v.clone()));
         }
 
+        if !self.redact_detectors.is_empty() {
+            let detectors = raw.log.redact_detectors.get_or_insert(Vec::new());
+            with_csv(self.redact_detectors)
+                .iter()
+                .for_each(|v| detectors.push(v.clone()));
+        }
+
+        if self.redact_hash_key.is_some() {
+            raw.log.redact_hash_key = self.redact_hash_key;
+        }
+
+        if !self.redact_fields.is_empty() {
+            let fields = raw.log.redact_fields.get_or_insert(Vec::new());
+            with_csv(self.redact_fields)
+                .iter()
+                .for_each(|v| fields.push(v.clone()));
+        }
+
+        if !self.drop_fields.is_empty() {
+            let fields = raw.log.drop_fields.get_or_insert(Vec::new());
+            with_csv(self.drop_fields)
+                .iter()
+                .for_each(|v| fields.push(v.clone()));
+        }
+
+        if !self.line_extract.is_empty() {
+            let regex = raw.log.line_extract_regex.get_or_insert(Vec::new());
+            with_csv(self.line_extract)
+                .iter()
+                .for_each(|v| regex.push(v.clone()));
+        }
+
+        if self.script_path.is_some() {
+            raw.log.script_path = self.script_path.map(PathBuf::from);
+        }
+
+        if self.script_max_operations.is_some() {
+            raw.log.script_max_operations = self.script_max_operations;
+        }
+
+        if self.script_max_string_size.is_some() {
+            raw.log.script_max_string_size = self.script_max_string_size;
+        }
+
+        if !self.wasm_plugins.is_empty() {
+            let paths = raw.log.wasm_plugins.get_or_insert(Vec::new());
+            with_csv(self.wasm_plugins)
+                .iter()
+                .for_each(|v| paths.push(PathBuf::from(v)));
+        }
+
+        if self.wasm_fuel.is_some() {
+            raw.log.wasm_fuel = self.wasm_fuel;
+        }
+
+        if self.wasm_max_memory.is_some() {
+            raw.log.wasm_max_memory = self.wasm_max_memory;
+        }
+
+        if self.level_detection.is_some() {
+            raw.log.level_detection = self.level_detection;
+        }
+
+        if self.level_stderr_as_error.is_some() {
+            raw.log.level_stderr_as_error = self.level_stderr_as_error;
+        }
+
+        if !self.min_level.is_empty() {
+            let levels = raw.log.min_level.get_or_insert(Vec::new());
+            with_csv(self.min_level)
+                .iter()
+                .for_each(|v| levels.push(v.clone()));
+        }
+
+        if !self.poll_dirs.is_empty() {
+            let paths = raw.log.poll_dirs.get_or_insert(Vec::new());
+            with_csv(self.poll_dirs)
+                .iter()
+                .for_each(|v| paths.push(PathBuf::from(v)));
+        }
+
+        if self.poll_interval_ms.is_some() {
+            raw.log.poll_interval_ms = self.poll_interval_ms;
+        }
+
+        if self.file_idle_timeout_ms.is_some() {
+            raw.log.file_idle_timeout_ms = self.file_idle_timeout_ms;
+        }
+
+        if self.max_open_files.is_some() {
+            raw.log.max_open_files = self.max_open_files;
+        }
+
+        if self.truncate_reread_limit.is_some() {
+            raw.log.truncate_reread_limit = self.truncate_reread_limit;
+        }
+
+        if !self.file_encodings.is_empty() {
+            let encodings = raw.log.file_encodings.get_or_insert(Vec::new());
+            with_csv(self.file_encodings)
+                .iter()
+                .for_each(|v| encodings.push(v.clone()));
+        }
+
+        if self.max_line_length.is_some() {
+            raw.log.max_line_length = self.max_line_length;
+        }
+
+        if self.long_lines.is_some() {
+            raw.log.long_lines = self.long_lines;
+        }
+
         raw
     }
 
@@ -602,6 +945,7 @@ mod test {
     fn merge_should_override_values_from_config_file() {
         let argv = ArgumentOptions {
             log_dirs: vec_strings!("/my/path", "/my/other/path"),
+            log_files: vec_strings!("/srv/*/logs/**/*.log,/var/app/out.txt"),
             os_hostname: some_string!("my_host"),
             host: some_string!("server_host"),
             endpoint_path: some_string!("/path/to/endpoint"),
@@ -614,6 +958,7 @@ mod test {
             metrics_port: Some(9089),
             tags: vec_strings!("a", "b"),
             lookback: Some(Lookback::Start),
+            ignore_older: some_string!("7d"),
             use_k8s_enrichment: Some(K8sTrackingConf::Always),
             log_k8s_events: Some(K8sTrackingConf::Never),
             journald_paths: vec_strings!("/a"),
@@ -622,6 +967,31 @@ mod test {
             ingest_buffer_size: Some(222222),
             retry_dir: some_string!("/tmp/argv"),
             retry_disk_limit: Some(Bytes::new(123456, Unit::Byte).unwrap()),
+            script_path: some_string!("/etc/logdna/script.rhai"),
+            script_max_operations: Some(5000),
+            script_max_string_size: Some(4096),
+            wasm_plugins: vec_strings!("/a.wasm,/b.wasm"),
+            wasm_fuel: Some(300),
+            wasm_max_memory: Some(1048576),
+            level_detection: Some(true),
+            level_stderr_as_error: Some(false),
+            min_level: vec_strings!("info,nginx:warn"),
+            k8s_exclude_namespaces: vec_strings!("kube-system, monitoring"),
+            k8s_include_labels: vec_strings!("app=api"),
+            k8s_exclude_containers: vec_strings!("istio-proxy"),
+            k8s_workload_enrichment: Some(false),
+            k8s_node_labels: vec_strings!(
+                "topology.kubernetes.io/*,node.kubernetes.io/instance-type"
+            ),
+            k8s_metadata_wait_ms: Some(500),
+            poll_dirs: vec_strings!("/mnt/nfs,/var/lib/overlay"),
+            poll_interval_ms: Some(2000),
+            file_idle_timeout_ms: Some(60000),
+            max_open_files: Some(512),
+            truncate_reread_limit: Some(1048576),
+            file_encodings: vec_strings!("/var/log/legacy/*.log:latin1,C:/logs/*.log:utf-16le"),
+            max_line_length: Some(32768),
+            long_lines: some_string!("split"),
             ..ArgumentOptions::default()
         };
         let config = argv.merge(RawConfig::default());
@@ -644,12 +1014,70 @@ mod test {
             vec_paths!["/var/log", "/my/path", "/my/other/path"]
         );
         assert_eq!(config.log.lookback, some_string!("start"));
+        assert_eq!(config.log.ignore_older, some_string!("7d"));
         assert_eq!(config.log.use_k8s_enrichment, some_string!("always"));
         assert_eq!(config.log.log_k8s_events, some_string!("never"));
         assert_eq!(config.log.db_path, Some(PathBuf::from("a/b/c")));
         assert_eq!(config.log.metrics_port, Some(9089));
         assert_eq!(config.journald.paths, Some(vec_paths!["/a"]));
         assert_eq!(config.startup.option, Some(String::from("teston")));
+        assert_eq!(
+            config.log.script_path,
+            Some(PathBuf::from("/etc/logdna/script.rhai"))
+        );
+        assert_eq!(config.log.script_max_operations, Some(5000));
+        assert_eq!(config.log.script_max_string_size, Some(4096));
+        assert_eq!(
+            config.log.wasm_plugins,
+            Some(vec_paths!["/a.wasm", "/b.wasm"])
+        );
+        assert_eq!(config.log.wasm_fuel, Some(300));
+        assert_eq!(config.log.wasm_max_memory, Some(1048576));
+        assert_eq!(config.log.level_detection, Some(true));
+        assert_eq!(config.log.level_stderr_as_error, Some(false));
+        assert_eq!(
+            config.log.min_level,
+            Some(vec_strings!("info", "nginx:warn"))
+        );
+        assert_eq!(
+            config.log.k8s_exclude_namespaces,
+            Some(vec_strings!("kube-system", "monitoring"))
+        );
+        assert_eq!(config.log.k8s_include_labels, Some(vec_strings!("app=api")));
+        assert_eq!(
+            config.log.k8s_exclude_containers,
+            Some(vec_strings!("istio-proxy"))
+        );
+        assert_eq!(config.log.k8s_workload_enrichment, Some(false));
+        assert_eq!(
+            config.log.k8s_node_labels,
+            Some(vec_strings!(
+                "topology.kubernetes.io/*",
+                "node.kubernetes.io/instance-type"
+            ))
+        );
+        assert_eq!(config.log.k8s_metadata_wait_ms, Some(500));
+        assert_eq!(
+            config.log.poll_dirs,
+            Some(vec_paths!["/mnt/nfs", "/var/lib/overlay"])
+        );
+        assert_eq!(config.log.poll_interval_ms, Some(2000));
+        assert_eq!(config.log.file_idle_timeout_ms, Some(60000));
+        assert_eq!(config.log.max_open_files, Some(512));
+        assert_eq!(config.log.truncate_reread_limit, Some(1048576));
+        assert_eq!(
+            config.log.file_encodings,
+            Some(vec_strings!(
+                "/var/log/legacy/*.log:latin1",
+                "C:/logs/*.log:utf-16le"
+            ))
+        );
+        assert_eq!(config.log.max_line_length, Some(32768));
+        assert_eq!(config.log.long_lines, some_string!("split"));
+        assert_eq!(
+            config.log.files,
+            Some(vec_strings!("/srv/*/logs/**/*.log", "/var/app/out.txt"))
+        );
     }
 
     #[test]
@@ -706,6 +1134,11 @@ mod test {
             line_exclusion: vec_strings!["d,e, f"],
             line_inclusion: vec_strings![" g, h, i"],
             line_redact: vec_strings![r"j\,k,l"],
+            line_extract: vec_strings![r"id=(?P<id>\d+)"],
+            redact_detectors: vec_strings!["email:hash, jwt"],
+            redact_hash_key: some_string!("secret"),
+            redact_fields: vec_strings!["meta:*.password"],
+            drop_fields: vec_strings!["annotations:a/*,labels:b"],
             ..ArgumentOptions::default()
         };
         let config = argv.merge(RawConfig::default());
@@ -719,6 +1152,23 @@ mod test {
             Some(vec_strings!["g", "h", "i"])
         );
         assert_eq!(config.log.line_redact_regex, Some(vec_strings!["j,k", "l"]));
+        assert_eq!(
+            config.log.line_extract_regex,
+            Some(vec_strings![r"id=(?P<id>\d+)"])
+        );
+        assert_eq!(
+            config.log.redact_detectors,
+            Some(vec_strings!["email:hash", "jwt"])
+        );
+        assert_eq!(config.log.redact_hash_key, some_string!("secret"));
+        assert_eq!(
+            config.log.redact_fields,
+            Some(vec_strings!["meta:*.password"])
+        );
+        assert_eq!(
+            config.log.drop_fields,
+            Some(vec_strings!["annotations:a/*", "labels:b"])
+        );
     }
 
     #[test]
@@ -1,6 +1,7 @@
 pub const INGESTION_KEY: &str = "MZ_INGESTION_KEY";
 pub const CONFIG_FILE: &str = "MZ_CONFIG_FILE";
 pub const LOG_DIRS: &str = "MZ_LOG_DIRS";
+pub const LOG_FILES: &str = "MZ_LOG_FILES";
 pub const TAGS: &str = "MZ_TAGS";
 pub const HOST: &str = "MZ_HOST";
 pub const ENDPOINT: &str = "MZ_ENDPOINT";
@@ -16,6 +17,7 @@ pub const IP: &str = "MZ_IP";
 pub const MAC: &str = "MZ_MAC";
 pub const JOURNALD_PATHS: &str = "MZ_JOURNALD_PATHS";
 pub const LOOKBACK: &str = "MZ_LOOKBACK";
+pub const IGNORE_OLDER: &str = "MZ_IGNORE_OLDER";
 pub const DB_PATH: &str = "MZ_DB_PATH";
 pub const METRICS_PORT: &str = "MZ_METRICS_PORT";
 pub const USE_K8S_LOG_ENRICHMENT: &str = "MZ_USE_K8S_LOG_ENRICHMENT";
@@ -24,10 +26,41 @@ pub const K8S_STARTUP_LEASE: &str = "MZ_K8S_STARTUP_LEASE";
 pub const LINE_EXCLUSION: &str = "MZ_LINE_EXCLUSION_REGEX";
 pub const LINE_INCLUSION: &str = "MZ_LINE_INCLUSION_REGEX";
 pub const REDACT: &str = "MZ_REDACT_REGEX";
+pub const REDACT_DETECTORS: &str = "MZ_REDACT_DETECTORS";
+pub const REDACT_HASH_KEY: &str = "MZ_REDACT_HASH_KEY";
+pub const REDACT_FIELDS: &str = "MZ_REDACT_FIELDS";
+pub const DROP_FIELDS: &str = "MZ_DROP_FIELDS";
+pub const LINE_EXTRACT: &str = "MZ_LINE_EXTRACT_REGEX";
 pub const INGEST_TIMEOUT: &str = "MZ_INGEST_TIMEOUT";
 pub const INGEST_BUFFER_SIZE: &str = "MZ_INGEST_BUFFER_SIZE";
 pub const RETRY_DIR: &str = "MZ_RETRY_DIR";
 pub const RETRY_DISK_LIMIT: &str = "MZ_RETRY_DISK_LIMIT";
+pub const SCRIPT_PATH: &str = "MZ_SCRIPT_PATH";
+pub const SCRIPT_MAX_OPERATIONS: &str = "MZ_SCRIPT_MAX_OPERATIONS";
+pub const SCRIPT_MAX_STRING_SIZE: &str = "MZ_SCRIPT_MAX_STRING_SIZE";
+pub const WASM_PLUGINS: &str = "MZ_WASM_PLUGINS";
+pub const WASM_FUEL: &str = "MZ_WASM_FUEL";
+pub const WASM_MAX_MEMORY: &str = "MZ_WASM_MAX_MEMORY";
+pub const LEVEL_DETECTION: &str = "MZ_LEVEL_DETECTION";
+pub const LEVEL_STDERR_AS_ERROR: &str = "MZ_LEVEL_STDERR_AS_ERROR";
+pub const MIN_LEVEL: &str = "MZ_MIN_LEVEL";
+pub const K8S_INCLUDE_NAMESPACES: &str = "MZ_K8S_INCLUDE_NAMESPACES";
+pub const K8S_EXCLUDE_NAMESPACES: &str = "MZ_K8S_EXCLUDE_NAMESPACES";
+pub const K8S_INCLUDE_LABELS: &str = "MZ_K8S_INCLUDE_LABELS";
+pub const K8S_EXCLUDE_LABELS: &str = "MZ_K8S_EXCLUDE_LABELS";
+pub const K8S_INCLUDE_CONTAINERS: &str = "MZ_K8S_INCLUDE_CONTAINERS";
+pub const K8S_EXCLUDE_CONTAINERS: &str = "MZ_K8S_EXCLUDE_CONTAINERS";
+pub const K8S_WORKLOAD_ENRICHMENT: &str = "MZ_K8S_WORKLOAD_ENRICHMENT";
+pub const K8S_NODE_LABELS: &str = "MZ_K8S_NODE_LABELS";
+pub const K8S_METADATA_WAIT_MS: &str = "MZ_K8S_METADATA_WAIT_MS";
+pub const POLL_DIRS: &str = "MZ_POLL_DIRS";
+pub const POLL_INTERVAL_MS: &str = "MZ_POLL_INTERVAL_MS";
+pub const FILE_IDLE_TIMEOUT_MS: &str = "MZ_FILE_IDLE_TIMEOUT_MS";
+pub const MAX_OPEN_FILES: &str = "MZ_MAX_OPEN_FILES";
+pub const TRUNCATE_REREAD_LIMIT: &str = "MZ_TRUNCATE_REREAD_LIMIT";
+pub const FILE_ENCODINGS: &str = "MZ_FILE_ENCODINGS";
+pub const MAX_LINE_LENGTH: &str = "MZ_MAX_LINE_LENGTH";
+pub const LONG_LINES: &str = "MZ_LONG_LINES";
 
 // unused or deprecated
 pub const INGESTION_KEY_ALTERNATE: &str = "LOGDNA_AGENT_KEY";
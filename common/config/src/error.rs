@@ -13,7 +13,12 @@ pub enum ConfigError {
     Glob(globber::Error),
     Regex(fs::rule::RuleError),
     NotADirectory(fs::cache::DirPathBufError),
+    FileTarget(fs::cache::ParseFileTargetError),
     Lookback(fs::lookback::ParseLookbackError),
+    Encoding(fs::encoding::ParseEncodingError),
+    LongLines(fs::cache::ParseLongLinesError),
+    LogLevel(crate::ParseLogLevel),
+    RedactRule(crate::raw::ParseRedactRuleError),
 }
 
 impl Display for ConfigError {
@@ -36,7 +41,12 @@ impl Display for ConfigError {
             ConfigError::Glob(e) => write!(f, "{}", e),
             ConfigError::Regex(e) => write!(f, "{}", e),
             ConfigError::NotADirectory(e) => write!(f, "{}", e),
+            ConfigError::FileTarget(e) => write!(f, "{}", e),
             ConfigError::Lookback(e) => write!(f, "{}", e),
+            ConfigError::Encoding(e) => write!(f, "{}", e),
+            ConfigError::LongLines(e) => write!(f, "{}", e),
+            ConfigError::LogLevel(e) => write!(f, "{}", e),
+            ConfigError::RedactRule(e) => write!(f, "{}", e),
         }
     }
 }
@@ -77,8 +87,38 @@ impl From<fs::cache::DirPathBufError> for ConfigError {
     }
 }
 
+impl From<fs::cache::ParseFileTargetError> for ConfigError {
+    fn from(e: fs::cache::ParseFileTargetError) -> Self {
+        ConfigError::FileTarget(e)
+    }
+}
+
 impl From<fs::lookback::ParseLookbackError> for ConfigError {
     fn from(e: fs::lookback::ParseLookbackError) -> Self {
         ConfigError::Lookback(e)
     }
 }
+
+impl From<fs::encoding::ParseEncodingError> for ConfigError {
+    fn from(e: fs::encoding::ParseEncodingError) -> Self {
+        ConfigError::Encoding(e)
+    }
+}
+
+impl From<fs::cache::ParseLongLinesError> for ConfigError {
+    fn from(e: fs::cache::ParseLongLinesError) -> Self {
+        ConfigError::LongLines(e)
+    }
+}
+
+impl From<crate::ParseLogLevel> for ConfigError {
+    fn from(e: crate::ParseLogLevel) -> Self {
+        ConfigError::LogLevel(e)
+    }
+}
+
+impl From<crate::raw::ParseRedactRuleError> for ConfigError {
+    fn from(e: crate::raw::ParseRedactRuleError) -> Self {
+        ConfigError::RedactRule(e)
+    }
+}
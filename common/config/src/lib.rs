@@ -2,6 +2,7 @@
 extern crate log;
 extern crate humanize_rs;
 
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::ffi::OsString;
 use std::fmt;
@@ -13,14 +14,19 @@ use sysinfo::{RefreshKind, System, SystemExt};
 
 use async_compression::Level;
 
-use fs::lookback::Lookback;
-use fs::rule::{RuleDef, Rules};
+use fs::cache::{FileTarget, OpenFilesConfig, ReadConfig, WatchConfig, DEFAULT_POLL_INTERVAL};
+use fs::lookback::{parse_duration, Lookback};
+use fs::rule::{DirRules, RuleDef, Rules};
 use fs::tail::DirPathBuf;
 use http::types::request::{Encoding, RequestTemplate, Schema};
 
 use crate::argv::ArgumentOptions;
 use crate::error::ConfigError;
 use crate::raw::Config as RawConfig;
+pub use crate::raw::{
+    ExtractRule, ExtractTarget, ExtractType, FieldAction, FieldRule, FieldTarget, K8sWatchRule,
+    MetricRule, MetricRuleType, RedactDetector, RedactRule, RedactStrategy,
+};
 
 mod argv;
 pub mod env_vars;
@@ -130,15 +136,151 @@ impl std::str::FromStr for K8sTrackingConf {
 #[derive(Debug)]
 pub struct LogConfig {
     pub dirs: Vec<DirPathBuf>,
+    pub files: Vec<FileTarget>,
     pub db_path: DbPath,
     pub metrics_port: Option<u16>,
     pub rules: Rules,
+    pub dir_rules: Vec<DirRules>,
+    pub dir_meta: Vec<DirMeta>,
     pub line_exclusion_regex: Vec<String>,
     pub line_inclusion_regex: Vec<String>,
     pub line_redact_regex: Vec<String>,
+    pub redact_rules: Vec<RedactRule>,
+    pub redact_hash_key: Option<String>,
+    pub field_rules: Vec<FieldRule>,
+    pub line_extract_rules: Vec<ExtractRule>,
     pub lookback: Lookback,
+    pub ignore_older: Option<Duration>,
     pub use_k8s_enrichment: K8sTrackingConf,
     pub log_k8s_events: K8sTrackingConf,
+    pub metric_rules: Vec<MetricRule>,
+    pub script: Option<ScriptConfig>,
+    pub wasm_plugins: Vec<PathBuf>,
+    pub wasm_fuel: u64,
+    pub wasm_max_memory: usize,
+    pub level: LevelConfig,
+    pub k8s_selection: K8sSelectionConfig,
+    pub k8s_workload_enrichment: bool,
+    pub k8s_node_labels: Vec<String>,
+    pub k8s_metadata_wait: Duration,
+    pub k8s_watch: Vec<K8sWatchRule>,
+    pub watch: WatchConfig,
+    pub open_files: OpenFilesConfig,
+    pub read: ReadConfig,
+}
+
+/// The app, env and tags of the lines of the files under a directory
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirMeta {
+    pub dir: PathBuf,
+    pub app: Option<String>,
+    pub env: Option<String>,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScriptConfig {
+    pub path: PathBuf,
+    pub max_operations: u64,
+    pub max_string_size: usize,
+}
+
+/// Level of a log line, ordered by severity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Notice,
+    Warn,
+    Error,
+    Critical,
+    Alert,
+    Emergency,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                LogLevel::Trace => "TRACE",
+                LogLevel::Debug => "DEBUG",
+                LogLevel::Info => "INFO",
+                LogLevel::Notice => "NOTICE",
+                LogLevel::Warn => "WARN",
+                LogLevel::Error => "ERROR",
+                LogLevel::Critical => "CRITICAL",
+                LogLevel::Alert => "ALERT",
+                LogLevel::Emergency => "EMERGENCY",
+            }
+        )
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+pub struct ParseLogLevel(String);
+
+impl std::str::FromStr for LogLevel {
+    type Err = ParseLogLevel;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "trace" | "trc" => Ok(LogLevel::Trace),
+            "debug" | "dbg" => Ok(LogLevel::Debug),
+            "info" | "information" | "informational" => Ok(LogLevel::Info),
+            "notice" => Ok(LogLevel::Notice),
+            "warn" | "warning" => Ok(LogLevel::Warn),
+            "error" | "err" => Ok(LogLevel::Error),
+            "critical" | "crit" | "fatal" => Ok(LogLevel::Critical),
+            "alert" => Ok(LogLevel::Alert),
+            "emergency" | "emerg" | "panic" => Ok(LogLevel::Emergency),
+            _ => Err(ParseLogLevel(format!("unknown log level {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LevelConfig {
+    /// Detect the level of the lines that don't have one from their content
+    pub detection: bool,
+    /// Use the error level for the container lines written to stderr
+    pub stderr_as_error: bool,
+    /// Lines with a lower level are not sent
+    pub min_level: Option<LogLevel>,
+    /// Overrides `min_level` for the lines of specific apps
+    pub min_level_by_app: HashMap<String, LogLevel>,
+}
+
+impl LevelConfig {
+    /// Parses the list of minimum levels, each value is either a level or `app:level`.
+    fn parse_min_levels(&mut self, values: &[String]) -> Result<(), ParseLogLevel> {
+        for value in values {
+            match value.rsplit_once(':') {
+                Some((app, level)) => {
+                    self.min_level_by_app
+                        .insert(app.trim().to_string(), level.parse()?);
+                }
+                None => self.min_level = Some(value.parse()?),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Selects the k8s pods to collect the logs from, an empty list doesn't filter.
+#[derive(Debug, Clone, Default)]
+pub struct K8sSelectionConfig {
+    pub include_namespaces: Vec<String>,
+    pub exclude_namespaces: Vec<String>,
+    /// Label selector requirements, all of them must match
+    pub include_labels: Vec<String>,
+    /// Label selector requirements, the pods matching all of them are excluded
+    pub exclude_labels: Vec<String>,
+    pub include_containers: Vec<String>,
+    pub exclude_containers: Vec<String>,
 }
 
 #[derive(Debug)]
@@ -154,6 +296,16 @@ pub struct K8sStartupLeaseConfig {
 const LOGDNA_PREFIX: &str = "LOGDNA_";
 const MEZMO_PREFIX: &str = "MZ_";
 
+/// Node labels describing the topology and the machine, added to the lines by default
+const DEFAULT_K8S_NODE_LABELS: &[&str] = &[
+    "topology.kubernetes.io/region",
+    "topology.kubernetes.io/zone",
+    "node.kubernetes.io/instance-type",
+    "cloud.google.com/gke-nodepool",
+    "eks.amazonaws.com/nodegroup",
+    "kubernetes.azure.com/agentpool",
+];
+
 impl Config {
     pub fn new<I>(args: I) -> Result<Self, ConfigError>
     where
@@ -183,6 +335,9 @@ impl Config {
         if let Some(ref mut key) = tmp_config.http.ingestion_key {
             *key = "REDACTED".to_string();
         }
+        if let Some(ref mut key) = tmp_config.log.redact_hash_key {
+            *key = "REDACTED".to_string();
+        }
 
         let yaml_str = match serde_yaml::to_string(&tmp_config) {
             Ok(v) => v,
@@ -319,6 +474,8 @@ impl TryFrom<RawConfig> for Config {
             require_ssl: raw.http.use_ssl.unwrap_or(true),
         };
 
+        let script_max_operations = raw.log.script_max_operations;
+        let script_max_string_size = raw.log.script_max_string_size;
         let mut log = LogConfig {
             dirs: raw
                 .log
@@ -334,17 +491,70 @@ impl TryFrom<RawConfig> for Config {
                         .ok()
                 })
                 .collect(),
+            files: raw
+                .log
+                .files
+                .unwrap_or_default()
+                .iter()
+                .map(|file| file.parse())
+                .collect::<Result<_, _>>()?,
             db_path: DbPath::from(raw.log.db_path),
             metrics_port: raw.log.metrics_port,
             rules: Rules::new(),
+            dir_rules: Vec::new(),
+            dir_meta: Vec::new(),
             line_exclusion_regex: raw.log.line_exclusion_regex.unwrap_or_default(),
             line_inclusion_regex: raw.log.line_inclusion_regex.unwrap_or_default(),
             line_redact_regex: raw.log.line_redact_regex.unwrap_or_default(),
+            redact_rules: raw
+                .log
+                .redact_detectors
+                .unwrap_or_default()
+                .iter()
+                .map(|d| d.parse::<RedactRule>())
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .chain(raw.log.redact_rules.unwrap_or_default())
+                .collect(),
+            redact_hash_key: raw.log.redact_hash_key,
+            field_rules: raw
+                .log
+                .redact_fields
+                .unwrap_or_default()
+                .iter()
+                .map(|f| FieldRule::parse(f, FieldAction::Redact))
+                .chain(
+                    raw.log
+                        .drop_fields
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|f| FieldRule::parse(f, FieldAction::Drop)),
+                )
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .chain(raw.log.field_rules.unwrap_or_default())
+                .collect(),
+            line_extract_rules: raw
+                .log
+                .line_extract_regex
+                .unwrap_or_default()
+                .into_iter()
+                .map(|regex| ExtractRule {
+                    regex,
+                    ..Default::default()
+                })
+                .chain(raw.log.line_extract_rules.unwrap_or_default())
+                .collect(),
             lookback: raw
                 .log
                 .lookback
                 .map(|s| s.parse::<Lookback>())
                 .unwrap_or_else(|| Ok(Lookback::default()))?,
+            ignore_older: raw
+                .log
+                .ignore_older
+                .map(|s| parse_duration(&s))
+                .transpose()?,
             use_k8s_enrichment: parse_k8s_tracking_or_warn(
                 raw.log.use_k8s_enrichment,
                 env_vars::USE_K8S_LOG_ENRICHMENT,
@@ -355,8 +565,76 @@ impl TryFrom<RawConfig> for Config {
                 env_vars::LOG_K8S_EVENTS,
                 K8sTrackingConf::Never,
             ),
+            metric_rules: raw.log.metric_rules.unwrap_or_default(),
+            script: raw.log.script_path.map(|path| ScriptConfig {
+                path,
+                max_operations: script_max_operations.unwrap_or(100_000),
+                max_string_size: script_max_string_size.unwrap_or(1024 * 1024),
+            }),
+            wasm_plugins: raw.log.wasm_plugins.unwrap_or_default(),
+            wasm_fuel: raw.log.wasm_fuel.unwrap_or(1_000_000),
+            wasm_max_memory: raw.log.wasm_max_memory.unwrap_or(64 * 1024 * 1024),
+            level: LevelConfig {
+                detection: raw.log.level_detection.unwrap_or(false),
+                stderr_as_error: raw.log.level_stderr_as_error.unwrap_or(false),
+                ..Default::default()
+            },
+            k8s_selection: K8sSelectionConfig {
+                include_namespaces: raw.log.k8s_include_namespaces.unwrap_or_default(),
+                exclude_namespaces: raw.log.k8s_exclude_namespaces.unwrap_or_default(),
+                include_labels: raw.log.k8s_include_labels.unwrap_or_default(),
+                exclude_labels: raw.log.k8s_exclude_labels.unwrap_or_default(),
+                include_containers: raw.log.k8s_include_containers.unwrap_or_default(),
+                exclude_containers: raw.log.k8s_exclude_containers.unwrap_or_default(),
+            },
+            k8s_workload_enrichment: raw.log.k8s_workload_enrichment.unwrap_or(false),
+            k8s_node_labels: raw.log.k8s_node_labels.unwrap_or_else(|| {
+                DEFAULT_K8S_NODE_LABELS
+                    .iter()
+                    .map(|l| l.to_string())
+                    .collect()
+            }),
+            k8s_metadata_wait: Duration::from_millis(raw.log.k8s_metadata_wait_ms.unwrap_or(1_000)),
+            k8s_watch: raw.log.k8s_watch.unwrap_or_default(),
+            watch: WatchConfig {
+                poll_dirs: raw.log.poll_dirs.unwrap_or_default(),
+                poll_interval: raw
+                    .log
+                    .poll_interval_ms
+                    .filter(|ms| *ms > 0)
+                    .map_or(DEFAULT_POLL_INTERVAL, Duration::from_millis),
+            },
+            open_files: OpenFilesConfig {
+                idle_timeout: raw
+                    .log
+                    .file_idle_timeout_ms
+                    .filter(|ms| *ms > 0)
+                    .map(Duration::from_millis),
+                max_open: raw.log.max_open_files.filter(|max| *max > 0),
+            },
+            read: ReadConfig {
+                truncate_reread_limit: raw.log.truncate_reread_limit,
+                encodings: raw
+                    .log
+                    .file_encodings
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|value| value.parse())
+                    .collect::<Result<_, _>>()?,
+                max_line_len: raw.log.max_line_length.filter(|len| *len > 0),
+                long_lines: raw
+                    .log
+                    .long_lines
+                    .as_deref()
+                    .map(str::parse)
+                    .transpose()?
+                    .unwrap_or_default(),
+            },
         };
 
+        log.level
+            .parse_min_levels(&raw.log.min_level.unwrap_or_default())?;
+
         if log.use_k8s_enrichment == K8sTrackingConf::Never
             && log.log_k8s_events == K8sTrackingConf::Always
         {
@@ -368,24 +646,74 @@ impl TryFrom<RawConfig> for Config {
             );
         }
 
-        if let Some(rules) = raw.log.include {
-            for glob in rules.glob {
-                log.rules.add_inclusion(RuleDef::glob_rule(&*glob)?)
-            }
+        let include = raw.log.include.unwrap_or_default();
+        let exclude = raw.log.exclude.unwrap_or_default();
+        add_rules(&mut log.rules, &include, &exclude)?;
 
-            for regex in rules.regex {
-                log.rules.add_inclusion(RuleDef::regex_rule(&*regex)?)
+        for dir_rule in raw.log.dir_rules.unwrap_or_default() {
+            let dir = dir_rule.path.clone();
+            if !dir.is_absolute() {
+                warn!("{} is not an absolute path", dir.display());
+                continue;
             }
-        }
 
-        if let Some(rules) = raw.log.exclude {
-            for glob in rules.glob {
-                log.rules.add_exclusion(RuleDef::glob_rule(&*glob)?)
+            // The directories with their own rules are tailed even when not in log.dirs, the
+            // rules of the missing ones apply once they're created under a log directory
+            if !log.dirs.iter().any(|d| dir.starts_with(d)) {
+                match DirPathBuf::try_from(dir.clone()) {
+                    Ok(dir) => log.dirs.push(dir),
+                    Err(e) => warn!(
+                        "{} is not a valid directory {}, it's not tailed unless it's created \
+                        under a log directory",
+                        dir.display(),
+                        e
+                    ),
+                }
             }
 
-            for regex in rules.regex {
-                log.rules.add_exclusion(RuleDef::regex_rule(&*regex)?)
+            let mut rules = Rules::new();
+            add_rules(
+                &mut rules,
+                dir_rule.include.as_ref().unwrap_or(&include),
+                dir_rule.exclude.as_ref().unwrap_or(&exclude),
+            )?;
+            log.dir_rules.push(DirRules {
+                dir: dir.clone(),
+                rules,
+                lookback: dir_rule
+                    .lookback
+                    .map(|s| s.parse::<Lookback>())
+                    .transpose()?,
+            });
+
+            let tags = dir_rule
+                .tags
+                .iter()
+                .flat_map(|tags| tags.split(','))
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>();
+            if dir_rule.app.is_some() || dir_rule.env.is_some() || !tags.is_empty() {
+                log.dir_meta.push(DirMeta {
+                    dir: dir.clone(),
+                    app: dir_rule.app,
+                    env: dir_rule.env,
+                    tags,
+                });
             }
+
+            let dir_glob = dir.join("**").to_string_lossy().into_owned();
+            log.line_extract_rules.extend(
+                dir_rule
+                    .line_extract_rules
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|rule| ExtractRule {
+                        path: rule.path.or_else(|| Some(dir_glob.clone())),
+                        ..rule
+                    }),
+            );
         }
 
         let startup = K8sStartupLeaseConfig {
@@ -405,6 +733,30 @@ impl TryFrom<RawConfig> for Config {
     }
 }
 
+fn add_rules(
+    rules: &mut Rules,
+    include: &raw::Rules,
+    exclude: &raw::Rules,
+) -> Result<(), ConfigError> {
+    for glob in include.glob.iter() {
+        rules.add_inclusion(RuleDef::glob_rule(&**glob)?)
+    }
+
+    for regex in include.regex.iter() {
+        rules.add_inclusion(RuleDef::regex_rule(&**regex)?)
+    }
+
+    for glob in exclude.glob.iter() {
+        rules.add_exclusion(RuleDef::glob_rule(&**glob)?)
+    }
+
+    for regex in exclude.regex.iter() {
+        rules.add_exclusion(RuleDef::regex_rule(&**regex)?)
+    }
+
+    Ok(())
+}
+
 pub fn get_hostname() -> Option<String> {
     let path = PathBuf::from("/etc/logdna-hostname");
     if path.exists() {
@@ -487,6 +839,7 @@ mod tests {
     use std::fs::OpenOptions;
     use std::io::Write;
 
+    use fs::cache::LongLines;
     use scopeguard::guard;
 
     use super::*;
@@ -512,6 +865,187 @@ mod tests {
         assert!(user_agent.contains('(') && user_agent.contains(')'));
     }
 
+    #[test]
+    fn test_min_levels() {
+        let mut raw = RawConfig::default();
+        raw.http.ingestion_key = Some("emptyingestionkey".to_string());
+        raw.log.level_detection = Some(true);
+        raw.log.min_level = Some(vec!["info".to_string(), "nginx:Warning".to_string()]);
+        let config = Config::try_from(raw).unwrap();
+        assert!(config.log.level.detection);
+        assert!(!config.log.level.stderr_as_error);
+        assert_eq!(config.log.level.min_level, Some(LogLevel::Info));
+        assert_eq!(
+            config.log.level.min_level_by_app.get("nginx"),
+            Some(&LogLevel::Warn)
+        );
+
+        let mut raw = RawConfig::default();
+        raw.http.ingestion_key = Some("emptyingestionkey".to_string());
+        raw.log.min_level = Some(vec!["nginx:verbose".to_string()]);
+        assert!(matches!(
+            Config::try_from(raw),
+            Err(ConfigError::LogLevel(_))
+        ));
+    }
+
+    #[test]
+    fn test_lookback_durations() {
+        let mut raw = RawConfig::default();
+        raw.http.ingestion_key = Some("emptyingestionkey".to_string());
+        raw.log.lookback = Some("2h".to_string());
+        raw.log.ignore_older = Some("7d".to_string());
+        let config = Config::try_from(raw).unwrap();
+        assert_eq!(
+            config.log.lookback,
+            Lookback::Duration(Duration::from_secs(2 * 3600))
+        );
+        assert_eq!(
+            config.log.ignore_older,
+            Some(Duration::from_secs(7 * 86400))
+        );
+
+        let mut raw = RawConfig::default();
+        raw.http.ingestion_key = Some("emptyingestionkey".to_string());
+        raw.log.ignore_older = Some("7 days".to_string());
+        assert!(matches!(
+            Config::try_from(raw),
+            Err(ConfigError::Lookback(_))
+        ));
+    }
+
+    #[test]
+    fn test_file_encodings() {
+        let mut raw = RawConfig::default();
+        raw.http.ingestion_key = Some("emptyingestionkey".to_string());
+        raw.log.file_encodings = Some(vec!["/var/log/legacy/*.log:latin1".to_string()]);
+        let config = Config::try_from(raw).unwrap();
+        assert_eq!(
+            config.log.read.encodings[0].to_string(),
+            "/var/log/legacy/*.log:windows-1252"
+        );
+
+        let mut raw = RawConfig::default();
+        raw.http.ingestion_key = Some("emptyingestionkey".to_string());
+        raw.log.file_encodings = Some(vec!["/var/log/legacy/*.log:ebcdic".to_string()]);
+        assert!(matches!(
+            Config::try_from(raw),
+            Err(ConfigError::Encoding(_))
+        ));
+    }
+
+    #[test]
+    fn test_long_lines() {
+        let mut raw = RawConfig::default();
+        raw.http.ingestion_key = Some("emptyingestionkey".to_string());
+        let config = Config::try_from(raw).unwrap();
+        assert_eq!(config.log.read.max_line_len, None);
+        assert_eq!(config.log.read.long_lines, LongLines::Truncate);
+
+        let mut raw = RawConfig::default();
+        raw.http.ingestion_key = Some("emptyingestionkey".to_string());
+        raw.log.max_line_length = Some(16384);
+        raw.log.long_lines = Some("Skip".to_string());
+        let config = Config::try_from(raw).unwrap();
+        assert_eq!(config.log.read.max_line_len, Some(16384));
+        assert_eq!(config.log.read.long_lines, LongLines::Skip);
+
+        let mut raw = RawConfig::default();
+        raw.http.ingestion_key = Some("emptyingestionkey".to_string());
+        raw.log.long_lines = Some("wrap".to_string());
+        assert!(matches!(
+            Config::try_from(raw),
+            Err(ConfigError::LongLines(_))
+        ));
+    }
+
+    #[test]
+    fn test_log_files() {
+        let mut raw = RawConfig::default();
+        raw.http.ingestion_key = Some("emptyingestionkey".to_string());
+        raw.log.files = Some(vec![
+            "/srv/*/logs/**/*.log".to_string(),
+            "/var/app/out.txt".to_string(),
+        ]);
+        let config = Config::try_from(raw).unwrap();
+        assert_eq!(config.log.files.len(), 2);
+        assert_eq!(config.log.files[0].base(), Path::new("/srv"));
+        assert_eq!(config.log.files[1].base(), Path::new("/var/app"));
+
+        let mut raw = RawConfig::default();
+        raw.http.ingestion_key = Some("emptyingestionkey".to_string());
+        raw.log.files = Some(vec!["logs/*.log".to_string()]);
+        assert!(matches!(
+            Config::try_from(raw),
+            Err(ConfigError::FileTarget(_))
+        ));
+    }
+
+    #[test]
+    fn test_dir_rules() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let app = tempdir.path().join("app");
+        std::fs::create_dir(&app).unwrap();
+        let missing = tempdir.path().join("missing");
+
+        let mut raw = RawConfig::default();
+        raw.http.ingestion_key = Some("emptyingestionkey".to_string());
+        raw.log.dir_rules = Some(vec![
+            raw::DirRule {
+                path: app.clone(),
+                include: Some(raw::Rules {
+                    glob: vec!["*.txt".to_string()],
+                    regex: Vec::new(),
+                }),
+                lookback: Some("none".to_string()),
+                app: Some("app".to_string()),
+                line_extract_rules: Some(vec![ExtractRule {
+                    regex: r"user=(?P<user>\w+)".to_string(),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            },
+            raw::DirRule {
+                path: missing.clone(),
+                app: Some("missing".to_string()),
+                tags: Some("a, b".to_string()),
+                ..Default::default()
+            },
+        ]);
+        let config = Config::try_from(raw).unwrap();
+
+        assert!(config.log.dirs.iter().any(|dir| app.starts_with(dir)));
+        // The missing directory isn't tailed but keeps its rules
+        assert!(!config.log.dirs.iter().any(|dir| missing.starts_with(dir)));
+        assert_eq!(config.log.dir_rules.len(), 2);
+        let dir_rules = &config.log.dir_rules[0];
+        assert!(dir_rules.rules.passes(&app.join("a.txt")).is_ok());
+        assert!(!dir_rules.rules.passes(&app.join("a.log")).is_ok());
+        assert_eq!(dir_rules.lookback, Some(Lookback::None));
+        assert_eq!(config.log.dir_rules[1].dir, missing);
+        assert_eq!(
+            config.log.dir_meta,
+            vec![
+                DirMeta {
+                    dir: app.clone(),
+                    app: Some("app".to_string()),
+                    env: None,
+                    tags: Vec::new(),
+                },
+                DirMeta {
+                    dir: missing,
+                    app: Some("missing".to_string()),
+                    env: None,
+                    tags: vec!["a".to_string(), "b".to_string()],
+                }
+            ]
+        );
+        assert_eq!(
+            config.log.line_extract_rules.last().unwrap().path,
+            Some(app.join("**").to_string_lossy().into_owned())
+        );
+    }
+
     #[test]
     fn test_db_path() {
         // Default
@@ -545,6 +1079,7 @@ mod tests {
         assert_eq!(config.log.use_k8s_enrichment, K8sTrackingConf::Always);
         assert_eq!(config.log.log_k8s_events, K8sTrackingConf::Never);
         assert_eq!(config.log.lookback, Lookback::None);
+        assert_eq!(config.log.ignore_older, None);
         assert_eq!(
             config
                 .log
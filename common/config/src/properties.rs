@@ -39,18 +39,51 @@ from_env_name!(IP);
 from_env_name!(MAC);
 from_env_name!(JOURNALD_PATHS);
 from_env_name!(LOOKBACK);
+from_env_name!(IGNORE_OLDER);
 from_env_name!(DB_PATH);
 from_env_name!(METRICS_PORT);
 from_env_name!(USE_K8S_LOG_ENRICHMENT);
 from_env_name!(LOG_K8S_EVENTS);
 from_env_name!(K8S_STARTUP_LEASE);
+from_env_name!(K8S_INCLUDE_NAMESPACES);
+from_env_name!(K8S_EXCLUDE_NAMESPACES);
+from_env_name!(K8S_INCLUDE_LABELS);
+from_env_name!(K8S_EXCLUDE_LABELS);
+from_env_name!(K8S_INCLUDE_CONTAINERS);
+from_env_name!(K8S_EXCLUDE_CONTAINERS);
+from_env_name!(K8S_WORKLOAD_ENRICHMENT);
+from_env_name!(K8S_NODE_LABELS);
+from_env_name!(K8S_METADATA_WAIT_MS);
 from_env_name!(LINE_EXCLUSION);
 from_env_name!(LINE_INCLUSION);
 from_env_name!(REDACT);
+from_env_name!(REDACT_DETECTORS);
+from_env_name!(REDACT_HASH_KEY);
+from_env_name!(REDACT_FIELDS);
+from_env_name!(DROP_FIELDS);
+from_env_name!(LINE_EXTRACT);
 from_env_name!(INGEST_TIMEOUT);
 from_env_name!(INGEST_BUFFER_SIZE);
 from_env_name!(RETRY_DIR);
 from_env_name!(RETRY_DISK_LIMIT);
+from_env_name!(SCRIPT_PATH);
+from_env_name!(SCRIPT_MAX_OPERATIONS);
+from_env_name!(SCRIPT_MAX_STRING_SIZE);
+from_env_name!(WASM_PLUGINS);
+from_env_name!(WASM_FUEL);
+from_env_name!(WASM_MAX_MEMORY);
+from_env_name!(LEVEL_DETECTION);
+from_env_name!(LEVEL_STDERR_AS_ERROR);
+from_env_name!(MIN_LEVEL);
+from_env_name!(POLL_DIRS);
+from_env_name!(POLL_INTERVAL_MS);
+from_env_name!(FILE_IDLE_TIMEOUT_MS);
+from_env_name!(MAX_OPEN_FILES);
+from_env_name!(TRUNCATE_REREAD_LIMIT);
+from_env_name!(LOG_FILES);
+from_env_name!(FILE_ENCODINGS);
+from_env_name!(MAX_LINE_LENGTH);
+from_env_name!(LONG_LINES);
 
 enum Key {
     FromEnv(&'static str),
@@ -196,6 +229,13 @@ fn from_property_map(map: HashMap<String, String>) -> Result<Config, ConfigError
             .collect();
     }
 
+    if let Some(value) = map.get(&LOG_FILES) {
+        let files = result.log.files.get_or_insert(Vec::new());
+        argv::split_by_comma(value)
+            .iter()
+            .for_each(|v| files.push(v.to_string()));
+    }
+
     if let Some(value) = map.get(&METRICS_PORT) {
         result.log.metrics_port = Some(u16::from_str(value).map_err(|e| {
             ConfigError::PropertyInvalid(format!("metrics port property is invalid: {}", e))
@@ -238,11 +278,73 @@ fn from_property_map(map: HashMap<String, String>) -> Result<Config, ConfigError
     }
 
     result.log.lookback = map.get_string(&LOOKBACK);
+    result.log.ignore_older = map.get_string(&IGNORE_OLDER);
     result.log.use_k8s_enrichment = map.get_string(&USE_K8S_LOG_ENRICHMENT);
     result.log.log_k8s_events = map.get_string(&LOG_K8S_EVENTS);
     result.log.db_path = map.get(&DB_PATH).map(PathBuf::from);
     result.startup.option = map.get_string(&K8S_STARTUP_LEASE);
 
+    if let Some(value) = map.get(&K8S_INCLUDE_NAMESPACES) {
+        let values = result.log.k8s_include_namespaces.get_or_insert(Vec::new());
+        argv::split_by_comma(value)
+            .iter()
+            .for_each(|v| values.push(v.to_string()));
+    }
+
+    if let Some(value) = map.get(&K8S_EXCLUDE_NAMESPACES) {
+        let values = result.log.k8s_exclude_namespaces.get_or_insert(Vec::new());
+        argv::split_by_comma(value)
+            .iter()
+            .for_each(|v| values.push(v.to_string()));
+    }
+
+    if let Some(value) = map.get(&K8S_INCLUDE_LABELS) {
+        let values = result.log.k8s_include_labels.get_or_insert(Vec::new());
+        argv::split_by_comma(value)
+            .iter()
+            .for_each(|v| values.push(v.to_string()));
+    }
+
+    if let Some(value) = map.get(&K8S_EXCLUDE_LABELS) {
+        let values = result.log.k8s_exclude_labels.get_or_insert(Vec::new());
+        argv::split_by_comma(value)
+            .iter()
+            .for_each(|v| values.push(v.to_string()));
+    }
+
+    if let Some(value) = map.get(&K8S_INCLUDE_CONTAINERS) {
+        let values = result.log.k8s_include_containers.get_or_insert(Vec::new());
+        argv::split_by_comma(value)
+            .iter()
+            .for_each(|v| values.push(v.to_string()));
+    }
+
+    if let Some(value) = map.get(&K8S_EXCLUDE_CONTAINERS) {
+        let values = result.log.k8s_exclude_containers.get_or_insert(Vec::new());
+        argv::split_by_comma(value)
+            .iter()
+            .for_each(|v| values.push(v.to_string()));
+    }
+
+    if let Some(value) = map.get_string(&K8S_WORKLOAD_ENRICHMENT) {
+        result.log.k8s_workload_enrichment = Some(bool::from_str(&value).map_err(|e| {
+            ConfigError::PropertyInvalid(format!("k8s_workload_enrichment is invalid: {}", e))
+        })?);
+    }
+
+    if let Some(value) = map.get(&K8S_NODE_LABELS) {
+        let values = result.log.k8s_node_labels.get_or_insert(Vec::new());
+        argv::split_by_comma(value)
+            .iter()
+            .for_each(|v| values.push(v.to_string()));
+    }
+
+    if let Some(value) = map.get(&K8S_METADATA_WAIT_MS) {
+        result.log.k8s_metadata_wait_ms = Some(value.parse().map_err(|e| {
+            ConfigError::PropertyInvalid(format!("k8s_metadata_wait_ms is invalid: {}", e))
+        })?);
+    }
+
     if let Some(value) = map.get(&LINE_EXCLUSION) {
         let regex_rules = result.log.line_exclusion_regex.get_or_insert(Vec::new());
         argv::split_by_comma(value)
@@ -264,6 +366,135 @@ fn from_property_map(map: HashMap<String, String>) -> Result<Config, ConfigError
             .for_each(|v| regex_rules.push(v.to_string()));
     }
 
+    if let Some(value) = map.get(&REDACT_DETECTORS) {
+        let detectors = result.log.redact_detectors.get_or_insert(Vec::new());
+        argv::split_by_comma(value)
+            .iter()
+            .for_each(|v| detectors.push(v.to_string()));
+    }
+
+    result.log.redact_hash_key = map.get(&REDACT_HASH_KEY).cloned();
+
+    if let Some(value) = map.get(&REDACT_FIELDS) {
+        let fields = result.log.redact_fields.get_or_insert(Vec::new());
+        argv::split_by_comma(value)
+            .iter()
+            .for_each(|v| fields.push(v.to_string()));
+    }
+
+    if let Some(value) = map.get(&DROP_FIELDS) {
+        let fields = result.log.drop_fields.get_or_insert(Vec::new());
+        argv::split_by_comma(value)
+            .iter()
+            .for_each(|v| fields.push(v.to_string()));
+    }
+
+    if let Some(value) = map.get(&LINE_EXTRACT) {
+        let regex_rules = result.log.line_extract_regex.get_or_insert(Vec::new());
+        argv::split_by_comma(value)
+            .iter()
+            .for_each(|v| regex_rules.push(v.to_string()));
+    }
+
+    result.log.script_path = map.get(&SCRIPT_PATH).map(PathBuf::from);
+
+    if let Some(value) = map.get(&SCRIPT_MAX_OPERATIONS) {
+        result.log.script_max_operations = Some(value.parse().map_err(|e| {
+            ConfigError::PropertyInvalid(format!("script_max_operations is invalid: {}", e))
+        })?);
+    }
+
+    if let Some(value) = map.get(&SCRIPT_MAX_STRING_SIZE) {
+        result.log.script_max_string_size = Some(value.parse().map_err(|e| {
+            ConfigError::PropertyInvalid(format!("script_max_string_size is invalid: {}", e))
+        })?);
+    }
+
+    if let Some(value) = map.get(&WASM_PLUGINS) {
+        let paths = result.log.wasm_plugins.get_or_insert(Vec::new());
+        argv::split_by_comma(value)
+            .iter()
+            .for_each(|v| paths.push(PathBuf::from(v)));
+    }
+
+    if let Some(value) = map.get(&WASM_FUEL) {
+        result.log.wasm_fuel =
+            Some(value.parse().map_err(|e| {
+                ConfigError::PropertyInvalid(format!("wasm_fuel is invalid: {}", e))
+            })?);
+    }
+
+    if let Some(value) = map.get(&WASM_MAX_MEMORY) {
+        result.log.wasm_max_memory = Some(value.parse().map_err(|e| {
+            ConfigError::PropertyInvalid(format!("wasm_max_memory is invalid: {}", e))
+        })?);
+    }
+
+    if let Some(value) = map.get_string(&LEVEL_DETECTION) {
+        result.log.level_detection = Some(bool::from_str(&value).map_err(|e| {
+            ConfigError::PropertyInvalid(format!("level_detection is invalid: {}", e))
+        })?);
+    }
+
+    if let Some(value) = map.get_string(&LEVEL_STDERR_AS_ERROR) {
+        result.log.level_stderr_as_error = Some(bool::from_str(&value).map_err(|e| {
+            ConfigError::PropertyInvalid(format!("level_stderr_as_error is invalid: {}", e))
+        })?);
+    }
+
+    if let Some(value) = map.get(&MIN_LEVEL) {
+        let levels = result.log.min_level.get_or_insert(Vec::new());
+        argv::split_by_comma(value)
+            .iter()
+            .for_each(|v| levels.push(v.to_string()));
+    }
+
+    if let Some(value) = map.get(&POLL_DIRS) {
+        let paths = result.log.poll_dirs.get_or_insert(Vec::new());
+        argv::split_by_comma(value)
+            .iter()
+            .for_each(|v| paths.push(PathBuf::from(v)));
+    }
+
+    if let Some(value) = map.get(&POLL_INTERVAL_MS) {
+        result.log.poll_interval_ms = Some(value.parse().map_err(|e| {
+            ConfigError::PropertyInvalid(format!("poll_interval_ms is invalid: {}", e))
+        })?);
+    }
+
+    if let Some(value) = map.get(&FILE_IDLE_TIMEOUT_MS) {
+        result.log.file_idle_timeout_ms = Some(value.parse().map_err(|e| {
+            ConfigError::PropertyInvalid(format!("file_idle_timeout_ms is invalid: {}", e))
+        })?);
+    }
+
+    if let Some(value) = map.get(&MAX_OPEN_FILES) {
+        result.log.max_open_files = Some(value.parse().map_err(|e| {
+            ConfigError::PropertyInvalid(format!("max_open_files is invalid: {}", e))
+        })?);
+    }
+
+    if let Some(value) = map.get(&TRUNCATE_REREAD_LIMIT) {
+        result.log.truncate_reread_limit = Some(value.parse().map_err(|e| {
+            ConfigError::PropertyInvalid(format!("truncate_reread_limit is invalid: {}", e))
+        })?);
+    }
+
+    if let Some(value) = map.get(&FILE_ENCODINGS) {
+        let encodings = result.log.file_encodings.get_or_insert(Vec::new());
+        argv::split_by_comma(value)
+            .iter()
+            .for_each(|v| encodings.push(v.to_string()));
+    }
+
+    if let Some(value) = map.get(&MAX_LINE_LENGTH) {
+        result.log.max_line_length = Some(value.parse().map_err(|e| {
+            ConfigError::PropertyInvalid(format!("max_line_length is invalid: {}", e))
+        })?);
+    }
+
+    result.log.long_lines = map.get_string(&LONG_LINES);
+
     // Properties parser is very permissive
     // we need to validate that parsed was valid
     if result == Config::default() {
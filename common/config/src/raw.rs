@@ -4,10 +4,12 @@ use http::types::params::Params;
 use humanize_rs::bytes::Bytes;
 use serde::de::{Deserializer, Error, Unexpected, Visitor};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fs::File;
 use std::io::{ErrorKind, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::vec::Vec;
 
 pub fn filesize_deser<'de, D>(d: D) -> Result<Option<u64>, D::Error>
@@ -190,7 +192,7 @@ impl<T: PartialEq + Clone> Merge for Vec<T> {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
 pub struct Config {
     pub http: HttpConfig,
     pub log: LogConfig,
@@ -270,10 +272,12 @@ pub struct HttpConfig {
     pub retry_step_delay_ms: Option<usize>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct LogConfig {
     pub dirs: Vec<PathBuf>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub db_path: Option<PathBuf>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metrics_port: Option<u16>,
@@ -282,15 +286,396 @@ pub struct LogConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub exclude: Option<Rules>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub dir_rules: Option<Vec<DirRule>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub line_exclusion_regex: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub line_inclusion_regex: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub line_redact_regex: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub redact_detectors: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redact_rules: Option<Vec<RedactRule>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redact_hash_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redact_fields: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drop_fields: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field_rules: Option<Vec<FieldRule>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_extract_regex: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_extract_rules: Option<Vec<ExtractRule>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub lookback: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ignore_older: Option<String>,
     pub use_k8s_enrichment: Option<String>,
     pub log_k8s_events: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_include_namespaces: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_exclude_namespaces: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_include_labels: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_exclude_labels: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_include_containers: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_exclude_containers: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_workload_enrichment: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_node_labels: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_metadata_wait_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_watch: Option<Vec<K8sWatchRule>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metric_rules: Option<Vec<MetricRule>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script_max_operations: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script_max_string_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wasm_plugins: Option<Vec<PathBuf>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wasm_fuel: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wasm_max_memory: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level_detection: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level_stderr_as_error: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_level: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll_dirs: Option<Vec<PathBuf>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll_interval_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_idle_timeout_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_open_files: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncate_reread_limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_encodings: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_line_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub long_lines: Option<String>,
+}
+
+/// Built-in detectors of sensitive information.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactDetector {
+    /// Credit card numbers passing the Luhn check
+    CreditCard,
+    Email,
+    Ipv4,
+    Ipv6,
+    Jwt,
+    /// AWS access key ids and secret access keys
+    AwsKey,
+    /// The token of `Bearer` authorization values
+    BearerToken,
+}
+
+impl FromStr for RedactDetector {
+    type Err = ParseRedactRuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "credit_card" => Ok(RedactDetector::CreditCard),
+            "email" => Ok(RedactDetector::Email),
+            "ipv4" => Ok(RedactDetector::Ipv4),
+            "ipv6" => Ok(RedactDetector::Ipv6),
+            "jwt" => Ok(RedactDetector::Jwt),
+            "aws_key" => Ok(RedactDetector::AwsKey),
+            "bearer_token" => Ok(RedactDetector::BearerToken),
+            _ => Err(ParseRedactRuleError(format!(
+                "unknown redact detector {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// How the redacted values are replaced.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactStrategy {
+    /// Replaces the value with a constant, `[REDACTED]` by default
+    Constant,
+    /// Replaces the value with a keyed hash, the same value always maps to the same token
+    Hash,
+    /// Masks the value except for its last characters
+    Partial,
+    /// Replaces the digits and letters of the value with ones derived from a keyed hash,
+    /// keeping its length and separators. Like `Hash`, the value can't be recovered
+    FormatPreservingHash,
+}
+
+impl Default for RedactStrategy {
+    fn default() -> Self {
+        RedactStrategy::Constant
+    }
+}
+
+impl FromStr for RedactStrategy {
+    type Err = ParseRedactRuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "constant" => Ok(RedactStrategy::Constant),
+            "hash" => Ok(RedactStrategy::Hash),
+            "partial" => Ok(RedactStrategy::Partial),
+            "format_preserving_hash" => Ok(RedactStrategy::FormatPreservingHash),
+            _ => Err(ParseRedactRuleError(format!(
+                "unknown redact strategy {}",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+pub struct ParseRedactRuleError(String);
+
+/// Redacts the values found by a built-in `detector` or matching `regex`.
+///
+/// `replacement` is used by the constant strategy and `keep_last` (4 by default) by the
+/// partial one. The hash and format preserving hash strategies require `redact_hash_key`.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Default)]
+pub struct RedactRule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detector: Option<RedactDetector>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+    #[serde(default)]
+    pub strategy: RedactStrategy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replacement: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<usize>,
+}
+
+impl FromStr for RedactRule {
+    type Err = ParseRedactRuleError;
+
+    /// Parses `detector` or `detector:strategy`, as used in the env vars.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (detector, strategy) = match s.split_once(':') {
+            Some((detector, strategy)) => (detector, strategy.parse()?),
+            None => (s, RedactStrategy::default()),
+        };
+        Ok(RedactRule {
+            detector: Some(detector.parse()?),
+            strategy,
+            ..Default::default()
+        })
+    }
+}
+
+/// The line metadata a field rule applies to.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldTarget {
+    Annotations,
+    Labels,
+    /// The nested fields of the meta object, their key is the dot separated path
+    Meta,
+}
+
+impl FromStr for FieldTarget {
+    type Err = ParseRedactRuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "annotations" => Ok(FieldTarget::Annotations),
+            "labels" => Ok(FieldTarget::Labels),
+            "meta" => Ok(FieldTarget::Meta),
+            _ => Err(ParseRedactRuleError(format!("unknown field target {}", s))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldAction {
+    Redact,
+    /// Removes the field from the line
+    Drop,
+}
+
+impl Default for FieldAction {
+    fn default() -> Self {
+        FieldAction::Redact
+    }
+}
+
+/// Redacts or drops the fields of the `target` metadata whose key matches the `key` glob.
+///
+/// Redaction uses the detector, regex and strategy of the rule, without a detector nor
+/// a regex the whole value is redacted.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub struct FieldRule {
+    pub target: FieldTarget,
+    pub key: String,
+    #[serde(default)]
+    pub action: FieldAction,
+    #[serde(flatten)]
+    pub redact: RedactRule,
+}
+
+impl FieldRule {
+    /// Parses `target:key_glob`, as used in the env vars.
+    pub fn parse(s: &str, action: FieldAction) -> Result<FieldRule, ParseRedactRuleError> {
+        match s.split_once(':') {
+            Some((target, key)) => Ok(FieldRule {
+                target: target.parse()?,
+                key: key.trim().to_string(),
+                action,
+                redact: RedactRule::default(),
+            }),
+            None => Err(ParseRedactRuleError(format!(
+                "invalid field {}, expected target:key",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ExtractTarget {
+    Meta,
+    Labels,
+}
+
+impl Default for ExtractTarget {
+    fn default() -> Self {
+        ExtractTarget::Meta
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ExtractType {
+    String,
+    Int,
+    Float,
+    Bool,
+}
+
+/// Adds the named captures of `regex` to the meta (or labels) of the matching lines.
+///
+/// The rule can be limited to the lines of the files matching the `path` glob and/or
+/// to the lines of an `app`. Captures are strings unless a type is defined in `types`.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Default)]
+pub struct ExtractRule {
+    pub regex: String,
+    #[serde(default)]
+    pub target: ExtractTarget,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub types: BTreeMap<String, ExtractType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+}
+
+/// Overrides the global settings for the files under `path`.
+///
+/// The `include` and `exclude` rules replace the global ones when set, `app` and `env`
+/// override the ones of the lines and the `line_extract_rules` are limited to the files
+/// under the directory unless they have their own `path`.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Default)]
+pub struct DirRule {
+    pub path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include: Option<Rules>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude: Option<Rules>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lookback: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_extract_rules: Option<Vec<ExtractRule>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricRuleType {
+    Counter,
+    Histogram,
+}
+
+impl Default for MetricRuleType {
+    fn default() -> Self {
+        MetricRuleType::Counter
+    }
+}
+
+/// Derives a Prometheus metric from the lines matching `regex`.
+///
+/// `labels` lists the named captures (or the line fields `app`, `host`, `env`, `file`
+/// and `level`) used as label values, `value` names the capture holding the observed
+/// value. When not set, counters are incremented by one for each matching line.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct MetricRule {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub help: Option<String>,
+    #[serde(rename = "type", default)]
+    pub metric_type: MetricRuleType,
+    pub regex: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buckets: Option<Vec<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_cardinality: Option<usize>,
+}
+
+/// Watches the Kubernetes resources of `api_version` and `kind` (e.g. `apps/v1` and
+/// `Deployment`) and logs a line for each change.
+///
+/// The changes of the labels and annotations are always logged, the changes of the other
+/// fields (e.g. `spec` and `status`) only when `metadata_only` is not set. Changes under
+/// the `ignore_fields` paths, e.g. `status.conditions`, are not logged.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Default)]
+pub struct K8sWatchRule {
+    pub api_version: String,
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plural: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field_selector: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label_selector: Option<String>,
+    #[serde(default)]
+    pub metadata_only: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ignore_fields: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Default)]
@@ -319,7 +704,9 @@ impl Merge for JournaldConfig {
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Default)]
 pub struct Rules {
+    #[serde(default)]
     pub glob: Vec<String>,
+    #[serde(default)]
     pub regex: Vec<String>,
 }
 
@@ -390,6 +777,7 @@ impl Default for LogConfig {
     fn default() -> Self {
         LogConfig {
             dirs: vec!["/var/log/".into()],
+            files: None,
             db_path: None,
             metrics_port: None,
             include: Some(Rules {
@@ -413,12 +801,50 @@ impl Default for LogConfig {
                 ],
                 regex: Vec::new(),
             }),
+            dir_rules: None,
             line_exclusion_regex: None,
             line_inclusion_regex: None,
             line_redact_regex: None,
+            redact_detectors: None,
+            redact_rules: None,
+            redact_hash_key: None,
+            redact_fields: None,
+            drop_fields: None,
+            field_rules: None,
+            line_extract_regex: None,
+            line_extract_rules: None,
             lookback: None,
+            ignore_older: None,
             use_k8s_enrichment: None,
             log_k8s_events: None,
+            k8s_include_namespaces: None,
+            k8s_exclude_namespaces: None,
+            k8s_include_labels: None,
+            k8s_exclude_labels: None,
+            k8s_include_containers: None,
+            k8s_exclude_containers: None,
+            k8s_workload_enrichment: None,
+            k8s_node_labels: None,
+            k8s_metadata_wait_ms: None,
+            k8s_watch: None,
+            metric_rules: None,
+            script_path: None,
+            script_max_operations: None,
+            script_max_string_size: None,
+            wasm_plugins: None,
+            wasm_fuel: None,
+            wasm_max_memory: None,
+            level_detection: None,
+            level_stderr_as_error: None,
+            min_level: None,
+            poll_dirs: None,
+            poll_interval_ms: None,
+            file_idle_timeout_ms: None,
+            max_open_files: None,
+            truncate_reread_limit: None,
+            file_encodings: None,
+            max_line_length: None,
+            long_lines: None,
         }
     }
 }
@@ -426,22 +852,106 @@ impl Default for LogConfig {
 impl Merge for LogConfig {
     fn merge(&mut self, other: &Self, default: &Self) {
         self.dirs.merge(&other.dirs, &default.dirs);
+        self.files.merge(&other.files, &default.files);
         self.db_path.merge(&other.db_path, &default.db_path);
         self.metrics_port
             .merge(&other.metrics_port, &default.metrics_port);
         self.include.merge(&other.include, &default.include);
         self.exclude.merge(&other.exclude, &default.exclude);
+        self.dir_rules.merge(&other.dir_rules, &default.dir_rules);
         self.line_exclusion_regex
             .merge(&other.line_exclusion_regex, &default.line_exclusion_regex);
         self.line_inclusion_regex
             .merge(&other.line_inclusion_regex, &default.line_inclusion_regex);
         self.line_redact_regex
             .merge(&other.line_redact_regex, &default.line_redact_regex);
+        self.redact_detectors
+            .merge(&other.redact_detectors, &default.redact_detectors);
+        self.redact_rules
+            .merge(&other.redact_rules, &default.redact_rules);
+        self.redact_hash_key
+            .merge(&other.redact_hash_key, &default.redact_hash_key);
+        self.redact_fields
+            .merge(&other.redact_fields, &default.redact_fields);
+        self.drop_fields
+            .merge(&other.drop_fields, &default.drop_fields);
+        self.field_rules
+            .merge(&other.field_rules, &default.field_rules);
+        self.line_extract_regex
+            .merge(&other.line_extract_regex, &default.line_extract_regex);
+        self.line_extract_rules
+            .merge(&other.line_extract_rules, &default.line_extract_rules);
         self.lookback.merge(&other.lookback, &default.lookback);
+        self.ignore_older
+            .merge(&other.ignore_older, &default.ignore_older);
         self.use_k8s_enrichment
             .merge(&other.use_k8s_enrichment, &default.use_k8s_enrichment);
         self.log_k8s_events
             .merge(&other.log_k8s_events, &default.log_k8s_events);
+        self.k8s_include_namespaces.merge(
+            &other.k8s_include_namespaces,
+            &default.k8s_include_namespaces,
+        );
+        self.k8s_exclude_namespaces.merge(
+            &other.k8s_exclude_namespaces,
+            &default.k8s_exclude_namespaces,
+        );
+        self.k8s_include_labels
+            .merge(&other.k8s_include_labels, &default.k8s_include_labels);
+        self.k8s_exclude_labels
+            .merge(&other.k8s_exclude_labels, &default.k8s_exclude_labels);
+        self.k8s_include_containers.merge(
+            &other.k8s_include_containers,
+            &default.k8s_include_containers,
+        );
+        self.k8s_exclude_containers.merge(
+            &other.k8s_exclude_containers,
+            &default.k8s_exclude_containers,
+        );
+        self.k8s_workload_enrichment.merge(
+            &other.k8s_workload_enrichment,
+            &default.k8s_workload_enrichment,
+        );
+        self.k8s_node_labels
+            .merge(&other.k8s_node_labels, &default.k8s_node_labels);
+        self.k8s_metadata_wait_ms
+            .merge(&other.k8s_metadata_wait_ms, &default.k8s_metadata_wait_ms);
+        self.k8s_watch.merge(&other.k8s_watch, &default.k8s_watch);
+        self.metric_rules
+            .merge(&other.metric_rules, &default.metric_rules);
+        self.script_path
+            .merge(&other.script_path, &default.script_path);
+        self.script_max_operations
+            .merge(&other.script_max_operations, &default.script_max_operations);
+        self.script_max_string_size.merge(
+            &other.script_max_string_size,
+            &default.script_max_string_size,
+        );
+        self.wasm_plugins
+            .merge(&other.wasm_plugins, &default.wasm_plugins);
+        self.wasm_fuel.merge(&other.wasm_fuel, &default.wasm_fuel);
+        self.wasm_max_memory
+            .merge(&other.wasm_max_memory, &default.wasm_max_memory);
+        self.level_detection
+            .merge(&other.level_detection, &default.level_detection);
+        self.level_stderr_as_error
+            .merge(&other.level_stderr_as_error, &default.level_stderr_as_error);
+        self.min_level.merge(&other.min_level, &default.min_level);
+        self.poll_dirs.merge(&other.poll_dirs, &default.poll_dirs);
+        self.poll_interval_ms
+            .merge(&other.poll_interval_ms, &default.poll_interval_ms);
+        self.file_idle_timeout_ms
+            .merge(&other.file_idle_timeout_ms, &default.file_idle_timeout_ms);
+        self.max_open_files
+            .merge(&other.max_open_files, &default.max_open_files);
+        self.truncate_reread_limit
+            .merge(&other.truncate_reread_limit, &default.truncate_reread_limit);
+        self.file_encodings
+            .merge(&other.file_encodings, &default.file_encodings);
+        self.max_line_length
+            .merge(&other.max_line_length, &default.max_line_length);
+        self.long_lines
+            .merge(&other.long_lines, &default.long_lines);
     }
 }
 
@@ -703,6 +1213,301 @@ startup: {}
         Ok(())
     }
 
+    #[test]
+    fn test_yaml_file_metric_rules() -> io::Result<()> {
+        let dir = tempdir()?;
+        let file_name = dir.path().join("test.yml");
+        fs::write(
+            &file_name,
+            r#"
+http:
+  host: logs.logdna.prod
+log:
+  dirs:
+    - /var/log/
+  metric_rules:
+    - name: http_server_errors_total
+      regex: 'status=(?P<status>5\d\d)'
+      labels: [app, status]
+      max_cardinality: 50
+    - name: request_duration_ms
+      type: histogram
+      regex: 'duration_ms=(?P<duration>\d+)'
+      value: duration
+      buckets: [10, 100, 1000]
+journald: {}
+startup: {}
+"#,
+        )?;
+
+        let config = Config::parse(&file_name).unwrap();
+        let rules = config.log.metric_rules.unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].name, "http_server_errors_total");
+        assert_eq!(rules[0].metric_type, MetricRuleType::Counter);
+        assert_eq!(rules[0].labels, vec_strings!["app", "status"]);
+        assert_eq!(rules[0].max_cardinality, Some(50));
+        assert_eq!(rules[1].metric_type, MetricRuleType::Histogram);
+        assert_eq!(rules[1].value, some_string!("duration"));
+        assert_eq!(rules[1].buckets, Some(vec![10.0, 100.0, 1000.0]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_yaml_file_k8s_watch() -> io::Result<()> {
+        let dir = tempdir()?;
+        let file_name = dir.path().join("test.yml");
+        fs::write(
+            &file_name,
+            r#"
+http:
+  host: logs.logdna.prod
+log:
+  dirs:
+    - /var/log/
+  k8s_watch:
+    - api_version: apps/v1
+      kind: Deployment
+      namespace: default
+      label_selector: tier=web
+    - api_version: v1
+      kind: ConfigMap
+      metadata_only: true
+    - api_version: v1
+      kind: Node
+      ignore_fields: [status.conditions]
+journald: {}
+startup: {}
+"#,
+        )?;
+
+        let config = Config::parse(&file_name).unwrap();
+        let rules = config.log.k8s_watch.unwrap();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(
+            rules[0],
+            K8sWatchRule {
+                api_version: "apps/v1".to_string(),
+                kind: "Deployment".to_string(),
+                namespace: some_string!("default"),
+                label_selector: some_string!("tier=web"),
+                ..Default::default()
+            }
+        );
+        assert!(rules[1].metadata_only);
+        assert_eq!(rules[2].ignore_fields, vec_strings!["status.conditions"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_yaml_file_redact_rules() -> io::Result<()> {
+        let dir = tempdir()?;
+        let file_name = dir.path().join("test.yml");
+        fs::write(
+            &file_name,
+            r#"
+http:
+  host: logs.logdna.prod
+log:
+  dirs:
+    - /var/log/
+  redact_hash_key: secret
+  redact_rules:
+    - detector: email
+      strategy: hash
+    - detector: credit_card
+      strategy: partial
+      keep_last: 4
+    - regex: 'user_id=\d+'
+      replacement: '[USER]'
+journald: {}
+startup: {}
+"#,
+        )?;
+
+        let config = Config::parse(&file_name).unwrap();
+        assert_eq!(config.log.redact_hash_key, some_string!("secret"));
+        let rules = config.log.redact_rules.unwrap();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[0].detector, Some(RedactDetector::Email));
+        assert_eq!(rules[0].strategy, RedactStrategy::Hash);
+        assert_eq!(rules[1].detector, Some(RedactDetector::CreditCard));
+        assert_eq!(rules[1].strategy, RedactStrategy::Partial);
+        assert_eq!(rules[1].keep_last, Some(4));
+        assert_eq!(rules[2].regex, some_string!(r"user_id=\d+"));
+        assert_eq!(rules[2].strategy, RedactStrategy::Constant);
+        assert_eq!(rules[2].replacement, some_string!("[USER]"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_yaml_file_field_rules() -> io::Result<()> {
+        let dir = tempdir()?;
+        let file_name = dir.path().join("test.yml");
+        fs::write(
+            &file_name,
+            r#"
+http:
+  host: logs.logdna.prod
+log:
+  dirs:
+    - /var/log/
+  field_rules:
+    - target: annotations
+      key: kubectl.kubernetes.io/last-applied-configuration
+      action: drop
+    - target: meta
+      key: '*.email'
+      detector: email
+      strategy: hash
+journald: {}
+startup: {}
+"#,
+        )?;
+
+        let config = Config::parse(&file_name).unwrap();
+        let rules = config.log.field_rules.unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].target, FieldTarget::Annotations);
+        assert_eq!(rules[0].action, FieldAction::Drop);
+        assert_eq!(rules[0].redact, RedactRule::default());
+        assert_eq!(rules[1].target, FieldTarget::Meta);
+        assert_eq!(rules[1].key, "*.email");
+        assert_eq!(rules[1].action, FieldAction::Redact);
+        assert_eq!(rules[1].redact.detector, Some(RedactDetector::Email));
+        assert_eq!(rules[1].redact.strategy, RedactStrategy::Hash);
+
+        assert_eq!(
+            FieldRule::parse("labels:team-*", FieldAction::Drop).unwrap(),
+            FieldRule {
+                target: FieldTarget::Labels,
+                key: "team-*".to_string(),
+                action: FieldAction::Drop,
+                redact: RedactRule::default(),
+            }
+        );
+        assert!(FieldRule::parse("team-*", FieldAction::Drop).is_err());
+        assert!(FieldRule::parse("env:team-*", FieldAction::Drop).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_yaml_file_dir_rules() -> io::Result<()> {
+        let dir = tempdir()?;
+        let file_name = dir.path().join("test.yml");
+        fs::write(
+            &file_name,
+            r#"
+http:
+  host: logs.logdna.prod
+log:
+  dirs:
+    - /var/log/
+  dir_rules:
+    - path: /var/log/nginx
+      include:
+        glob:
+          - "*.log"
+          - "*.err"
+      app: nginx
+      tags: web,frontend
+    - path: /srv/app/logs
+      lookback: 2h
+      env: staging
+      line_extract_rules:
+        - regex: 'user=(?P<user>\w+)'
+journald: {}
+startup: {}
+"#,
+        )?;
+
+        let config = Config::parse(&file_name).unwrap();
+        let rules = config.log.dir_rules.unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].path, PathBuf::from("/var/log/nginx"));
+        assert_eq!(
+            rules[0].include,
+            Some(Rules {
+                glob: vec!["*.log".to_string(), "*.err".to_string()],
+                regex: Vec::new(),
+            })
+        );
+        assert_eq!(rules[0].exclude, None);
+        assert_eq!(rules[0].app.as_deref(), Some("nginx"));
+        assert_eq!(rules[0].tags.as_deref(), Some("web,frontend"));
+        assert_eq!(rules[1].lookback.as_deref(), Some("2h"));
+        assert_eq!(rules[1].env.as_deref(), Some("staging"));
+        assert_eq!(rules[1].line_extract_rules.as_ref().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_redact_rule_from_str() {
+        assert_eq!(
+            "email".parse::<RedactRule>().unwrap(),
+            RedactRule {
+                detector: Some(RedactDetector::Email),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            "aws_key:format_preserving_hash"
+                .parse::<RedactRule>()
+                .unwrap(),
+            RedactRule {
+                detector: Some(RedactDetector::AwsKey),
+                strategy: RedactStrategy::FormatPreservingHash,
+                ..Default::default()
+            }
+        );
+        assert!("phone".parse::<RedactRule>().is_err());
+        assert!("email:encrypt".parse::<RedactRule>().is_err());
+    }
+
+    #[test]
+    fn test_yaml_file_extract_rules() -> io::Result<()> {
+        let dir = tempdir()?;
+        let file_name = dir.path().join("test.yml");
+        fs::write(
+            &file_name,
+            r#"
+http:
+  host: logs.logdna.prod
+log:
+  dirs:
+    - /var/log/
+  line_extract_regex:
+    - 'user=(?P<user_id>\w+)'
+  line_extract_rules:
+    - regex: 'status=(?P<status>\d+) took=(?P<took>[\d.]+)'
+      types:
+        status: int
+        took: float
+      path: /var/log/nginx/*.log
+    - regex: 'team=(?P<team>\w+)'
+      target: labels
+      app: api
+journald: {}
+startup: {}
+"#,
+        )?;
+
+        let config = Config::parse(&file_name).unwrap();
+        assert_eq!(
+            config.log.line_extract_regex,
+            Some(vec_strings![r"user=(?P<user_id>\w+)"])
+        );
+        let rules = config.log.line_extract_rules.unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].target, ExtractTarget::Meta);
+        assert_eq!(rules[0].types.get("status"), Some(&ExtractType::Int));
+        assert_eq!(rules[0].types.get("took"), Some(&ExtractType::Float));
+        assert_eq!(rules[0].path, some_string!("/var/log/nginx/*.log"));
+        assert_eq!(rules[1].target, ExtractTarget::Labels);
+        assert_eq!(rules[1].app, some_string!("api"));
+        Ok(())
+    }
+
     #[test]
     fn test_all_new() -> io::Result<()> {
         let dir = tempdir()?;
@@ -2,7 +2,7 @@ use std::cell::RefCell;
 use std::ffi::OsString;
 use std::path::PathBuf;
 
-use inotify::WatchDescriptor;
+use crate::cache::WatchDescriptor;
 
 use crate::cache::TailedFile;
 use crate::cache::{Children, EntryKey};
@@ -8,6 +8,6 @@ pub enum Event {
     New(EntryKey),
     /// A file was written too
     Write(EntryKey),
-    /// A file was deleted
+    /// A file was deleted, or released after being drained once removed from the watched paths
     Delete(EntryKey),
 }
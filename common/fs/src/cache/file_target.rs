@@ -0,0 +1,198 @@
+use globber::Pattern;
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+const GLOB_CHARS: &[char] = &['*', '?', '[', '{'];
+
+#[derive(Error, std::fmt::Debug)]
+pub enum ParseFileTargetError {
+    #[error("{0} is not an absolute path")]
+    NotAbsolute(String),
+    #[error("{0} has no file name")]
+    NoFileName(String),
+    #[error(
+        "{0} doesn't start with a directory without wildcards, it would watch the whole filesystem"
+    )]
+    NoBaseDir(String),
+    #[error("Invalid glob pattern in {0}: {1}")]
+    Pattern(String, globber::Error),
+}
+
+#[derive(Clone, std::fmt::Debug)]
+enum ComponentMatcher {
+    /// `**`, any number of directories
+    Any,
+    Glob(Pattern),
+}
+
+/// A file or a glob pattern of files to tail, e.g. `/srv/*/logs/**/*.log`.
+///
+/// The pattern is matched by path component, `*` doesn't match across directories and
+/// `**` matches any number of them.
+#[derive(Clone, std::fmt::Debug)]
+pub struct FileTarget {
+    glob: String,
+    base: PathBuf,
+    components: Vec<ComponentMatcher>,
+}
+
+impl std::str::FromStr for FileTarget {
+    type Err = ParseFileTargetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let path = Path::new(s);
+        if !path.is_absolute() {
+            return Err(ParseFileTargetError::NotAbsolute(s.into()));
+        }
+
+        let names = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if names.is_empty() || s.ends_with('/') {
+            return Err(ParseFileTargetError::NoFileName(s.into()));
+        }
+
+        // The directories before the first wildcard, the file name is never part of it
+        let literal = names
+            .iter()
+            .take(names.len() - 1)
+            .take_while(|name| !name.contains(GLOB_CHARS))
+            .count();
+        // The directories under the base are watched recursively
+        if literal == 0 {
+            return Err(ParseFileTargetError::NoBaseDir(s.into()));
+        }
+        let base = names[..literal]
+            .iter()
+            .fold(PathBuf::from("/"), |base, name| base.join(name));
+
+        let components = names
+            .iter()
+            .map(|name| match name.as_str() {
+                "**" => Ok(ComponentMatcher::Any),
+                name => Pattern::new(name)
+                    .map(ComponentMatcher::Glob)
+                    .map_err(|e| ParseFileTargetError::Pattern(s.into(), e)),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(FileTarget {
+            glob: s.into(),
+            base,
+            components,
+        })
+    }
+}
+
+impl fmt::Display for FileTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.glob)
+    }
+}
+
+impl FileTarget {
+    /// Returns the deepest directory containing all the matching files.
+    pub fn base(&self) -> &Path {
+        &self.base
+    }
+
+    /// Returns true when the path matches the pattern.
+    pub fn matches(&self, path: &Path) -> bool {
+        matches_all(&self.components, &names(path))
+    }
+
+    /// Returns true when the directory can contain matching files, at any depth.
+    pub fn may_contain(&self, dir: &Path) -> bool {
+        matches_prefix(&self.components, &names(dir))
+    }
+}
+
+fn names(path: &Path) -> Vec<&OsStr> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        })
+        .collect()
+}
+
+fn matches_name(pattern: &Pattern, name: &OsStr) -> bool {
+    pattern.matches(&name.to_string_lossy())
+}
+
+fn matches_all(components: &[ComponentMatcher], names: &[&OsStr]) -> bool {
+    match components.split_first() {
+        None => names.is_empty(),
+        Some((ComponentMatcher::Any, rest)) => {
+            (0..=names.len()).any(|skip| matches_all(rest, &names[skip..]))
+        }
+        Some((ComponentMatcher::Glob(pattern), rest)) => match names.split_first() {
+            Some((name, names)) => matches_name(pattern, name) && matches_all(rest, names),
+            None => false,
+        },
+    }
+}
+
+fn matches_prefix(components: &[ComponentMatcher], names: &[&OsStr]) -> bool {
+    match (components.split_first(), names.split_first()) {
+        // The remaining components match the entries of the directory
+        (Some(_), None) => true,
+        (None, _) => false,
+        (Some((ComponentMatcher::Any, _)), Some(_)) => true,
+        (Some((ComponentMatcher::Glob(pattern), rest)), Some((name, names))) => {
+            matches_name(pattern, name) && matches_prefix(rest, names)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_target() {
+        let target: FileTarget = "/srv/*/logs/**/*.log".parse().unwrap();
+        assert_eq!(target.base(), Path::new("/srv"));
+        assert_eq!(target.to_string(), "/srv/*/logs/**/*.log");
+
+        assert!(target.matches(Path::new("/srv/app/logs/access.log")));
+        assert!(target.matches(Path::new("/srv/app/logs/2021/01/access.log")));
+        assert!(!target.matches(Path::new("/srv/app/logs/access.txt")));
+        assert!(!target.matches(Path::new("/srv/app/other/access.log")));
+        assert!(!target.matches(Path::new("/srv/a/b/logs/access.log")));
+
+        assert!(target.may_contain(Path::new("/")));
+        assert!(target.may_contain(Path::new("/srv")));
+        assert!(target.may_contain(Path::new("/srv/app")));
+        assert!(target.may_contain(Path::new("/srv/app/logs/2021")));
+        assert!(!target.may_contain(Path::new("/srv/app/other")));
+        assert!(!target.may_contain(Path::new("/var")));
+
+        let target: FileTarget = "/var/app/out.txt".parse().unwrap();
+        assert_eq!(target.base(), Path::new("/var/app"));
+        assert!(target.matches(Path::new("/var/app/out.txt")));
+        assert!(target.may_contain(Path::new("/var/app")));
+        assert!(!target.may_contain(Path::new("/var/app/out.txt")));
+
+        assert!(matches!(
+            "logs/*.log".parse::<FileTarget>(),
+            Err(ParseFileTargetError::NotAbsolute(_))
+        ));
+        assert!(matches!(
+            "/var/log/".parse::<FileTarget>(),
+            Err(ParseFileTargetError::NoFileName(_))
+        ));
+        for glob in &["/**/*.log", "/*/x.log", "/x.log"] {
+            assert!(matches!(
+                glob.parse::<FileTarget>(),
+                Err(ParseFileTargetError::NoBaseDir(_))
+            ));
+        }
+    }
+}
@@ -1,15 +1,16 @@
 use crate::cache::entry::Entry;
 use crate::cache::event::Event;
+use crate::cache::open_files::OpenFiles;
 use crate::cache::tailed_file::TailedFile;
 use crate::cache::watch::{WatchEvent, Watcher};
-use crate::lookback::Lookback;
-use crate::rule::{RuleDef, Rules, Status};
+use crate::lookback::{lookback_offset, Lookback};
+use crate::rule::{DirRules, RuleDef, Rules, Status};
 
-use state::{FileId, Span, SpanVec};
+use state::{FileId, FileOffsetWriteHandle, Span, SpanVec};
 
 use std::cell::RefCell;
 use std::collections::hash_map::Entry as HashMapEntry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::fs::read_dir;
 use std::iter::FromIterator;
@@ -18,10 +19,10 @@ use std::os::unix::fs::MetadataExt;
 use std::path::{Component, Path, PathBuf};
 use std::rc::Rc;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use std::{fmt, io};
 
 use futures::{Stream, StreamExt};
-use inotify::WatchDescriptor;
 use slotmap::{DefaultKey, SlotMap};
 use smallvec::SmallVec;
 use thiserror::Error;
@@ -31,10 +32,16 @@ use tokio::sync::Mutex;
 pub mod dir_path;
 pub mod entry;
 pub mod event;
+pub mod file_target;
 pub mod tailed_file;
 pub use dir_path::{DirPathBuf, DirPathBufError};
+pub use file_target::{FileTarget, ParseFileTargetError};
 use metrics::Metrics;
+pub use open_files::OpenFilesConfig;
+pub use tailed_file::{LongLines, ParseLongLinesError, ReadConfig};
+pub use watch::{WatchConfig, WatchDescriptor, DEFAULT_POLL_INTERVAL};
 
+mod open_files;
 mod watch;
 
 type Children = HashMap<OsString, EntryKey>;
@@ -48,14 +55,18 @@ type FsResult<T> = Result<T, Error>;
 
 pub const EVENT_STREAM_BUFFER_COUNT: usize = 1000;
 
+/// Upper bound of the interval between the checks for idle files
+const MAX_IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time a file removed from the watched paths is still read after its last write
+const DRAIN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("error watching: {0:?} {1:?}")]
     Watch(PathBuf, io::Error),
     #[error("got event for untracked watch descriptor: {0:?}")]
     WatchEvent(WatchDescriptor),
-    #[error("the inotify event queue has overflowed and events have presumably been lost")]
-    WatchOverflow,
     #[error("unexpected existing entry")]
     Existing,
     #[error("failed to find entry")]
@@ -148,6 +159,51 @@ fn get_resume_events(
         .filter_map(|e| async { e })
 }
 
+/// Periodically closes the idle files, never yields any events
+fn get_idle_checks(
+    fs: &Arc<Mutex<FileSystem>>,
+) -> impl Stream<Item = (Result<WatchEvent, std::io::Error>, EventTimestamp)> {
+    let open_files = fs
+        .try_lock()
+        .expect("couldn't lock filesystem cache")
+        .open_files
+        .clone();
+    let idle_timeout = open_files.lock().expect("poisoned").idle_timeout();
+    let interval = idle_timeout
+        .unwrap_or(MAX_IDLE_CHECK_INTERVAL)
+        .min(MAX_IDLE_CHECK_INTERVAL)
+        .max(Duration::from_millis(100));
+    tokio_stream::wrappers::IntervalStream::new(tokio::time::interval(interval))
+        .take_while(move |_| futures::future::ready(idle_timeout.is_some()))
+        .filter_map(move |_| {
+            open_files.lock().expect("poisoned").close_idle();
+            futures::future::ready(None)
+        })
+}
+
+/// Periodically releases the removed files that were drained
+fn get_drain_events(
+    fs: &Arc<Mutex<FileSystem>>,
+) -> impl Stream<Item = (Result<Event, Error>, EventTimestamp)> {
+    let interval = fs
+        .try_lock()
+        .expect("couldn't lock filesystem cache")
+        .drain_grace_period
+        .min(Duration::from_secs(1))
+        .max(Duration::from_millis(10));
+    let fs = fs.clone();
+    tokio_stream::wrappers::IntervalStream::new(tokio::time::interval(interval)).flat_map(
+        move |_| {
+            let events = fs
+                .try_lock()
+                .expect("couldn't lock filesystem cache")
+                .release_drained();
+            let event_time = OffsetDateTime::now_utc();
+            futures::stream::iter(events.into_iter().map(move |event| (Ok(event), event_time)))
+        },
+    )
+}
+
 pub struct FileSystem {
     watcher: Watcher,
     pub entries: Rc<RefCell<EntryMap>>,
@@ -158,34 +214,66 @@ pub struct FileSystem {
     wd_by_inode: HashMap<u64, WatchDescriptor>,
 
     master_rules: Rules,
+    dir_rules: Vec<DirRules>,
     initial_dirs: Vec<DirPathBuf>,
     initial_dir_rules: Rules,
+    file_targets: Vec<FileTarget>,
 
     initial_events: Vec<Event>,
 
     lookback_config: Lookback,
+    ignore_older: Option<Duration>,
     initial_offsets: HashMap<FileId, SpanVec>,
 
     resume_events_recv: async_channel::Receiver<(u64, EventTimestamp)>,
     resume_events_send: async_channel::Sender<(u64, EventTimestamp)>,
+
+    /// Files ignored because of `ignore_older` by their length, inserted once written
+    ignored: HashMap<PathBuf, u64>,
+
+    open_files: Arc<std::sync::Mutex<OpenFiles>>,
+
+    read_config: ReadConfig,
+    offsets_handle: Option<FileOffsetWriteHandle>,
+
+    /// Files removed from the watched paths that are still read, by their release deadline
+    draining: HashMap<EntryKey, Instant>,
+    drain_grace_period: Duration,
 }
 
 impl FileSystem {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         initial_dirs: Vec<DirPathBuf>,
+        file_targets: Vec<FileTarget>,
         initial_offsets: HashMap<FileId, SpanVec>,
         lookback_config: Lookback,
+        ignore_older: Option<Duration>,
         rules: Rules,
+        dir_rules: Vec<DirRules>,
+        watch_config: WatchConfig,
+        open_files_config: OpenFilesConfig,
+        read_config: ReadConfig,
+        offsets_handle: Option<FileOffsetWriteHandle>,
     ) -> Self {
         let (resume_events_send, resume_events_recv) = async_channel::unbounded();
 
-        initial_dirs.iter().for_each(|path| {
-            if !path.is_dir() {
-                panic!("initial dirs must be dirs")
-            }
-        });
+        // The directories can be removed after the configuration was loaded
+        let initial_dirs = initial_dirs
+            .into_iter()
+            .filter(|path| {
+                if !path.is_dir() {
+                    warn!("{:?} is not a directory, it's not tailed", path);
+                }
+                path.is_dir()
+            })
+            .collect::<Vec<_>>();
 
-        let mut watcher = Watcher::new().expect("unable to initialize inotify");
+        let mut watcher = Watcher::new(watch_config);
+        // The ignored files are not watched, the writes are reported by their directory
+        if ignore_older.is_some() {
+            watcher.watch_dir_writes();
+        }
 
         let mut entries = SlotMap::new();
         let root = entries.insert(Entry::Dir {
@@ -207,14 +295,23 @@ impl FileSystem {
             watch_descriptors: WatchDescriptors::new(),
             wd_by_inode: HashMap::new(),
             master_rules: rules,
+            dir_rules,
             initial_dirs: initial_dirs.clone(),
             initial_dir_rules,
+            file_targets: file_targets.clone(),
             lookback_config,
+            ignore_older,
             initial_offsets,
             watcher,
             initial_events: Vec::new(),
             resume_events_recv,
             resume_events_send,
+            ignored: HashMap::new(),
+            open_files: Arc::new(std::sync::Mutex::new(OpenFiles::new(open_files_config))),
+            read_config,
+            offsets_handle,
+            draining: HashMap::new(),
+            drain_grace_period: DRAIN_GRACE_PERIOD,
         };
 
         let entries = fs.entries.clone();
@@ -222,9 +319,16 @@ impl FileSystem {
         fs.register(fs.root, &mut entries)
             .expect("Unable to register root");
 
+        // Only the directories that can contain matching files are watched under the base
+        // directory of the file targets
         for dir in initial_dirs
             .into_iter()
             .map(|path| -> PathBuf { path.into() })
+            .chain(
+                file_targets
+                    .iter()
+                    .map(|target| target.base().to_path_buf()),
+            )
         {
             let mut path_cpy: PathBuf = dir.clone();
             loop {
@@ -258,7 +362,7 @@ impl FileSystem {
                             Event::New(entry_key) => {
                                 if let Some(entry) = entries.get(entry_key) {
                                     let path = fs.resolve_direct_path(entry, &entries);
-                                    if fs.is_initial_dir_target(&path) {
+                                    if fs.is_target(&path) {
                                         fs.initial_events.push(Event::Initialize(entry_key))
                                     }
                                 }
@@ -291,7 +395,10 @@ impl FileSystem {
 
         let initial_events = get_initial_events(&fs);
         let resume_events_recv = get_resume_events(&fs);
-        let events = futures::stream::select(resume_events_recv, events_stream.into_stream())
+        let idle_checks = get_idle_checks(&fs);
+        let drain_events = get_drain_events(&fs);
+        let internal_events = futures::stream::select(resume_events_recv, idle_checks);
+        let events = futures::stream::select(internal_events, events_stream)
             .map(|event_result| async { event_result })
             .buffered(EVENT_STREAM_BUFFER_COUNT)
             .map(move |(event, event_time)| {
@@ -300,7 +407,10 @@ impl FileSystem {
             })
             .flatten();
 
-        Ok(initial_events.chain(events))
+        Ok(futures::stream::select(
+            initial_events.chain(events),
+            drain_events,
+        ))
     }
 
     /// Handles inotify events and may produce Event(s) that are returned upstream through sender
@@ -315,6 +425,9 @@ impl FileSystem {
                 self.process_create(&wd, name, &mut _entries)
             }
             WatchEvent::Modify { wd } => self.process_modify(&wd),
+            WatchEvent::ModifyChild { wd, name } => {
+                self.process_modify_child(&wd, name, &mut _entries)
+            }
             WatchEvent::Delete { wd, name } | WatchEvent::MovedFrom { wd, name, .. } => {
                 self.process_delete(&wd, name, &mut _entries)
             }
@@ -350,7 +463,11 @@ impl FileSystem {
                 }
             }
             // Files are being updated too often for inotify to catch up
-            WatchEvent::Overflow => Err(Error::WatchOverflow),
+            WatchEvent::Overflow => {
+                Metrics::fs().increment_watch_overflows();
+                warn!("the inotify event queue overflowed, rescanning the tracked directories");
+                Ok(self.process_overflow(&mut _entries))
+            }
         }
     }
 
@@ -395,6 +512,10 @@ impl FileSystem {
         if let Some(mut entry_ptrs) = entry_ptrs_opt {
             let mut events = Vec::new();
             for entry_ptr in entry_ptrs.iter_mut() {
+                // Removed files are released once they stop being written to
+                if let Some(deadline) = self.draining.get_mut(entry_ptr) {
+                    *deadline = Instant::now() + self.drain_grace_period;
+                }
                 events.push(Event::Write(*entry_ptr));
             }
 
@@ -404,6 +525,25 @@ impl FileSystem {
         }
     }
 
+    /// Inserts a file ignored because of `ignore_older` once it's written again.
+    fn process_modify_child(
+        &mut self,
+        watch_descriptor: &WatchDescriptor,
+        name: OsString,
+        _entries: &mut EntryMap,
+    ) -> FsResult<Vec<Event>> {
+        let entry_key = self.get_first_entry(watch_descriptor)?;
+        let entry = _entries.get(entry_key).ok_or(Error::Lookup)?;
+        let mut path = self.resolve_direct_path(entry, _entries);
+        path.push(name);
+
+        let mut events = Vec::new();
+        if self.ignored.contains_key(&path) {
+            self.insert(&path, &mut events, _entries)?;
+        }
+        Ok(events)
+    }
+
     fn process_delete(
         &mut self,
         watch_descriptor: &WatchDescriptor,
@@ -418,6 +558,7 @@ impl FileSystem {
         path.push(name);
 
         let mut events = Vec::new();
+        self.ignored.remove(&path);
         if !self.initial_dirs.iter().any(|dir| dir.as_ref() == path) {
             self.remove(&path, &mut events, _entries)?;
         }
@@ -425,6 +566,81 @@ impl FileSystem {
         Ok(events)
     }
 
+    /// Rescans the tracked directories once inotify events were lost, returning the events of
+    /// the paths created, removed or replaced since and a write for every tracked file.
+    fn process_overflow(&mut self, _entries: &mut EntryMap) -> Vec<Event> {
+        let dirs = _entries
+            .iter()
+            .filter_map(|(key, entry)| match entry {
+                Entry::Dir { wd, children, .. } => Some((
+                    key,
+                    wd.clone(),
+                    self.resolve_direct_path(entry, _entries),
+                    children.clone(),
+                )),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut events = Vec::new();
+        for (key, wd, path, children) in dirs {
+            // The directory may have been removed along with its parent
+            if _entries.get(key).is_none() {
+                continue;
+            }
+            let current = match read_dir(&path) {
+                Ok(current) => current
+                    .filter_map(Result::ok)
+                    .map(|child| child.file_name())
+                    .collect::<HashSet<_>>(),
+                Err(_) => continue,
+            };
+
+            let mut created = Vec::new();
+            for (name, child) in children {
+                // A file replaced at the same path has a different inode
+                let replaced = match _entries.get(child) {
+                    Some(Entry::File { wd, .. }) => std::fs::symlink_metadata(path.join(&name))
+                        .map_or(false, |metadata| {
+                            self.wd_by_inode.get(&metadata.ino()) != Some(wd)
+                        }),
+                    _ => false,
+                };
+                if current.contains(&name) && !replaced {
+                    continue;
+                }
+                match self.process_delete(&wd, name.clone(), _entries) {
+                    Ok(removed) => events.extend(removed),
+                    Err(e) => warn!("unable to remove {:?}: {}", path.join(&name), e),
+                }
+                if replaced {
+                    created.push(name);
+                }
+            }
+            created.extend(current.into_iter().filter(|name| {
+                _entries
+                    .get(key)
+                    .and_then(Entry::children)
+                    .map_or(false, |children| !children.contains_key(name))
+                    && self.passes(&path.join(name), _entries)
+            }));
+            for name in created {
+                match self.process_create(&wd, name.clone(), _entries) {
+                    Ok(inserted) => events.extend(inserted),
+                    Err(e) => warn!("unable to insert {:?}: {}", path.join(&name), e),
+                }
+            }
+        }
+
+        events.extend(
+            _entries
+                .iter()
+                .filter(|(_, entry)| matches!(entry, Entry::File { .. }))
+                .map(|(key, _)| Event::Write(key)),
+        );
+        events
+    }
+
     fn process_move(
         &mut self,
         from_watch_descriptor: &WatchDescriptor,
@@ -463,7 +679,7 @@ impl FileSystem {
                 None
             }
         }
-        match self.lookback_config {
+        match *self.lookback_for(path) {
             Lookback::Start => {
                 _lookup_offset(&self.initial_offsets, &inode, path).unwrap_or_default()
             }
@@ -481,6 +697,11 @@ impl FileSystem {
                 .metadata()
                 .map(|m| [Span::new(0, m.len()).unwrap()].iter().collect())
                 .unwrap_or_default(),
+            Lookback::Duration(lookback) => _lookup_offset(&self.initial_offsets, &inode, path)
+                .unwrap_or_else(|| match lookback_offset(path, lookback) {
+                    Ok(0) | Err(_) => SpanVec::new(),
+                    Ok(offset) => [Span::new(0, offset).unwrap()].iter().collect(),
+                }),
         }
     }
 
@@ -528,7 +749,7 @@ impl FileSystem {
             into_components(&self.resolve_direct_path(entry, _entries));
         base_components.append(&mut components); // add components already discovered from previous recursive step
         let path: PathBuf = base_components.iter().collect();
-        if self.is_initial_dir_target(&path) {
+        if self.is_target(&path) {
             // only want paths that fall in our watch window
             paths.push(path); // condense components of path that lead to the true entry into a PathBuf
         }
@@ -622,6 +843,18 @@ impl FileSystem {
         match action {
             Action::Return(key) => Ok(Some(key)),
             Action::CreateFile => {
+                if self.is_older_than_ignored(path) {
+                    info!(
+                        "ignoring {:?}, not modified since the ignore_older threshold",
+                        path
+                    );
+                    // The file is inserted once it's written again
+                    if let Ok(metadata) = path.metadata() {
+                        self.ignored.insert(path.to_path_buf(), metadata.len());
+                    }
+                    return Ok(None);
+                }
+
                 let wd = self
                     .watcher
                     .watch(path)
@@ -630,11 +863,44 @@ impl FileSystem {
                 let inode = path.metadata().map_err(Error::File)?.ino();
                 self.wd_by_inode.insert(inode, wd.clone());
 
-                let offsets = self.get_initial_offset(path, inode.into());
+                // The file was moved back before it was released, it's read from the new entry
+                let drained = self
+                    .watch_descriptors
+                    .get(&wd)
+                    .into_iter()
+                    .flatten()
+                    .filter(|key| self.draining.contains_key(key))
+                    .copied()
+                    .collect::<Vec<_>>();
+                for key in drained {
+                    self.release(key, events, _entries);
+                }
+
+                // The content of a file ignored as stale is skipped
+                let offsets = match self.ignored.remove(path) {
+                    Some(len) if !self.initial_offsets.contains_key(&FileId::from(inode)) => {
+                        match path.metadata() {
+                            Ok(metadata) if metadata.len() >= len && len > 0 => {
+                                [Span::new(0, len).unwrap()].iter().collect()
+                            }
+                            _ => SpanVec::new(),
+                        }
+                    }
+                    _ => self.get_initial_offset(path, inode.into()),
+                };
                 let initial_offset = offsets.first().map(|offset| offset.end).unwrap_or(0);
 
-                let tf = TailedFile::new(path, offsets, Some(self.resume_events_send.clone()))
-                    .map_err(Error::File)?;
+                let tf = TailedFile::new(
+                    path,
+                    offsets,
+                    Some(self.resume_events_send.clone()),
+                    // Nothing is closed without limits, the files are not tracked
+                    Some(self.open_files.clone())
+                        .filter(|open_files| open_files.lock().expect("poisoned").is_limited()),
+                    &self.read_config,
+                    self.offsets_handle.clone(),
+                )
+                .map_err(Error::File)?;
 
                 info!("initialized {:?} with offset {}", path, initial_offset);
 
@@ -644,7 +910,6 @@ impl FileSystem {
                     wd,
                     data: RefCell::new(tf),
                 };
-                Metrics::fs().increment_tracked_files();
 
                 let new_key = self.register_as_child(parent_ref, new_entry, _entries)?;
                 events.push(Event::New(new_key));
@@ -783,6 +1048,23 @@ impl FileSystem {
         events: &mut Vec<Event>,
         _entries: &mut EntryMap,
     ) {
+        if let Some(Entry::File { wd, data, .. }) = _entries.get(entry_key) {
+            // A hard link to the same file keeps reading it
+            let is_linked = self.watch_descriptors.get(wd).map_or(false, |keys| {
+                keys.iter()
+                    .any(|key| *key != entry_key && !self.draining.contains_key(key))
+            });
+            if is_linked {
+                self.release(entry_key, events, _entries);
+            } else {
+                // It can't be reopened from its paths anymore once closed
+                let id = data.borrow().id();
+                self.open_files.lock().expect("poisoned").keep_open(id);
+                self.drain(entry_key, events);
+            }
+            return;
+        }
+
         self.unregister(entry_key, _entries);
         if let Some(entry) = _entries.get(entry_key) {
             let mut _children = vec![];
@@ -803,10 +1085,7 @@ impl FileSystem {
 
                     events.push(Event::Delete(entry_key));
                 }
-                Entry::File { .. } => {
-                    Metrics::fs().decrement_tracked_files();
-                    events.push(Event::Delete(entry_key));
-                }
+                Entry::File { .. } => {}
             }
 
             for child in _children {
@@ -820,6 +1099,50 @@ impl FileSystem {
         }
     }
 
+    /// Keeps reading a file removed from the watched paths, e.g. rotated away or deleted, until
+    /// it's not written to for the drain grace period. The process writing to the file may keep
+    /// it open for a while after the rotation.
+    fn drain(&mut self, entry_key: EntryKey, events: &mut Vec<Event>) {
+        debug!("draining removed file {:?}", entry_key);
+        self.draining
+            .insert(entry_key, Instant::now() + self.drain_grace_period);
+        events.push(Event::Write(entry_key));
+    }
+
+    /// Stops watching a removed file, the Delete event reads its last lines and drops it.
+    fn release(&mut self, entry_key: EntryKey, events: &mut Vec<Event>, _entries: &mut EntryMap) {
+        if self.draining.remove(&entry_key).is_some() {
+            if let Some(Entry::File { data, .. }) = _entries.get(entry_key) {
+                let id = data.borrow().id();
+                self.open_files.lock().expect("poisoned").release(id);
+            }
+        }
+        self.unregister(entry_key, _entries);
+        events.push(Event::Delete(entry_key));
+    }
+
+    /// Releases the drained files not written to within the grace period.
+    fn release_drained(&mut self) -> Vec<Event> {
+        let now = Instant::now();
+        let drained = self
+            .draining
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        let mut events = Vec::new();
+        if drained.is_empty() {
+            return events;
+        }
+        let _entries = self.entries.clone();
+        let mut _entries = _entries.borrow_mut();
+        for key in drained {
+            self.release(key, &mut events, &mut _entries);
+        }
+        events
+    }
+
     // `from` is the path from where the file or dir used to live
     // `to is the path to where the file or dir now lives
     // e.g from = /var/log/syslog and to = /var/log/syslog.1.log
@@ -860,7 +1183,10 @@ impl FileSystem {
                     .ok_or(Error::ParentNotValid)?
                     .insert(new_name, entry_key))
             }
-            None => self.insert(to, events, _entries),
+            None => {
+                self.ignored.remove(from);
+                self.insert(to, events, _entries)
+            }
         }
     }
 
@@ -914,10 +1240,16 @@ impl FileSystem {
                 Action::Return(key) => key,
                 Action::Lookup(ref link) => self.lookup(link, _entries).ok_or(Error::Lookup)?,
                 Action::CreateDir => {
-                    let wd = self
-                        .watcher
-                        .watch(&current_path)
-                        .map_err(|e| Error::Watch(current_path.to_owned(), e))?;
+                    // Once the inotify watches run low, they are kept for the directories
+                    // containing matching files
+                    let wd = if self.watcher.is_constrained()
+                        && !self.has_matching_files(&current_path, _entries)
+                    {
+                        self.watcher.poll(&current_path)
+                    } else {
+                        self.watcher.watch(&current_path)
+                    }
+                    .map_err(|e| Error::Watch(current_path.to_owned(), e))?;
 
                     let new_entry = Entry::Dir {
                         name: component.clone(),
@@ -1029,7 +1361,7 @@ impl FileSystem {
                     match symlink {
                         Entry::Symlink { rules, .. } => {
                             if let Status::Ok = rules.passes(path) {
-                                if let Status::Ok = self.master_rules.included(path) {
+                                if let Status::Ok = self.rules_for(path).included(path) {
                                     return true;
                                 }
                             }
@@ -1049,6 +1381,36 @@ impl FileSystem {
         false
     }
 
+    /// Returns the rules of the most specific directory override containing the path, or the
+    /// master rules
+    fn rules_for(&self, path: &Path) -> &Rules {
+        DirRules::for_path(&self.dir_rules, path).map_or(&self.master_rules, |dir| &dir.rules)
+    }
+
+    /// Returns the lookback of the most specific directory override containing the path, or
+    /// the global one
+    fn lookback_for(&self, path: &Path) -> &Lookback {
+        DirRules::for_path(&self.dir_rules, path)
+            .and_then(|dir| dir.lookback.as_ref())
+            .unwrap_or(&self.lookback_config)
+    }
+
+    /// Determines whether the path is within the initial dirs or matches the file targets
+    pub(crate) fn is_target(&self, path: &Path) -> bool {
+        self.is_initial_dir_target(path) || self.is_file_target(path)
+    }
+
+    /// Determines whether the path matches a file target (e.g. "/srv/*/logs/**/*.log") and
+    /// is not excluded, or it's a directory that can contain matching files
+    fn is_file_target(&self, path: &Path) -> bool {
+        self.file_targets.iter().any(|target| {
+            if target.matches(path) {
+                return self.rules_for(path).excluded(path) == Status::Ok;
+            }
+            target.may_contain(path) && path.is_dir()
+        })
+    }
+
     /// Determines whether the path is within the initial dir
     /// and either passes the master rules (e.g. "*.log") or it's a directory
     pub(crate) fn is_initial_dir_target(&self, path: &Path) -> bool {
@@ -1058,7 +1420,7 @@ impl FileSystem {
         }
 
         // The file should validate the file rules or be a directory
-        if self.master_rules.passes(path) != Status::Ok {
+        if self.rules_for(path).passes(path) != Status::Ok {
             if let Ok(metadata) = std::fs::metadata(path) {
                 return metadata.is_dir();
             }
@@ -1070,7 +1432,32 @@ impl FileSystem {
 
     /// Helper method for checking if a path passes exclusion/inclusion rules
     fn passes(&self, path: &Path, _entries: &EntryMap) -> bool {
-        self.is_initial_dir_target(path) || self.is_symlink_target(path, _entries)
+        self.is_target(path) || self.is_symlink_target(path, _entries)
+    }
+
+    /// Returns true when the file was last modified before the `ignore_older` threshold.
+    fn is_older_than_ignored(&self, path: &Path) -> bool {
+        let ignore_older = match self.ignore_older {
+            Some(ignore_older) => ignore_older,
+            None => return false,
+        };
+        match path.metadata().and_then(|m| m.modified()) {
+            Ok(modified) => SystemTime::now()
+                .duration_since(modified)
+                .map_or(false, |age| age > ignore_older),
+            Err(_) => false,
+        }
+    }
+
+    /// Returns true when the directory directly contains files passing the rules.
+    fn has_matching_files(&self, path: &Path, _entries: &EntryMap) -> bool {
+        match std::fs::read_dir(path) {
+            Ok(children) => children.filter_map(Result::ok).any(|child| {
+                !child.file_type().map_or(true, |t| t.is_dir())
+                    && self.passes(&child.path(), _entries)
+            }),
+            Err(_) => false,
+        }
     }
 
     fn entry_path_passes(&self, entry: EntryKey, name: &OsStr, _entries: &EntryMap) -> bool {
@@ -1106,7 +1493,9 @@ impl fmt::Debug for FileSystem {
         builder.field("symlinks", &&self.symlinks);
         builder.field("watch_descriptors", &&self.watch_descriptors);
         builder.field("master_rules", &&self.master_rules);
+        builder.field("dir_rules", &&self.dir_rules);
         builder.field("initial_dir_rules", &&self.initial_dir_rules);
+        builder.field("file_targets", &&self.file_targets);
         builder.field("initial_events", &&self.initial_events);
         builder.finish()
     }
@@ -1191,7 +1580,10 @@ mod tests {
     use crate::rule::{RuleDef, Rules};
     use crate::test::LOGGER;
     use std::convert::TryInto;
-    use std::fs::{copy, create_dir, hard_link, remove_dir_all, remove_file, rename, File};
+    use std::fs::{
+        copy, create_dir, create_dir_all, hard_link, remove_dir_all, remove_file, rename, File,
+    };
+    use std::io::Write;
     use std::os::unix::fs::symlink;
     use std::{io, panic};
     use tempfile::TempDir;
@@ -1239,9 +1631,16 @@ mod tests {
                 .as_path()
                 .try_into()
                 .unwrap_or_else(|_| panic!("{:?} is not a directory!", path))],
+            Vec::new(),
             HashMap::new(),
             Lookback::Start,
+            None,
             rules,
+            Vec::new(),
+            WatchConfig::default(),
+            OpenFilesConfig::default(),
+            ReadConfig::default(),
+            None,
         )
     }
 
@@ -1599,6 +1998,40 @@ mod tests {
         });
     }
 
+    // Reads a deleted file until it's released after the grace period
+    #[test]
+    fn filesystem_drain_deleted_file() {
+        run_test(|| {
+            let tempdir = TempDir::new().unwrap();
+            let path = tempdir.path().to_path_buf();
+
+            let file_path = path.join("file");
+            let mut file = File::create(file_path.clone()).unwrap();
+
+            let mut fs = new_fs::<()>(path, None);
+            fs.drain_grace_period = Duration::from_millis(200);
+            let fs = Arc::new(Mutex::new(fs));
+            let entry = lookup_entry!(fs, file_path).unwrap();
+
+            remove_file(&file_path).unwrap();
+            // The initial event of the file first
+            let events = take_events!(fs, 2);
+            assert!(matches!(events[0], Ok((Ok(Event::Initialize(key)), _)) if key == entry));
+            assert!(matches!(events[1], Ok((Ok(Event::Write(key)), _)) if key == entry));
+            assert!(lookup_entry!(fs, file_path).is_none());
+            assert!(fs.try_lock().unwrap().draining.contains_key(&entry));
+
+            // The process still holding the file keeps writing to it
+            writeln!(file, "line").unwrap();
+            let events = take_events!(fs, 1);
+            assert!(matches!(events[0], Ok((Ok(Event::Write(key)), _)) if key == entry));
+
+            let events = take_events!(fs, 1);
+            assert!(matches!(events[0], Ok((Ok(Event::Delete(key)), _)) if key == entry));
+            assert!(fs.try_lock().unwrap().draining.is_empty());
+        });
+    }
+
     // Deletes a symlink
     #[test]
     fn filesystem_delete_symlink() {
@@ -2099,4 +2532,210 @@ mod tests {
                 .any(|other| other.to_str() == double_nested_symlink_path.to_str()));
         });
     }
+
+    // Files not modified since the ignore_older threshold are not tracked
+    #[test]
+    fn filesystem_ignore_older() {
+        run_test(|| {
+            let tempdir = TempDir::new().unwrap();
+            let path = tempdir.path().to_path_buf();
+
+            let old = path.join("old.log");
+            File::create(&old)
+                .unwrap()
+                .set_modified(SystemTime::now() - Duration::from_secs(7200))
+                .unwrap();
+            let recent = path.join("recent.log");
+            File::create(&recent).unwrap();
+
+            let mut rules = Rules::new();
+            rules.add_inclusion(RuleDef::glob_rule(r"**").unwrap());
+            let fs = Arc::new(Mutex::new(FileSystem::new(
+                vec![path.as_path().try_into().unwrap()],
+                Vec::new(),
+                HashMap::new(),
+                Lookback::Start,
+                Some(Duration::from_secs(3600)),
+                rules,
+                Vec::new(),
+                WatchConfig::default(),
+                OpenFilesConfig::default(),
+                ReadConfig::default(),
+                None,
+            )));
+            assert!(lookup_entry!(fs, old).is_none());
+            assert!(lookup_entry!(fs, recent).is_some());
+        });
+    }
+
+    // The files under a directory with its own rules only pass those
+    #[test]
+    fn filesystem_dir_rules() {
+        run_test(|| {
+            let tempdir = TempDir::new().unwrap();
+            let path = tempdir.path().to_path_buf();
+            let nginx = path.join("nginx");
+            let app = path.join("app");
+            create_dir(&nginx).unwrap();
+            create_dir(&app).unwrap();
+            for file in &["access.log", "access.txt"] {
+                File::create(nginx.join(file)).unwrap();
+                File::create(app.join(file)).unwrap();
+            }
+
+            let mut rules = Rules::new();
+            rules.add_inclusion(RuleDef::glob_rule("*.log").unwrap());
+            let mut app_rules = Rules::new();
+            app_rules.add_inclusion(RuleDef::glob_rule("*.txt").unwrap());
+            let fs = Arc::new(Mutex::new(FileSystem::new(
+                vec![path.as_path().try_into().unwrap()],
+                Vec::new(),
+                HashMap::new(),
+                Lookback::Start,
+                None,
+                rules,
+                vec![DirRules {
+                    dir: app.clone(),
+                    rules: app_rules,
+                    lookback: None,
+                }],
+                WatchConfig::default(),
+                OpenFilesConfig::default(),
+                ReadConfig::default(),
+                None,
+            )));
+            assert!(lookup_entry!(fs, nginx.join("access.log")).is_some());
+            assert!(lookup_entry!(fs, nginx.join("access.txt")).is_none());
+            assert!(lookup_entry!(fs, app.join("access.log")).is_none());
+            assert!(lookup_entry!(fs, app.join("access.txt")).is_some());
+        });
+    }
+
+    // Only the files and directories matching the file targets are tracked
+    #[test]
+    fn filesystem_file_targets() {
+        run_test(|| {
+            let tempdir = TempDir::new().unwrap();
+            let path = tempdir.path().to_path_buf();
+            let logs = path.join("srv/a/logs");
+            let other = path.join("srv/a/other");
+            create_dir_all(logs.join("2021")).unwrap();
+            create_dir_all(&other).unwrap();
+            File::create(logs.join("access.log")).unwrap();
+            File::create(logs.join("2021/access.log")).unwrap();
+            File::create(logs.join("access.txt")).unwrap();
+            File::create(other.join("access.log")).unwrap();
+            let single = path.join("out.txt");
+            File::create(&single).unwrap();
+
+            let targets = vec![
+                format!("{}/srv/*/logs/**/*.log", path.display())
+                    .parse()
+                    .unwrap(),
+                single.to_str().unwrap().parse().unwrap(),
+            ];
+            let fs = Arc::new(Mutex::new(FileSystem::new(
+                Vec::new(),
+                targets,
+                HashMap::new(),
+                Lookback::Start,
+                None,
+                Rules::new(),
+                Vec::new(),
+                WatchConfig::default(),
+                OpenFilesConfig::default(),
+                ReadConfig::default(),
+                None,
+            )));
+            assert!(lookup_entry!(fs, logs.join("access.log")).is_some());
+            assert!(lookup_entry!(fs, logs.join("2021/access.log")).is_some());
+            assert!(lookup_entry!(fs, logs.join("access.txt")).is_none());
+            assert!(lookup_entry!(fs, other).is_none());
+            assert!(lookup_entry!(fs, single).is_some());
+
+            // The directories created later are watched when they match
+            let new_logs = path.join("srv/b/logs");
+            create_dir_all(&new_logs).unwrap();
+            take_events!(fs, 1);
+            File::create(new_logs.join("access.log")).unwrap();
+            take_events!(fs, 1);
+            assert!(lookup_entry!(fs, new_logs.join("access.log")).is_some());
+        });
+    }
+
+    // Follows the files of a polled directory
+    #[test]
+    fn filesystem_poll_dir() {
+        run_test(|| {
+            let tempdir = TempDir::new().unwrap();
+            let path = tempdir.path().to_path_buf();
+
+            let mut rules = Rules::new();
+            rules.add_inclusion(RuleDef::glob_rule(r"**").unwrap());
+            let fs = Arc::new(Mutex::new(FileSystem::new(
+                vec![path.as_path().try_into().unwrap()],
+                Vec::new(),
+                HashMap::new(),
+                Lookback::Start,
+                None,
+                rules,
+                Vec::new(),
+                WatchConfig {
+                    poll_dirs: vec![path.clone()],
+                    poll_interval: std::time::Duration::from_millis(50),
+                },
+                OpenFilesConfig::default(),
+                ReadConfig::default(),
+                None,
+            )));
+
+            let a = path.join("a");
+            File::create(&a).unwrap();
+            take_events!(fs, 1);
+            assert!(lookup_entry!(fs, a).is_some());
+
+            let old = path.join("a.old");
+            rename(&a, &old).unwrap();
+            take_events!(fs, 1);
+            assert!(lookup_entry!(fs, a).is_none());
+            assert!(lookup_entry!(fs, old).is_some());
+
+            remove_file(&old).unwrap();
+            take_events!(fs, 1);
+            assert!(lookup_entry!(fs, old).is_none());
+        });
+    }
+
+    // Recovers the changes lost in an overflow of the inotify queue by rescanning the dirs
+    #[test]
+    fn filesystem_overflow_rescan() {
+        run_test(|| {
+            let tempdir = TempDir::new().unwrap();
+            let path = tempdir.path().to_path_buf();
+
+            let a = path.join("a.log");
+            let b = path.join("b.log");
+            let c = path.join("c.log");
+            File::create(&a).unwrap();
+            File::create(&b).unwrap();
+            let mut fs = new_fs::<()>(path.clone(), None);
+
+            // Changes made without processing their events
+            remove_file(&a).unwrap();
+            File::create(&c).unwrap();
+            let events = fs.process(WatchEvent::Overflow).unwrap();
+
+            let entries = fs.entries.clone();
+            let entries = entries.borrow();
+            assert!(fs.lookup(&a, &entries).is_none());
+            let b = fs.lookup(&b, &entries).unwrap();
+            let c = fs.lookup(&c, &entries).unwrap();
+            assert!(events
+                .iter()
+                .any(|e| matches!(e, Event::Write(key) if *key == b)));
+            assert!(events
+                .iter()
+                .any(|e| matches!(e, Event::Write(key) if *key == c)));
+        });
+    }
 }
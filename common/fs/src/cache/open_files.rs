@@ -0,0 +1,254 @@
+use crate::cache::tailed_file::TailedFileInner;
+
+use futures::lock::Mutex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+/// Limits the file handles kept open by the tailed files, nothing is closed by default.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OpenFilesConfig {
+    /// Files not read for this long are closed until their next write
+    pub idle_timeout: Option<Duration>,
+    /// Maximum number of open files, the least recently read ones are closed first
+    pub max_open: Option<usize>,
+}
+
+impl OpenFilesConfig {
+    /// Returns true when the open files are limited, otherwise they are not tracked.
+    pub fn is_limited(&self) -> bool {
+        self.idle_timeout.is_some() || self.max_open.is_some()
+    }
+}
+
+/// Tracks the open tailed files by their last use, closing the idle and least recently used
+/// ones. Files in use by a reader or being drained are never closed.
+pub(crate) struct OpenFiles {
+    config: OpenFilesConfig,
+    next_id: u64,
+    last_used: HashMap<u64, Instant>,
+    by_use: BTreeMap<(Instant, u64), Weak<Mutex<TailedFileInner>>>,
+    /// Files removed from the watched paths, they can't be reopened once closed
+    draining: HashSet<u64>,
+}
+
+impl OpenFiles {
+    pub(crate) fn new(config: OpenFilesConfig) -> Self {
+        OpenFiles {
+            config,
+            next_id: 0,
+            last_used: HashMap::new(),
+            by_use: BTreeMap::new(),
+            draining: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn idle_timeout(&self) -> Option<Duration> {
+        self.config.idle_timeout
+    }
+
+    pub(crate) fn is_limited(&self) -> bool {
+        self.config.is_limited()
+    }
+
+    /// Keeps a file open until it's released, while it's drained.
+    pub(crate) fn keep_open(&mut self, id: u64) {
+        self.draining.insert(id);
+        if let Some(last_used) = self.last_used.remove(&id) {
+            self.by_use.remove(&(last_used, id));
+        }
+    }
+
+    /// Forgets a drained file once released.
+    pub(crate) fn release(&mut self, id: u64) {
+        self.draining.remove(&id);
+    }
+
+    pub(crate) fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Records the use of an open file, closing the least recently used files over the limit.
+    pub(crate) fn touch(&mut self, id: u64, inner: &Arc<Mutex<TailedFileInner>>) {
+        if self.draining.contains(&id) {
+            return;
+        }
+        let now = Instant::now();
+        if let Some(last_used) = self.last_used.insert(id, now) {
+            self.by_use.remove(&(last_used, id));
+        }
+        self.by_use.insert((now, id), Arc::downgrade(inner));
+
+        if let Some(max_open) = self.config.max_open {
+            let excess = self.last_used.len().saturating_sub(max_open);
+            if excess > 0 {
+                self.close(excess, Some(id), None);
+            }
+        }
+    }
+
+    /// Closes the files not used within the idle timeout.
+    pub(crate) fn close_idle(&mut self) {
+        let idle_timeout = match self.config.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return,
+        };
+        if let Some(used_before) = Instant::now().checked_sub(idle_timeout) {
+            self.close(usize::MAX, None, Some(used_before));
+        }
+    }
+
+    /// Closes up to `count` of the least recently used files, skipping `skip` and the files
+    /// in use, and stopping at the first file used after `used_before`.
+    fn close(&mut self, mut count: usize, skip: Option<u64>, used_before: Option<Instant>) {
+        let mut last = None;
+        while count > 0 {
+            let next = match last {
+                Some(last) => self
+                    .by_use
+                    .range((Bound::Excluded(last), Bound::Unbounded))
+                    .next(),
+                None => self.by_use.iter().next(),
+            };
+            let key = match next {
+                Some((key, _)) => *key,
+                None => break,
+            };
+            last = Some(key);
+            if used_before.map_or(false, |used_before| key.0 > used_before) {
+                break;
+            }
+            if skip == Some(key.1) {
+                continue;
+            }
+            let closed = match self.by_use.get(&key).and_then(Weak::upgrade) {
+                // The file is no longer tailed
+                None => true,
+                Some(inner) => match inner.try_lock() {
+                    Some(mut inner) => {
+                        inner.close();
+                        true
+                    }
+                    None => false,
+                },
+            };
+            if closed {
+                self.by_use.remove(&key);
+                self.last_used.remove(&key.1);
+                count -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::tailed_file::{LazyLineSerializer, ReadConfig, TailedFile};
+    use futures::StreamExt;
+    use state::SpanVec;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    fn tailed_file(
+        path: &PathBuf,
+        open_files: &Arc<std::sync::Mutex<OpenFiles>>,
+    ) -> TailedFile<LazyLineSerializer> {
+        std::fs::File::create(path).unwrap();
+        TailedFile::new(
+            path,
+            SpanVec::new(),
+            None,
+            Some(open_files.clone()),
+            &ReadConfig::default(),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_close_least_recently_used() {
+        let dir = tempdir().unwrap();
+        let open_files = Arc::new(std::sync::Mutex::new(OpenFiles::new(OpenFilesConfig {
+            idle_timeout: None,
+            max_open: Some(2),
+        })));
+        let paths = (0..3)
+            .map(|i| dir.path().join(format!("{}.log", i)))
+            .collect::<Vec<_>>();
+        let mut files = paths
+            .iter()
+            .map(|path| tailed_file(path, &open_files))
+            .collect::<Vec<_>>();
+        assert!(!files[0].is_open());
+        assert!(files[1].is_open());
+        assert!(files[2].is_open());
+
+        // Closed files are reopened on their next write, from where they were left
+        writeln!(
+            OpenOptions::new().append(true).open(&paths[0]).unwrap(),
+            "line"
+        )
+        .unwrap();
+        let lines = tokio_test::block_on(async {
+            files[0]
+                .tail(vec![paths[0].clone()])
+                .await
+                .unwrap()
+                .collect::<Vec<_>>()
+                .await
+        });
+        assert_eq!(lines.len(), 1);
+        assert!(files[0].is_open());
+        assert!(!files[1].is_open());
+    }
+
+    #[test]
+    fn test_close_idle() {
+        let dir = tempdir().unwrap();
+        let open_files = Arc::new(std::sync::Mutex::new(OpenFiles::new(OpenFilesConfig {
+            idle_timeout: Some(Duration::from_millis(0)),
+            max_open: None,
+        })));
+        let path = dir.path().join("a.log");
+        let mut file = tailed_file(&path, &open_files);
+        assert!(file.is_open());
+        open_files.lock().unwrap().close_idle();
+        assert!(!file.is_open());
+
+        // Files replaced by another one can't be reopened
+        std::fs::rename(&path, dir.path().join("a.log.1")).unwrap();
+        std::fs::File::create(&path).unwrap();
+        writeln!(OpenOptions::new().append(true).open(&path).unwrap(), "line").unwrap();
+        let lines = tokio_test::block_on(file.tail(vec![path.clone()]));
+        assert!(lines.is_none());
+        assert!(!file.is_open());
+    }
+
+    #[test]
+    fn test_keep_draining_files_open() {
+        let dir = tempdir().unwrap();
+        let open_files = Arc::new(std::sync::Mutex::new(OpenFiles::new(OpenFilesConfig {
+            idle_timeout: Some(Duration::from_millis(0)),
+            max_open: Some(1),
+        })));
+        let paths = (0..2)
+            .map(|i| dir.path().join(format!("{}.log", i)))
+            .collect::<Vec<_>>();
+        let first = tailed_file(&paths[0], &open_files);
+        open_files.lock().unwrap().keep_open(first.id());
+
+        // Neither the limit nor the idle timeout close it while it's drained
+        let second = tailed_file(&paths[1], &open_files);
+        open_files.lock().unwrap().close_idle();
+        assert!(first.is_open());
+        assert!(!second.is_open());
+
+        open_files.lock().unwrap().release(first.id());
+        assert!(open_files.lock().unwrap().draining.is_empty());
+    }
+}
@@ -1,11 +1,17 @@
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
+use std::hash::{Hash, Hasher};
+use std::io::Seek;
 use std::ops::DerefMut;
 use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::Arc;
 
+use crate::cache::open_files::OpenFiles;
+use crate::encoding::{self, FileEncoding};
+
 use http::types::body::{KeyValueMap, LineBufferMut, LineBuilder, LineMeta, LineMetaMut};
 use http::types::error::LineMetaError;
 use http::types::serialize::{
@@ -13,7 +19,7 @@ use http::types::serialize::{
     SerializeUtf8, SerializeValue,
 };
 
-use state::{GetOffset, SpanVec};
+use state::{FileOffsetWriteHandle, GetOffset, SpanVec};
 
 use metrics::Metrics;
 
@@ -22,14 +28,16 @@ use async_trait::async_trait;
 
 use bytes::Bytes;
 
-use futures::io::AsyncBufReadExt;
+use encoding_rs::{Encoding, UTF_8};
+
 use futures::lock::Mutex;
 use futures::{stream, Stream, StreamExt};
 
-use serde_json::Value;
+use serde_json::{json, Value};
 
+use thiserror::Error;
 use time::OffsetDateTime;
-use tokio::io::{AsyncSeekExt, BufReader, SeekFrom};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, BufReader, SeekFrom};
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
 
 #[derive(Debug)]
@@ -303,18 +311,270 @@ impl GetOffset for LazyLineSerializer {
     }
 }
 
-#[derive(Debug)]
+/// Number of bytes at the start of a file hashed to detect it was truncated and rewritten
+const FINGERPRINT_LEN: u64 = 256;
+
+/// What is done with the lines longer than the maximum line length.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LongLines {
+    /// Sends the start of the line, flagged with `truncated` in its meta
+    Truncate,
+    /// Sends the line in chunks of the maximum length
+    Split,
+    /// Drops the line
+    Skip,
+}
+
+impl Default for LongLines {
+    fn default() -> Self {
+        LongLines::Truncate
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("Unknown long lines strategy: {0}, expected truncate, split or skip")]
+pub struct ParseLongLinesError(String);
+
+impl std::str::FromStr for LongLines {
+    type Err = ParseLongLinesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "truncate" => Ok(LongLines::Truncate),
+            "split" => Ok(LongLines::Split),
+            "skip" => Ok(LongLines::Skip),
+            _ => Err(ParseLongLinesError(s.into())),
+        }
+    }
+}
+
+/// Settings for reading the tailed files.
+#[derive(Clone, Debug, Default)]
+pub struct ReadConfig {
+    /// Maximum number of bytes read again from the end of a truncated file, the whole file is
+    /// read when unset
+    pub truncate_reread_limit: Option<u64>,
+    /// Encodings of the files by path, used when a file has no byte order mark. Files are read
+    /// as UTF-8 otherwise
+    pub encodings: Vec<FileEncoding>,
+    /// Maximum length of the lines in bytes read from the file, without their newline
+    pub max_line_len: Option<usize>,
+    pub long_lines: LongLines,
+}
+
 pub struct TailedFileInner {
-    reader: Compat<tokio::io::BufReader<tokio::fs::File>>,
+    /// Closed while the file is idle, see `OpenFiles`
+    reader: Option<Compat<tokio::io::BufReader<tokio::fs::File>>>,
+    /// Position of the reader, where it resumes once reopened
+    position: u64,
     buf: Vec<u8>,
     initial_offsets: SpanVec,
     offset: u64,
     inode: u64,
+    id: u64,
+    open_files: Option<Arc<std::sync::Mutex<OpenFiles>>>,
+    /// Length and hash of the first bytes of the file
+    fingerprint: Option<(u64, u64)>,
+    truncate_reread_limit: Option<u64>,
+    offsets_handle: Option<FileOffsetWriteHandle>,
+    /// Encoding the lines are decoded from, UTF-8 lines are read as is
+    encoding: Option<&'static Encoding>,
+    /// Partial line of a file read in another encoding, decoded once complete
+    undecoded: Vec<u8>,
+    /// Length of the byte order mark, unset until the file is long enough to detect it
+    bom_len: Option<u64>,
+    max_line_len: Option<(usize, LongLines)>,
+    /// Set while the rest of a line longer than the maximum is read
+    in_long_line: bool,
+}
+
+impl std::fmt::Debug for TailedFileInner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TailedFileInner")
+            .field("open", &self.reader.is_some())
+            .field("position", &self.position)
+            .field("offset", &self.offset)
+            .field("inode", &self.inode)
+            .finish()
+    }
+}
+
+impl TailedFileInner {
+    /// Closes the file handle, the file is reopened by `reopen` on its next write.
+    pub(crate) fn close(&mut self) {
+        if self.reader.take().is_some() {
+            debug!("closed idle file with inode {}", self.inode);
+            Metrics::fs().decrement_tracked_files();
+        }
+    }
+
+    /// Reopens the file closed by `close` from one of its paths, at its previous position.
+    /// Returns false when the file can't be found under its paths anymore.
+    fn reopen(&mut self, paths: &[PathBuf]) -> bool {
+        if self.reader.is_some() {
+            return true;
+        }
+        for path in paths {
+            let mut file = match std::fs::File::open(path) {
+                Ok(file) => file,
+                Err(_) => continue,
+            };
+            // The path may now belong to another file, e.g. after a rotation
+            match file.metadata() {
+                Ok(metadata) if metadata.ino() == self.inode => {}
+                _ => continue,
+            }
+            if let Err(e) = file.seek(std::io::SeekFrom::Start(self.position)) {
+                warn!("unable to reopen {:?}: {}", path, e);
+                return false;
+            }
+            debug!("reopened {:?} at {}", path, self.position);
+            self.reader = Some(BufReader::new(tokio::fs::File::from_std(file)).compat());
+            Metrics::fs().increment_tracked_files();
+            return true;
+        }
+        debug!("unable to reopen {:?}, the file is gone", paths.first());
+        false
+    }
+
+    fn reader(&mut self) -> &mut Compat<tokio::io::BufReader<tokio::fs::File>> {
+        self.reader.as_mut().expect("file should be open")
+    }
+
+    /// Reads the first `len` bytes of the file, leaving the reader where it was.
+    async fn read_head(&mut self, len: u64) -> std::io::Result<Vec<u8>> {
+        let file = self.reader().get_mut().get_mut();
+        let current = file.seek(SeekFrom::Current(0)).await?;
+        file.seek(SeekFrom::Start(0)).await?;
+        let mut head = vec![0; len as usize];
+        let read = file.read_exact(&mut head).await;
+        file.seek(SeekFrom::Start(current)).await?;
+        read?;
+        Ok(head)
+    }
+
+    /// Hashes the first `len` bytes of the file, leaving the reader where it was.
+    async fn hash_head(&mut self, len: u64) -> std::io::Result<u64> {
+        let head = self.read_head(len).await?;
+        let mut hasher = DefaultHasher::new();
+        head.hash(&mut hasher);
+        Ok(hasher.finish())
+    }
+
+    /// Detects the encoding of the file from its byte order mark, which is skipped when reading
+    /// from the start of the file. Files without one keep the encoding set for their path.
+    async fn detect_bom(&mut self, len: u64) -> std::io::Result<()> {
+        if self.bom_len.is_some() {
+            return Ok(());
+        }
+        let head = self.read_head(len.min(encoding::MAX_BOM_LEN)).await?;
+        let bom_len = match Encoding::for_bom(&head) {
+            Some((encoding, bom_len)) => {
+                debug!("detected {} in inode {}", encoding.name(), self.inode);
+                self.encoding = Some(encoding).filter(|encoding| *encoding != UTF_8);
+                bom_len as u64
+            }
+            // Not long enough to tell yet
+            None if len < encoding::MAX_BOM_LEN => return Ok(()),
+            None => 0,
+        };
+        self.bom_len = Some(bom_len);
+
+        if bom_len > 0 && self.position == 0 {
+            self.reader()
+                .get_mut()
+                .seek(SeekFrom::Start(bom_len))
+                .await?;
+            self.offset = bom_len;
+            self.position = bom_len;
+        }
+        Ok(())
+    }
+
+    /// Returns true when the file was truncated since it was last read, either it's shorter
+    /// than what was read or its first bytes changed because it was rewritten since.
+    async fn is_truncated(&mut self, len: u64) -> bool {
+        if self.position > len {
+            return true;
+        }
+        match self.fingerprint {
+            Some((head_len, hash)) => match self.hash_head(head_len).await {
+                Ok(current) => current != hash,
+                Err(e) => {
+                    warn!("unable to read the start of inode {}: {}", self.inode, e);
+                    false
+                }
+            },
+            None => false,
+        }
+    }
+
+    /// Fingerprints the first bytes of the file, until it's long enough to hash all of them.
+    async fn update_fingerprint(&mut self, len: u64) {
+        let head_len = len.min(FINGERPRINT_LEN);
+        if head_len <= self.fingerprint.map_or(0, |(head_len, _)| head_len) {
+            return;
+        }
+        match self.hash_head(head_len).await {
+            Ok(hash) => self.fingerprint = Some((head_len, hash)),
+            Err(e) => warn!("unable to read the start of inode {}: {}", self.inode, e),
+        }
+    }
+
+    /// Reads a truncated file again from its start, or from the first line within the re-read
+    /// limit of its end. The offsets of its previous content, flushed or staged, are dropped
+    /// from the state.
+    async fn restart(&mut self, len: u64) -> std::io::Result<()> {
+        self.fingerprint = None;
+        self.initial_offsets = SpanVec::new();
+        self.buf.clear();
+        self.undecoded.clear();
+        self.in_long_line = false;
+        if let Some(offsets_handle) = self.offsets_handle.as_ref() {
+            if let Err(e) = offsets_handle.delete(self.inode).await {
+                warn!("unable to drop the offsets of inode {}: {}", self.inode, e);
+            }
+        }
+
+        let start = match self.truncate_reread_limit {
+            Some(limit) if len > limit => len - limit,
+            _ => 0,
+        };
+        let bom_len = self.bom_len.unwrap_or(0);
+        let encoding = self.encoding;
+        // Seeking the buffered reader discards the lines buffered before the truncation
+        let reader = self.reader();
+        let offset = if start <= bom_len {
+            reader.get_mut().seek(SeekFrom::Start(bom_len)).await?
+        } else {
+            // Skip the end of the line cut by the limit, from the start of its code unit
+            let unit_len = encoding::code_unit_len(encoding);
+            let from = (start - 1) / unit_len * unit_len;
+            reader.get_mut().seek(SeekFrom::Start(from)).await?;
+            let (mut skipped, mut undecoded) = (Vec::new(), Vec::new());
+            let (count, _) =
+                encoding::read_line(reader, &mut skipped, &mut undecoded, encoding, None).await?;
+            from + count as u64
+        };
+        self.offset = offset;
+        self.position = offset;
+        Ok(())
+    }
+}
+
+impl Drop for TailedFileInner {
+    fn drop(&mut self) {
+        if self.reader.is_some() {
+            Metrics::fs().decrement_tracked_files();
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct TailedFile<T> {
     inner: Arc<Mutex<TailedFileInner>>,
+    /// Identifies the file in `OpenFiles`
+    id: u64,
     resume_events_sender: Option<Sender<(u64, OffsetDateTime)>>,
     _phantom: std::marker::PhantomData<T>,
 }
@@ -324,22 +584,77 @@ impl<T> TailedFile<T> {
         path: &std::path::Path,
         initial_offsets: SpanVec,
         resume_events_sender: Option<Sender<(u64, OffsetDateTime)>>,
+        open_files: Option<Arc<std::sync::Mutex<OpenFiles>>>,
+        read_config: &ReadConfig,
+        offsets_handle: Option<FileOffsetWriteHandle>,
     ) -> Result<Self, std::io::Error> {
+        let reader = BufReader::new(tokio::fs::File::from_std(
+            std::fs::OpenOptions::new().read(true).open(path)?,
+        ))
+        .compat();
+        let inode = path.metadata()?.ino();
+        let encoding = encoding::for_path(&read_config.encodings, path);
+        Metrics::fs().increment_tracked_files();
+        let id = open_files.as_ref().map_or(0, |open_files| {
+            open_files.lock().expect("poisoned").next_id()
+        });
+        let inner = Arc::new(Mutex::new(TailedFileInner {
+            reader: Some(reader),
+            position: 0,
+            buf: Vec::new(),
+            offset: 0,
+            initial_offsets,
+            inode,
+            id,
+            open_files: open_files.clone(),
+            fingerprint: None,
+            truncate_reread_limit: read_config.truncate_reread_limit,
+            offsets_handle,
+            encoding,
+            undecoded: Vec::new(),
+            bom_len: None,
+            max_line_len: read_config
+                .max_line_len
+                .map(|max_line_len| (max_line_len, read_config.long_lines)),
+            in_long_line: false,
+        }));
+        if let Some(open_files) = open_files {
+            open_files.lock().expect("poisoned").touch(id, &inner);
+        }
         Ok(Self {
-            inner: Arc::new(Mutex::new(TailedFileInner {
-                reader: BufReader::new(tokio::fs::File::from_std(
-                    std::fs::OpenOptions::new().read(true).open(path)?,
-                ))
-                .compat(),
-                buf: Vec::new(),
-                offset: 0,
-                initial_offsets,
-                inode: path.metadata()?.ino(),
-            })),
+            inner,
+            id,
             resume_events_sender,
             _phantom: std::marker::PhantomData::<T>,
         })
     }
+
+    /// Reopens the file when it was closed and records its use, returning false when it
+    /// can't be reopened.
+    fn open(
+        inner: &Arc<Mutex<TailedFileInner>>,
+        locked: &mut TailedFileInner,
+        paths: &[PathBuf],
+    ) -> bool {
+        if !locked.reopen(paths) {
+            return false;
+        }
+        if let Some(open_files) = locked.open_files.as_ref() {
+            open_files.lock().expect("poisoned").touch(locked.id, inner);
+        }
+        true
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_open(&self) -> bool {
+        self.inner
+            .try_lock()
+            .map_or(true, |inner| inner.reader.is_some())
+    }
 }
 
 impl TailedFile<LineBuilder> {
@@ -348,8 +663,11 @@ impl TailedFile<LineBuilder> {
         // get the file len
         {
             let mut inner = self.inner.lock().await;
+            if !Self::open(&self.inner, &mut inner, &paths) {
+                return None;
+            }
             let len = match inner
-                .reader
+                .reader()
                 .get_ref()
                 .get_ref()
                 .metadata()
@@ -366,44 +684,36 @@ impl TailedFile<LineBuilder> {
             if let Some(initial_offset) = inner.initial_offsets.pop_first().map(|offset| offset.end)
             {
                 inner.offset = inner
-                    .reader
+                    .reader()
                     .get_mut()
                     .get_mut()
                     .seek(SeekFrom::Start(initial_offset))
                     .await
                     .map_err(|e| error!("{:?}", e))
                     .unwrap_or(0);
+                inner.position = inner.offset;
                 info!("initial_offset {} for {}", inner.offset, inner.inode);
             };
 
-            // if we are at the end of the file there's no work to do
-            if inner.offset == len {
-                return None;
-            }
-
-            // if the offset is greater than the file's len
-            // it's very likely a truncation occurred
-            if inner.offset > len {
+            if inner.is_truncated(len).await {
                 info!(
                     "{:?} was truncated from {} to {}",
-                    &paths[0], inner.offset, len
+                    &paths[0], inner.position, len
                 );
-                // Reset offset back to the start... ish?
-                // TODO: Work out the purpose of the 8192 something to do with lookback? That seems wrong.
-                inner.offset = if len < 8192 { 0 } else { len };
-                // seek to the offset, this creates the "tailing" effect
-                let offset = inner.offset;
-                if let Err(e) = inner
-                    .reader
-                    .get_mut()
-                    .get_mut()
-                    .seek(SeekFrom::Start(offset))
-                    .await
-                {
+                if let Err(e) = inner.restart(len).await {
                     error!("error seeking {:?}", e);
                     return None;
                 }
             }
+            inner.update_fingerprint(len).await;
+            if let Err(e) = inner.detect_bom(len).await {
+                warn!("unable to read the start of {:?}: {}", &paths[0], e);
+            }
+
+            // if we are at the end of the file there's no work to do
+            if inner.offset == len {
+                return None;
+            }
         }
 
         Some(
@@ -412,23 +722,39 @@ impl TailedFile<LineBuilder> {
                 let mut borrow = reader.try_lock().unwrap();
                 let TailedFileInner {
                     ref mut reader,
+                    ref mut position,
                     ref mut buf,
                     ref mut offset,
+                    ref mut undecoded,
+                    ref encoding,
+                    ref max_line_len,
+                    ref mut in_long_line,
                     ..
                 } = borrow.deref_mut();
 
                 let res = {
-                    let mut pinned_reader = Pin::new(reader);
+                    let mut pinned_reader = Pin::new(reader.as_mut()?);
                     if let Some(c) = buf.last() {
                         if *c == b'\n' {
                             buf.clear();
                         }
                     }
-                    pinned_reader.read_until(b'\n', buf).await
+                    read_next_line(
+                        &mut pinned_reader,
+                        buf,
+                        undecoded,
+                        *encoding,
+                        *max_line_len,
+                        in_long_line,
+                    )
+                    .await
                 };
-                let c = match res {
-                    Ok(c) if c == 0 => return None,
-                    Ok(n) => n,
+                let (c, truncated) = match res {
+                    Ok((c, _)) if c == 0 => return None,
+                    Ok((n, truncated)) => {
+                        *position += n as u64;
+                        (n, truncated)
+                    }
                     Err(e) => {
                         warn!("error encountered while tailing file: {}", e);
                         return Some((Err(e), rc_reader));
@@ -436,14 +762,15 @@ impl TailedFile<LineBuilder> {
                 };
                 let n: u64 = c.try_into().unwrap();
                 *offset += n;
-                let mut s = String::from_utf8_lossy(&buf[..c]).to_string();
+                // Decoded and truncated lines don't have the length read from the file
+                let mut s = String::from_utf8_lossy(buf).to_string();
                 if s.ends_with('\n') {
                     s.pop();
                     if s.ends_with('\r') {
                         s.pop();
                     }
                     Metrics::fs().add_bytes(n);
-                    Some((Ok(s), rc_reader))
+                    Some((Ok((s, truncated)), rc_reader))
                 } else {
                     None
                 }
@@ -455,14 +782,19 @@ impl TailedFile<LineBuilder> {
                     async move {
                         let paths = paths.clone();
                         line_res.ok().map({
-                            move |line| {
+                            move |(line, truncated)| {
                                 debug!("tailer sendings lines for {:?}", paths);
                                 stream::iter(paths.into_iter().map({
                                     move |path| {
                                         Metrics::fs().increment_lines();
-                                        LineBuilder::new()
+                                        let line = LineBuilder::new()
                                             .line(line.clone())
-                                            .file(path.to_str().unwrap_or("").to_string())
+                                            .file(path.to_str().unwrap_or("").to_string());
+                                        if truncated {
+                                            line.meta(json!({ "truncated": true }))
+                                        } else {
+                                            line
+                                        }
                                     }
                                 }))
                             }
@@ -490,9 +822,19 @@ impl LazyLines {
         resume_channel_send: Option<Sender<(u64, OffsetDateTime)>>,
     ) -> Self {
         let (initial_end, initial_offset): (Option<u64>, Option<u64>) = {
-            let inner = &mut reader.lock().await.reader;
-
-            let inner = inner.get_mut().get_mut();
+            let mut locked = reader.lock().await;
+            let inner = match locked.reader.as_mut() {
+                Some(inner) => inner.get_mut().get_mut(),
+                None => {
+                    return Self {
+                        reader: reader.clone(),
+                        total_read: 0,
+                        target_read: None,
+                        paths: Arc::new(paths),
+                        resume_channel_send,
+                    }
+                }
+            };
             let initial_offset = match inner.seek(SeekFrom::Current(0)).await {
                 Ok(v) => Some(v),
                 Err(e) => {
@@ -537,8 +879,11 @@ impl TailedFile<LazyLineSerializer> {
     ) -> Option<impl Stream<Item = LazyLineSerializer>> {
         let target_read = {
             let mut inner = self.inner.lock().await;
+            if !Self::open(&self.inner, &mut inner, &paths) {
+                return None;
+            }
             let len = match inner
-                .reader
+                .reader()
                 .get_ref()
                 .get_ref()
                 .metadata()
@@ -555,46 +900,37 @@ impl TailedFile<LazyLineSerializer> {
             if let Some(initial_offset) = inner.initial_offsets.pop_first().map(|offset| offset.end)
             {
                 inner.offset = inner
-                    .reader
+                    .reader()
                     .get_mut()
                     .get_mut()
                     .seek(SeekFrom::Start(initial_offset))
                     .await
                     .map_err(|e| error!("{:?}", e))
-                    .unwrap_or(0)
+                    .unwrap_or(0);
+                inner.position = inner.offset;
             };
 
-            let target_read = inner.initial_offsets.first().map(|offsets| offsets.start);
-
-            // if we are at the end of the file there's no work to do
-            if inner.offset == len {
-                return None;
-            }
-
-            // if the offset is greater than the file's len
-            // it's very likely a truncation occurred
-            if inner.offset > len {
+            if inner.is_truncated(len).await {
                 info!(
                     "{:?} was truncated from {} to {}",
-                    &paths[0], inner.offset, len
+                    &paths[0], inner.position, len
                 );
-                // Reset offset back to the start... ish?
-                // TODO: Work out the purpose of the 8192 something to do with lookback? That seems wrong.
-                inner.offset = if len < 8192 { 0 } else { len };
-                // seek to the offset, this creates the "tailing" effect
-                let offset = inner.offset;
-                if let Err(e) = inner
-                    .reader
-                    .get_mut()
-                    .get_mut()
-                    .seek(SeekFrom::Start(offset))
-                    .await
-                {
+                if let Err(e) = inner.restart(len).await {
                     error!("error seeking {:?}", e);
                     return None;
                 }
             }
-            target_read
+            inner.update_fingerprint(len).await;
+            if let Err(e) = inner.detect_bom(len).await {
+                warn!("unable to read the start of {:?}: {}", &paths[0], e);
+            }
+
+            // if we are at the end of the file there's no work to do
+            if inner.offset == len {
+                return None;
+            }
+
+            inner.initial_offsets.first().map(|offsets| offsets.start)
         };
 
         Some(
@@ -624,12 +960,32 @@ impl TailedFile<LazyLineSerializer> {
                     let mut borrow = rc_reader.try_lock().unwrap();
                     let TailedFileInner {
                         ref mut reader,
+                        ref mut position,
                         ref mut buf,
                         ref mut offset,
                         ref inode,
+                        ref mut undecoded,
+                        ref encoding,
+                        ref max_line_len,
+                        ref mut in_long_line,
                         ..
                     } = borrow.deref_mut();
 
+                    // The file was closed by another file reaching the open files limit,
+                    // continue on the next event once it's reopened
+                    let reader = match reader.as_mut() {
+                        Some(reader) => reader,
+                        None => {
+                            if let Some(sender) = resume_channel_send {
+                                if let Err(e) = sender.try_send((*inode, OffsetDateTime::now_utc()))
+                                {
+                                    warn!("Couldn't send tailer continuation event: {}", e);
+                                };
+                            }
+                            return None;
+                        }
+                    };
+
                     let mut initial_offset = *offset;
                     if let Some(c) = buf.last() {
                         if *c == b'\n' {
@@ -639,6 +995,9 @@ impl TailedFile<LazyLineSerializer> {
                                 .expect("Couldn't convert buffer length to u64")
                         }
                     }
+                    // The partial lines of decoded files are kept undecoded
+                    initial_offset -= u64::try_from(undecoded.len())
+                        .expect("Couldn't convert buffer length to u64");
 
                     let mut pinned_reader = Pin::new(reader);
                     // If we've read more than a 16 KB from this one event and reached the end
@@ -662,9 +1021,20 @@ impl TailedFile<LazyLineSerializer> {
                     }
 
                     // Read a line into the internal buffer
-                    let result = pinned_reader.read_until(b'\n', buf).await;
+                    let result = read_next_line(
+                        &mut pinned_reader,
+                        buf,
+                        undecoded,
+                        *encoding,
+                        *max_line_len,
+                        in_long_line,
+                    )
+                    .await;
+                    if let Ok((count, _)) = &result {
+                        *position += *count as u64;
+                    }
                     match result {
-                        Ok(count) if count > 0 => {
+                        Ok((count, truncated)) if count > 0 => {
                             if let Some(c) = buf.last() {
                                 if *c == b'\n' {
                                     *total_read += count;
@@ -678,11 +1048,15 @@ impl TailedFile<LazyLineSerializer> {
                                         let rc_reader = rc_reader.clone();
                                         let current_offset = (*inode, initial_offset, *offset);
                                         move |path_idx| {
-                                            LazyLineSerializer::new(
+                                            let mut line = LazyLineSerializer::new(
                                                 rc_reader.clone(),
                                                 paths[path_idx].clone(),
                                                 current_offset,
-                                            )
+                                            );
+                                            if truncated {
+                                                line.meta = Some(json!({ "truncated": true }));
+                                            }
+                                            line
                                         }
                                     });
                                     Some((Ok(stream::iter(ret)), lazy_lines))
@@ -712,6 +1086,72 @@ impl TailedFile<LazyLineSerializer> {
     }
 }
 
+/// Reads the next line into `buf`, cutting the lines longer than the maximum line length.
+/// Returns the number of bytes read from the file and whether the line was truncated.
+async fn read_next_line<R>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    undecoded: &mut Vec<u8>,
+    encoding: Option<&'static Encoding>,
+    max_line_len: Option<(usize, LongLines)>,
+    in_long_line: &mut bool,
+) -> std::io::Result<(usize, bool)>
+where
+    R: futures::io::AsyncBufRead + Unpin,
+{
+    let (max_len, long_lines) = match max_line_len {
+        Some(max_line_len) => max_line_len,
+        None => {
+            let (count, _) = encoding::read_line(reader, buf, undecoded, encoding, None).await?;
+            return Ok((count, false));
+        }
+    };
+    let mut count = 0;
+    loop {
+        let (read, full) =
+            encoding::read_line(reader, buf, undecoded, encoding, Some(max_len)).await?;
+        count += read;
+        if !full {
+            // The end of a truncated or skipped line is dropped
+            if buf.last() == Some(&b'\n')
+                && std::mem::replace(in_long_line, false)
+                && long_lines != LongLines::Split
+            {
+                buf.clear();
+                continue;
+            }
+            return Ok((count, false));
+        }
+
+        let continued = std::mem::replace(in_long_line, true);
+        if !continued {
+            Metrics::fs().increment_long_lines();
+        }
+        match long_lines {
+            LongLines::Split => {
+                end_line(buf, undecoded, encoding);
+                return Ok((count, false));
+            }
+            LongLines::Truncate if !continued => {
+                end_line(buf, undecoded, encoding);
+                return Ok((count, true));
+            }
+            _ => {
+                buf.clear();
+                undecoded.clear();
+            }
+        }
+    }
+}
+
+/// Ends a line cut at the maximum line length, decoding it if needed.
+fn end_line(buf: &mut Vec<u8>, undecoded: &mut Vec<u8>, encoding: Option<&'static Encoding>) {
+    if let Some(encoding) = encoding {
+        encoding::decode(buf, undecoded, encoding);
+    }
+    buf.push(b'\n');
+}
+
 /// Returns a Bytes using a copy of the line without the last char.
 fn line_bytes(buf: &[u8]) -> Bytes {
     // This method can be removed once we re-implement a line reader
@@ -726,6 +1166,8 @@ mod tests {
     use tempfile::tempdir;
 
     use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::Path;
 
     #[test]
     fn lazy_lines_should_get_set_line_with_rules() {
@@ -764,22 +1206,229 @@ mod tests {
         }
     }
 
+    fn tail_lines(file: &mut TailedFile<LazyLineSerializer>, path: &Path) -> Vec<String> {
+        tokio_test::block_on(async {
+            let mut lines = Vec::new();
+            if let Some(stream) = file.tail(vec![path.to_path_buf()]).await {
+                futures::pin_mut!(stream);
+                while let Some(mut line) = stream.next().await {
+                    let line = std::str::from_utf8(line.get_line_buffer().unwrap()).unwrap();
+                    lines.push(line.to_string());
+                }
+            }
+            lines
+        })
+    }
+
+    #[test]
+    fn tail_should_restart_truncated_files() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.log");
+        let mut writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let mut file = TailedFile::new(
+            &path,
+            SpanVec::new(),
+            None,
+            None,
+            &ReadConfig::default(),
+            None,
+        )
+        .unwrap();
+
+        (0..1000).for_each(|i| writeln!(writer, "first {}", i).unwrap());
+        assert_eq!(tail_lines(&mut file, &path).len(), 1000);
+
+        // Truncated and written past the previous length before the next read
+        writer.set_len(0).unwrap();
+        (0..2000).for_each(|i| writeln!(writer, "second {}", i).unwrap());
+        let lines = tail_lines(&mut file, &path);
+        assert_eq!(lines.len(), 2000);
+        assert_eq!(lines[0], "second 0");
+
+        // Truncated to a shorter length
+        writer.set_len(0).unwrap();
+        writeln!(writer, "third").unwrap();
+        assert_eq!(tail_lines(&mut file, &path), vec!["third"]);
+    }
+
+    #[test]
+    fn tail_should_limit_truncated_files_reread() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.log");
+        let mut writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let config = ReadConfig {
+            truncate_reread_limit: Some(27),
+            ..Default::default()
+        };
+        let mut file = TailedFile::new(&path, SpanVec::new(), None, None, &config, None).unwrap();
+
+        (0..10).for_each(|i| writeln!(writer, "first {}", i).unwrap());
+        assert_eq!(tail_lines(&mut file, &path).len(), 10);
+
+        // Only the lines within the last 27 bytes are read again
+        writer.set_len(0).unwrap();
+        (0..10).for_each(|i| writeln!(writer, "second {}", i).unwrap());
+        assert_eq!(
+            tail_lines(&mut file, &path),
+            vec!["second 7", "second 8", "second 9"]
+        );
+
+        // Lines cut by the limit are skipped
+        writer.set_len(0).unwrap();
+        (0..10).for_each(|i| writeln!(writer, "third {}", i).unwrap());
+        assert_eq!(
+            tail_lines(&mut file, &path),
+            vec!["third 7", "third 8", "third 9"]
+        );
+    }
+
+    #[test]
+    fn tail_should_decode_files_with_a_bom() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.log");
+        let mut writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let mut file = TailedFile::new(
+            &path,
+            SpanVec::new(),
+            None,
+            None,
+            &ReadConfig::default(),
+            None,
+        )
+        .unwrap();
+
+        let utf16 = |text: &str| {
+            text.encode_utf16()
+                .flat_map(|unit| unit.to_le_bytes().to_vec())
+                .collect::<Vec<_>>()
+        };
+        writer.write_all(&[0xff, 0xfe]).unwrap();
+        writer.write_all(&utf16("first\nsecond ")).unwrap();
+        assert_eq!(tail_lines(&mut file, &path), vec!["first"]);
+        // Offsets are tracked in bytes of the file
+        assert_eq!(tokio_test::block_on(file.inner.lock()).offset, 14);
+
+        writer.write_all(&utf16("\u{0a0a}\n")).unwrap();
+        assert_eq!(tail_lines(&mut file, &path), vec!["second \u{0a0a}"]);
+    }
+
+    #[test]
+    fn tail_should_decode_files_by_path() {
+        let dir = tempdir().unwrap();
+        let config = ReadConfig {
+            encodings: vec![
+                format!("{}/*.latin1:latin1", dir.path().display())
+                    .parse()
+                    .unwrap(),
+                format!("{}/*.utf16:utf-16be", dir.path().display())
+                    .parse()
+                    .unwrap(),
+            ],
+            ..Default::default()
+        };
+
+        let path = dir.path().join("test.latin1");
+        std::fs::write(&path, b"caf\xe9\n").unwrap();
+        let mut file = TailedFile::new(&path, SpanVec::new(), None, None, &config, None).unwrap();
+        assert_eq!(tail_lines(&mut file, &path), vec!["caf\u{e9}"]);
+
+        let path = dir.path().join("test.utf16");
+        let bytes = "\u{0a0d}\n"
+            .encode_utf16()
+            .flat_map(|unit| unit.to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+        std::fs::write(&path, bytes).unwrap();
+        let mut file = TailedFile::new(&path, SpanVec::new(), None, None, &config, None).unwrap();
+        assert_eq!(tail_lines(&mut file, &path), vec!["\u{0a0d}"]);
+
+        // A byte order mark takes precedence over the path
+        let path = dir.path().join("bom.latin1");
+        std::fs::write(&path, "\u{feff}caf\u{e9}\n").unwrap();
+        let mut file = TailedFile::new(&path, SpanVec::new(), None, None, &config, None).unwrap();
+        assert_eq!(tail_lines(&mut file, &path), vec!["caf\u{e9}"]);
+    }
+
+    #[test]
+    fn tail_should_cut_long_lines() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.log");
+        std::fs::write(&path, "abcdefghij\nabc\n").unwrap();
+        let tailed_file = |long_lines| {
+            let config = ReadConfig {
+                max_line_len: Some(4),
+                long_lines,
+                ..Default::default()
+            };
+            TailedFile::new(&path, SpanVec::new(), None, None, &config, None).unwrap()
+        };
+
+        assert_eq!(
+            tail_lines(&mut tailed_file(LongLines::Truncate), &path),
+            vec!["abcd", "abc"]
+        );
+        assert_eq!(
+            tail_lines(&mut tailed_file(LongLines::Split), &path),
+            vec!["abcd", "efgh", "ij", "abc"]
+        );
+        assert_eq!(
+            tail_lines(&mut tailed_file(LongLines::Skip), &path),
+            vec!["abc"]
+        );
+
+        // Truncated lines are flagged in their meta
+        let mut file = tailed_file(LongLines::Truncate);
+        let metas = tokio_test::block_on(async {
+            file.tail(vec![path.clone()])
+                .await
+                .unwrap()
+                .map(|line| line.meta)
+                .collect::<Vec<_>>()
+                .await
+        });
+        assert_eq!(metas, vec![Some(json!({ "truncated": true })), None]);
+    }
+
     fn get_line() -> LazyLineSerializer {
         let file_path = tempdir().unwrap().into_path().join("test.log");
         let file_inner = Arc::new(Mutex::new(TailedFileInner {
-            reader: BufReader::new(tokio::fs::File::from_std(
-                OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(true)
-                    .open(&file_path)
-                    .unwrap(),
-            ))
-            .compat(),
+            reader: Some(
+                BufReader::new(tokio::fs::File::from_std(
+                    OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create(true)
+                        .open(&file_path)
+                        .unwrap(),
+                ))
+                .compat(),
+            ),
+            position: 0,
             buf: Vec::new(),
             offset: 0,
             initial_offsets: SpanVec::new(),
             inode: 0,
+            id: 0,
+            open_files: None,
+            fingerprint: None,
+            truncate_reread_limit: None,
+            offsets_handle: None,
+            encoding: None,
+            undecoded: Vec::new(),
+            bom_len: None,
+            max_line_len: None,
+            in_long_line: false,
         }));
         LazyLineSerializer::new(file_inner, "file/path.log".to_owned(), (0, 0, 0))
     }
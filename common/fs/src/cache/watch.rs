@@ -1,19 +1,118 @@
-use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
-use std::collections::HashMap;
+use inotify::{EventMask, Inotify, WatchMask};
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsString;
+use std::fs::{read_dir, symlink_metadata, Metadata};
 use std::io;
-use std::path::Path;
+use std::os::unix::fs::{DirEntryExt, MetadataExt};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use futures::future::Either;
 use futures::{Stream, StreamExt};
+use metrics::Metrics;
 use smallvec::SmallVec;
 
 use time::OffsetDateTime;
 use tokio::sync::Mutex;
-use tokio::time::Instant;
+use tokio::time::{Instant, MissedTickBehavior};
 
 const INOTIFY_EVENT_GRACE_PERIOD_MS: u64 = 10;
+const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+const INOTIFY_LIMITS_PATH: &str = "/proc/sys/fs/inotify";
+// Errors returned by inotify once the watch or instance limits are reached
+const ENOSPC: i32 = 28;
+const EMFILE: i32 = 24;
+
+/// Filesystem types on which inotify doesn't report the changes made by other hosts. Overlay
+/// isn't one of them: inotify reports the changes made through the overlay mount, only the
+/// changes made directly to its layers are missed, see `WatchConfig::poll_dirs`.
+const NETWORK_FS_TYPES: &[&str] = &[
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "ceph",
+    "glusterfs",
+    "9p",
+    "afs",
+    "fuse",
+];
+
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Configures how the paths are watched.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchConfig {
+    /// Directories watched by polling instead of inotify, in addition to the ones on
+    /// network filesystems
+    pub poll_dirs: Vec<PathBuf>,
+    /// Interval between two polls of the polled paths
+    pub poll_interval: Duration,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        WatchConfig {
+            poll_dirs: Vec::new(),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+}
+
+/// The inotify limits of the host, `None` when they can't be read.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct InotifyLimits {
+    max_user_watches: Option<u64>,
+    max_user_instances: Option<u64>,
+    max_queued_events: Option<u64>,
+}
+
+impl InotifyLimits {
+    fn read(dir: &Path) -> Self {
+        let read = |name: &str| {
+            std::fs::read_to_string(dir.join(name))
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+        };
+        InotifyLimits {
+            max_user_watches: read("max_user_watches"),
+            max_user_instances: read("max_user_instances"),
+            max_queued_events: read("max_queued_events"),
+        }
+    }
+
+    fn report(&self) {
+        info!(
+            "inotify limits: max_user_watches={}, max_user_instances={}, max_queued_events={}",
+            display_limit(self.max_user_watches),
+            display_limit(self.max_user_instances),
+            display_limit(self.max_queued_events),
+        );
+        let limits = [
+            ("max_user_watches", self.max_user_watches),
+            ("max_user_instances", self.max_user_instances),
+            ("max_queued_events", self.max_queued_events),
+        ];
+        for (name, value) in limits.iter() {
+            if let Some(value) = value {
+                Metrics::fs().set_inotify_limit(name, *value);
+            }
+        }
+    }
+}
+
+fn display_limit(limit: Option<u64>) -> String {
+    limit.map_or_else(|| "unknown".to_string(), |limit| limit.to_string())
+}
+
+/// Identifies a watched path, either by its inotify watch or by its polled entry.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum WatchDescriptor {
+    Inotify(inotify::WatchDescriptor),
+    Poll(u64),
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum WatchEvent {
@@ -24,6 +123,12 @@ pub enum WatchEvent {
     Modify {
         wd: WatchDescriptor,
     },
+    /// A file of a directory was written, only reported once `Watcher::watch_dir_writes` is
+    /// set, for the files that are not watched themselves
+    ModifyChild {
+        wd: WatchDescriptor,
+        name: OsString,
+    },
     Delete {
         wd: WatchDescriptor,
         name: OsString,
@@ -57,33 +162,584 @@ where
 }
 
 pub struct Watcher {
-    inotify: Inotify,
+    inotify: Option<Inotify>,
+    poller: Arc<std::sync::Mutex<Poller>>,
+    config: WatchConfig,
+    mounts: Vec<(PathBuf, String)>,
+    polled_mounts: HashSet<PathBuf>,
+    limits: InotifyLimits,
+    inotify_watches: HashSet<inotify::WatchDescriptor>,
+    /// Set once inotify refused a watch because of `max_user_watches`
+    exhausted: bool,
+    /// Set on the first refused watch and kept: `max_user_watches` is shared with the other
+    /// processes of the user, so the count of the agent's own watches can't tell how close
+    /// the limit is
+    constrained: bool,
+    /// Paths polled because of the inotify limits
+    fallbacks: HashSet<u64>,
+    /// Set when the writes to the files of the directories are reported
+    dir_writes: bool,
 }
 
 impl Watcher {
-    pub fn new() -> io::Result<Self> {
-        Ok(Self {
-            inotify: Inotify::init()?,
-        })
+    /// Creates the watcher, polling all the paths when inotify can't be initialized.
+    pub fn new(config: WatchConfig) -> Self {
+        let limits = InotifyLimits::read(Path::new(INOTIFY_LIMITS_PATH));
+        limits.report();
+        let inotify = match Inotify::init() {
+            Ok(inotify) => Some(inotify),
+            Err(e) if e.raw_os_error() == Some(EMFILE) => {
+                warn!(
+                    "the inotify instance limit (fs.inotify.max_user_instances={}) is reached, \
+                    polling all the paths",
+                    display_limit(limits.max_user_instances)
+                );
+                None
+            }
+            Err(e) => {
+                warn!("unable to initialize inotify, polling all the paths: {}", e);
+                None
+            }
+        };
+        let mounts = match std::fs::read_to_string(MOUNTINFO_PATH) {
+            Ok(mountinfo) => parse_mountinfo(&mountinfo),
+            Err(e) => {
+                debug!("unable to read {}: {}", MOUNTINFO_PATH, e);
+                Vec::new()
+            }
+        };
+        let watcher = Self {
+            inotify,
+            poller: Default::default(),
+            config,
+            mounts,
+            polled_mounts: HashSet::new(),
+            limits,
+            inotify_watches: HashSet::new(),
+            exhausted: false,
+            constrained: false,
+            fallbacks: HashSet::new(),
+            dir_writes: false,
+        };
+        watcher.report();
+        watcher
+    }
+
+    /// Reports the writes to the files of the directories watched from now on as
+    /// `ModifyChild`, e.g. to notice the writes to files that are not watched.
+    pub fn watch_dir_writes(&mut self) {
+        self.dir_writes = true;
+        self.poller.lock().expect("couldn't lock poller").dir_writes = true;
     }
 
+    /// Watches the path with inotify, unless it's selected for polling. Paths are polled
+    /// instead once the inotify watches are exhausted.
     pub fn watch<P: AsRef<Path>>(&mut self, path: P) -> io::Result<WatchDescriptor> {
-        self.inotify
-            .add_watch(path.as_ref(), watch_mask(path.as_ref()))
+        let path = path.as_ref();
+        if self.inotify.is_none() || self.should_poll(path) {
+            let wd = self.poll_path(path);
+            self.report();
+            return wd;
+        }
+
+        if !self.exhausted {
+            let inotify = self.inotify.as_mut().expect("inotify is initialized");
+            match inotify.add_watch(path, watch_mask(path, self.dir_writes)) {
+                Ok(wd) => {
+                    self.inotify_watches.insert(wd.clone());
+                    self.report();
+                    return Ok(WatchDescriptor::Inotify(wd));
+                }
+                Err(e) if e.raw_os_error() == Some(ENOSPC) => {
+                    warn!(
+                        "the inotify watch limit (fs.inotify.max_user_watches={}) is reached \
+                        with {} watches, polling the next paths",
+                        display_limit(self.limits.max_user_watches),
+                        self.inotify_watches.len()
+                    );
+                    self.exhausted = true;
+                    self.constrained = true;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.poll(path)
+    }
+
+    /// Polls a path that would otherwise be watched by inotify, to spare the inotify watches.
+    pub fn poll<P: AsRef<Path>>(&mut self, path: P) -> io::Result<WatchDescriptor> {
+        let wd = self.poll_path(path.as_ref())?;
+        if let WatchDescriptor::Poll(id) = wd {
+            if self.inotify.is_some() && self.fallbacks.insert(id) {
+                debug!("polling {:?} because of the inotify limits", path.as_ref());
+                Metrics::fs().increment_watch_fallbacks();
+            }
+        }
+        self.report();
+        Ok(wd)
+    }
+
+    fn poll_path(&mut self, path: &Path) -> io::Result<WatchDescriptor> {
+        self.poller
+            .lock()
+            .expect("couldn't lock poller")
+            .watch(path)
+            .map(WatchDescriptor::Poll)
+    }
+
+    /// Returns true once inotify refused a watch, from then on the released watches are kept
+    /// for the paths with matching files.
+    pub fn is_constrained(&self) -> bool {
+        self.inotify.is_some() && self.constrained
     }
 
     pub fn unwatch(&mut self, wd: WatchDescriptor) -> io::Result<()> {
-        self.inotify.rm_watch(wd)
+        let result = match (wd, self.inotify.as_mut()) {
+            (WatchDescriptor::Inotify(wd), Some(inotify)) => {
+                // The watch is gone even when it was already removed by the kernel
+                self.inotify_watches.remove(&wd);
+                if self.exhausted {
+                    debug!("an inotify watch was released, watching the next paths with inotify");
+                    self.exhausted = false;
+                }
+                inotify.rm_watch(wd)
+            }
+            (WatchDescriptor::Poll(id), _) => {
+                self.fallbacks.remove(&id);
+                self.poller
+                    .lock()
+                    .expect("couldn't lock poller")
+                    .unwatch(id)
+            }
+            (WatchDescriptor::Inotify(_), None) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "inotify is disabled",
+            )),
+        };
+        self.report();
+        result
+    }
+
+    /// Updates the watch metrics, the watcher is degraded while paths are polled because of
+    /// the inotify limits.
+    fn report(&self) {
+        let polled = self.poller.lock().expect("couldn't lock poller").len();
+        Metrics::fs().set_watches(self.inotify_watches.len(), polled);
+        Metrics::fs().set_watch_degraded(self.inotify.is_none() || !self.fallbacks.is_empty());
+    }
+
+    /// Returns true when the path is selected for polling or on a network filesystem.
+    fn should_poll(&mut self, path: &Path) -> bool {
+        if self
+            .config
+            .poll_dirs
+            .iter()
+            .any(|dir| path.starts_with(dir))
+        {
+            return true;
+        }
+
+        let mount = self
+            .mounts
+            .iter()
+            .filter(|(mount_point, _)| path.starts_with(mount_point))
+            .max_by_key(|(mount_point, _)| mount_point.as_os_str().len());
+        match mount {
+            Some((mount_point, fs_type)) if is_network_fs(fs_type) => {
+                if self.polled_mounts.insert(mount_point.clone()) {
+                    info!(
+                        "polling the paths under {:?}, mounted as {}",
+                        mount_point, fs_type
+                    );
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn event_stream(
+        &mut self,
+    ) -> std::io::Result<impl Stream<Item = (Result<WatchEvent, std::io::Error>, OffsetDateTime)>>
+    {
+        let inotify_events = match self.inotify.as_mut() {
+            Some(inotify) => {
+                let buffer = vec![0; 4096].into_boxed_slice();
+                WatchEventStream {
+                    event_stream: inotify.event_stream(buffer)?,
+                }
+                .into_stream()
+                .left_stream()
+            }
+            None => futures::stream::pending().right_stream(),
+        };
+
+        let mut interval = tokio::time::interval(self.config.poll_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let poller = self.poller.clone();
+        let poll_events = tokio_stream::wrappers::IntervalStream::new(interval)
+            .then(move |_| {
+                let poller = poller.clone();
+                async move {
+                    // The paths are read on the blocking threads, without holding the poller
+                    let (targets, dir_writes) = {
+                        let poller = poller.lock().expect("couldn't lock poller");
+                        (poller.targets(), poller.dir_writes)
+                    };
+                    if targets.is_empty() {
+                        return Vec::new();
+                    }
+                    match tokio::task::spawn_blocking(move || Poller::scan(targets, dir_writes))
+                        .await
+                    {
+                        Ok(scanned) => poller.lock().expect("couldn't lock poller").apply(scanned),
+                        Err(e) => {
+                            warn!("unable to poll the watched paths: {}", e);
+                            Vec::new()
+                        }
+                    }
+                }
+            })
+            .flat_map(|events| {
+                let now = OffsetDateTime::now_utc();
+                futures::stream::iter(events.into_iter().map(move |event| (Ok(event), now)))
+            });
+
+        Ok(futures::stream::select(inotify_events, poll_events))
+    }
+}
+
+fn is_network_fs(fs_type: &str) -> bool {
+    // FUSE filesystems are reported as fuse.<name> (e.g. fuse.sshfs)
+    let base = fs_type.split('.').next().unwrap_or_default();
+    NETWORK_FS_TYPES.contains(&base)
+}
+
+/// Parses the mount points and filesystem types out of /proc/self/mountinfo.
+fn parse_mountinfo(mountinfo: &str) -> Vec<(PathBuf, String)> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (mount, fs) = line.split_once(" - ")?;
+            let mount_point = mount.split(' ').nth(4)?;
+            let fs_type = fs.split(' ').next()?;
+            Some((
+                PathBuf::from(unescape_mount_point(mount_point)),
+                fs_type.to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// Decodes the octal escapes (e.g. `\040` for a space) of the mountinfo paths.
+fn unescape_mount_point(raw: &str) -> String {
+    let bytes = raw.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() {
+            let digits = std::str::from_utf8(&bytes[i + 1..i + 4]).ok();
+            if let Some(c) = digits.and_then(|d| u8::from_str_radix(d, 8).ok()) {
+                result.push(c);
+                i += 4;
+                continue;
+            }
+        }
+        result.push(bytes[i]);
+        i += 1;
     }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+#[derive(Debug, PartialEq)]
+enum PolledState {
+    File {
+        ino: u64,
+        len: u64,
+        modified: Option<SystemTime>,
+    },
+    Dir {
+        dev: u64,
+        children: HashMap<OsString, u64>,
+        /// Modification time of the files, only read when the writes are reported
+        modified: HashMap<OsString, SystemTime>,
+    },
+}
 
-    pub fn event_stream(&mut self) -> std::io::Result<WatchEventStream> {
-        let buffer = vec![0; 4096].into_boxed_slice();
-        Ok(WatchEventStream {
-            event_stream: self.inotify.event_stream(buffer)?,
+impl PolledState {
+    fn new(path: &Path, metadata: &Metadata, dir_writes: bool) -> io::Result<Self> {
+        Ok(if metadata.is_dir() {
+            PolledState::Dir {
+                dev: metadata.dev(),
+                children: read_children(path)?,
+                modified: if dir_writes {
+                    read_modified(path)
+                } else {
+                    HashMap::new()
+                },
+            }
+        } else {
+            PolledState::File {
+                ino: metadata.ino(),
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+            }
         })
     }
 }
 
+struct PolledPath {
+    path: PathBuf,
+    key: (u64, u64),
+    state: PolledState,
+}
+
+/// The state of a polled path read by `Poller::scan`, `None` when it can't be read.
+enum Scanned {
+    Dir(
+        Option<HashMap<OsString, u64>>,
+        HashMap<OsString, SystemTime>,
+    ),
+    File(Option<Metadata>),
+}
+
+/// Watches paths by comparing their metadata on every poll, for the filesystems on which
+/// inotify is not reliable. Like inotify, the same file is watched once even when watched
+/// through several paths.
+#[derive(Default)]
+struct Poller {
+    next_id: u64,
+    polled: HashMap<u64, PolledPath>,
+    ids: HashMap<(u64, u64), u64>,
+    /// Set when the writes to the files of the directories are reported
+    dir_writes: bool,
+}
+
+impl Poller {
+    fn watch(&mut self, path: &Path) -> io::Result<u64> {
+        let metadata = symlink_metadata(path)?;
+        let key = (metadata.dev(), metadata.ino());
+        if let Some(id) = self.ids.get(&key) {
+            return Ok(*id);
+        }
+
+        let state = PolledState::new(path, &metadata, self.dir_writes)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.ids.insert(key, id);
+        self.polled.insert(
+            id,
+            PolledPath {
+                path: path.to_path_buf(),
+                key,
+                state,
+            },
+        );
+        Ok(id)
+    }
+
+    fn len(&self) -> usize {
+        self.polled.len()
+    }
+
+    fn unwatch(&mut self, id: u64) -> io::Result<()> {
+        let polled = self
+            .polled
+            .remove(&id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "path is not polled"))?;
+        self.ids.remove(&polled.key);
+        Ok(())
+    }
+
+    /// Polls the paths in place, see `scan` and `apply`.
+    #[cfg(test)]
+    fn poll(&mut self) -> Vec<WatchEvent> {
+        let scanned = Poller::scan(self.targets(), self.dir_writes);
+        self.apply(scanned)
+    }
+
+    /// Returns the polled paths to scan and whether they are directories.
+    fn targets(&self) -> Vec<(u64, PathBuf, bool)> {
+        self.polled
+            .iter()
+            .map(|(id, polled)| {
+                let is_dir = matches!(polled.state, PolledState::Dir { .. });
+                (*id, polled.path.clone(), is_dir)
+            })
+            .collect()
+    }
+
+    /// Reads the current state of the paths, the blocking part of a poll.
+    fn scan(targets: Vec<(u64, PathBuf, bool)>, dir_writes: bool) -> Vec<(u64, PathBuf, Scanned)> {
+        targets
+            .into_iter()
+            .map(|(id, path, is_dir)| {
+                let scanned = if is_dir {
+                    let modified = if dir_writes {
+                        read_modified(&path)
+                    } else {
+                        HashMap::new()
+                    };
+                    Scanned::Dir(read_children(&path).ok(), modified)
+                } else {
+                    Scanned::File(symlink_metadata(&path).ok())
+                };
+                (id, path, scanned)
+            })
+            .collect()
+    }
+
+    /// Compares the scanned paths with their previous state, returning the same events as
+    /// inotify would. Files renamed between two polls are matched by inode. The paths
+    /// unwatched or renamed since the scan are left for the next poll.
+    fn apply(&mut self, scanned: Vec<(u64, PathBuf, Scanned)>) -> Vec<WatchEvent> {
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+        let mut modified = Vec::new();
+
+        for (id, path, scanned) in scanned {
+            let polled = match self
+                .polled
+                .get_mut(&id)
+                .filter(|polled| polled.path == path)
+            {
+                Some(polled) => polled,
+                None => continue,
+            };
+            let id = &id;
+            match (&mut polled.state, scanned) {
+                (
+                    PolledState::Dir {
+                        dev,
+                        children,
+                        modified: last_modified,
+                    },
+                    Scanned::Dir(current, current_modified),
+                ) => {
+                    // Directories that are gone are reported by their parent
+                    let current = match current {
+                        Some(current) => current,
+                        None => continue,
+                    };
+                    for (name, time) in current_modified.iter() {
+                        let written = last_modified.get(name).map_or(false, |last| last != time);
+                        if written && current.get(name) == children.get(name) {
+                            modified.push(WatchEvent::ModifyChild {
+                                wd: WatchDescriptor::Poll(*id),
+                                name: name.clone(),
+                            });
+                        }
+                    }
+                    *last_modified = current_modified;
+                    for (name, ino) in children.iter() {
+                        if current.get(name) != Some(ino) {
+                            removed.push((*id, name.clone(), (*dev, *ino)));
+                        }
+                    }
+                    for (name, ino) in current.iter() {
+                        if children.get(name) != Some(ino) {
+                            added.push((*id, name.clone(), (*dev, *ino)));
+                        }
+                    }
+                    *children = current;
+                }
+                (
+                    PolledState::File {
+                        ino,
+                        len,
+                        modified: last_modified,
+                    },
+                    Scanned::File(metadata),
+                ) => {
+                    // A different file at the same path is reported by the parent directory
+                    let metadata = match metadata {
+                        Some(metadata) if metadata.ino() == *ino => metadata,
+                        _ => continue,
+                    };
+                    let current_modified = metadata.modified().ok();
+                    if metadata.len() != *len || current_modified != *last_modified {
+                        *len = metadata.len();
+                        *last_modified = current_modified;
+                        modified.push(WatchEvent::Modify {
+                            wd: WatchDescriptor::Poll(*id),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut events = Vec::new();
+        let mut creates = Vec::new();
+        let mut renames = Vec::new();
+        for (to_id, to_name, key) in added {
+            match removed.iter().position(|(_, _, k)| *k == key) {
+                Some(idx) => {
+                    let (from_id, from_name, _) = removed.swap_remove(idx);
+                    renames.push((
+                        self.polled[&from_id].path.join(&from_name),
+                        self.polled[&to_id].path.join(&to_name),
+                    ));
+                    events.push(WatchEvent::Move {
+                        from_wd: WatchDescriptor::Poll(from_id),
+                        from_name,
+                        to_wd: WatchDescriptor::Poll(to_id),
+                        to_name,
+                    });
+                }
+                None => creates.push(WatchEvent::Create {
+                    wd: WatchDescriptor::Poll(to_id),
+                    name: to_name,
+                }),
+            }
+        }
+        events.extend(removed.into_iter().map(|(id, name, _)| WatchEvent::Delete {
+            wd: WatchDescriptor::Poll(id),
+            name,
+        }));
+        events.extend(creates);
+        events.extend(modified);
+
+        // The renamed paths keep being polled under their new name
+        for (from, to) in renames {
+            for polled in self.polled.values_mut() {
+                if let Ok(rest) = polled.path.strip_prefix(&from) {
+                    polled.path = if rest.as_os_str().is_empty() {
+                        to.clone()
+                    } else {
+                        to.join(rest)
+                    };
+                }
+            }
+        }
+
+        events
+    }
+}
+
+fn read_children(path: &Path) -> io::Result<HashMap<OsString, u64>> {
+    let mut children = HashMap::new();
+    for entry in read_dir(path)? {
+        let entry = entry?;
+        children.insert(entry.file_name(), entry.ino());
+    }
+    Ok(children)
+}
+
+/// Reads the modification time of the files of a directory, skipping the ones that can't be
+/// read.
+fn read_modified(path: &Path) -> HashMap<OsString, SystemTime> {
+    read_dir(path)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok().filter(Metadata::is_file)?;
+            Some((entry.file_name(), metadata.modified().ok()?))
+        })
+        .collect()
+}
+
 pub struct WatchEventStream {
     event_stream: inotify::EventStream<Box<[u8]>>,
 }
@@ -124,6 +780,7 @@ impl WatchEventStream {
                             async move {
                                 match raw_event {
                                     Ok(raw_event) => {
+                                        let raw_wd = WatchDescriptor::Inotify(raw_event.wd.clone());
                                         Ok(if raw_event.mask.contains(EventMask::MOVED_FROM) {
                                             // Check if we have seen the corresponding MOVED_TO
                                             let mut unmatched_move_to =
@@ -145,7 +802,7 @@ impl WatchEventStream {
                                                     unmatched_move_to.swap_remove(idx)
                                                 {
                                                     Some(WatchEvent::Move {
-                                                        from_wd: raw_event.wd.clone(),
+                                                        from_wd: raw_wd.clone(),
                                                         from_name: raw_event.name.unwrap(),
                                                         to_wd: wd,
                                                         to_name: name,
@@ -159,7 +816,7 @@ impl WatchEventStream {
                                                 unmatched_move_from.lock().await.push((
                                                     Instant::now(),
                                                     WatchEvent::MovedFrom {
-                                                        wd: raw_event.wd.clone(),
+                                                        wd: raw_wd.clone(),
                                                         name: raw_event.name.unwrap(),
                                                         cookie: raw_event.cookie,
                                                     },
@@ -189,7 +846,7 @@ impl WatchEventStream {
                                                     Some(WatchEvent::Move {
                                                         from_wd: wd,
                                                         from_name: name,
-                                                        to_wd: raw_event.wd.clone(),
+                                                        to_wd: raw_wd.clone(),
                                                         to_name: raw_event.name.unwrap(),
                                                     })
                                                 } else {
@@ -201,7 +858,7 @@ impl WatchEventStream {
                                                 unmatched_move_to.lock().await.push((
                                                     Instant::now(),
                                                     WatchEvent::MovedTo {
-                                                        wd: raw_event.wd.clone(),
+                                                        wd: raw_wd.clone(),
                                                         name: raw_event.name.unwrap(),
                                                         cookie: raw_event.cookie,
                                                     },
@@ -228,13 +885,13 @@ impl WatchEventStream {
                                                     .entry(raw_event_name.clone())
                                                     .or_insert_with(SmallVec::new)
                                                     .push(WatchEvent::Create {
-                                                        wd: raw_event.wd.clone(),
+                                                        wd: raw_wd.clone(),
                                                         name: raw_event_name,
                                                     });
                                                 None
                                             } else {
                                                 Some(WatchEvent::Create {
-                                                    wd: raw_event.wd.clone(),
+                                                    wd: raw_wd.clone(),
                                                     name: raw_event_name,
                                                 })
                                             }
@@ -258,31 +915,34 @@ impl WatchEventStream {
                                                     .entry(raw_event_name.clone())
                                                     .or_insert_with(SmallVec::new)
                                                     .push(WatchEvent::Delete {
-                                                        wd: raw_event.wd.clone(),
+                                                        wd: raw_wd.clone(),
                                                         name: raw_event_name,
                                                     });
                                                 None
                                             } else {
                                                 Some(WatchEvent::Delete {
-                                                    wd: raw_event.wd.clone(),
+                                                    wd: raw_wd.clone(),
                                                     name: raw_event_name,
                                                 })
                                             }
                                         } else if raw_event.mask.contains(EventMask::MODIFY) {
+                                            // Written file of a watched directory
+                                            if let Some(name) = raw_event.name {
+                                                return Ok(Some(WatchEvent::ModifyChild {
+                                                    wd: raw_wd.clone(),
+                                                    name,
+                                                }));
+                                            }
                                             let unmatched_move_from =
                                                 unmatched_move_from.lock().await;
                                             if !unmatched_move_from.is_empty() {
                                                 buffered_modify_events.lock().await.push((
                                                     Instant::now(),
-                                                    WatchEvent::Modify {
-                                                        wd: raw_event.wd.clone(),
-                                                    },
+                                                    WatchEvent::Modify { wd: raw_wd.clone() },
                                                 ));
                                                 None
                                             } else {
-                                                Some(WatchEvent::Modify {
-                                                    wd: raw_event.wd.clone(),
-                                                })
+                                                Some(WatchEvent::Modify { wd: raw_wd.clone() })
                                             }
                                         } else if raw_event.mask.contains(EventMask::Q_OVERFLOW) {
                                             Some(WatchEvent::Overflow)
@@ -404,9 +1064,16 @@ impl WatchEventStream {
 }
 
 // returns the watch mask depending on if a path is a file or dir
-fn watch_mask<P: AsRef<Path>>(path: P) -> WatchMask {
+fn watch_mask<P: AsRef<Path>>(path: P, dir_writes: bool) -> WatchMask {
     if path.as_ref().is_file() {
         WatchMask::MODIFY | WatchMask::DONT_FOLLOW
+    } else if dir_writes {
+        WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::DONT_FOLLOW
+            | WatchMask::MOVED_TO
+            | WatchMask::MOVED_FROM
+            | WatchMask::MODIFY
     } else {
         WatchMask::CREATE
             | WatchMask::DELETE
@@ -415,3 +1082,171 @@ fn watch_mask<P: AsRef<Path>>(path: P) -> WatchMask {
             | WatchMask::MOVED_FROM
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{remove_file, rename, File};
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mountinfo = "\
+            22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+            40 22 0:35 / /mnt/nfs\\040share rw,relatime shared:20 - nfs4 srv:/export rw\n\
+            41 22 0:36 / /home/user/remote rw,nosuid - fuse.sshfs user@host: rw\n";
+        assert_eq!(
+            parse_mountinfo(mountinfo),
+            vec![
+                (PathBuf::from("/"), "ext4".to_string()),
+                (PathBuf::from("/mnt/nfs share"), "nfs4".to_string()),
+                (PathBuf::from("/home/user/remote"), "fuse.sshfs".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_network_fs() {
+        assert!(is_network_fs("nfs4"));
+        assert!(is_network_fs("cifs"));
+        assert!(is_network_fs("fuse.sshfs"));
+        assert!(!is_network_fs("fuseblk"));
+        assert!(!is_network_fs("ext4"));
+        assert!(!is_network_fs("overlay"));
+    }
+
+    #[test]
+    fn test_inotify_limits() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join("max_user_watches"), "8192\n").unwrap();
+        std::fs::write(dir.path().join("max_user_instances"), "invalid").unwrap();
+        assert_eq!(
+            InotifyLimits::read(dir.path()),
+            InotifyLimits {
+                max_user_watches: Some(8192),
+                max_user_instances: None,
+                max_queued_events: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_watcher_fallback() {
+        let dir = TempDir::new().unwrap();
+        let mut watcher = Watcher::new(WatchConfig::default());
+        let wd = watcher.watch(dir.path()).unwrap();
+        assert!(matches!(wd, WatchDescriptor::Inotify(_)));
+        assert!(!watcher.is_constrained());
+
+        // Paths are polled once the watches are exhausted
+        watcher.exhausted = true;
+        watcher.constrained = true;
+        let a = dir.path().join("a.log");
+        File::create(&a).unwrap();
+        let fallback = watcher.watch(&a).unwrap();
+        assert!(matches!(fallback, WatchDescriptor::Poll(_)));
+        assert_eq!(watcher.fallbacks.len(), 1);
+
+        watcher.unwatch(fallback).unwrap();
+        assert!(watcher.fallbacks.is_empty());
+        watcher.unwatch(wd).unwrap();
+        assert!(!watcher.exhausted);
+        // The released watches are kept for the paths with matching files
+        assert!(watcher.is_constrained());
+    }
+
+    #[test]
+    fn test_poller_dir_writes() {
+        let dir = TempDir::new().unwrap();
+        let a = dir.path().join("a.log");
+        let mut file = File::create(&a).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(3600))
+            .unwrap();
+        let mut poller = Poller {
+            dir_writes: true,
+            ..Default::default()
+        };
+        let dir_wd = WatchDescriptor::Poll(poller.watch(dir.path()).unwrap());
+        assert!(poller.poll().is_empty());
+
+        // The files of the directory are not polled themselves
+        writeln!(file, "line").unwrap();
+        assert_eq!(
+            poller.poll(),
+            vec![WatchEvent::ModifyChild {
+                wd: dir_wd,
+                name: "a.log".into()
+            }]
+        );
+        assert!(poller.poll().is_empty());
+    }
+
+    #[test]
+    fn test_poller_events() {
+        let dir = TempDir::new().unwrap();
+        let mut poller = Poller::default();
+        let dir_wd = WatchDescriptor::Poll(poller.watch(dir.path()).unwrap());
+        assert!(poller.poll().is_empty());
+
+        let a = dir.path().join("a.log");
+        let mut file = File::create(&a).unwrap();
+        assert_eq!(
+            poller.poll(),
+            vec![WatchEvent::Create {
+                wd: dir_wd.clone(),
+                name: "a.log".into()
+            }]
+        );
+
+        let file_id = poller.watch(&a).unwrap();
+        // The same file is polled once
+        assert_eq!(poller.watch(&a).unwrap(), file_id);
+        writeln!(file, "line").unwrap();
+        assert_eq!(
+            poller.poll(),
+            vec![WatchEvent::Modify {
+                wd: WatchDescriptor::Poll(file_id)
+            }]
+        );
+
+        // Renamed files are matched by inode and keep being polled under their new name
+        let b = dir.path().join("b.log");
+        rename(&a, &b).unwrap();
+        File::create(&a).unwrap();
+        assert_eq!(
+            poller.poll(),
+            vec![
+                WatchEvent::Move {
+                    from_wd: dir_wd.clone(),
+                    from_name: "a.log".into(),
+                    to_wd: dir_wd.clone(),
+                    to_name: "b.log".into()
+                },
+                WatchEvent::Create {
+                    wd: dir_wd.clone(),
+                    name: "a.log".into()
+                }
+            ]
+        );
+        writeln!(file, "line").unwrap();
+        assert_eq!(
+            poller.poll(),
+            vec![WatchEvent::Modify {
+                wd: WatchDescriptor::Poll(file_id)
+            }]
+        );
+
+        remove_file(&b).unwrap();
+        assert_eq!(
+            poller.poll(),
+            vec![WatchEvent::Delete {
+                wd: dir_wd,
+                name: "b.log".into()
+            }]
+        );
+
+        poller.unwatch(file_id).unwrap();
+        assert!(poller.unwatch(file_id).is_err());
+    }
+}
@@ -0,0 +1,323 @@
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use futures::io::{AsyncBufRead, AsyncBufReadExt};
+use globber::Pattern;
+use std::fmt;
+use std::path::Path;
+use std::pin::Pin;
+use thiserror::Error;
+
+/// Length of the longest byte order mark, the one of UTF-8
+pub(crate) const MAX_BOM_LEN: u64 = 3;
+
+/// The character encoding of the files matching a glob pattern, e.g.
+/// `/var/log/legacy/*.log:windows-1252`.
+#[derive(Clone, Debug)]
+pub struct FileEncoding {
+    pattern: Pattern,
+    glob: String,
+    encoding: &'static Encoding,
+}
+
+#[derive(Error, Debug)]
+pub enum ParseEncodingError {
+    #[error("Invalid file encoding: {0}, expected e.g. /var/log/*.log:utf-16le")]
+    Format(String),
+    #[error("Invalid glob pattern in file encoding: {0}")]
+    Pattern(String),
+    #[error("Unknown encoding: {0}")]
+    Unknown(String),
+}
+
+impl std::str::FromStr for FileEncoding {
+    type Err = ParseEncodingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // The encoding labels have no colons, unlike some paths
+        let (glob, label) = s
+            .rsplit_once(':')
+            .filter(|(glob, label)| !glob.trim().is_empty() && !label.trim().is_empty())
+            .ok_or_else(|| ParseEncodingError::Format(s.into()))?;
+        let glob = glob.trim();
+        let pattern = Pattern::new(glob)
+            .map_err(|e| ParseEncodingError::Pattern(format!("{}: {}", glob, e)))?;
+        let encoding = Encoding::for_label(label.trim().as_bytes())
+            .ok_or_else(|| ParseEncodingError::Unknown(label.trim().into()))?;
+        Ok(FileEncoding {
+            pattern,
+            glob: glob.into(),
+            encoding,
+        })
+    }
+}
+
+impl fmt::Display for FileEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.glob, self.encoding.name().to_lowercase())
+    }
+}
+
+/// Returns the encoding of the first pattern matching the path, files are read as UTF-8 when
+/// none matches.
+pub(crate) fn for_path(encodings: &[FileEncoding], path: &Path) -> Option<&'static Encoding> {
+    let path = path.to_string_lossy();
+    encodings
+        .iter()
+        .find(|encoding| encoding.pattern.matches(&path))
+        .map(|encoding| encoding.encoding)
+        .filter(|encoding| *encoding != UTF_8)
+}
+
+/// Returns the newline as encoded in the file.
+fn newline(encoding: &'static Encoding) -> &'static [u8] {
+    if encoding == UTF_16LE {
+        b"\n\0"
+    } else if encoding == UTF_16BE {
+        b"\0\n"
+    } else {
+        b"\n"
+    }
+}
+
+/// Returns the length of the code units of the encoding, the lines start on one.
+pub(crate) fn code_unit_len(encoding: Option<&'static Encoding>) -> u64 {
+    encoding.map_or(1, |encoding| newline(encoding).len() as u64)
+}
+
+/// Returns the number of bytes in the buffer of the reader, filled when it's empty. Unlike
+/// `fill_buf` the reader is polled once, tokio's files are pending again at the end of file.
+async fn fill_buf_len<R>(reader: &mut R) -> std::io::Result<usize>
+where
+    R: AsyncBufRead + Unpin,
+{
+    futures::future::poll_fn(|cx| {
+        Pin::new(&mut *reader)
+            .poll_fill_buf(cx)
+            .map_ok(|available| available.len())
+    })
+    .await
+}
+
+/// Reads a line like `read_until`, stopping before the line goes over `limit` bytes, without
+/// its newline. Files in another encoding than UTF-8 are read into `undecoded` until the line
+/// is complete and then decoded into `buf`. Returns the number of bytes read from the file and
+/// whether the line was cut at the limit, in which case it's left undecoded.
+pub(crate) async fn read_line<R>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    undecoded: &mut Vec<u8>,
+    encoding: Option<&'static Encoding>,
+    limit: Option<usize>,
+) -> std::io::Result<(usize, bool)>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => return read_utf8_line(reader, buf, limit).await,
+    };
+    let limit = limit.unwrap_or(usize::MAX);
+    let newline = newline(encoding);
+    let unit_len = newline.len();
+    let mut count = 0;
+    loop {
+        if fill_buf_len(reader).await? == 0 {
+            return Ok((count, false));
+        }
+        let available = reader.fill_buf().await?;
+        let mut used = 0;
+        let mut complete = false;
+        let mut full = false;
+        for (i, byte) in available.iter().enumerate() {
+            // The lines start on a code unit, a UTF-16 newline is only matched on a whole one
+            // and the lines are cut between two of them
+            let on_unit = undecoded.len() % unit_len == 0;
+            let over = undecoded.len() + unit_len > limit.max(unit_len);
+            if on_unit && over && !available[i..].starts_with(newline) {
+                full = true;
+                break;
+            }
+            undecoded.push(*byte);
+            used += 1;
+            if undecoded.len() % unit_len == 0 && undecoded.ends_with(newline) {
+                complete = true;
+                break;
+            }
+        }
+        reader.consume_unpin(used);
+        count += used;
+        if full {
+            return Ok((count, true));
+        }
+        if complete {
+            decode(buf, undecoded, encoding);
+            return Ok((count, false));
+        }
+    }
+}
+
+/// Reads a UTF-8 line, cut between two chars when it goes over the limit.
+async fn read_utf8_line<R>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    limit: Option<usize>,
+) -> std::io::Result<(usize, bool)>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return Ok((reader.read_until(b'\n', buf).await?, false)),
+    };
+    let mut count = 0;
+    loop {
+        if fill_buf_len(reader).await? == 0 {
+            return Ok((count, false));
+        }
+        let available = reader.fill_buf().await?;
+        let room = limit.saturating_sub(buf.len());
+        let searched = &available[..available.len().min(room + 1)];
+        let (used, done, full) = match memchr::memchr(b'\n', searched) {
+            Some(i) => (i + 1, true, false),
+            None if available.len() <= room => (available.len(), false, false),
+            None => {
+                // Back to the start of the char cut by the limit, if any
+                let is_continuation = |i: &usize| available[*i] & 0xc0 == 0x80;
+                let cut = (room.saturating_sub(3)..=room)
+                    .rev()
+                    .find(|i| !is_continuation(i))
+                    .filter(|cut| *cut > 0)
+                    .unwrap_or(room);
+                (cut, true, true)
+            }
+        };
+        buf.extend_from_slice(&available[..used]);
+        reader.consume_unpin(used);
+        count += used;
+        if done {
+            return Ok((count, full));
+        }
+    }
+}
+
+/// Decodes the line read into `undecoded` to `buf`.
+pub(crate) fn decode(buf: &mut Vec<u8>, undecoded: &mut Vec<u8>, encoding: &'static Encoding) {
+    let (line, _) = encoding.decode_without_bom_handling(undecoded);
+    buf.clear();
+    buf.extend_from_slice(line.as_bytes());
+    undecoded.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::WINDOWS_1252;
+
+    #[test]
+    fn test_parse_file_encoding() {
+        let encoding: FileEncoding = "/var/log/legacy/*.log:latin1".parse().unwrap();
+        assert_eq!(encoding.encoding, WINDOWS_1252);
+        assert_eq!(encoding.to_string(), "/var/log/legacy/*.log:windows-1252");
+        assert_eq!(
+            for_path(&[encoding], Path::new("/var/log/legacy/app.log")),
+            Some(WINDOWS_1252)
+        );
+
+        let encoding: FileEncoding = "C:/logs/*.log: UTF-16LE".parse().unwrap();
+        assert_eq!(encoding.encoding, UTF_16LE);
+        assert_eq!(for_path(&[encoding], Path::new("/var/log/app.log")), None);
+
+        assert!(matches!(
+            "/var/log/*.log".parse::<FileEncoding>(),
+            Err(ParseEncodingError::Format(_))
+        ));
+        assert!(matches!(
+            "/var/log/*.log:ebcdic".parse::<FileEncoding>(),
+            Err(ParseEncodingError::Unknown(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_utf16_lines() {
+        // U+0A0A and U+0D0A contain newline bytes outside of a newline code unit
+        let text = "a\u{0a0a}b\n\u{0d0a}\nc";
+        let mut input = Vec::new();
+        for unit in text.encode_utf16() {
+            input.extend_from_slice(&unit.to_le_bytes());
+        }
+        let mut reader = futures::io::Cursor::new(input);
+        let mut buf = Vec::new();
+        let mut undecoded = Vec::new();
+        tokio_test::block_on(async {
+            let (count, _) = read_line(&mut reader, &mut buf, &mut undecoded, Some(UTF_16LE), None)
+                .await
+                .unwrap();
+            assert_eq!(count, 8);
+            assert_eq!(buf, "a\u{0a0a}b\n".as_bytes());
+
+            read_line(&mut reader, &mut buf, &mut undecoded, Some(UTF_16LE), None)
+                .await
+                .unwrap();
+            assert_eq!(buf, "\u{0d0a}\n".as_bytes());
+
+            // Partial lines are kept until they are complete
+            let (count, _) = read_line(&mut reader, &mut buf, &mut undecoded, Some(UTF_16LE), None)
+                .await
+                .unwrap();
+            assert_eq!(count, 2);
+            assert_eq!(undecoded, b"c\0");
+        });
+    }
+
+    #[test]
+    fn test_read_long_lines() {
+        let mut reader = futures::io::Cursor::new("ab\u{e9}cd\nabc\n".as_bytes().to_vec());
+        let mut buf = Vec::new();
+        let mut undecoded = Vec::new();
+        tokio_test::block_on(async {
+            // The line is cut before the char going over the limit
+            let read = read_line(&mut reader, &mut buf, &mut undecoded, None, Some(3))
+                .await
+                .unwrap();
+            assert_eq!(read, (2, true));
+            assert_eq!(buf, b"ab");
+
+            // The newline doesn't count in the limit
+            buf.clear();
+            read_line(&mut reader, &mut buf, &mut undecoded, None, Some(3))
+                .await
+                .unwrap();
+            assert_eq!(buf, "\u{e9}c".as_bytes());
+            buf.clear();
+            let read = read_line(&mut reader, &mut buf, &mut undecoded, None, Some(3))
+                .await
+                .unwrap();
+            assert_eq!(read, (2, false));
+            assert_eq!(buf, b"d\n");
+            buf.clear();
+            let read = read_line(&mut reader, &mut buf, &mut undecoded, None, Some(3))
+                .await
+                .unwrap();
+            assert_eq!(read, (4, false));
+            assert_eq!(buf, b"abc\n");
+        });
+
+        let input = "abc\n"
+            .encode_utf16()
+            .flat_map(|unit| unit.to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+        let mut reader = futures::io::Cursor::new(input);
+        tokio_test::block_on(async {
+            let read = read_line(
+                &mut reader,
+                &mut buf,
+                &mut undecoded,
+                Some(UTF_16BE),
+                Some(3),
+            )
+            .await
+            .unwrap();
+            assert_eq!(read, (2, true));
+            assert_eq!(undecoded, b"\0a");
+        });
+    }
+}
@@ -6,6 +6,8 @@ extern crate lazy_static;
 
 /// Prototype
 pub mod cache;
+/// Character encodings of the tailed files
+pub mod encoding;
 /// Contains the error type(s) for this crate
 pub mod error;
 /// Lookback config
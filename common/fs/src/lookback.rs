@@ -1,17 +1,35 @@
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
 use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 
+/// The lines of a file are searched by bisection until the cutoff is within this window
+const SCAN_WINDOW: u64 = 64 * 1024;
+/// Maximum number of lines read after a bisection point to find a timestamp
+const MAX_PROBE_LINES: usize = 16;
+/// Only the beginning of the lines is checked for a timestamp
+const MAX_TIMESTAMP_PREFIX: usize = 64;
+
+const DURATION_UNITS: &[(&str, u64)] = &[("d", 86_400), ("h", 3_600), ("m", 60), ("s", 1)];
+
 #[derive(Clone, std::fmt::Debug, PartialEq)]
 pub enum Lookback {
     Start,
     SmallFiles,
     None,
+    /// Starts from the first line written within the duration
+    Duration(Duration),
 }
 
 #[derive(Error, Debug)]
 pub enum ParseLookbackError {
     #[error("Unknown lookback strategy: {0}")]
     Unknown(String),
+    #[error("Invalid duration: {0}, expected e.g. 30m, 2h or 7d")]
+    Duration(String),
 }
 
 impl std::str::FromStr for Lookback {
@@ -27,6 +45,9 @@ impl std::str::FromStr for Lookback {
             "start" => Ok(Lookback::Start),
             "smallfiles" => Ok(Lookback::SmallFiles),
             "none" => Ok(Lookback::None),
+            other if other.starts_with(|c: char| c.is_ascii_digit()) => {
+                parse_duration(other).map(Lookback::Duration)
+            }
             _ => Err(ParseLookbackError::Unknown(s.into())),
         }
     }
@@ -34,15 +55,12 @@ impl std::str::FromStr for Lookback {
 
 impl fmt::Display for Lookback {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Lookback::Start => "start",
-                Lookback::SmallFiles => "smallfiles",
-                Lookback::None => "none",
-            }
-        )
+        match self {
+            Lookback::Start => write!(f, "start"),
+            Lookback::SmallFiles => write!(f, "smallfiles"),
+            Lookback::None => write!(f, "none"),
+            Lookback::Duration(duration) => write!(f, "{}", format_duration(*duration)),
+        }
     }
 }
 
@@ -51,3 +69,297 @@ impl Default for Lookback {
         Lookback::None
     }
 }
+
+/// Parses durations made of days, hours, minutes and seconds, e.g. `2h` or `1h30m`.
+pub fn parse_duration(s: &str) -> Result<Duration, ParseLookbackError> {
+    let invalid = || ParseLookbackError::Duration(s.into());
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut secs: u64 = 0;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let value: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+        let (unit, unit_secs) = DURATION_UNITS
+            .iter()
+            .find(|(unit, _)| rest.starts_with(unit))
+            .ok_or_else(invalid)?;
+        secs = value
+            .checked_mul(*unit_secs)
+            .and_then(|value| secs.checked_add(value))
+            .ok_or_else(invalid)?;
+        rest = &rest[unit.len()..];
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// Formats the duration with the largest unit dividing it, e.g. `2h` or `90m`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (unit, unit_secs) = DURATION_UNITS
+        .iter()
+        .find(|(_, unit_secs)| secs % unit_secs == 0 && secs > 0)
+        .unwrap_or(&("s", 1));
+    format!("{}{}", secs / unit_secs, unit)
+}
+
+/// Returns the offset of the first line written within `lookback`.
+///
+/// Files not modified within the lookback are read from their end. Otherwise the lines are
+/// dated by their leading timestamp (RFC 3339 or `YYYY-MM-DD HH:MM:SS` in local time, and the
+/// `time` field of JSON lines). When no line can be dated, the file is read from the start
+/// if it was created within the lookback and from its end otherwise.
+pub fn lookback_offset(path: &Path, lookback: Duration) -> io::Result<u64> {
+    let metadata = path.metadata()?;
+    let len = metadata.len();
+    let cutoff = SystemTime::now()
+        .checked_sub(lookback)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    if metadata.modified()? < cutoff {
+        return Ok(len);
+    }
+
+    let cutoff_time = DateTime::<Utc>::from(cutoff);
+    let mut reader = BufReader::new(File::open(path)?);
+    match find_line_since(&mut reader, len, cutoff_time)? {
+        Some(offset) => Ok(offset),
+        None => {
+            let created = metadata.created().map(|created| created >= cutoff);
+            Ok(if created.unwrap_or(false) { 0 } else { len })
+        }
+    }
+}
+
+/// Bisects the file to the first line dated after the cutoff, returning `None` when no line
+/// can be dated. Lines without a timestamp belong to the previous dated line.
+fn find_line_since<R: BufRead + Seek>(
+    reader: &mut R,
+    len: u64,
+    cutoff: DateTime<Utc>,
+) -> io::Result<Option<u64>> {
+    let (mut low, mut high) = (0, len);
+    let mut dated = false;
+    while high - low > SCAN_WINDOW {
+        let mid = low + (high - low) / 2;
+        match probe(reader, mid)? {
+            Some(time) if time < cutoff => {
+                dated = true;
+                low = mid;
+            }
+            Some(_) => {
+                dated = true;
+                high = mid;
+            }
+            None => break,
+        }
+    }
+
+    // Scan the lines from the start of the window
+    reader.seek(SeekFrom::Start(low))?;
+    let mut offset = low;
+    if low > 0 {
+        offset += skip_line(reader)?;
+    }
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)? as u64;
+        if read == 0 {
+            return Ok(if dated { Some(len) } else { None });
+        }
+        if let Some(time) = line_time(&line) {
+            dated = true;
+            if time >= cutoff {
+                return Ok(Some(offset));
+            }
+        }
+        offset += read;
+    }
+}
+
+/// Returns the time of the first dated line after `pos`.
+fn probe<R: BufRead + Seek>(reader: &mut R, pos: u64) -> io::Result<Option<DateTime<Utc>>> {
+    reader.seek(SeekFrom::Start(pos))?;
+    skip_line(reader)?;
+    let mut line = Vec::new();
+    for _ in 0..MAX_PROBE_LINES {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if let Some(time) = line_time(&line) {
+            return Ok(Some(time));
+        }
+    }
+    Ok(None)
+}
+
+/// Skips the rest of the current line, returning the number of bytes skipped.
+fn skip_line<R: BufRead>(reader: &mut R) -> io::Result<u64> {
+    let mut skipped = Vec::new();
+    reader
+        .read_until(b'\n', &mut skipped)
+        .map(|read| read as u64)
+}
+
+/// Parses the timestamp at the beginning of the line.
+fn line_time(line: &[u8]) -> Option<DateTime<Utc>> {
+    let start = line.iter().position(|b| !b.is_ascii_whitespace())?;
+    let line = &line[start..];
+
+    // Docker json-file lines, e.g. {"log":"...","stream":"stdout","time":"2021-03-04T05:06:07Z"}
+    if line.starts_with(b"{") {
+        let line = String::from_utf8_lossy(line);
+        let start = line.find("\"time\":\"")? + "\"time\":\"".len();
+        let end = start + line[start..].find('"')?;
+        return parse_time(&line[start..end]);
+    }
+
+    let prefix = String::from_utf8_lossy(&line[..line.len().min(MAX_TIMESTAMP_PREFIX)]);
+    let mut tokens = prefix
+        .trim_start_matches('[')
+        .split(|c: char| c.is_whitespace() || c == ']');
+    let first = tokens.next()?;
+    parse_time(first.trim_end_matches(',')).or_else(|| {
+        let second = tokens.next()?;
+        parse_time(&format!("{}T{}", first, second.trim_end_matches(',')))
+    })
+}
+
+fn parse_time(s: &str) -> Option<DateTime<Utc>> {
+    // Timestamps must start with a date
+    if !s.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Some(time.with_timezone(&Utc));
+    }
+    // Timestamps without an offset are written in the local time of the host
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f")
+        .ok()
+        .and_then(|time| Local.from_local_datetime(&time).earliest())
+        .map(|time| time.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_parse_lookback() {
+        assert_eq!("start".parse::<Lookback>().unwrap(), Lookback::Start);
+        assert_eq!(
+            "2h".parse::<Lookback>().unwrap(),
+            Lookback::Duration(Duration::from_secs(7200))
+        );
+        assert_eq!(
+            "1h30m".parse::<Lookback>().unwrap(),
+            Lookback::Duration(Duration::from_secs(5400))
+        );
+        assert!("2x".parse::<Lookback>().is_err());
+        assert!("h".parse::<Lookback>().is_err());
+        assert!("2h30".parse::<Lookback>().is_err());
+
+        // The configuration is passed around as a string
+        for value in &["2h", "90m", "7d", "45s"] {
+            assert_eq!(&value.parse::<Lookback>().unwrap().to_string(), value);
+        }
+    }
+
+    #[test]
+    fn test_line_time() {
+        let time = Utc.ymd(2021, 3, 4).and_hms(5, 6, 7);
+        for line in &[
+            "2021-03-04T05:06:07Z stdout F message\n",
+            "2021-03-04T07:06:07+02:00 message",
+            "{\"log\":\"message\\n\",\"stream\":\"stdout\",\"time\":\"2021-03-04T05:06:07Z\"}",
+        ] {
+            assert_eq!(line_time(line.as_bytes()), Some(time), "{}", line);
+        }
+        // Timestamps without an offset are in local time
+        let time = Local.ymd(2021, 3, 4).and_hms(5, 6, 7).with_timezone(&Utc);
+        for line in &[
+            "2021-03-04 05:06:07 INFO message",
+            "[2021-03-04 05:06:07] message",
+        ] {
+            assert_eq!(line_time(line.as_bytes()), Some(time), "{}", line);
+        }
+        assert_eq!(line_time(b"Mar  4 05:06:07 host message"), None);
+        assert_eq!(line_time(b"  at com.example.Main(Main.java:12)"), None);
+    }
+
+    fn dated_lines(count: i64) -> (Vec<u8>, Vec<u64>) {
+        let start = Utc.ymd(2021, 3, 4).and_hms(0, 0, 0);
+        let mut content = Vec::new();
+        let mut offsets = Vec::new();
+        for i in 0..count {
+            offsets.push(content.len() as u64);
+            let time = start + chrono::Duration::seconds(i);
+            writeln!(content, "{} line {}", time.to_rfc3339(), i).unwrap();
+            // Lines without a timestamp, e.g. stack traces
+            if i % 3 == 0 {
+                writeln!(content, "    continued {}", i).unwrap();
+            }
+        }
+        (content, offsets)
+    }
+
+    #[test]
+    fn test_find_line_since() {
+        let (content, offsets) = dated_lines(20_000);
+        let len = content.len() as u64;
+        assert!(len > SCAN_WINDOW * 8);
+        let mut reader = Cursor::new(content);
+        let start = Utc.ymd(2021, 3, 4).and_hms(0, 0, 0);
+
+        for i in &[0, 1, 3, 4, 9_999, 19_999] {
+            let cutoff = start + chrono::Duration::seconds(*i);
+            assert_eq!(
+                find_line_since(&mut reader, len, cutoff).unwrap(),
+                Some(offsets[*i as usize])
+            );
+        }
+        // No line is recent enough
+        let cutoff = start + chrono::Duration::seconds(20_000);
+        assert_eq!(
+            find_line_since(&mut reader, len, cutoff).unwrap(),
+            Some(len)
+        );
+
+        // Lines that can't be dated
+        let mut reader = Cursor::new(b"first\nsecond\n".to_vec());
+        assert_eq!(find_line_since(&mut reader, 13, cutoff).unwrap(), None);
+    }
+
+    #[test]
+    fn test_lookback_offset() {
+        let now = Utc::now();
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "{} old",
+            (now - chrono::Duration::hours(3)).to_rfc3339()
+        )
+        .unwrap();
+        let recent = file.as_file().metadata().unwrap().len();
+        writeln!(
+            file,
+            "{} recent",
+            (now - chrono::Duration::hours(1)).to_rfc3339()
+        )
+        .unwrap();
+        let len = file.as_file().metadata().unwrap().len();
+
+        let offset = |hours: u64| lookback_offset(file.path(), Duration::from_secs(hours * 3600));
+        assert_eq!(offset(2).unwrap(), recent);
+        assert_eq!(offset(4).unwrap(), 0);
+        assert_eq!(offset(0).unwrap(), len);
+    }
+}
@@ -1,6 +1,8 @@
-use std::fmt::Debug;
-use std::path::Path;
+use std::fmt::{self, Debug};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use crate::lookback::Lookback;
 use globber::{Error as PatternError, Pattern};
 use pcre2::{bytes::Regex, Error as RegexError};
 use std::os::unix::ffi::OsStrExt;
@@ -11,11 +13,23 @@ pub trait Rule {
     fn matches(&self, value: &Path) -> bool;
 }
 
+/// A rule backed by a function, used to match paths against state outside of the
+/// file system (e.g. the metadata of the pod writing the file)
+#[derive(Clone)]
+pub struct FnRule(Arc<dyn Fn(&Path) -> bool + Send + Sync>);
+
+impl Debug for FnRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FnRule")
+    }
+}
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum RuleDef {
     RegexRule(Regex),
     GlobRule(Pattern),
+    FnRule(FnRule),
 }
 
 impl RuleDef {
@@ -32,6 +46,14 @@ impl RuleDef {
             Pattern::new(pattern.into()).map_err(RuleError::Pattern)?,
         ))
     }
+
+    /// Creates a new FnRule from a function
+    pub fn fn_rule<F>(f: F) -> Self
+    where
+        F: Fn(&Path) -> bool + Send + Sync + 'static,
+    {
+        Self::FnRule(FnRule(Arc::new(f)))
+    }
 }
 
 impl Rule for RuleDef {
@@ -39,6 +61,7 @@ impl Rule for RuleDef {
         match self {
             Self::RegexRule(re) => re.is_match(value.as_os_str().as_bytes()).unwrap_or(false),
             Self::GlobRule(p) => p.matches(&value.to_string_lossy()),
+            Self::FnRule(f) => (f.0)(value),
         }
     }
 }
@@ -133,3 +156,21 @@ impl Rules {
         &self.exclusion
     }
 }
+
+/// Rules and lookback overriding the global ones for the files under a directory
+#[derive(Debug, Clone)]
+pub struct DirRules {
+    pub dir: PathBuf,
+    pub rules: Rules,
+    pub lookback: Option<Lookback>,
+}
+
+impl DirRules {
+    /// Returns the overrides of the most specific directory containing the path, if any
+    pub fn for_path<'a>(dir_rules: &'a [DirRules], path: &Path) -> Option<&'a DirRules> {
+        dir_rules
+            .iter()
+            .filter(|dir_rules| path.starts_with(&dir_rules.dir))
+            .max_by_key(|dir_rules| dir_rules.dir.components().count())
+    }
+}
@@ -2,16 +2,20 @@ use crate::cache::entry::Entry;
 use crate::cache::event::Event;
 use crate::cache::tailed_file::LazyLineSerializer;
 pub use crate::cache::DirPathBuf;
-use crate::cache::{EntryKey, Error as CacheError, FileSystem, EVENT_STREAM_BUFFER_COUNT};
+use crate::cache::{
+    EntryKey, Error as CacheError, FileSystem, FileTarget, OpenFilesConfig, ReadConfig,
+    WatchConfig, EVENT_STREAM_BUFFER_COUNT,
+};
 use crate::lookback::Lookback;
-use crate::rule::Rules;
+use crate::rule::{DirRules, Rules};
 
 use metrics::Metrics;
-use state::{FileId, SpanVec};
+use state::{FileId, FileOffsetWriteHandle, SpanVec};
 use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
 
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 
 use futures::{ready, Future, Stream, StreamExt};
@@ -59,7 +63,7 @@ async fn handle_event(
                     // If the file's passes the rules tail it
                     info!("initialize event for file {:?}, target {:?}", name, path);
 
-                    if fs.is_initial_dir_target(&path) {
+                    if fs.is_target(&path) {
                         return data.borrow_mut().tail(vec![path]).await;
                     }
                 }
@@ -235,18 +239,33 @@ pub fn process(
 
 impl Tailer {
     /// Creates new instance of Tailer
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         watched_dirs: Vec<DirPathBuf>,
+        file_targets: Vec<FileTarget>,
         rules: Rules,
+        dir_rules: Vec<DirRules>,
         lookback_config: Lookback,
+        ignore_older: Option<Duration>,
         initial_offsets: Option<HashMap<FileId, SpanVec>>,
+        watch_config: WatchConfig,
+        open_files_config: OpenFilesConfig,
+        read_config: ReadConfig,
+        offsets_handle: Option<FileOffsetWriteHandle>,
     ) -> Self {
         Self {
             fs_cache: Arc::new(Mutex::new(FileSystem::new(
                 watched_dirs,
+                file_targets,
                 initial_offsets.unwrap_or_default(),
                 lookback_config,
+                ignore_older,
                 rules,
+                dir_rules,
+                watch_config,
+                open_files_config,
+                read_config,
+                offsets_handle,
             ))),
             event_times: Arc::new(Mutex::new(HashMap::new())),
         }
@@ -381,9 +400,16 @@ mod test {
                         .path()
                         .try_into()
                         .unwrap_or_else(|_| panic!("{:?} is not a directory!", dir.path()))],
+                    Vec::new(),
                     rules,
+                    Vec::new(),
                     Lookback::None,
                     None,
+                    None,
+                    WatchConfig::default(),
+                    OpenFilesConfig::default(),
+                    ReadConfig::default(),
+                    None,
                 );
 
                 let stream = process(tailer)
@@ -429,9 +455,16 @@ mod test {
                         .path()
                         .try_into()
                         .unwrap_or_else(|_| panic!("{:?} is not a directory!", dir.path()))],
+                    Vec::new(),
                     rules,
+                    Vec::new(),
                     Lookback::SmallFiles,
                     None,
+                    None,
+                    WatchConfig::default(),
+                    OpenFilesConfig::default(),
+                    ReadConfig::default(),
+                    None,
                 );
 
                 let stream = process(tailer)
@@ -458,6 +491,62 @@ mod test {
         });
     }
 
+    // A file ignored as stale is tailed from its end once it's written again
+    #[test]
+    fn ignore_older_written() {
+        run_test(|| {
+            tokio_test::block_on(async {
+                let mut rules = Rules::new();
+                rules.add_inclusion(RuleDef::glob_rule(r"**").unwrap());
+
+                let dir = tempdir().expect("Couldn't create temp dir...");
+                let file_path = dir.path().join("test.log");
+                let mut file = File::create(&file_path).expect("Couldn't create temp log file...");
+                writeln!(file, "This is an old log line")
+                    .expect("Couldn't write to temp log file...");
+                file.set_modified(std::time::SystemTime::now() - Duration::from_secs(7200))
+                    .expect("Couldn't set the modification time...");
+
+                let tailer = Tailer::new(
+                    vec![dir
+                        .path()
+                        .try_into()
+                        .unwrap_or_else(|_| panic!("{:?} is not a directory!", dir.path()))],
+                    Vec::new(),
+                    rules,
+                    Vec::new(),
+                    Lookback::Start,
+                    Some(Duration::from_secs(3600)),
+                    None,
+                    WatchConfig::default(),
+                    OpenFilesConfig::default(),
+                    ReadConfig::default(),
+                    None,
+                );
+
+                let stream = process(tailer)
+                    .expect("failed to read events")
+                    .timeout(std::time::Duration::from_millis(2000));
+
+                let log_line = "This is a new log line";
+                let write_files = async move {
+                    tokio::time::sleep(tokio::time::Duration::from_millis(250)).await;
+                    writeln!(file, "{}", log_line).expect("Couldn't write to temp log file...");
+                    file.sync_all().expect("Failed to sync file");
+                };
+                let (_, events) =
+                    futures::join!(tokio::spawn(write_files), take_events!(stream, 1));
+                let mut events = events.into_iter().flatten().collect::<Vec<_>>();
+                assert_eq!(events.len(), 1, "{:?}, {:?}", events.len(), &events);
+                let event = events[0].as_mut().unwrap();
+                let line = std::str::from_utf8(event.get_line_buffer().unwrap())
+                    .unwrap()
+                    .to_string();
+                assert_eq!(line, log_line);
+            });
+        });
+    }
+
     #[test]
     fn start_lookback() {
         run_test(|| {
@@ -482,9 +571,16 @@ mod test {
                         .path()
                         .try_into()
                         .unwrap_or_else(|_| panic!("{:?} is not a directory!", dir.path()))],
+                    Vec::new(),
                     rules,
+                    Vec::new(),
                     Lookback::Start,
                     None,
+                    None,
+                    WatchConfig::default(),
+                    OpenFilesConfig::default(),
+                    ReadConfig::default(),
+                    None,
                 );
 
                 let stream = process(tailer)
@@ -511,6 +607,126 @@ mod test {
         })
     }
 
+    #[derive(Clone, Copy)]
+    enum Rotation {
+        CopyTruncate,
+        CreateMove,
+        CreateCopy,
+    }
+
+    const ROTATED_LINES: usize = 1500;
+
+    /// Continuously writes numbered lines to a log file rotated a third of the way through,
+    /// returns the numbers of the lines read by the tailer.
+    fn tail_rotated_file(rotation: Rotation) -> Vec<usize> {
+        let mut rules = Rules::new();
+        rules.add_inclusion(RuleDef::glob_rule(r"**").unwrap());
+
+        let dir = tempdir().expect("Couldn't create temp dir...");
+        let logs = dir.path().join("logs");
+        std::fs::create_dir(&logs).unwrap();
+        let log = logs.join("app.log");
+        File::create(&log).expect("Couldn't create temp log file...");
+
+        let tailer = Tailer::new(
+            vec![logs.as_path().try_into().unwrap()],
+            Vec::new(),
+            rules,
+            Vec::new(),
+            Lookback::Start,
+            None,
+            None,
+            WatchConfig::default(),
+            OpenFilesConfig::default(),
+            ReadConfig::default(),
+            None,
+        );
+
+        let writer = std::thread::spawn({
+            let old = dir.path().join("app.log.1");
+            move || {
+                let open = |path: &std::path::Path| {
+                    std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .unwrap()
+                };
+                let mut file = open(&log);
+                for i in 0..ROTATED_LINES {
+                    if i == ROTATED_LINES / 3 {
+                        match rotation {
+                            Rotation::CopyTruncate => {
+                                // The lines that were not read before the truncation are only
+                                // in the copy, give the tailer time to catch up like logrotate
+                                // between its runs
+                                std::thread::sleep(std::time::Duration::from_millis(500));
+                                std::fs::copy(&log, &old).unwrap();
+                                file.set_len(0).unwrap();
+                            }
+                            Rotation::CreateMove => std::fs::rename(&log, &old).unwrap(),
+                            Rotation::CreateCopy => {
+                                std::fs::copy(&log, &old).unwrap();
+                                std::fs::remove_file(&log).unwrap();
+                            }
+                        }
+                    }
+                    // The application keeps writing to the rotated file until it reopens it
+                    if i == 2 * ROTATED_LINES / 3 && !matches!(rotation, Rotation::CopyTruncate) {
+                        file = open(&log);
+                    }
+                    writeln!(file, "line {}", i).expect("Couldn't write to temp log file...");
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                }
+            }
+        });
+
+        let numbers = tokio_test::block_on(async {
+            let stream = process(tailer)
+                .expect("failed to read events")
+                .timeout(std::time::Duration::from_millis(2000));
+            futures::pin_mut!(stream);
+
+            let mut numbers = Vec::new();
+            loop {
+                match futures::StreamExt::next(&mut stream).await {
+                    Some(Ok(Ok(mut line))) => {
+                        let line = std::str::from_utf8(line.get_line_buffer().unwrap()).unwrap();
+                        numbers.push(line.trim_start_matches("line ").parse().unwrap());
+                    }
+                    Some(Ok(Err(e))) => debug!("{:?}", e),
+                    _ => break,
+                }
+            }
+            numbers
+        });
+        writer.join().unwrap();
+        numbers
+    }
+
+    fn assert_no_lines_lost(rotation: Rotation) {
+        run_test(|| {
+            let mut numbers = tail_rotated_file(rotation);
+            numbers.sort_unstable();
+            assert_eq!(numbers, (0..ROTATED_LINES).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn rotate_copytruncate() {
+        assert_no_lines_lost(Rotation::CopyTruncate);
+    }
+
+    #[test]
+    fn rotate_create_move() {
+        assert_no_lines_lost(Rotation::CreateMove);
+    }
+
+    #[test]
+    fn rotate_create_copy() {
+        assert_no_lines_lost(Rotation::CreateCopy);
+    }
+
     #[tokio::test]
     async fn restart_tailer_with_empty_stream() {
         let mut rules = Rules::new();
@@ -30,6 +30,7 @@ const KEY_MESSAGE: &str = "MESSAGE";
 const KEY_SYSTEMD_UNIT: &str = "_SYSTEMD_UNIT";
 const KEY_SYSLOG_IDENTIFIER: &str = "SYSLOG_IDENTIFIER";
 const KEY_CONTAINER_NAME: &str = "CONTAINER_NAME";
+const KEY_PRIORITY: &str = "PRIORITY";
 const DEFAULT_APP: &str = "UNKNOWN_SYSTEMD_APP";
 
 #[derive(Default)]
@@ -143,8 +144,10 @@ where
 }
 
 impl JournaldExportDecoder {
+    /// Builds the line of a record, `levels` sets its level from the record priority.
     fn process_default_record(
         record: &JournalRecord,
+        levels: bool,
     ) -> Result<Option<LineBuilder>, JournalCtlError> {
         let message = match record.get(KEY_MESSAGE) {
             Some(message) => message,
@@ -170,10 +173,17 @@ impl JournaldExportDecoder {
             })
             .unwrap_or(default_app);
 
+        let mut line = LineBuilder::new().line(message.to_string_lossy()).file(app);
+        if let Some(level) = record
+            .get(KEY_PRIORITY)
+            .filter(|_| levels)
+            .and_then(|p| crate::priority_to_level(&p.to_string_lossy()))
+        {
+            line = line.level(level);
+        }
+
         //Metrics::journald().add_bytes(message.len());
-        Ok(Some(
-            LineBuilder::new().line(message.to_string_lossy()).file(app),
-        ))
+        Ok(Some(line))
     }
 }
 
@@ -250,7 +260,9 @@ impl Decoder for JournaldExportDecoder {
     }
 }
 
-pub fn create_journalctl_source() -> Result<impl Stream<Item = LineBuilder>, std::io::Error> {
+pub fn create_journalctl_source(
+    levels: bool,
+) -> Result<impl Stream<Item = LineBuilder>, std::io::Error> {
     let mut journalctl_process = tokio::process::Command::new(JOURNALCTL_CMD)
         // The current boot
         .arg("-b")
@@ -273,18 +285,20 @@ pub fn create_journalctl_source() -> Result<impl Stream<Item = LineBuilder>, std
 
     info!("Listening to journalctl");
     Ok(
-        FramedRead::new(journalctl_stdout, decoder).filter_map(|r| async move {
+        FramedRead::new(journalctl_stdout, decoder).filter_map(move |r| async move {
             match r {
-                Ok(record) => match JournaldExportDecoder::process_default_record(&record) {
-                    Ok(r) => {
-                        trace!("received a record from journalctl");
-                        r
-                    }
-                    Err(e) => {
-                        warn!("Encountered error in journald record: {}", e);
-                        None
+                Ok(record) => {
+                    match JournaldExportDecoder::process_default_record(&record, levels) {
+                        Ok(r) => {
+                            trace!("received a record from journalctl");
+                            r
+                        }
+                        Err(e) => {
+                            warn!("Encountered error in journald record: {}", e);
+                            None
+                        }
                     }
-                },
+                }
                 Err(e) => {
                     warn!("Encountered error while parsing journalctl output: {}", e);
                     None
@@ -486,6 +500,20 @@ _SOURCE_REALTIME_TIMESTAMP=1623323451155218
             values[3].get("INVOCATION_ID").unwrap().to_string_lossy(),
             String::new()
         );
+
+        let line = JournaldExportDecoder::process_default_record(&values[0], true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(line.level.as_deref(), Some("INFO"));
+        let line = JournaldExportDecoder::process_default_record(&values[1], true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(line.level, None);
+        // The priority is only used once the levels are
+        let line = JournaldExportDecoder::process_default_record(&values[0], false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(line.level, None);
     }
 
     #[cfg(feature = "libjournald")]
@@ -498,7 +526,7 @@ _SOURCE_REALTIME_TIMESTAMP=1623323451155218
         let _ = env_logger::Builder::from_default_env().try_init();
         journal::print(1, "Reader got the correct line 1!");
         sleep(Duration::from_millis(50)).await;
-        let mut stream = Box::pin(create_journalctl_source().unwrap());
+        let mut stream = Box::pin(create_journalctl_source(false).unwrap());
         sleep(Duration::from_millis(50)).await;
         journal::print(1, "Reader got the correct line 2!");
 
@@ -2,3 +2,18 @@
 pub mod libjournald;
 
 pub mod journalctl;
+
+/// Maps the syslog `PRIORITY` of a journal record to a line level.
+pub fn priority_to_level(priority: &str) -> Option<&'static str> {
+    match priority.trim() {
+        "0" => Some("EMERGENCY"),
+        "1" => Some("ALERT"),
+        "2" => Some("CRITICAL"),
+        "3" => Some("ERROR"),
+        "4" => Some("WARN"),
+        "5" => Some("NOTICE"),
+        "6" => Some("INFO"),
+        "7" => Some("DEBUG"),
+        _ => None,
+    }
+}
@@ -4,7 +4,9 @@ use http::types::body::LineBuilder;
 use log::{debug, info, warn};
 use std::path::PathBuf;
 
-pub fn create_source(paths: &[PathBuf]) -> impl FutureStream<Item = LineBuilder> {
+/// Streams the records of the journald paths, `levels` sets the level of the lines from the
+/// record priority.
+pub fn create_source(paths: &[PathBuf], levels: bool) -> impl FutureStream<Item = LineBuilder> {
     let mut journal_files: Vec<PathBuf> = Vec::new();
     let mut journal_directories: Vec<PathBuf> = Vec::new();
     for path in paths {
@@ -23,10 +25,10 @@ pub fn create_source(paths: &[PathBuf]) -> impl FutureStream<Item = LineBuilder>
     debug!("initialising journald streams");
     let mut streams: Vec<Stream> = journal_directories
         .into_iter()
-        .map(|dir| Stream::new(Path::Directory(dir)))
+        .map(|dir| Stream::new(Path::Directory(dir), levels))
         .collect();
     if !journal_files.is_empty() {
-        streams.push(Stream::new(Path::Files(journal_files)));
+        streams.push(Stream::new(Path::Files(journal_files), levels));
     }
 
     let combined_stream: SelectAll<<Vec<Stream> as IntoIterator>::Item> = select_all(streams);
@@ -22,6 +22,7 @@ const KEY_MESSAGE: &str = "MESSAGE";
 const KEY_SYSTEMD_UNIT: &str = "_SYSTEMD_UNIT";
 const KEY_SYSLOG_IDENTIFIER: &str = "SYSLOG_IDENTIFIER";
 const KEY_CONTAINER_NAME: &str = "CONTAINER_NAME";
+const KEY_PRIORITY: &str = "PRIORITY";
 const DEFAULT_APP: &str = "UNKNOWN_SYSTEMD_APP";
 
 static WARN_INTERVAL: Duration = Duration::from_secs(300);
@@ -41,16 +42,18 @@ pub struct Stream {
     receiver: Option<Receiver<LineBuilder>>,
     shared_state: Arc<Mutex<SharedState>>,
     path: Path,
+    levels: bool,
     thread_stop_chan: Option<oneshot::Sender<()>>,
 }
 
 impl Stream {
-    pub fn new(path: Path) -> Self {
+    pub fn new(path: Path, levels: bool) -> Self {
         let mut stream = Self {
             thread: None,
             receiver: None,
             shared_state: Arc::new(Mutex::new(SharedState { waker: None })),
             path,
+            levels,
             thread_stop_chan: None,
         };
 
@@ -67,8 +70,9 @@ impl Stream {
         let (sender, receiver) = sync_channel(100);
         let thread_shared_state = self.shared_state.clone();
         let path = self.path.clone();
+        let levels = self.levels;
         let thread = thread::spawn(move || {
-            let mut journal = Reader::new(path);
+            let mut journal = Reader::new(path, levels);
 
             let call_waker = || {
                 let mut shared_state = match thread_shared_state.lock() {
@@ -189,10 +193,12 @@ impl Drop for Stream {
 struct Reader {
     reader: Journal,
     last_warn: Option<Instant>,
+    /// Set the level of the lines from the record priority
+    levels: bool,
 }
 
 impl Reader {
-    fn new(path: Path) -> Self {
+    fn new(path: Path, levels: bool) -> Self {
         let mut reader = match path {
             Path::Directory(path) => Journal::open_directory(&path, JournalFiles::All, false)
                 .expect("Could not open journald reader for directory"),
@@ -208,6 +214,7 @@ impl Reader {
         Self {
             reader,
             last_warn: None,
+            levels,
         }
     }
 
@@ -283,8 +290,17 @@ impl Reader {
             .or_else(|| record.get(KEY_SYSLOG_IDENTIFIER))
             .unwrap_or(&default_app);
 
+        let mut line = LineBuilder::new().line(message).file(app);
+        if let Some(level) = record
+            .get(KEY_PRIORITY)
+            .filter(|_| self.levels)
+            .and_then(|p| crate::priority_to_level(p))
+        {
+            line = line.level(level);
+        }
+
         Metrics::journald().add_bytes(message.len());
-        Ok(Some(LineBuilder::new().line(message).file(app)))
+        Ok(Some(line))
     }
 }
 
@@ -308,7 +324,7 @@ mod tests {
         let _ = env_logger::Builder::from_default_env().try_init();
         journal::print(1, "Reader got the correct line!");
         sleep(Duration::from_millis(50)).await;
-        let mut reader = Reader::new(Path::Directory(JOURNALD_LOG_PATH.into()));
+        let mut reader = Reader::new(Path::Directory(JOURNALD_LOG_PATH.into()), false);
 
         let record_status = reader.process_next_record();
         if let Ok(Some(line)) = record_status {
@@ -327,7 +343,7 @@ mod tests {
         let _ = env_logger::Builder::from_default_env().try_init();
         journal::print(1, "Reader got the correct line 1!");
         sleep(Duration::from_millis(50)).await;
-        let mut stream = Stream::new(Path::Directory(JOURNALD_LOG_PATH.into()));
+        let mut stream = Stream::new(Path::Directory(JOURNALD_LOG_PATH.into()), false);
         sleep(Duration::from_millis(50)).await;
         journal::print(1, "Reader got the correct line 2!");
 
@@ -20,4 +20,6 @@ pub enum K8sEventStreamError {
     SerializationError(#[from] serde_json::Error),
     #[error(transparent)]
     K8sError(#[from] kube::Error),
+    #[error("lost the leadership of the k8s event collection")]
+    LeadershipLost,
 }
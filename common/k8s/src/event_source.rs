@@ -1,17 +1,15 @@
-use core::cmp::{Ord, Ordering};
 use std::convert::TryInto;
 use std::convert::{Into, TryFrom};
 use std::num::NonZeroI64;
 use std::sync::Arc;
 
-use backoff::ExponentialBackoff;
 use crossbeam::atomic::AtomicCell;
 
 use chrono::Duration;
 
-use futures::{stream::try_unfold, Stream, StreamExt, TryStreamExt};
+use futures::{Stream, StreamExt, TryStreamExt};
 
-use k8s_openapi::api::core::v1::{Event, ObjectReference, Pod};
+use k8s_openapi::api::core::v1::{Event, ObjectReference};
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 use kube::api::ListParams;
 use kube::{
@@ -19,8 +17,6 @@ use kube::{
     Api, Client, Config,
 };
 
-use pin_utils::pin_mut;
-
 use serde::Serialize;
 
 use http::types::body::LineBuilder;
@@ -28,13 +24,12 @@ use http::types::body::LineBuilder;
 use metrics::Metrics;
 
 use crate::errors::{K8sError, K8sEventStreamError};
+use crate::leader::{Checkpoint, EventCheckpoints, LeaderElection, K8S_EVENTS_LEASE_NAME};
 
 use crate::restarting_stream::{RequiresRestart, RestartingStream};
 
 use regex::Regex;
 
-const CONTAINER_NAME: &str = "logdna-agent";
-
 lazy_static! {
     static ref APP_REGEX: Regex = {
         match Regex::new(r"\{(.+?)\}") {
@@ -223,7 +218,7 @@ pub struct K8sEventStream {
     pub client: Client,
     pod_name: String,
     namespace: String,
-    pod_label: String,
+    checkpoints: Arc<EventCheckpoints>,
 }
 
 pub enum StreamElem<T> {
@@ -232,20 +227,21 @@ pub enum StreamElem<T> {
 }
 
 impl K8sEventStream {
-    pub fn new(client: Client, pod_name: String, namespace: String, pod_label: String) -> Self {
+    pub fn new(client: Client, pod_name: String, namespace: String) -> Self {
         Self {
             client,
             pod_name,
             namespace,
-            pod_label,
+            checkpoints: Default::default(),
         }
     }
 
-    pub fn try_default(
-        pod_name: String,
-        namespace: String,
-        pod_label: String,
-    ) -> Result<Self, K8sError> {
+    /// Returns the checkpoints of the events, the batches of lines acknowledge them once sent.
+    pub fn checkpoints(&self) -> Arc<EventCheckpoints> {
+        self.checkpoints.clone()
+    }
+
+    pub fn try_default(pod_name: String, namespace: String) -> Result<Self, K8sError> {
         let config = match Config::from_cluster_env() {
             Ok(v) => v,
             Err(e) => {
@@ -255,167 +251,15 @@ impl K8sEventStream {
                 )))
             }
         };
-        Ok(Self::new(
-            Client::try_from(config)?,
-            pod_name,
-            namespace,
-            pod_label,
-        ))
-    }
-
-    #[allow(clippy::map_flatten)]
-    async fn get_oldest_pod(
-        api: Api<Pod>,
-        label: &str,
-    ) -> Result<Option<String>, K8sEventStreamError> {
-        let lp = ListParams::default().labels(&format!("app.kubernetes.io/name={}", label)); // filter instances by label
-        let pod_list = api.list(&lp).await.map_err(K8sEventStreamError::K8sError)?;
-        let oldest_post = pod_list
-            .iter()
-            .filter_map(|p| -> Option<(Option<u64>, &Time, &str)> {
-                if let (pod_gen, Some(pod_started_at), Some(pod_name)) =
-                    // get pod generation, it will be there if there is an update strategy
-                    (
-                        p.metadata
-                            .labels
-                            .as_ref()
-                            .map(|l| {
-                                l.get("pod-template-generation")
-                                    .and_then(|g| g.parse::<u64>().ok())
-                            })
-                            .flatten(),
-                        get_pod_started_at(p),
-                        p.metadata.name.as_ref(),
-                    )
-                {
-                    Some((pod_gen, pod_started_at, pod_name))
-                } else {
-                    None
-                }
-            })
-            // Keeps oldest pod
-            .reduce(
-                move |(gen, started_at, name),
-                      (o_gen, o_started_at, o_name)|
-                      -> (Option<u64>, &Time, &str) {
-                    // For our purposes later generations should sort earlier than later ones
-                    if Ordering::reverse(Ord::cmp(&gen, &o_gen))
-                        .then(Ord::cmp(started_at, o_started_at))
-                        .then(Ord::cmp(name, o_name))
-                        == Ordering::Less
-                    {
-                        (o_gen, o_started_at, o_name)
-                    } else {
-                        (gen, started_at, name)
-                    }
-                },
-            )
-            .map(|(_, _, name)| name.to_string());
-        Ok(oldest_post)
-    }
-
-    fn waiter_stream<T>(
-        pod_name: impl Into<String>,
-        namespace: impl Into<String>,
-        pod_label: impl Into<String>,
-        client: Arc<Client>,
-        delete_time: Arc<AtomicCell<Option<NonZeroI64>>>,
-    ) -> impl Stream<Item = Result<StreamElem<T>, K8sEventStreamError>> {
-        let pod_name = pod_name.into();
-        let namespace = namespace.into();
-        let pod_label = pod_label.into();
-
-        let waiter = move |_| {
-            let pod_name = pod_name.clone();
-            let namespace = namespace.clone();
-            let pod_label = pod_label.clone();
-            let client = client.clone();
-
-            let delete_time = delete_time.clone();
-            let pods: Api<Pod> = Api::namespaced(client.as_ref().clone(), &namespace);
-            // subscribe to pod api and filter pods by POD_APP_LABEL
-
-            #[allow(clippy::map_flatten)]
-            async move {
-                // Find the oldest pod of the latest generation
-                enum Cont {
-                    Cont,
-                    Break,
-                }
-
-                if let Some(oldest_pod_name) =
-                    K8sEventStream::get_oldest_pod(pods.clone(), &pod_label).await?
-                {
-                    if oldest_pod_name == pod_name {
-                        info!("begin logging k8s events");
-                        Ok(None)
-                    } else {
-                        info!("watching pod {}", oldest_pod_name);
-                        let params = ListParams::default()
-                            .timeout(30)
-                            .labels(&format!("app.kubernetes.io/name={}", &pod_label)) // filter instances by label
-                            .fields(&format!("metadata.name={}", oldest_pod_name)); // filter instances by label
-                        let stream = watcher(pods.clone(), params)
-                            .skip_while(|e| {
-                                let matched = matches!(e, Ok(watcher::Event::<Pod>::Restarted(_)));
-                                async move { matched }
-                            })
-                            .map({
-                                move |e| match e {
-                                    Ok(watcher::Event::Deleted(e)) => {
-                                        info!("previous k8s event logger deleted");
-                                        delete_time.store(
-                                            e.metadata
-                                                .deletion_timestamp
-                                                .map(|t| {
-                                                    info!(
-                                                        "Ignoring k8s events before {}",
-                                                        t.0 - chrono::Duration::seconds(2)
-                                                    );
-                                                    NonZeroI64::new(t.0.timestamp() - 2)
-                                                })
-                                                .flatten(),
-                                        );
-                                        Cont::Break
-                                    }
-                                    _ => Cont::Cont,
-                                }
-                            })
-                            .filter_map(|e| async move {
-                                match e {
-                                    Cont::Cont => None,
-                                    Cont::Break => Some(((), ())),
-                                }
-                            });
-                        pin_mut!(stream);
-                        Ok(stream.next().await)
-                    }
-                } else {
-                    Ok(Some(((), ())))
-                }
-            }
-        };
-
-        let waiter = Arc::new(waiter);
-
-        try_unfold((), {
-            let waiter = waiter.clone();
-            move |_| {
-                let waiter = waiter.clone();
-                backoff::future::retry(ExponentialBackoff::default(), move || waiter(()))
-            }
-        })
-        .map(|r: Result<(), K8sEventStreamError>| match r {
-            Ok(_) => Ok(StreamElem::Waiting),
-            Err(e) => Err(e),
-        })
+        Ok(Self::new(Client::try_from(config)?, pod_name, namespace))
     }
 
     pub fn active_stream(
         client: Arc<Client>,
         latest_event_time: Arc<AtomicCell<Option<NonZeroI64>>>,
-        previous_event_logger_delete_time: Arc<AtomicCell<Option<NonZeroI64>>>,
-    ) -> impl Stream<Item = Result<StreamElem<LineBuilder>, K8sEventStreamError>> {
+        resume_from: Option<Checkpoint>,
+    ) -> impl Stream<Item = Result<StreamElem<(LineBuilder, Option<Checkpoint>)>, K8sEventStreamError>>
+    {
         let events: Api<Event> = Api::all(client.as_ref().clone());
         let params = ListParams::default();
 
@@ -425,19 +269,23 @@ impl K8sEventStream {
             .filter({
                 move |event| {
                     let latest_event_time = latest_event_time.clone();
-                    let earliest = previous_event_logger_delete_time.clone();
-                    let ret = latest_event_time
-                        .load()
-                        .or_else(|| earliest.load())
-                        .and_then(|earliest| {
+                    let ret = event.as_ref().ok().and_then(|e| {
+                        // Skip the events sent by the previous leader
+                        if let (Some(resume_from), Some(checkpoint)) =
+                            (resume_from.as_ref(), Checkpoint::from_event(e))
+                        {
+                            if resume_from.covers(&checkpoint) {
+                                return Some(false);
+                            }
+                        }
+                        latest_event_time.load().and_then(|earliest| {
                             let earliest =
                                 chrono::NaiveDateTime::from_timestamp(earliest.into(), 0);
-                            event.as_ref().ok().and_then(|e| {
-                                e.last_timestamp
-                                    .as_ref()
-                                    .map(|l| earliest < l.0.naive_utc())
-                            })
-                        });
+                            e.last_timestamp
+                                .as_ref()
+                                .map(|l| earliest < l.0.naive_utc())
+                        })
+                    });
                     async move { ret.unwrap_or(true) }
                 }
             })
@@ -448,15 +296,18 @@ impl K8sEventStream {
                         .last_timestamp
                         .as_ref()
                         .and_then(|t| NonZeroI64::new(t.0.timestamp() - 2));
+                    // Carried by the line, the checkpoint is stored in the lease once the
+                    // batch of the line is sent
+                    let this_checkpoint = Checkpoint::from_event(&e);
 
                     let ret = LineBuilder::try_from(EventLog::from(e)).map(|l| {
                         Metrics::k8s().increment_lines();
                         l
                     });
                     if ret.is_ok() {
-                        latest_event_time.store(this_event_time)
+                        latest_event_time.store(this_event_time);
                     };
-                    ret
+                    ret.map(|l| (l, this_checkpoint))
                 }) {
                     Ok(Ok(l)) => Ok(StreamElem::Event(l)),
                     Ok(Err(e)) => Err(e),
@@ -466,33 +317,35 @@ impl K8sEventStream {
     }
 
     pub async fn create_stream(
-        pod_name: impl Into<String>,
-        namespace: impl Into<String>,
-        pod_label: impl Into<String>,
+        election: Arc<LeaderElection>,
         client: Arc<Client>,
         latest_event_time: Arc<AtomicCell<Option<NonZeroI64>>>,
-    ) -> impl Stream<Item = Result<LineBuilder, K8sEventStreamError>> {
-        let pod_name = pod_name.into();
-        let namespace = namespace.into();
-        let previous_event_logger_delete_time: Arc<AtomicCell<Option<NonZeroI64>>> =
-            Arc::new(AtomicCell::new(None));
-
-        // Retry is handled internally with exponential backoff
-        let waiting_stream = K8sEventStream::waiter_stream(
-            pod_name,
-            namespace,
-            pod_label,
-            client.clone(),
-            previous_event_logger_delete_time.clone(),
-        );
-
-        let event_stream = K8sEventStream::active_stream(
-            client,
-            latest_event_time,
-            previous_event_logger_delete_time,
-        );
+        checkpoints: Arc<EventCheckpoints>,
+    ) -> impl Stream<Item = Result<(LineBuilder, Option<Checkpoint>), K8sEventStreamError>> {
+        // Only the leader collects the events, the other agents wait to take over
+        let leader_stream = futures::stream::once({
+            let election = election.clone();
+            async move { election.acquire().await }
+        })
+        .map(move |previous| {
+            info!("begin logging k8s events");
+            // The checkpoint of this agent is newer than the one in the lease, when set
+            let resume_from = checkpoints.resume().or(previous);
+            let election = election.clone();
+            let lost = {
+                let checkpoints = checkpoints.clone();
+                async move { election.hold(checkpoints).await }
+            };
+            K8sEventStream::active_stream(client.clone(), latest_event_time.clone(), resume_from)
+                .take_until(lost)
+                // Go back to waiting for the lease once the leadership is lost
+                .chain(futures::stream::once(async {
+                    Err(K8sEventStreamError::LeadershipLost)
+                }))
+        })
+        .flatten();
 
-        waiting_stream.chain(event_stream).filter_map(|e| async {
+        leader_stream.filter_map(|e| async {
             match e {
                 Ok(StreamElem::Event(l)) => Some(Ok(l)),
                 Ok(StreamElem::Waiting) => None,
@@ -501,24 +354,29 @@ impl K8sEventStream {
         })
     }
 
-    pub async fn event_stream(self) -> Result<impl Stream<Item = LineBuilder> + Send, String> {
+    /// Streams the lines of the events along with their checkpoint, the line batches record
+    /// the checkpoints in `checkpoints()` as the lines are added to them.
+    pub async fn event_stream(
+        self,
+    ) -> Result<impl Stream<Item = (LineBuilder, Option<Checkpoint>)> + Send, String> {
         let client = std::sync::Arc::new(self.client.clone());
 
         let latest_event_time: Arc<AtomicCell<Option<NonZeroI64>>> =
             Arc::new(AtomicCell::new(None));
-        let pod_name = self.pod_name.clone();
-        let namespace = self.namespace.clone();
-        let pod_label = self.pod_label.clone();
+        let election = Arc::new(LeaderElection::new(
+            self.client.clone(),
+            &self.namespace,
+            K8S_EVENTS_LEASE_NAME,
+            &self.pod_name,
+        ));
+        let checkpoints = self.checkpoints.clone();
 
-        let _latest_event_time = latest_event_time.clone();
-        let _client = client.clone();
         let start_stream = move || {
             K8sEventStream::create_stream(
-                pod_name.clone(),
-                namespace.clone(),
-                pod_label.clone(),
-                _client.clone(),
-                _latest_event_time.clone(),
+                election.clone(),
+                client.clone(),
+                latest_event_time.clone(),
+                checkpoints.clone(),
             )
         };
 
@@ -527,29 +385,10 @@ impl K8sEventStream {
                 warn!("Restarting Stream");
                 RequiresRestart::Yes
             }
+            Err(K8sEventStreamError::LeadershipLost) => RequiresRestart::Yes,
             _ => RequiresRestart::No,
         });
 
         Ok(restarting_stream.await.filter_map(|e| async { e.ok() }))
     }
 }
-
-fn get_pod_started_at(p: &Pod) -> Option<&Time> {
-    // WTB do notation
-    p.status.as_ref().and_then(|s| {
-        s.container_statuses.as_ref().and_then(|cs| {
-            cs.iter()
-                .filter_map(|c| {
-                    if c.name == CONTAINER_NAME && c.ready {
-                        Some(c.state.as_ref().and_then(|st| {
-                            st.running.as_ref().and_then(|rs| rs.started_at.as_ref())
-                        }))
-                    } else {
-                        None
-                    }
-                })
-                .next()
-                .flatten()
-        })
-    })
-}
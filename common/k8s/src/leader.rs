@@ -0,0 +1,459 @@
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::api::core::v1::Event;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use kube::api::{Api, PostParams};
+use kube::Client;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Name of the lease held by the agent collecting the cluster events
+pub const K8S_EVENTS_LEASE_NAME: &str = "logdna-agent-k8s-events";
+
+const LEASE_DURATION: Duration = Duration::from_secs(15);
+const RETRY_PERIOD: Duration = Duration::from_secs(5);
+
+const RESOURCE_VERSION_ANNOTATION: &str = "logdna.com/last-event-resource-version";
+const UID_ANNOTATION: &str = "logdna.com/last-event-uid";
+const TIME_ANNOTATION: &str = "logdna.com/last-event-time";
+
+/// The last event sent by the leader, stored in the lease so the next leader resumes from it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Checkpoint {
+    pub resource_version: String,
+    pub uid: String,
+    /// The last timestamp of the event, or its event time
+    pub time: Option<DateTime<Utc>>,
+}
+
+impl Checkpoint {
+    /// Builds the checkpoint of an event, `None` when it has no resource version.
+    pub fn from_event(event: &Event) -> Option<Checkpoint> {
+        Some(Checkpoint {
+            resource_version: event.metadata.resource_version.clone()?,
+            uid: event.metadata.uid.clone().unwrap_or_default(),
+            time: event
+                .last_timestamp
+                .as_ref()
+                .map(|t| t.0)
+                .or_else(|| event.event_time.as_ref().map(|t| t.0))
+                .or_else(|| event.first_timestamp.as_ref().map(|t| t.0)),
+        })
+    }
+
+    fn from_meta(meta: &ObjectMeta) -> Option<Checkpoint> {
+        let annotations = meta.annotations.as_ref()?;
+        Some(Checkpoint {
+            resource_version: annotations.get(RESOURCE_VERSION_ANNOTATION)?.clone(),
+            uid: annotations.get(UID_ANNOTATION).cloned().unwrap_or_default(),
+            time: annotations
+                .get(TIME_ANNOTATION)
+                .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                .map(|t| t.with_timezone(&Utc)),
+        })
+    }
+
+    fn write_meta(&self, meta: &mut ObjectMeta) {
+        let annotations = meta.annotations.get_or_insert_with(Default::default);
+        annotations.insert(
+            RESOURCE_VERSION_ANNOTATION.to_string(),
+            self.resource_version.clone(),
+        );
+        annotations.insert(UID_ANNOTATION.to_string(), self.uid.clone());
+        match self.time {
+            Some(time) => annotations.insert(TIME_ANNOTATION.to_string(), time.to_rfc3339()),
+            None => annotations.remove(TIME_ANNOTATION),
+        };
+    }
+
+    /// Returns true when the event was sent before the checkpoint was taken. Resource versions
+    /// are opaque, so the events are ordered by their time. The events of the same second
+    /// as the checkpoint are sent again, except for the checkpoint itself.
+    pub fn covers(&self, event: &Checkpoint) -> bool {
+        if event.uid == self.uid && event.resource_version == self.resource_version {
+            return true;
+        }
+        match (self.time, event.time) {
+            (Some(last), Some(time)) => time < last,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct CheckpointsState {
+    /// Newest event added to a batch, the initial list isn't in time order
+    added: Option<Checkpoint>,
+    /// Batches being sent in order, with the newest event added when each was emitted and
+    /// whether it's acknowledged
+    batches: VecDeque<(u64, Option<Checkpoint>, bool)>,
+    next_batch: u64,
+    /// Newest event of the batches sent, along with all the batches before them
+    acknowledged: Option<Checkpoint>,
+    /// Set once a batch failed, the events after the acknowledged one are sent again by the
+    /// next leader
+    failed: bool,
+}
+
+/// Tracks the events sent by the leader. The checkpoint stored in the lease only advances
+/// once the batches containing the events are sent or stored for retry.
+#[derive(Default)]
+pub struct EventCheckpoints {
+    state: Mutex<CheckpointsState>,
+}
+
+impl EventCheckpoints {
+    /// Records an event whose line is added to the batch being built.
+    pub fn add(&self, checkpoint: Checkpoint) {
+        let mut state = self.state.lock();
+        let newer = state
+            .added
+            .as_ref()
+            .map_or(true, |added| !added.covers(&checkpoint));
+        if newer {
+            state.added = Some(checkpoint);
+        }
+    }
+
+    /// Tracks a batch once emitted, containing the events added since the previous one.
+    pub fn batch(self: &Arc<Self>) -> EventBatch {
+        let mut state = self.state.lock();
+        let id = state.next_batch;
+        state.next_batch += 1;
+        if !state.failed {
+            let added = state.added.clone();
+            state.batches.push_back((id, added, false));
+        }
+        EventBatch {
+            checkpoints: self.clone(),
+            id,
+            sent: false,
+        }
+    }
+
+    /// Returns the newest event known to be sent.
+    pub fn acknowledged(&self) -> Option<Checkpoint> {
+        self.state.lock().acknowledged.clone()
+    }
+
+    /// Returns the event to resume from when the leadership is acquired again, the events
+    /// of the previous term that were not acknowledged are sent again.
+    pub fn resume(&self) -> Option<Checkpoint> {
+        let mut state = self.state.lock();
+        state.failed = false;
+        state.batches.clear();
+        state.added = state.acknowledged.clone();
+        state.acknowledged.clone()
+    }
+
+    fn complete(&self, id: u64, sent: bool) {
+        let mut state = self.state.lock();
+        if !sent {
+            // The acknowledged event doesn't advance past the events of a failed batch
+            state.failed = true;
+            state.batches.clear();
+            return;
+        }
+        if let Some(batch) = state.batches.iter_mut().find(|(batch, _, _)| *batch == id) {
+            batch.2 = true;
+        }
+        while state.batches.front().map_or(false, |(_, _, done)| *done) {
+            if let Some((_, Some(checkpoint), _)) = state.batches.pop_front() {
+                state.acknowledged = Some(checkpoint);
+            }
+        }
+    }
+}
+
+/// A batch of lines being sent, it acknowledges the events added to it and to the batches
+/// before it.
+pub struct EventBatch {
+    checkpoints: Arc<EventCheckpoints>,
+    id: u64,
+    sent: bool,
+}
+
+impl EventBatch {
+    /// Marks the batch as sent or stored for retry.
+    pub fn acknowledge(mut self) {
+        self.sent = true;
+        self.checkpoints.complete(self.id, true);
+    }
+}
+
+impl Drop for EventBatch {
+    fn drop(&mut self) {
+        // Dropped without being acknowledged, the batch wasn't sent nor stored for retry
+        if !self.sent {
+            self.checkpoints.complete(self.id, false);
+        }
+    }
+}
+
+enum Election {
+    Leader(Option<Checkpoint>),
+    Follower(Option<String>),
+}
+
+/// Elects a single agent of the cluster using a `coordination.k8s.io/v1` Lease. The leader
+/// renews the lease periodically, other agents take it over once it expires.
+pub struct LeaderElection {
+    api: Api<Lease>,
+    name: String,
+    identity: String,
+}
+
+fn expired(spec: &LeaseSpec, now: DateTime<Utc>) -> bool {
+    match (&spec.renew_time, spec.lease_duration_seconds) {
+        (Some(renew_time), Some(duration)) => {
+            renew_time.0 + chrono::Duration::seconds(duration.into()) < now
+        }
+        _ => true,
+    }
+}
+
+/// Returns the holder preventing `identity` from acquiring the lease, if any.
+fn current_holder(spec: &LeaseSpec, identity: &str, now: DateTime<Utc>) -> Option<String> {
+    spec.holder_identity
+        .clone()
+        .filter(|holder| !holder.is_empty() && holder != identity && !expired(spec, now))
+}
+
+impl LeaderElection {
+    pub fn new(client: Client, namespace: &str, name: &str, identity: &str) -> Self {
+        LeaderElection {
+            api: Api::namespaced(client, namespace),
+            name: name.to_string(),
+            identity: identity.to_string(),
+        }
+    }
+
+    fn spec(&self, previous: Option<&LeaseSpec>, now: DateTime<Utc>) -> LeaseSpec {
+        let renewal = previous
+            .and_then(|s| s.holder_identity.as_ref())
+            .map_or(false, |holder| *holder == self.identity);
+        let transitions = previous.and_then(|s| s.lease_transitions).unwrap_or(0);
+        LeaseSpec {
+            holder_identity: Some(self.identity.clone()),
+            lease_duration_seconds: Some(LEASE_DURATION.as_secs() as i32),
+            acquire_time: if renewal {
+                previous.and_then(|s| s.acquire_time.clone())
+            } else {
+                Some(MicroTime(now))
+            },
+            renew_time: Some(MicroTime(now)),
+            lease_transitions: Some(if renewal || previous.is_none() {
+                transitions
+            } else {
+                transitions + 1
+            }),
+        }
+    }
+
+    /// Acquires or renews the lease, storing the checkpoint in it when set.
+    async fn try_acquire(&self, checkpoint: Option<&Checkpoint>) -> Result<Election, kube::Error> {
+        let now = Utc::now();
+        let result = match self.api.get(&self.name).await {
+            Ok(mut lease) => {
+                let spec = lease.spec.take().unwrap_or_default();
+                if let Some(holder) = current_holder(&spec, &self.identity, now) {
+                    return Ok(Election::Follower(Some(holder)));
+                }
+                let previous = Checkpoint::from_meta(&lease.metadata);
+                if let Some(checkpoint) = checkpoint {
+                    checkpoint.write_meta(&mut lease.metadata);
+                }
+                lease.spec = Some(self.spec(Some(&spec), now));
+                // The resource version of the lease makes the replace fail when another
+                // agent updated it in the meantime
+                self.api
+                    .replace(&self.name, &PostParams::default(), &lease)
+                    .await
+                    .map(|_| Election::Leader(previous))
+            }
+            Err(kube::Error::Api(e)) if e.code == 404 => {
+                let mut lease = Lease {
+                    metadata: ObjectMeta {
+                        name: Some(self.name.clone()),
+                        ..Default::default()
+                    },
+                    spec: Some(self.spec(None, now)),
+                };
+                if let Some(checkpoint) = checkpoint {
+                    checkpoint.write_meta(&mut lease.metadata);
+                }
+                self.api
+                    .create(&PostParams::default(), &lease)
+                    .await
+                    .map(|_| Election::Leader(None))
+            }
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Err(kube::Error::Api(e)) if e.code == 409 => Ok(Election::Follower(None)),
+            result => result,
+        }
+    }
+
+    /// Waits until this agent is the leader, returning the checkpoint left by the previous one.
+    pub async fn acquire(&self) -> Option<Checkpoint> {
+        let mut last_holder = None;
+        loop {
+            match self.try_acquire(None).await {
+                Ok(Election::Leader(checkpoint)) => {
+                    info!("acquired the {} lease", self.name);
+                    return checkpoint;
+                }
+                Ok(Election::Follower(holder)) => {
+                    if holder.is_some() && holder != last_holder {
+                        info!(
+                            "the {} lease is held by {}",
+                            self.name,
+                            holder.as_deref().unwrap_or_default()
+                        );
+                        last_holder = holder;
+                    }
+                }
+                Err(e) => warn!("unable to acquire the {} lease: {}", self.name, e),
+            }
+            tokio::time::sleep(RETRY_PERIOD).await;
+        }
+    }
+
+    /// Renews the lease while this agent is the leader, storing the latest acknowledged
+    /// checkpoint in it. Completes when the leadership is lost, before the lease can be taken
+    /// over.
+    pub async fn hold(&self, checkpoints: Arc<EventCheckpoints>) {
+        let mut renewed_at = Instant::now();
+        loop {
+            tokio::time::sleep(RETRY_PERIOD).await;
+            let current = checkpoints.acknowledged();
+            match self.try_acquire(current.as_ref()).await {
+                Ok(Election::Leader(_)) => renewed_at = Instant::now(),
+                Ok(Election::Follower(holder)) => {
+                    warn!(
+                        "lost the {} lease to {}",
+                        self.name,
+                        holder.as_deref().unwrap_or("another agent")
+                    );
+                    return;
+                }
+                Err(e) => {
+                    warn!("unable to renew the {} lease: {}", self.name, e);
+                    if renewed_at.elapsed() + RETRY_PERIOD >= LEASE_DURATION {
+                        warn!("giving up the {} lease", self.name);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn lease_spec(holder: Option<&str>, renewed_secs_ago: i64) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: holder.map(String::from),
+            lease_duration_seconds: Some(15),
+            renew_time: Some(MicroTime(
+                Utc::now() - chrono::Duration::seconds(renewed_secs_ago),
+            )),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_current_holder() {
+        let now = Utc::now();
+        assert_eq!(
+            current_holder(&lease_spec(Some("agent-a"), 5), "agent-b", now),
+            Some("agent-a".to_string())
+        );
+        // Expired leases are taken over
+        assert_eq!(
+            current_holder(&lease_spec(Some("agent-a"), 20), "agent-b", now),
+            None
+        );
+        assert_eq!(
+            current_holder(&lease_spec(Some("agent-b"), 5), "agent-b", now),
+            None
+        );
+        assert_eq!(current_holder(&lease_spec(None, 5), "agent-b", now), None);
+        assert_eq!(current_holder(&LeaseSpec::default(), "agent-b", now), None);
+    }
+
+    fn checkpoint(resource_version: &str, secs: i64) -> Checkpoint {
+        Checkpoint {
+            resource_version: resource_version.to_string(),
+            uid: format!("uid-{}", resource_version),
+            time: Some(Utc.timestamp(1_600_000_000 + secs, 0)),
+        }
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let last = checkpoint("1200", 20);
+        let mut meta = ObjectMeta::default();
+        last.write_meta(&mut meta);
+        assert_eq!(Checkpoint::from_meta(&meta), Some(last.clone()));
+        assert_eq!(Checkpoint::from_meta(&ObjectMeta::default()), None);
+
+        // The events are ordered by time, not by their opaque resource version
+        assert!(last.covers(&checkpoint("1300", 10)));
+        assert!(last.covers(&checkpoint("1200", 20)));
+        assert!(!last.covers(&checkpoint("1100", 30)));
+        // Other events of the same second are sent again
+        assert!(!last.covers(&checkpoint("1201", 20)));
+
+        // Without a time only the same event version is covered
+        let last = Checkpoint {
+            time: None,
+            ..checkpoint("abc", 0)
+        };
+        assert!(last.covers(&checkpoint("abc", 0)));
+        assert!(!last.covers(&checkpoint("abd", 0)));
+        assert!(!last.covers(&Checkpoint::default()));
+    }
+
+    #[test]
+    fn test_event_checkpoints() {
+        let checkpoints = Arc::new(EventCheckpoints::default());
+        // The initial list isn't in time order
+        checkpoints.add(checkpoint("1200", 20));
+        checkpoints.add(checkpoint("1100", 10));
+        let first = checkpoints.batch();
+        checkpoints.add(checkpoint("1300", 30));
+        let second = checkpoints.batch();
+        assert_eq!(checkpoints.acknowledged(), None);
+
+        // The batches are acknowledged in order
+        second.acknowledge();
+        assert_eq!(checkpoints.acknowledged(), None);
+        first.acknowledge();
+        assert_eq!(checkpoints.acknowledged(), Some(checkpoint("1300", 30)));
+
+        // The checkpoint doesn't advance past a failed batch
+        checkpoints.add(checkpoint("1400", 40));
+        let third = checkpoints.batch();
+        checkpoints.add(checkpoint("1500", 50));
+        let fourth = checkpoints.batch();
+        drop(third);
+        fourth.acknowledge();
+        checkpoints.add(checkpoint("1600", 60));
+        checkpoints.batch().acknowledge();
+        assert_eq!(checkpoints.acknowledged(), Some(checkpoint("1300", 30)));
+
+        // The events after it are sent again once resumed
+        assert_eq!(checkpoints.resume(), Some(checkpoint("1300", 30)));
+        checkpoints.add(checkpoint("1400", 40));
+        checkpoints.batch().acknowledge();
+        assert_eq!(checkpoints.acknowledged(), Some(checkpoint("1400", 40)));
+    }
+}
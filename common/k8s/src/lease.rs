@@ -1,13 +1,17 @@
-use chrono::Utc;
-use k8s_openapi::api::coordination::v1::Lease;
-use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
-use kube::api::{Api, ListParams, Patch, PatchParams};
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use kube::api::{Api, ListParams, Patch, PatchParams, PostParams};
 use kube::core::ObjectList;
 use kube::Client;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::task::JoinHandle;
 
 pub const K8S_STARTUP_LEASE_LABEL: &str = "process=logdna-agent-startup";
 pub const K8S_STARTUP_LEASE_RETRY_ATTEMPTS: i32 = 3;
+/// Duration used for the startup leases that don't set `leaseDurationSeconds`
+pub const K8S_STARTUP_LEASE_DEFAULT_DURATION: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Serialize, Deserialize)]
 struct LeasePatchSpec {
@@ -20,84 +24,211 @@ struct LeasePatchSpec {
 struct LeasePatchValue {
     #[serde(rename = "holderIdentity")]
     holder_identity: Option<String>,
-    #[serde(rename = "aquireTime")]
+    #[serde(rename = "acquireTime")]
     acquire_time: MicroTime,
+    #[serde(rename = "renewTime")]
+    renew_time: Option<MicroTime>,
 }
 
-pub async fn get_available_lease(lease_label: &str, lease_client: &Api<Lease>) -> Option<String> {
-    let lease_info = get_lease_list(lease_label, lease_client).await;
-    for lease in lease_info.unwrap().into_iter() {
-        let lease_name = lease.metadata.name.unwrap();
-        match lease.spec.unwrap().holder_identity {
-            Some(lease_owner) => {
-                info!("Lease {} is OWNED by {:?}.", lease_name, lease_owner)
+fn lease_duration(spec: &LeaseSpec) -> Duration {
+    spec.lease_duration_seconds
+        .filter(|d| *d > 0)
+        .map(|d| Duration::from_secs(d as u64))
+        .unwrap_or(K8S_STARTUP_LEASE_DEFAULT_DURATION)
+}
+
+/// Returns the last time the lease was written, from its managed fields or creation time.
+fn last_written(meta: &ObjectMeta) -> Option<DateTime<Utc>> {
+    meta.managed_fields
+        .iter()
+        .flatten()
+        .filter_map(|fields| fields.time.as_ref().map(|time| time.0))
+        .max()
+        .or_else(|| meta.creation_timestamp.as_ref().map(|time| time.0))
+}
+
+/// Returns true when the lease has no holder or when its holder stopped renewing it. Leases
+/// held without a renew or acquire time, like the ones claimed by older agents writing
+/// `aquireTime`, are stale once the lease duration passed since they were last written.
+fn is_available(lease: &Lease, now: DateTime<Utc>) -> bool {
+    let spec = match lease.spec.as_ref() {
+        Some(spec) => spec,
+        None => return true,
+    };
+    match spec.holder_identity.as_deref() {
+        None | Some("") => true,
+        Some(_) => {
+            let last = spec
+                .renew_time
+                .as_ref()
+                .or(spec.acquire_time.as_ref())
+                .map(|time| time.0)
+                .or_else(|| last_written(&lease.metadata));
+            match (last, chrono::Duration::from_std(lease_duration(spec))) {
+                (Some(last), Ok(duration)) => last + duration < now,
+                // Without any time the lease is taken over
+                (None, _) => true,
+                (_, Err(_)) => false,
             }
-            None => {
-                info!("Lease {} NOT OWNED...", lease_name);
-                return Some(lease_name);
+        }
+    }
+}
+
+/// Returns the name of the first lease that is free or held by a stale holder.
+pub async fn get_available_lease(
+    lease_label: &str,
+    lease_client: &Api<Lease>,
+) -> Result<Option<String>, kube::Error> {
+    let lease_info = get_lease_list(lease_label, lease_client).await?;
+    let now = Utc::now();
+    for lease in lease_info.into_iter() {
+        let lease_name = match lease.metadata.name.as_ref() {
+            Some(name) => name,
+            None => continue,
+        };
+        let holder = lease.spec.as_ref().and_then(|s| s.holder_identity.as_ref());
+        if is_available(&lease, now) {
+            match holder {
+                Some(holder) if !holder.is_empty() => {
+                    info!("Lease {} held by {} is STALE...", lease_name, holder)
+                }
+                _ => info!("Lease {} NOT OWNED...", lease_name),
             }
+            return Ok(Some(lease_name.clone()));
         }
+        info!("Lease {} is OWNED by {:?}.", lease_name, holder);
     }
-    None
+    Ok(None)
 }
 
+/// Claims the lease for `pod_name`, returning its duration. Returns `None` when another
+/// agent claimed it first.
 pub async fn claim_lease(
-    lease_name: String,
-    pod_name: String,
+    lease_name: &str,
+    pod_name: &str,
     lease_client: &Api<Lease>,
-    return_ref: &mut Option<String>,
-) {
-    let patch_value = LeasePatchValue {
-        holder_identity: Some(pod_name),
-        acquire_time: MicroTime(Utc::now()),
-    };
+) -> Result<Option<Duration>, kube::Error> {
+    let mut lease = lease_client.get(lease_name).await?;
+    let now = Utc::now();
+    if !is_available(&lease, now) {
+        return Ok(None);
+    }
 
-    let patch_spec = LeasePatchSpec { spec: patch_value };
-    let patch = Patch::Merge(patch_spec);
-    let pp = PatchParams::apply(&lease_name);
-    let patch_lease = lease_client.patch(&lease_name, &pp, &patch).await;
-    match patch_lease {
-        Ok(ref patch) => {
-            info!(
-                "Lease {} now owned by {:?}",
-                &lease_name,
-                &patch
-                    .spec
-                    .as_ref()
-                    .unwrap()
-                    .holder_identity
-                    .as_ref()
-                    .unwrap(),
-            );
+    let previous = lease.spec.take().unwrap_or_default();
+    let duration = lease_duration(&previous);
+    let transitions = previous.lease_transitions.unwrap_or(0);
+    lease.spec = Some(LeaseSpec {
+        holder_identity: Some(pod_name.to_string()),
+        lease_duration_seconds: Some(duration.as_secs() as i32),
+        acquire_time: Some(MicroTime(now)),
+        renew_time: Some(MicroTime(now)),
+        lease_transitions: Some(if previous.holder_identity.is_some() {
+            transitions + 1
+        } else {
+            transitions
+        }),
+    });
+
+    // The resource version of the lease makes the replace fail when another agent
+    // claimed it in the meantime
+    match lease_client
+        .replace(lease_name, &PostParams::default(), &lease)
+        .await
+    {
+        Ok(_) => {
+            info!("Lease {} now owned by {}", lease_name, pod_name);
+            Ok(Some(duration))
         }
-        Err(e) => {
-            error!("Issue patching lease: {:?}", e)
+        Err(kube::Error::Api(e)) if e.code == 409 => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Updates the renew time of the lease, returning false when it's no longer held by `pod_name`.
+pub async fn renew_lease(
+    lease_name: &str,
+    pod_name: &str,
+    lease_client: &Api<Lease>,
+) -> Result<bool, kube::Error> {
+    let mut lease = lease_client.get(lease_name).await?;
+    match lease.spec.as_mut() {
+        Some(spec) if spec.holder_identity.as_deref() == Some(pod_name) => {
+            spec.renew_time = Some(MicroTime(Utc::now()));
         }
+        _ => return Ok(false),
+    }
+    match lease_client
+        .replace(lease_name, &PostParams::default(), &lease)
+        .await
+    {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+        Err(e) => Err(e),
     }
-    *return_ref = Some(lease_name);
 }
 
-pub async fn release_lease(lease_name: &str, lease_client: &Api<Lease>) {
+pub async fn release_lease(lease_name: &str, lease_client: &Api<Lease>) -> Result<(), kube::Error> {
     let patch_value = LeasePatchValue {
         holder_identity: None,
         acquire_time: MicroTime(Utc::now()),
+        renew_time: None,
     };
     let patch_spec = LeasePatchSpec { spec: patch_value };
     let patch = Patch::Merge(patch_spec);
     let pp = PatchParams::apply(lease_name);
-    let patch_lease = lease_client.patch(lease_name, &pp, &patch).await;
-    match patch_lease {
-        Ok(ref patch) => {
-            info!(
-                "Lease {} has now been released to {:?}",
-                &lease_name,
-                &patch.spec.as_ref().unwrap().holder_identity
-            );
-        }
-        Err(e) => {
-            error!("Issue releasing lease: {:?}", e)
+    lease_client.patch(lease_name, &pp, &patch).await?;
+    info!("Lease {} has now been released", lease_name);
+    Ok(())
+}
+
+/// A startup lease held by the agent, renewed in the background until released.
+pub struct ClaimedLease {
+    name: String,
+    lease_client: Api<Lease>,
+    renewal: JoinHandle<()>,
+}
+
+impl ClaimedLease {
+    /// Starts renewing a lease claimed with [`claim_lease`], before its duration elapses.
+    pub fn new(
+        name: String,
+        pod_name: String,
+        lease_client: Api<Lease>,
+        duration: Duration,
+    ) -> Self {
+        let renewal = {
+            let name = name.clone();
+            let lease_client = lease_client.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(duration / 3).await;
+                    match renew_lease(&name, &pod_name, &lease_client).await {
+                        Ok(true) => debug!("Lease {} renewed", name),
+                        Ok(false) => {
+                            warn!("Lease {} is no longer owned by {}", name, pod_name);
+                            return;
+                        }
+                        Err(e) => warn!("Unable to renew lease {}: {}", name, e),
+                    }
+                }
+            })
+        };
+        ClaimedLease {
+            name,
+            lease_client,
+            renewal,
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Stops renewing the lease and releases it.
+    pub async fn release(self) -> Result<(), kube::Error> {
+        self.renewal.abort();
+        release_lease(&self.name, &self.lease_client).await
+    }
 }
 
 // TODO: This may not be needed.
@@ -119,6 +250,7 @@ async fn get_lease_list(
 mod tests {
     use super::*;
     use chrono::{TimeZone, Utc};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ManagedFieldsEntry, Time};
 
     #[tokio::test]
     async fn test_leasepatchspec_object_serialize() {
@@ -129,16 +261,69 @@ mod tests {
         let test_leasepatchvalue = LeasePatchValue {
             holder_identity: Some(test_agent),
             acquire_time: MicroTime(test_date),
+            renew_time: None,
         };
 
         let test_leasepatchspec = LeasePatchSpec {
             spec: test_leasepatchvalue,
         };
 
+        let value = serde_json::to_value(&test_leasepatchspec).unwrap();
+        assert!(value["spec"]["acquireTime"].is_string());
+        assert!(value["spec"]["renewTime"].is_null());
+
         assert_eq!(
             test_leasepatchspec.spec.holder_identity.unwrap(),
             test_expected_agent
         );
         assert_eq!(test_leasepatchspec.spec.acquire_time, MicroTime(test_date));
     }
+
+    fn lease(holder: Option<&str>, renewed_secs_ago: Option<i64>) -> Lease {
+        Lease {
+            metadata: ObjectMeta::default(),
+            spec: Some(LeaseSpec {
+                holder_identity: holder.map(String::from),
+                renew_time: renewed_secs_ago
+                    .map(|secs| MicroTime(Utc::now() - chrono::Duration::seconds(secs))),
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn test_is_available() {
+        let now = Utc::now();
+        assert!(is_available(&Lease::default(), now));
+        assert!(is_available(&lease(None, None), now));
+        assert!(is_available(&lease(Some(""), Some(5)), now));
+        assert!(!is_available(&lease(Some("agent-a"), Some(5)), now));
+
+        // Holders that stopped renewing the lease are taken over
+        assert!(is_available(&lease(Some("agent-a"), Some(60)), now));
+
+        // The duration of the lease is honoured
+        let mut held = lease(Some("agent-a"), Some(60));
+        held.spec.as_mut().unwrap().lease_duration_seconds = Some(120);
+        assert!(!is_available(&held, now));
+
+        // Falls back to the acquire time
+        let mut held = lease(Some("agent-a"), None);
+        held.spec.as_mut().unwrap().acquire_time =
+            Some(MicroTime(now - chrono::Duration::seconds(60)));
+        assert!(is_available(&held, now));
+
+        // Then to the last time the lease was written
+        let mut held = lease(Some("agent-a"), None);
+        held.metadata.creation_timestamp = Some(Time(now - chrono::Duration::seconds(300)));
+        assert!(is_available(&held, now));
+        held.metadata.managed_fields = Some(vec![ManagedFieldsEntry {
+            time: Some(Time(now - chrono::Duration::seconds(5))),
+            ..Default::default()
+        }]);
+        assert!(!is_available(&held, now));
+
+        // Leases held without any time are taken over
+        assert!(is_available(&lease(Some("agent-a"), None), now));
+    }
 }
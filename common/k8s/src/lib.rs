@@ -14,8 +14,10 @@ use hyper::http::header;
 
 pub mod errors;
 pub mod event_source;
+pub mod leader;
 pub mod lease;
 pub mod middleware;
+pub mod resource_source;
 pub mod restarting_stream;
 
 /// Manually create the k8s http client so that we can add a user-agent header
@@ -1,6 +1,12 @@
 use crate::errors::K8sError;
-use crate::middleware::parse_container_path;
+use crate::middleware::pending::{PendingLines, PendingPods};
+use crate::middleware::workload::{pod_fields, OwnerStores};
+use crate::middleware::{
+    parse_container_path, NodeLabels, PodConfigCache, PodSelection, SelectionError,
+};
+use config::{K8sSelectionConfig, LogConfig};
 use futures::StreamExt;
+use globber::Pattern;
 use http::types::body::{KeyValueMap, LineBufferMut};
 use k8s_openapi::api::core::v1::Pod;
 use kube::{
@@ -11,9 +17,15 @@ use kube::{
         utils::StreamBackoff,
         watcher::{watcher, Event as WatcherEvent},
     },
-    Api, Client,
+    Api, Client, Resource,
 };
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::path::Path;
 use std::pin::Pin;
+use std::sync::Arc;
 
 use backoff::ExponentialBackoff;
 use metrics::Metrics;
@@ -28,18 +40,53 @@ pub enum Error {
     Utf(#[from] std::string::FromUtf8Error),
     #[error(transparent)]
     K8s(#[from] kube::Error),
+    #[error(transparent)]
+    Selection(#[from] SelectionError),
+    #[error("invalid node label glob {0}: {1}")]
+    Glob(String, globber::Error),
+}
+
+/// Watches a resource of the cluster, returning its store and the future driving it.
+fn reflect<K>(
+    api: Api<K>,
+) -> (
+    reflector::Store<K>,
+    impl futures::Future<Output = ()> + Send,
+)
+where
+    K: Resource + Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+    K::DynamicType: Default + Eq + Hash + Clone + Send + Sync,
+{
+    let writer = reflector::store::Writer::default();
+    let store = writer.as_reader();
+    let watched = StreamBackoff::new(
+        watcher(api, ListParams::default()),
+        ExponentialBackoff::default(),
+    );
+    let driver = reflector(writer, watched).for_each(|r| async move {
+        if let Err(e) = r {
+            log::warn!("k8s watch stream error: {}", e);
+        }
+    });
+    (store, driver)
 }
 
 pub struct K8sMetadata {
     store: reflector::Store<Pod>,
+    owners: Option<OwnerStores>,
+    node_labels: NodeLabels,
+    selection: Arc<PodSelection>,
+    pod_configs: Arc<PodConfigCache>,
+    pending: Arc<PendingPods>,
 }
 
 impl K8sMetadata {
     pub async fn new(
         client: Client,
         node_name: Option<&str>,
+        config: &LogConfig,
     ) -> Result<(Pin<Box<dyn futures::Future<Output = ()> + Send>>, Self), Error> {
-        let api = Api::<Pod>::all(client);
+        let api = Api::<Pod>::all(client.clone());
 
         let store_writer = reflector::store::Writer::default();
         let store = store_writer.as_reader();
@@ -53,47 +100,142 @@ impl K8sMetadata {
         let watched = StreamBackoff::new(watcher(api, params), ExponentialBackoff::default());
 
         let reflector = reflector(store_writer, watched);
+        let pod_configs = Arc::new(PodConfigCache::new(config.redact_hash_key.clone()));
+        let (pending, fetcher) = PendingPods::new(client.clone(), config.k8s_metadata_wait);
 
         let driver = {
             let store = store.clone();
+            let pod_configs = pod_configs.clone();
+            let pending = pending.clone();
             async move {
-                reflector
-                    .filter_map(|r| async {
-                        match r {
+                // The events are handled synchronously, borrowing in async blocks would keep
+                // the driver from being Send
+                let pods = reflector
+                    .filter_map(|r| {
+                        futures::future::ready(match r {
                             Ok(event) => Some(event),
                             Err(e) => {
                                 log::warn!("k8s watch stream error: {}", e);
                                 None
                             }
-                        }
+                        })
                     })
-                    .for_each(|p| async {
-                        K8sMetadata::handle_pod(&store, p)
+                    .for_each(|p| {
+                        K8sMetadata::handle_pod(&store, &pod_configs, &pending, p)
                             .unwrap_or_else(|e| log::warn!("unable to process pod event: {}", e));
-                    })
-                    .await
+                        futures::future::ready(())
+                    });
+                futures::join!(pods, fetcher);
             }
         };
 
-        Ok((Box::pin(driver), K8sMetadata { store }))
+        let node_labels = NodeLabels::new(
+            config
+                .k8s_node_labels
+                .iter()
+                .map(|l| Pattern::new(l).map_err(|e| Error::Glob(l.clone(), e)))
+                .collect::<Result<_, _>>()?,
+        );
+        let driver: Pin<Box<dyn futures::Future<Output = ()> + Send>> =
+            match node_name.filter(|_| !config.k8s_node_labels.is_empty()) {
+                Some(node) => {
+                    let node_driver = node_labels.watch(client.clone(), node);
+                    Box::pin(async move {
+                        futures::join!(driver, node_driver);
+                    })
+                }
+                None => Box::pin(driver),
+            };
+
+        // The owners can't be filtered by node, they are only watched when enabled
+        let (driver, owners): (Pin<Box<dyn futures::Future<Output = ()> + Send>>, _) =
+            if config.k8s_workload_enrichment {
+                let (replica_sets, replica_sets_driver) = reflect(Api::all(client.clone()));
+                let (jobs, jobs_driver) = reflect(Api::all(client));
+                (
+                    Box::pin(async move {
+                        futures::join!(driver, replica_sets_driver, jobs_driver);
+                    }),
+                    Some(OwnerStores { replica_sets, jobs }),
+                )
+            } else {
+                (driver, None)
+            };
+
+        Ok((
+            driver,
+            K8sMetadata {
+                store,
+                owners,
+                node_labels,
+                selection: Arc::new(PodSelection::default()),
+                pod_configs,
+                pending,
+            },
+        ))
+    }
+
+    /// Sets the rules selecting the pods to collect the logs from.
+    pub fn with_selection(mut self, config: &K8sSelectionConfig) -> Result<Self, Error> {
+        self.selection = Arc::new(PodSelection::new(config)?);
+        Ok(self)
+    }
+
+    /// Returns the stage holding the first lines of the new pods until they are known, it
+    /// runs on the line stream before the middlewares.
+    pub fn pending_lines(&self) -> PendingLines {
+        PendingLines {
+            store: self.store.clone(),
+            pending: self.pending.clone(),
+            selection: self.selection.clone(),
+        }
+    }
+
+    /// Returns a function matching the container log files of the excluded pods, used to
+    /// avoid tailing them. The lines of pods that were not known when the file was found
+    /// are dropped by the middleware instead.
+    pub fn excluded_path_filter(&self) -> impl Fn(&Path) -> bool + Send + Sync + 'static {
+        let store = self.store.clone();
+        let selection = self.selection.clone();
+        move |path| {
+            path.to_str()
+                .and_then(parse_container_path)
+                .map_or(false, |p| {
+                    let obj_ref = ObjectRef::new(&p.pod_name).within(&p.pod_namespace);
+                    selection.excludes(
+                        &p.pod_namespace,
+                        &p.container_name,
+                        store.get(&obj_ref).as_deref(),
+                    )
+                })
+        }
     }
 
     fn handle_pod(
         reader: &reflector::Store<Pod>,
+        pod_configs: &PodConfigCache,
+        pending: &PendingPods,
         event: WatcherEvent<Pod>,
     ) -> Result<(), K8sError> {
         match event {
             WatcherEvent::Applied(pod) => {
                 let obj_ref = ObjectRef::from_obj(&pod);
+                // The store has the pod from now on
+                pending.remove(&obj_ref);
                 if reader.get(&obj_ref).is_none() {
                     Metrics::k8s().increment_creates();
                 }
             }
-            WatcherEvent::Deleted(_) => {
+            WatcherEvent::Deleted(pod) => {
+                let obj_ref = ObjectRef::from_obj(&pod);
+                pending.remove(&obj_ref);
+                pod_configs.remove(&obj_ref);
                 Metrics::k8s().increment_deletes();
             }
             WatcherEvent::Restarted(pods) => {
-                for _ in pods {
+                pod_configs.retain(&pods);
+                for pod in pods {
+                    pending.remove(&ObjectRef::from_obj(&pod));
                     Metrics::k8s().increment_creates();
                 }
             }
@@ -105,13 +247,50 @@ impl K8sMetadata {
 impl Middleware for K8sMetadata {
     fn run(&self) {}
 
-    #[allow(clippy::question_mark)]
     fn process<'a>(&self, line: &'a mut dyn LineBufferMut) -> Status<&'a mut dyn LineBufferMut> {
+        let line = match self.process_container_line(line) {
+            Status::Ok(line) => line,
+            Status::Skip => return Status::Skip,
+        };
+        // All the lines of the node get its labels, including the host files and journald
+        if self.node_labels.apply(line).is_err() {
+            return Status::Skip;
+        }
+        Status::Ok(line)
+    }
+}
+
+impl K8sMetadata {
+    #[allow(clippy::question_mark)]
+    fn process_container_line<'a>(
+        &self,
+        line: &'a mut dyn LineBufferMut,
+    ) -> Status<&'a mut dyn LineBufferMut> {
         if let Some(file_name) = line.get_file() {
             if let Some(parse_result) = parse_container_path(file_name) {
                 let obj_ref =
                     ObjectRef::new(&parse_result.pod_name).within(&parse_result.pod_namespace);
-                if let Some(pod) = self.store.get(&obj_ref) {
+                // Avoid waiting for the pods that are excluded by their namespace or container
+                if self.selection.excludes(
+                    &parse_result.pod_namespace,
+                    &parse_result.container_name,
+                    None,
+                ) {
+                    return Status::Skip;
+                }
+                // The first lines of a new pod are often read before the watch receives it
+                let pod = self
+                    .store
+                    .get(&obj_ref)
+                    .or_else(|| self.pending.fetched(&obj_ref));
+                if self.selection.excludes(
+                    &parse_result.pod_namespace,
+                    &parse_result.container_name,
+                    pod.as_deref(),
+                ) {
+                    return Status::Skip;
+                }
+                if let Some(pod) = pod {
                     if let Some(ref annotations) = pod.metadata.annotations {
                         if line
                             .set_annotations(
@@ -136,6 +315,18 @@ impl Middleware for K8sMetadata {
                             return Status::Skip;
                         };
                     }
+                    let fields =
+                        pod_fields(&pod, &parse_result.container_name, self.owners.as_ref());
+                    if !fields.is_empty() && set_k8s_meta(line, fields).is_err() {
+                        return Status::Skip;
+                    }
+                    // The pod annotations take precedence over the agent defaults, the
+                    // meta overrides and line rules run afterwards
+                    if let Some(config) = self.pod_configs.get(&pod) {
+                        return config.apply(line);
+                    }
+                } else {
+                    Metrics::k8s().increment_unenriched_lines();
                 }
             }
         }
@@ -143,6 +334,26 @@ impl Middleware for K8sMetadata {
     }
 }
 
+/// Adds the fields to the "k8s" object of the line meta.
+fn set_k8s_meta(
+    line: &mut dyn LineBufferMut,
+    fields: Map<String, Value>,
+) -> Result<(), http::types::error::LineMetaError> {
+    let mut meta = match line.get_meta() {
+        None => Map::new(),
+        Some(Value::Object(existing)) => existing.clone(),
+        // Meta that is not an object can't hold the fields
+        Some(_) => return Ok(()),
+    };
+    match meta.get_mut("k8s") {
+        Some(Value::Object(existing)) => existing.extend(fields),
+        _ => {
+            meta.insert("k8s".to_string(), Value::Object(fields));
+        }
+    }
+    line.set_meta(Value::Object(meta))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -238,6 +449,33 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_process_skips_excluded_pods() {
+        let excluded_file = "/var/log/containers/first_excluded_sample-f39155eb652f5161f4a34b1fbd89a4d361e76ccb6c3cdc0e2c18e0d0abb26516.log";
+        let included_file = "/var/log/containers/second_included_sample-f39155eb652f5161f4a34b1fbd89a4d361e76ccb6c3cdc0e2c18e0d0abb26516.log";
+        let mut annotated = get_pod_metadata("first", "excluded");
+        annotated
+            .annotations
+            .insert("logdna.com/exclude".to_string(), "true".to_string());
+        let k8s_meta = get_instance(vec![annotated, get_pod_metadata("second", "included")])
+            .with_selection(&K8sSelectionConfig {
+                exclude_containers: vec!["istio-proxy".to_string()],
+                ..Default::default()
+            })
+            .unwrap();
+
+        let mut line = LineBuilder::new().line("abc").file(excluded_file);
+        assert!(matches!(k8s_meta.process(&mut line), Status::Skip));
+        let mut line = LineBuilder::new().line("abc").file(included_file);
+        assert!(matches!(k8s_meta.process(&mut line), Status::Ok(_)));
+
+        let filter = k8s_meta.excluded_path_filter();
+        assert!(filter(Path::new(excluded_file)));
+        assert!(!filter(Path::new(included_file)));
+        assert!(filter(Path::new("/var/log/containers/unknown_pod_istio-proxy-f39155eb652f5161f4a34b1fbd89a4d361e76ccb6c3cdc0e2c18e0d0abb26516.log")));
+        assert!(!filter(Path::new("/var/log/syslog")));
+    }
+
     fn get_instance(pods: Vec<PodMetadata>) -> K8sMetadata {
         let mut store_w = reflector::store::Writer::default();
         for meta in pods.into_iter() {
@@ -263,6 +501,11 @@ mod tests {
         }
         K8sMetadata {
             store: store_w.as_reader(),
+            owners: None,
+            node_labels: NodeLabels::default(),
+            selection: Arc::new(PodSelection::default()),
+            pod_configs: Arc::new(PodConfigCache::default()),
+            pending: Arc::new(PendingPods::default()),
         }
     }
 
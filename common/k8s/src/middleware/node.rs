@@ -0,0 +1,152 @@
+use backoff::ExponentialBackoff;
+use futures::StreamExt;
+use globber::Pattern;
+use http::types::body::{KeyValueMap, LineBufferMut};
+use http::types::error::LineMetaError;
+use k8s_openapi::api::core::v1::Node;
+use kube::api::ListParams;
+use kube::runtime::utils::StreamBackoff;
+use kube::runtime::watcher::{watcher, Event as WatcherEvent};
+use kube::{Api, Client};
+use parking_lot::RwLock;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Keeps the selected labels of the agent node, up to date with the node changes.
+#[derive(Clone, Default)]
+pub struct NodeLabels {
+    patterns: Arc<Vec<Pattern>>,
+    labels: Arc<RwLock<BTreeMap<String, String>>>,
+}
+
+impl NodeLabels {
+    pub fn new(patterns: Vec<Pattern>) -> Self {
+        NodeLabels {
+            patterns: Arc::new(patterns),
+            labels: Default::default(),
+        }
+    }
+
+    /// Watches the node, returning the future driving the watch.
+    pub fn watch(
+        &self,
+        client: Client,
+        node_name: &str,
+    ) -> impl futures::Future<Output = ()> + Send + 'static {
+        let api = Api::<Node>::all(client);
+        let params = ListParams::default().fields(&format!("metadata.name={}", node_name));
+        let watched = StreamBackoff::new(watcher(api, params), ExponentialBackoff::default());
+        let node_labels = self.clone();
+        watched.for_each(move |event| {
+            match event {
+                Ok(WatcherEvent::Applied(node)) => node_labels.update(Some(&node)),
+                Ok(WatcherEvent::Restarted(nodes)) => node_labels.update(nodes.first()),
+                Ok(WatcherEvent::Deleted(_)) => node_labels.update(None),
+                Err(e) => log::warn!("k8s node watch stream error: {}", e),
+            }
+            futures::future::ready(())
+        })
+    }
+
+    fn update(&self, node: Option<&Node>) {
+        let labels = node
+            .and_then(|n| n.metadata.labels.as_ref())
+            .map(|labels| {
+                labels
+                    .iter()
+                    .filter(|(k, _)| self.patterns.iter().any(|p| p.matches(k)))
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut current = self.labels.write();
+        if *current != labels {
+            info!("node labels updated: {:?}", labels);
+            *current = labels;
+        }
+    }
+
+    /// Adds the node labels to the line, the labels already set (i.e. by the pod) are kept.
+    pub(crate) fn apply(&self, line: &mut dyn LineBufferMut) -> Result<(), LineMetaError> {
+        let node_labels = self.labels.read();
+        if node_labels.is_empty() {
+            return Ok(());
+        }
+
+        let mut labels = KeyValueMap::new();
+        for (k, v) in node_labels.iter() {
+            labels.insert(k.clone(), v.clone());
+        }
+        if let Some(existing) = line.get_labels() {
+            for (k, v) in existing.iter() {
+                labels.insert(k.clone(), v.clone());
+            }
+        }
+        line.set_labels(labels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::types::body::LineBuilder;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    fn node(labels: &[(&str, &str)]) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some("node-1".to_string()),
+                labels: Some(
+                    labels
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                ),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_node_labels() {
+        let node_labels = NodeLabels::new(vec![
+            Pattern::new("topology.kubernetes.io/*").unwrap(),
+            Pattern::new("node.kubernetes.io/instance-type").unwrap(),
+        ]);
+        node_labels.update(Some(&node(&[
+            ("topology.kubernetes.io/zone", "us-east-1a"),
+            ("node.kubernetes.io/instance-type", "m5.large"),
+            ("kubernetes.io/hostname", "node-1"),
+        ])));
+
+        let mut line = LineBuilder::new()
+            .line("abc")
+            .labels(KeyValueMap::new().add("node.kubernetes.io/instance-type", "pod"));
+        node_labels.apply(&mut line).unwrap();
+        assert_eq!(
+            line.labels.unwrap(),
+            KeyValueMap::new()
+                .add("node.kubernetes.io/instance-type", "pod")
+                .add("topology.kubernetes.io/zone", "us-east-1a")
+        );
+
+        // The labels follow the node changes
+        node_labels.update(Some(&node(&[(
+            "topology.kubernetes.io/zone",
+            "us-east-1b",
+        )])));
+        let mut line = LineBuilder::new().line("abc");
+        node_labels.apply(&mut line).unwrap();
+        assert_eq!(
+            line.labels.unwrap(),
+            KeyValueMap::new().add("topology.kubernetes.io/zone", "us-east-1b")
+        );
+
+        node_labels.update(None);
+        let mut line = LineBuilder::new().line("abc");
+        node_labels.apply(&mut line).unwrap();
+        assert!(line.labels.is_none());
+    }
+}
@@ -0,0 +1,422 @@
+use crate::middleware::{parse_container_path, PodSelection};
+use futures::channel::mpsc;
+use futures::{Future, Stream, StreamExt};
+use http::types::body::LineBufferMut;
+use k8s_openapi::api::core::v1::Pod;
+use kube::runtime::reflector::{ObjectRef, Store};
+use kube::{Api, Client};
+use parking_lot::Mutex;
+use pin_project_lite::pin_project;
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::Sleep;
+
+/// Maximum number of unknown pods waited for at the same time
+const MAX_PENDING_PODS: usize = 256;
+/// Maximum number of lines held while waiting for their pods
+const MAX_HELD_LINES: usize = 4096;
+/// Maximum number of pods fetched from the API at the same time
+const MAX_CONCURRENT_FETCHES: usize = 8;
+/// The store doesn't notify its updates, it's checked at this interval while lines are held
+const STORE_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Default)]
+struct State {
+    /// Time until which the lines of each unknown pod are held
+    deadlines: HashMap<ObjectRef<Pod>, Instant>,
+    /// Pods fetched from the API that didn't reach the store yet
+    fetched: HashMap<ObjectRef<Pod>, Arc<Pod>>,
+}
+
+/// The pods that are not in the store yet, fetched from the API while their lines are held
+/// for a limited time by [`PendingLines`].
+#[derive(Default)]
+pub(crate) struct PendingPods {
+    wait: Duration,
+    requests: Option<mpsc::UnboundedSender<ObjectRef<Pod>>>,
+    state: Mutex<State>,
+}
+
+impl PendingPods {
+    /// Creates the pending pods, returning the future fetching the requested pods.
+    pub(crate) fn new(
+        client: Client,
+        wait: Duration,
+    ) -> (Arc<Self>, impl Future<Output = ()> + Send + 'static) {
+        let (sender, receiver) = mpsc::unbounded();
+        let pending = Arc::new(PendingPods {
+            wait,
+            requests: Some(sender),
+            state: Default::default(),
+        });
+
+        let fetcher = {
+            let pending = pending.clone();
+            receiver.for_each_concurrent(MAX_CONCURRENT_FETCHES, move |obj_ref| {
+                let pending = pending.clone();
+                let namespace = obj_ref.namespace.clone().unwrap_or_default();
+                let api = Api::<Pod>::namespaced(client.clone(), &namespace);
+                async move {
+                    let pod = match api.get(&obj_ref.name).await {
+                        Ok(pod) => Some(pod),
+                        Err(e) => {
+                            debug!("unable to fetch pod {}/{}: {}", namespace, obj_ref.name, e);
+                            None
+                        }
+                    };
+                    pending.arrive(obj_ref, pod);
+                }
+            })
+        };
+        (pending, fetcher)
+    }
+
+    /// Starts fetching the pod, returns false when its lines can't be held. Only the first
+    /// lines of a pod are held, a pod is requested once.
+    fn request(&self, obj_ref: &ObjectRef<Pod>) -> bool {
+        let requests = match self.requests.as_ref().filter(|_| !self.wait.is_zero()) {
+            Some(requests) => requests,
+            None => return false,
+        };
+        let mut state = self.state.lock();
+        if state.deadlines.contains_key(obj_ref) || state.fetched.contains_key(obj_ref) {
+            return false;
+        }
+        if state.deadlines.len() >= MAX_PENDING_PODS {
+            let now = Instant::now();
+            state.deadlines.retain(|_, deadline| *deadline > now);
+            if state.deadlines.len() >= MAX_PENDING_PODS {
+                return false;
+            }
+        }
+        if requests.unbounded_send(obj_ref.clone()).is_err() {
+            return false;
+        }
+        state
+            .deadlines
+            .insert(obj_ref.clone(), Instant::now() + self.wait);
+        true
+    }
+
+    /// Returns true once the lines of the pod can be released, when the pod is known, it
+    /// couldn't be fetched or its deadline passed.
+    fn is_resolved(&self, obj_ref: &ObjectRef<Pod>, store: &Store<Pod>) -> bool {
+        if store.get(obj_ref).is_some() {
+            return true;
+        }
+        let state = self.state.lock();
+        state.fetched.contains_key(obj_ref)
+            || state
+                .deadlines
+                .get(obj_ref)
+                .map_or(true, |deadline| *deadline <= Instant::now())
+    }
+
+    /// Returns the pod fetched from the API, until the store has it.
+    pub(crate) fn fetched(&self, obj_ref: &ObjectRef<Pod>) -> Option<Arc<Pod>> {
+        self.state.lock().fetched.get(obj_ref).cloned()
+    }
+
+    /// Releases the lines held for the pod, `None` when it couldn't be fetched.
+    fn arrive(&self, obj_ref: ObjectRef<Pod>, pod: Option<Pod>) {
+        let mut state = self.state.lock();
+        match pod {
+            Some(pod) => {
+                state.fetched.insert(obj_ref, Arc::new(pod));
+            }
+            None => {
+                state.deadlines.insert(obj_ref, Instant::now());
+            }
+        }
+    }
+
+    /// Forgets the pod, used once the store has it or it was deleted.
+    pub(crate) fn remove(&self, obj_ref: &ObjectRef<Pod>) {
+        let mut state = self.state.lock();
+        state.deadlines.remove(obj_ref);
+        state.fetched.remove(obj_ref);
+    }
+}
+
+/// Holds the first lines of the pods that are not known yet, see [`PendingLines::hold`].
+#[derive(Clone)]
+pub struct PendingLines {
+    pub(crate) store: Store<Pod>,
+    pub(crate) pending: Arc<PendingPods>,
+    pub(crate) selection: Arc<PodSelection>,
+}
+
+impl PendingLines {
+    /// Holds the lines of each new pod in the stream, for a limited time, until the pod is in
+    /// the store or fetched from the API. The lines of the other pods and sources go through
+    /// without waiting. `line` returns the line buffer of an item of the stream.
+    pub fn hold<S, T, F>(self, stream: S, line: F) -> HeldLines<S, T, F>
+    where
+        S: Stream<Item = T>,
+        F: FnMut(&mut T) -> &mut dyn LineBufferMut,
+    {
+        HeldLines {
+            stream,
+            line,
+            pending: self,
+            held: HashMap::new(),
+            held_count: 0,
+            released: VecDeque::new(),
+            poll_interval: None,
+            done: false,
+        }
+    }
+
+    /// Returns the pod of the line when the line is from a selected container.
+    fn pod_ref(&self, line: &dyn LineBufferMut) -> Option<ObjectRef<Pod>> {
+        let parsed = line.get_file().and_then(parse_container_path)?;
+        if self
+            .selection
+            .excludes(&parsed.pod_namespace, &parsed.container_name, None)
+        {
+            return None;
+        }
+        Some(ObjectRef::new(&parsed.pod_name).within(&parsed.pod_namespace))
+    }
+}
+
+pin_project! {
+    #[must_use = "streams do nothing unless polled"]
+    pub struct HeldLines<S, T, F> {
+        #[pin]
+        stream: S,
+        line: F,
+        pending: PendingLines,
+        held: HashMap<ObjectRef<Pod>, VecDeque<T>>,
+        held_count: usize,
+        released: VecDeque<T>,
+        poll_interval: Option<Pin<Box<Sleep>>>,
+        done: bool,
+    }
+}
+
+impl<S, T, F> Stream for HeldLines<S, T, F>
+where
+    S: Stream<Item = T>,
+    F: FnMut(&mut T) -> &mut dyn LineBufferMut,
+{
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut this = self.project();
+        loop {
+            if let Some(line) = this.released.pop_front() {
+                return Poll::Ready(Some(line));
+            }
+
+            if !this.held.is_empty() {
+                let interval = this
+                    .poll_interval
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep(STORE_POLL_INTERVAL)));
+                if interval.as_mut().poll(cx).is_ready() {
+                    *this.poll_interval = None;
+                    let pending = &this.pending;
+                    let resolved = this
+                        .held
+                        .keys()
+                        .filter(|obj_ref| pending.pending.is_resolved(obj_ref, &pending.store))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    for obj_ref in resolved {
+                        if let Some(lines) = this.held.remove(&obj_ref) {
+                            *this.held_count -= lines.len();
+                            this.released.extend(lines);
+                        }
+                    }
+                    continue;
+                }
+            }
+
+            if *this.done {
+                return Poll::Ready(None);
+            }
+
+            let mut line = match this.stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(line)) => line,
+                Poll::Ready(None) => {
+                    *this.done = true;
+                    for (_, lines) in this.held.drain() {
+                        this.released.extend(lines);
+                    }
+                    *this.held_count = 0;
+                    continue;
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+
+            let buffer = (this.line)(&mut line);
+            let obj_ref = match this.pending.pod_ref(buffer) {
+                Some(obj_ref) => obj_ref,
+                None => {
+                    this.released.push_back(line);
+                    continue;
+                }
+            };
+            let full = *this.held_count >= MAX_HELD_LINES;
+            if let Some(mut lines) = this.held.remove(&obj_ref) {
+                if full {
+                    // The pod is released early, its next lines are not held again
+                    *this.held_count -= lines.len();
+                    lines.push_back(line);
+                    this.released.extend(lines);
+                } else {
+                    // The file is read further while the line is held, its buffer is kept
+                    buffer.get_line_buffer();
+                    lines.push_back(line);
+                    *this.held_count += 1;
+                    this.held.insert(obj_ref, lines);
+                }
+            } else if !full
+                && this.pending.store.get(&obj_ref).is_none()
+                && this.pending.pending.request(&obj_ref)
+            {
+                buffer.get_line_buffer();
+                *this.held_count += 1;
+                this.held.insert(obj_ref, VecDeque::from(vec![line]));
+            } else {
+                this.released.push_back(line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::types::body::LineBuilder;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use kube::runtime::reflector::store::Writer;
+    use kube::runtime::watcher::Event;
+
+    const POD_FILE: &str = "/var/log/containers/pod_default_app-\
+        0123456789012345678901234567890123456789012345678901234567890123.log";
+
+    fn pod(name: &str) -> Pod {
+        Pod {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                namespace: Some("default".to_string()),
+                ..Default::default()
+            },
+            spec: None,
+            status: None,
+        }
+    }
+
+    fn pending_lines(
+        wait: Duration,
+        store: Store<Pod>,
+    ) -> (PendingLines, mpsc::UnboundedReceiver<ObjectRef<Pod>>) {
+        let (sender, receiver) = mpsc::unbounded();
+        let pending = Arc::new(PendingPods {
+            wait,
+            requests: Some(sender),
+            ..Default::default()
+        });
+        let lines = PendingLines {
+            store,
+            pending,
+            selection: Arc::new(PodSelection::default()),
+        };
+        (lines, receiver)
+    }
+
+    fn line_buffer(line: &mut LineBuilder) -> &mut dyn LineBufferMut {
+        line
+    }
+
+    fn line(file: &str, text: &str) -> LineBuilder {
+        LineBuilder::new().file(file).line(text)
+    }
+
+    async fn next_line<S: Stream<Item = LineBuilder> + Unpin>(lines: &mut S) -> Option<String> {
+        tokio::time::timeout(Duration::from_secs(5), lines.next())
+            .await
+            .expect("no line released")
+            .and_then(|line| line.line)
+    }
+
+    #[tokio::test]
+    async fn test_holds_lines_until_fetched() {
+        let store = Writer::<Pod>::default().as_reader();
+        let (pending, mut requests) = pending_lines(Duration::from_secs(30), store);
+        let pods = pending.pending.clone();
+        let (sender, receiver) = mpsc::unbounded();
+        let mut lines = pending.hold(receiver, line_buffer);
+
+        sender.unbounded_send(line(POD_FILE, "first")).unwrap();
+        sender
+            .unbounded_send(line("/var/log/syslog", "other"))
+            .unwrap();
+        sender.unbounded_send(line(POD_FILE, "second")).unwrap();
+
+        // The lines of the other files are not delayed
+        assert_eq!(next_line(&mut lines).await.as_deref(), Some("other"));
+        let requested = requests.next().await.unwrap();
+        assert_eq!(requested, ObjectRef::new("pod").within("default"));
+
+        pods.arrive(requested.clone(), Some(pod("pod")));
+        assert_eq!(next_line(&mut lines).await.as_deref(), Some("first"));
+        assert_eq!(next_line(&mut lines).await.as_deref(), Some("second"));
+        assert!(pods.fetched(&requested).is_some());
+
+        pods.remove(&requested);
+        assert!(pods.state.lock().fetched.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_holds_lines_until_stored() {
+        let mut writer = Writer::<Pod>::default();
+        let (pending, _requests) = pending_lines(Duration::from_secs(30), writer.as_reader());
+        let (sender, receiver) = mpsc::unbounded();
+        let mut lines = pending.hold(receiver, line_buffer);
+
+        sender.unbounded_send(line(POD_FILE, "first")).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        writer.apply_watcher_event(&Event::Applied(pod("pod")));
+        assert_eq!(next_line(&mut lines).await.as_deref(), Some("first"));
+    }
+
+    #[tokio::test]
+    async fn test_releases_lines_after_deadline() {
+        let store = Writer::<Pod>::default().as_reader();
+        let (pending, _requests) = pending_lines(Duration::from_millis(50), store.clone());
+        let (sender, receiver) = mpsc::unbounded();
+        let mut lines = pending.hold(receiver, line_buffer);
+
+        sender.unbounded_send(line(POD_FILE, "first")).unwrap();
+        let started = Instant::now();
+        assert_eq!(next_line(&mut lines).await.as_deref(), Some("first"));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        // The next lines of the pod are not held again
+        sender.unbounded_send(line(POD_FILE, "second")).unwrap();
+        let started = Instant::now();
+        assert_eq!(next_line(&mut lines).await.as_deref(), Some("second"));
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        // The held lines are released when the stream ends
+        let (pending, _requests) = pending_lines(Duration::from_secs(30), store.clone());
+        let mut lines = pending.hold(
+            futures::stream::iter(vec![line(POD_FILE, "first")]),
+            line_buffer,
+        );
+        assert_eq!(next_line(&mut lines).await.as_deref(), Some("first"));
+        assert!(lines.next().await.is_none());
+
+        // Nothing is held when waiting is disabled
+        let (pending, _requests) = pending_lines(Duration::from_secs(0), store);
+        let (sender, receiver) = mpsc::unbounded();
+        let mut lines = pending.hold(receiver, line_buffer);
+        sender.unbounded_send(line(POD_FILE, "first")).unwrap();
+        assert_eq!(next_line(&mut lines).await.as_deref(), Some("first"));
+    }
+}
@@ -0,0 +1,303 @@
+use config::RedactRule;
+use http::types::body::LineBufferMut;
+use k8s_openapi::api::core::v1::Pod;
+use kube::runtime::reflector::ObjectRef;
+use middleware::redact::Redactor;
+use middleware::Status;
+use parking_lot::Mutex;
+use regex::bytes::Regex;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+/// Overrides the app of the lines
+pub const APP_ANNOTATION: &str = "logdna.com/app";
+/// Parses the lines with the given format, only `json` is supported
+pub const PARSER_ANNOTATION: &str = "logdna.com/parser";
+/// Comma separated redaction detectors, e.g. `email,credit_card:partial`
+pub const REDACT_ANNOTATION: &str = "logdna.com/redact";
+
+/// The annotations the settings of a pod are built from
+const ANNOTATIONS: [&str; 3] = [APP_ANNOTATION, PARSER_ANNOTATION, REDACT_ANNOTATION];
+
+lazy_static! {
+    static ref REGEX_CRI_PREFIX: Regex =
+        Regex::new(r"^[0-9]{4}-[0-9]{2}-[0-9]{2}T\S+ (?:stdout|stderr) [PF] ").unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Parser {
+    Json,
+}
+
+/// Per pod settings read from the pod annotations.
+pub struct PodConfig {
+    app: Option<String>,
+    parser: Option<Parser>,
+    redactors: Vec<Redactor>,
+}
+
+impl PodConfig {
+    /// Builds the settings of a pod, invalid annotation values are logged and ignored.
+    fn new(name: &str, annotations: &BTreeMap<String, String>, hash_key: Option<&str>) -> Self {
+        let app = annotations
+            .get(APP_ANNOTATION)
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .map(String::from);
+
+        let parser = annotations.get(PARSER_ANNOTATION).and_then(|v| {
+            match v.trim().to_lowercase().as_str() {
+                "json" => Some(Parser::Json),
+                other => {
+                    warn!(
+                        "pod {} has an unknown {}: {}",
+                        name, PARSER_ANNOTATION, other
+                    );
+                    None
+                }
+            }
+        });
+
+        let redactors = annotations
+            .get(REDACT_ANNOTATION)
+            .map(|v| {
+                v.split(',')
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty())
+                    .filter_map(|v| {
+                        let redactor = v
+                            .parse::<RedactRule>()
+                            .map_err(|e| e.to_string())
+                            .and_then(|r| Redactor::new(&r, hash_key).map_err(|e| e.to_string()));
+                        match redactor {
+                            Ok(r) => Some(r),
+                            Err(e) => {
+                                warn!("pod {} has an invalid {}: {}", name, REDACT_ANNOTATION, e);
+                                None
+                            }
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        PodConfig {
+            app,
+            parser,
+            redactors,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.app.is_none() && self.parser.is_none() && self.redactors.is_empty()
+    }
+
+    pub(crate) fn apply<'a>(
+        &self,
+        line: &'a mut dyn LineBufferMut,
+    ) -> Status<&'a mut dyn LineBufferMut> {
+        if let Some(app) = &self.app {
+            if line.set_app(app.clone()).is_err() {
+                return Status::Skip;
+            }
+        }
+
+        if !self.redactors.is_empty() {
+            let mut value = match line.get_line_buffer() {
+                Some(v) => v.to_vec(),
+                None => return Status::Skip,
+            };
+            let mut changed = false;
+            for redactor in self.redactors.iter() {
+                if let Some(redacted) = redactor.redact(&value) {
+                    value = redacted;
+                    changed = true;
+                }
+            }
+            if changed && line.set_line_buffer(value).is_err() {
+                return Status::Skip;
+            }
+        }
+
+        if self.parser == Some(Parser::Json) {
+            if let Some(Value::Object(fields)) = line.get_line_buffer().and_then(parse_json) {
+                let mut meta = match line.get_meta() {
+                    Some(Value::Object(existing)) => existing.clone(),
+                    _ => Default::default(),
+                };
+                meta.extend(fields);
+                if line.set_meta(Value::Object(meta)).is_err() {
+                    return Status::Skip;
+                }
+            }
+        }
+
+        Status::Ok(line)
+    }
+}
+
+/// Parses the message of a container line, skipping the CRI prefix and unwrapping the
+/// docker json-file format.
+fn parse_json(line: &[u8]) -> Option<Value> {
+    let message = match REGEX_CRI_PREFIX.find(line) {
+        Some(m) => &line[m.end()..],
+        None => line,
+    };
+    let value: Value = serde_json::from_slice(message).ok()?;
+    match &value["log"] {
+        Value::String(log) if value.get("stream").is_some() => serde_json::from_str(log).ok(),
+        _ => Some(value),
+    }
+}
+
+/// Caches the settings of the pods until their annotations change.
+#[derive(Default)]
+pub struct PodConfigCache {
+    hash_key: Option<String>,
+    entries: Mutex<HashMap<ObjectRef<Pod>, CachedConfig>>,
+}
+
+/// The settings of a pod with the hash of the annotations they were built from
+type CachedConfig = (u64, Arc<PodConfig>);
+
+/// Hashes the annotations read by `PodConfig`, the resource version can't be used as it
+/// changes on every status update of the pod.
+fn hash_annotations(annotations: &BTreeMap<String, String>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for name in ANNOTATIONS.iter() {
+        annotations.get(*name).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+impl PodConfigCache {
+    pub fn new(hash_key: Option<String>) -> Self {
+        PodConfigCache {
+            hash_key,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the settings of the pod, `None` when it has none.
+    pub fn get(&self, pod: &Pod) -> Option<Arc<PodConfig>> {
+        let annotations = pod.metadata.annotations.as_ref()?;
+        let obj_ref = ObjectRef::from_obj(pod);
+        let hash = hash_annotations(annotations);
+
+        let mut entries = self.entries.lock();
+        if let Some((cached_hash, config)) = entries.get(&obj_ref) {
+            if *cached_hash == hash {
+                return Some(config.clone()).filter(|c| !c.is_empty());
+            }
+        }
+
+        let config = Arc::new(PodConfig::new(
+            &obj_ref.name,
+            annotations,
+            self.hash_key.as_deref(),
+        ));
+        entries.insert(obj_ref, (hash, config.clone()));
+        Some(config).filter(|c| !c.is_empty())
+    }
+
+    pub fn remove(&self, obj_ref: &ObjectRef<Pod>) {
+        self.entries.lock().remove(obj_ref);
+    }
+
+    /// Drops the entries of the pods that are not in the list, used when the watch restarts.
+    pub fn retain(&self, pods: &[Pod]) {
+        let refs = pods.iter().map(ObjectRef::from_obj).collect::<Vec<_>>();
+        self.entries.lock().retain(|k, _| refs.contains(k));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::types::body::LineBuilder;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use serde_json::json;
+
+    fn pod(version: &str, annotations: &[(&str, &str)]) -> Pod {
+        Pod {
+            metadata: ObjectMeta {
+                name: Some("pod".to_string()),
+                namespace: Some("default".to_string()),
+                resource_version: Some(version.to_string()),
+                annotations: Some(
+                    annotations
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                ),
+                ..Default::default()
+            },
+            spec: None,
+            status: None,
+        }
+    }
+
+    fn apply(config: &PodConfig, line: &str) -> LineBuilder {
+        let mut line = LineBuilder::new().line(line).app("original");
+        assert!(matches!(config.apply(&mut line), Status::Ok(_)));
+        line
+    }
+
+    #[test]
+    fn test_apply_annotations() {
+        let cache = PodConfigCache::default();
+        let config = cache
+            .get(&pod(
+                "1",
+                &[
+                    (APP_ANNOTATION, "checkout"),
+                    (PARSER_ANNOTATION, "json"),
+                    (REDACT_ANNOTATION, "email, unknown"),
+                ],
+            ))
+            .unwrap();
+
+        let line = apply(
+            &config,
+            r#"2022-01-02T15:04:05.1Z stdout F {"user":"a@b.com","status":200}"#,
+        );
+        assert_eq!(line.app.as_deref(), Some("checkout"));
+        assert_eq!(
+            line.line.as_deref(),
+            Some(r#"2022-01-02T15:04:05.1Z stdout F {"user":"[REDACTED]","status":200}"#)
+        );
+        assert_eq!(
+            line.meta,
+            Some(json!({"user": "[REDACTED]", "status": 200}))
+        );
+
+        // Lines that are not json are left as is
+        let line = apply(&config, "not json");
+        assert_eq!(line.meta, None);
+    }
+
+    #[test]
+    fn test_cache_by_annotations() {
+        let cache = PodConfigCache::default();
+        assert!(cache.get(&pod("1", &[("other", "value")])).is_none());
+
+        let config = cache.get(&pod("2", &[(APP_ANNOTATION, "a")])).unwrap();
+        let cached = cache.get(&pod("2", &[(APP_ANNOTATION, "a")])).unwrap();
+        assert!(Arc::ptr_eq(&config, &cached));
+
+        // Status updates and unrelated annotations don't rebuild the settings
+        let cached = cache
+            .get(&pod("3", &[(APP_ANNOTATION, "a"), ("other", "value")]))
+            .unwrap();
+        assert!(Arc::ptr_eq(&config, &cached));
+
+        let updated = cache.get(&pod("4", &[(APP_ANNOTATION, "b")])).unwrap();
+        assert_eq!(updated.app.as_deref(), Some("b"));
+
+        cache.retain(&[]);
+        assert!(cache.entries.lock().is_empty());
+    }
+}
@@ -0,0 +1,362 @@
+use config::K8sSelectionConfig;
+use globber::Pattern;
+use k8s_openapi::api::core::v1::Pod;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Pods with this annotation set to "true" are never tailed
+pub const EXCLUDE_ANNOTATION: &str = "logdna.com/exclude";
+
+#[derive(Debug, Error)]
+pub enum SelectionError {
+    #[error("invalid glob {0}: {1}")]
+    Glob(String, globber::Error),
+    #[error("invalid label selector requirement \"{0}\"")]
+    LabelSelector(String),
+}
+
+#[derive(Debug, PartialEq)]
+enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    In(String, Vec<String>),
+    NotIn(String, Vec<String>),
+    Exists(String),
+    DoesNotExist(String),
+}
+
+impl Requirement {
+    fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        match self {
+            Requirement::Equals(k, v) => labels.get(k) == Some(v),
+            Requirement::NotEquals(k, v) => labels.get(k) != Some(v),
+            Requirement::In(k, values) => labels.get(k).map_or(false, |v| values.contains(v)),
+            Requirement::NotIn(k, values) => labels.get(k).map_or(true, |v| !values.contains(v)),
+            Requirement::Exists(k) => labels.contains_key(k),
+            Requirement::DoesNotExist(k) => !labels.contains_key(k),
+        }
+    }
+}
+
+impl FromStr for Requirement {
+    type Err = SelectionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SelectionError::LabelSelector(s.to_string());
+        let s = s.trim();
+
+        let key = |k: &str| -> Result<String, SelectionError> {
+            if is_label_key(k) {
+                Ok(k.to_string())
+            } else {
+                Err(invalid())
+            }
+        };
+        let value = |v: &str| -> Result<String, SelectionError> {
+            let v = v.trim();
+            if is_label_value(v) {
+                Ok(v.to_string())
+            } else {
+                Err(invalid())
+            }
+        };
+        let set_values = |values: &str| -> Result<Vec<String>, SelectionError> {
+            let values = values.trim();
+            if !values.starts_with('(') || !values.ends_with(')') {
+                return Err(invalid());
+            }
+            values[1..values.len() - 1].split(',').map(value).collect()
+        };
+
+        if let Some(k) = s.strip_prefix('!') {
+            return Ok(Requirement::DoesNotExist(key(k.trim())?));
+        }
+
+        // The key is followed by the operator, the set based ones are told apart from a key
+        // starting with `in` by the space or parenthesis after them
+        let key_end = s
+            .find(|c: char| !(c.is_ascii_alphanumeric() || "-_./".contains(c)))
+            .unwrap_or(s.len());
+        let k = key(&s[..key_end])?;
+        let rest = s[key_end..].trim_start();
+        let set_operator = |operator: &str| {
+            rest.strip_prefix(operator)
+                .filter(|v| v.starts_with(|c: char| c.is_whitespace() || c == '('))
+        };
+
+        if rest.is_empty() {
+            Ok(Requirement::Exists(k))
+        } else if let Some(v) = set_operator("notin") {
+            Ok(Requirement::NotIn(k, set_values(v)?))
+        } else if let Some(v) = set_operator("in") {
+            Ok(Requirement::In(k, set_values(v)?))
+        } else if let Some(v) = rest.strip_prefix("!=") {
+            Ok(Requirement::NotEquals(k, value(v)?))
+        } else if let Some(v) = rest.strip_prefix("==").or_else(|| rest.strip_prefix('=')) {
+            Ok(Requirement::Equals(k, value(v)?))
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+/// Whether the key follows the label key syntax, a name with an optional DNS subdomain
+/// prefix, e.g. `app.kubernetes.io/name`
+fn is_label_key(key: &str) -> bool {
+    let (prefix, name) = match key.split_once('/') {
+        Some((prefix, name)) => (Some(prefix), name),
+        None => (None, key),
+    };
+    let is_subdomain = |prefix: &str| {
+        prefix.len() <= 253
+            && prefix.split('.').all(|part| {
+                !part.is_empty()
+                    && !part.starts_with('-')
+                    && !part.ends_with('-')
+                    && part
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            })
+    };
+    !name.is_empty() && is_label_value(name) && prefix.map_or(true, is_subdomain)
+}
+
+/// Whether the value follows the label value syntax, up to 63 alphanumeric characters,
+/// dashes, underscores and dots, starting and ending with an alphanumeric one
+fn is_label_value(value: &str) -> bool {
+    value.len() <= 63
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        && value
+            .chars()
+            .next()
+            .map_or(true, |c| c.is_ascii_alphanumeric())
+        && value
+            .chars()
+            .last()
+            .map_or(true, |c| c.is_ascii_alphanumeric())
+}
+
+/// Label selector using the kubectl syntax, e.g. `app=api,tier in (web,api),!canary`
+#[derive(Debug, Default, PartialEq)]
+struct LabelSelector(Vec<Requirement>);
+
+impl LabelSelector {
+    /// Parses the requirements of a selector, the values split by commas (i.e. from a
+    /// comma separated env var) are joined back to keep the sets of values.
+    fn parse(values: &[String]) -> Result<LabelSelector, SelectionError> {
+        values.join(",").parse()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.0.iter().all(|r| r.matches(labels))
+    }
+}
+
+impl FromStr for LabelSelector {
+    type Err = SelectionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut requirements = Vec::new();
+        let mut depth = 0;
+        let mut start = 0;
+        for (i, c) in s.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                ',' if depth == 0 => {
+                    requirements.push(&s[start..i]);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        requirements.push(&s[start..]);
+
+        Ok(LabelSelector(
+            requirements
+                .into_iter()
+                .filter(|r| !r.trim().is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+fn compile_globs(values: &[String]) -> Result<Vec<Pattern>, SelectionError> {
+    values
+        .iter()
+        .map(|v| Pattern::new(v).map_err(|e| SelectionError::Glob(v.clone(), e)))
+        .collect()
+}
+
+/// Decides which pods and containers the logs are collected from.
+#[derive(Debug, Default)]
+pub struct PodSelection {
+    include_namespaces: Vec<Pattern>,
+    exclude_namespaces: Vec<Pattern>,
+    include_labels: LabelSelector,
+    exclude_labels: LabelSelector,
+    include_containers: Vec<Pattern>,
+    exclude_containers: Vec<Pattern>,
+}
+
+impl PodSelection {
+    pub fn new(config: &K8sSelectionConfig) -> Result<PodSelection, SelectionError> {
+        Ok(PodSelection {
+            include_namespaces: compile_globs(&config.include_namespaces)?,
+            exclude_namespaces: compile_globs(&config.exclude_namespaces)?,
+            include_labels: LabelSelector::parse(&config.include_labels)?,
+            exclude_labels: LabelSelector::parse(&config.exclude_labels)?,
+            include_containers: compile_globs(&config.include_containers)?,
+            exclude_containers: compile_globs(&config.exclude_containers)?,
+        })
+    }
+
+    /// Returns true when the logs of the container should not be collected. The pod is
+    /// `None` when it's not known yet, only the rules using the path are evaluated then.
+    pub fn excludes(&self, namespace: &str, container: &str, pod: Option<&Pod>) -> bool {
+        let namespace_selected = selected(
+            &self.include_namespaces,
+            &self.exclude_namespaces,
+            namespace,
+        );
+        let container_selected = selected(
+            &self.include_containers,
+            &self.exclude_containers,
+            container,
+        );
+        if !namespace_selected || !container_selected {
+            return true;
+        }
+
+        let metadata = match pod {
+            Some(pod) => &pod.metadata,
+            None => return false,
+        };
+
+        if metadata
+            .annotations
+            .as_ref()
+            .and_then(|a| a.get(EXCLUDE_ANNOTATION))
+            .map_or(false, |v| v.trim().eq_ignore_ascii_case("true"))
+        {
+            return true;
+        }
+
+        let empty = BTreeMap::new();
+        let labels = metadata.labels.as_ref().unwrap_or(&empty);
+        if !self.include_labels.matches(labels) {
+            return true;
+        }
+        !self.exclude_labels.is_empty() && self.exclude_labels.matches(labels)
+    }
+}
+
+fn selected(include: &[Pattern], exclude: &[Pattern], value: &str) -> bool {
+    (include.is_empty() || include.iter().any(|p| p.matches(value)))
+        && !exclude.iter().any(|p| p.matches(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    fn labels(values: &[(&str, &str)]) -> BTreeMap<String, String> {
+        values
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn pod(labels: BTreeMap<String, String>, annotations: BTreeMap<String, String>) -> Pod {
+        Pod {
+            metadata: ObjectMeta {
+                name: Some("pod".to_string()),
+                namespace: Some("default".to_string()),
+                labels: Some(labels),
+                annotations: Some(annotations),
+                ..Default::default()
+            },
+            spec: None,
+            status: None,
+        }
+    }
+
+    #[test]
+    fn test_label_selector() {
+        let selector: LabelSelector = "app=api, tier in (web, api),!canary,env!=dev"
+            .parse()
+            .unwrap();
+        assert!(selector.matches(&labels(&[("app", "api"), ("tier", "web")])));
+        assert!(!selector.matches(&labels(&[("app", "api"), ("tier", "db")])));
+        let canary = labels(&[("app", "api"), ("tier", "web"), ("canary", "")]);
+        assert!(!selector.matches(&canary));
+        let dev = labels(&[("app", "api"), ("tier", "web"), ("env", "dev")]);
+        assert!(!selector.matches(&dev));
+
+        // The values split from a comma separated list are joined back
+        let selector =
+            LabelSelector::parse(&["tier notin (web".to_string(), "api)".to_string()]).unwrap();
+        assert!(selector.matches(&labels(&[("tier", "db")])));
+        assert!(!selector.matches(&labels(&[("tier", "api")])));
+
+        assert!("app in web".parse::<LabelSelector>().is_err());
+        assert!("=api".parse::<LabelSelector>().is_err());
+        assert!("!canary=true".parse::<LabelSelector>().is_err());
+        assert!("tier in (a=b)".parse::<LabelSelector>().is_err());
+        assert!("app = a b".parse::<LabelSelector>().is_err());
+
+        // Keys starting like an operator and prefixed keys
+        assert_eq!(
+            "index in (a),inner,app.kubernetes.io/name==api,tier notin(db)"
+                .parse::<LabelSelector>()
+                .unwrap(),
+            LabelSelector(vec![
+                Requirement::In("index".to_string(), vec!["a".to_string()]),
+                Requirement::Exists("inner".to_string()),
+                Requirement::Equals("app.kubernetes.io/name".to_string(), "api".to_string()),
+                Requirement::NotIn("tier".to_string(), vec!["db".to_string()]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_excludes() {
+        let selection = PodSelection::new(&K8sSelectionConfig {
+            exclude_namespaces: vec!["kube-*".to_string()],
+            include_labels: vec!["team".to_string()],
+            exclude_labels: vec!["team=qa".to_string()],
+            exclude_containers: vec!["istio-proxy".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let team_a = pod(labels(&[("team", "a")]), BTreeMap::new());
+        assert!(!selection.excludes("default", "api", Some(&team_a)));
+        assert!(selection.excludes("kube-system", "api", Some(&team_a)));
+        assert!(selection.excludes("default", "istio-proxy", Some(&team_a)));
+
+        let qa = pod(labels(&[("team", "qa")]), BTreeMap::new());
+        assert!(selection.excludes("default", "api", Some(&qa)));
+        let no_team = pod(BTreeMap::new(), BTreeMap::new());
+        assert!(selection.excludes("default", "api", Some(&no_team)));
+
+        let annotated = pod(
+            labels(&[("team", "a")]),
+            labels(&[(EXCLUDE_ANNOTATION, "true")]),
+        );
+        assert!(selection.excludes("default", "api", Some(&annotated)));
+
+        // Unknown pods are only filtered by namespace and container
+        assert!(!selection.excludes("default", "api", None));
+        assert!(selection.excludes("kube-system", "api", None));
+    }
+}
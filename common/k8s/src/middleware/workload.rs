@@ -0,0 +1,254 @@
+use k8s_openapi::api::apps::v1::ReplicaSet;
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, OwnerReference};
+use kube::runtime::reflector::{ObjectRef, Store};
+use serde_json::{Map, Value};
+
+/// Label set by the Deployment controller on its ReplicaSets and pods
+const POD_TEMPLATE_HASH_LABEL: &str = "pod-template-hash";
+
+/// Stores of the intermediate owners, used to find the workload controlling a pod.
+#[derive(Clone)]
+pub(crate) struct OwnerStores {
+    pub(crate) replica_sets: Store<ReplicaSet>,
+    pub(crate) jobs: Store<Job>,
+}
+
+fn controller(meta: &ObjectMeta) -> Option<&OwnerReference> {
+    let owners = meta.owner_references.as_ref()?;
+    owners
+        .iter()
+        .find(|o| o.controller == Some(true))
+        .or_else(|| owners.first())
+}
+
+/// Returns the kind and name of the workload controlling the pod, resolving the
+/// ReplicaSets to their Deployment and the Jobs to their CronJob.
+pub(crate) fn resolve_workload(
+    pod: &Pod,
+    stores: Option<&OwnerStores>,
+) -> Option<(String, String)> {
+    let owner = controller(&pod.metadata)?;
+    let namespace = pod.metadata.namespace.as_deref().unwrap_or_default();
+
+    let parent = match owner.kind.as_str() {
+        "ReplicaSet" => stores
+            .and_then(|stores| {
+                stores
+                    .replica_sets
+                    .get(&ObjectRef::new(&owner.name).within(namespace))
+            })
+            .and_then(|rs| controller(&rs.metadata).cloned())
+            // Deployments name their ReplicaSets with the pod template hash as suffix
+            .or_else(|| {
+                pod.metadata
+                    .labels
+                    .as_ref()
+                    .and_then(|l| l.get(POD_TEMPLATE_HASH_LABEL))
+                    .and_then(|hash| owner.name.strip_suffix(&format!("-{}", hash)))
+                    .map(|name| OwnerReference {
+                        kind: "Deployment".to_string(),
+                        name: name.to_string(),
+                        ..Default::default()
+                    })
+            }),
+        "Job" => stores.and_then(|stores| {
+            stores
+                .jobs
+                .get(&ObjectRef::new(&owner.name).within(namespace))
+                .and_then(|job| controller(&job.metadata).cloned())
+        }),
+        _ => None,
+    };
+
+    let workload = match &parent {
+        Some(p) if p.kind == "Deployment" || p.kind == "CronJob" => p,
+        _ => owner,
+    };
+    Some((workload.kind.clone(), workload.name.clone()))
+}
+
+/// Builds the fields describing the workload, node and container of a pod.
+pub(crate) fn pod_fields(
+    pod: &Pod,
+    container: &str,
+    stores: Option<&OwnerStores>,
+) -> Map<String, Value> {
+    let mut fields = Map::new();
+
+    if let Some((kind, name)) = resolve_workload(pod, stores) {
+        fields.insert("workload_kind".to_string(), Value::String(kind));
+        fields.insert("workload_name".to_string(), Value::String(name));
+    }
+
+    if let Some(node) = pod.spec.as_ref().and_then(|s| s.node_name.clone()) {
+        fields.insert("node_name".to_string(), Value::String(node));
+    }
+
+    let status = pod.status.as_ref();
+    if let Some(ip) = status.and_then(|s| s.pod_ip.clone()) {
+        fields.insert("pod_ip".to_string(), Value::String(ip));
+    }
+
+    let container_status = status
+        .and_then(|s| s.container_statuses.as_ref())
+        .into_iter()
+        .chain(status.and_then(|s| s.init_container_statuses.as_ref()))
+        .flatten()
+        .find(|c| c.name == container);
+
+    let image = pod
+        .spec
+        .as_ref()
+        .map(|s| {
+            s.containers
+                .iter()
+                .chain(s.init_containers.iter().flatten())
+        })
+        .and_then(|mut c| c.find(|c| c.name == container))
+        .and_then(|c| c.image.clone())
+        .or_else(|| container_status.map(|c| c.image.clone()));
+    if let Some(image) = image {
+        fields.insert("container_image".to_string(), Value::String(image));
+    }
+
+    if let Some(c) = container_status {
+        fields.insert("restart_count".to_string(), Value::from(c.restart_count));
+    }
+
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::api::core::v1::{Container, ContainerStatus, PodSpec, PodStatus};
+    use kube::runtime::reflector::store::Writer;
+    use kube::runtime::watcher::Event;
+    use serde_json::json;
+
+    fn owner(kind: &str, name: &str) -> Option<Vec<OwnerReference>> {
+        Some(vec![OwnerReference {
+            kind: kind.to_string(),
+            name: name.to_string(),
+            controller: Some(true),
+            ..Default::default()
+        }])
+    }
+
+    fn meta(name: &str, owner_references: Option<Vec<OwnerReference>>) -> ObjectMeta {
+        ObjectMeta {
+            name: Some(name.to_string()),
+            namespace: Some("default".to_string()),
+            owner_references,
+            ..Default::default()
+        }
+    }
+
+    fn pod(owner_references: Option<Vec<OwnerReference>>) -> Pod {
+        Pod {
+            metadata: meta("pod", owner_references),
+            spec: None,
+            status: None,
+        }
+    }
+
+    fn stores() -> OwnerStores {
+        let mut replica_sets = Writer::default();
+        replica_sets.apply_watcher_event(&Event::Applied(ReplicaSet {
+            metadata: meta("api-7d9f8b6c4", owner("Deployment", "api")),
+            ..Default::default()
+        }));
+        let mut jobs = Writer::default();
+        jobs.apply_watcher_event(&Event::Applied(Job {
+            metadata: meta("backup-27400000", owner("CronJob", "backup")),
+            ..Default::default()
+        }));
+        OwnerStores {
+            replica_sets: replica_sets.as_reader(),
+            jobs: jobs.as_reader(),
+        }
+    }
+
+    #[test]
+    fn test_resolve_workload() {
+        let stores = stores();
+        let resolve = |owners| resolve_workload(&pod(owners), Some(&stores));
+
+        assert_eq!(
+            resolve(owner("ReplicaSet", "api-7d9f8b6c4")),
+            Some(("Deployment".to_string(), "api".to_string()))
+        );
+        assert_eq!(
+            resolve(owner("Job", "backup-27400000")),
+            Some(("CronJob".to_string(), "backup".to_string()))
+        );
+        assert_eq!(
+            resolve(owner("StatefulSet", "db")),
+            Some(("StatefulSet".to_string(), "db".to_string()))
+        );
+        // Owners that are not known are used as is
+        assert_eq!(
+            resolve(owner("ReplicaSet", "other")),
+            Some(("ReplicaSet".to_string(), "other".to_string()))
+        );
+        assert_eq!(resolve(None), None);
+
+        // Until the ReplicaSet is known the Deployment is found from the pod template hash
+        let mut p = pod(owner("ReplicaSet", "api-7d9f8b6c4"));
+        p.metadata.labels = Some(
+            vec![(POD_TEMPLATE_HASH_LABEL.to_string(), "7d9f8b6c4".to_string())]
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(
+            resolve_workload(&p, None),
+            Some(("Deployment".to_string(), "api".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_pod_fields() {
+        let mut p = pod(owner("DaemonSet", "agent"));
+        p.spec = Some(PodSpec {
+            node_name: Some("node-1".to_string()),
+            containers: vec![Container {
+                name: "app".to_string(),
+                image: Some("repo/app:1.0".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        p.status = Some(PodStatus {
+            pod_ip: Some("10.0.0.1".to_string()),
+            container_statuses: Some(vec![ContainerStatus {
+                name: "app".to_string(),
+                restart_count: 3,
+                ..Default::default()
+            }]),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            Value::Object(pod_fields(&p, "app", None)),
+            json!({
+                "workload_kind": "DaemonSet",
+                "workload_name": "agent",
+                "node_name": "node-1",
+                "pod_ip": "10.0.0.1",
+                "container_image": "repo/app:1.0",
+                "restart_count": 3
+            })
+        );
+        assert_eq!(
+            Value::Object(pod_fields(&p, "other", None)),
+            json!({
+                "workload_kind": "DaemonSet",
+                "workload_name": "agent",
+                "node_name": "node-1",
+                "pod_ip": "10.0.0.1"
+            })
+        );
+    }
+}
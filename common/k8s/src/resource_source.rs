@@ -0,0 +1,584 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use backoff::ExponentialBackoff;
+use futures::{Stream, StreamExt};
+use kube::api::{ApiResource, DynamicObject, GroupVersionKind, ListParams};
+use kube::runtime::utils::StreamBackoff;
+use kube::runtime::watcher::{watcher, Event as WatcherEvent};
+use kube::{Api, Client};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use config::K8sWatchRule;
+use http::types::body::LineBuilder;
+use metrics::Metrics;
+
+use crate::errors::K8sEventStreamError;
+use crate::leader::LeaderElection;
+use crate::restarting_stream::{RequiresRestart, RestartingStream};
+
+/// Name of the lease held by the agent collecting the resource changes
+pub const K8S_RESOURCES_LEASE_NAME: &str = "logdna-agent-k8s-resources";
+
+/// Annotations holding a copy of the whole object, e.g. the data of a ConfigMap, that are
+/// never compared
+const UNTRACKED_ANNOTATIONS: &[&str] = &["kubectl.kubernetes.io/last-applied-configuration"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    Added,
+    Updated,
+    Deleted,
+}
+
+impl Action {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Action::Added => "added",
+            Action::Updated => "updated",
+            Action::Deleted => "deleted",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct ResourceChange {
+    action: Action,
+    name: String,
+    namespace: Option<String>,
+    resource_version: Option<String>,
+    diff: Option<Map<String, Value>>,
+}
+
+#[derive(Serialize, Debug)]
+struct ResourceLogLine {
+    message: String,
+    kube: ResourceLogLineInner,
+}
+
+#[derive(Serialize, Debug)]
+struct ResourceLogLineInner {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    action: Action,
+    api_version: String,
+    resource: String,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resource_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diff: Option<Map<String, Value>>,
+}
+
+/// Keeps the last known state of the watched objects to find what changed.
+struct ResourceTracker {
+    rule: K8sWatchRule,
+    listed: bool,
+    objects: HashMap<(Option<String>, String), Map<String, Value>>,
+}
+
+impl ResourceTracker {
+    fn new(rule: K8sWatchRule) -> Self {
+        ResourceTracker {
+            rule,
+            listed: false,
+            objects: HashMap::new(),
+        }
+    }
+
+    /// Returns the fields of the object that are compared between versions, the other
+    /// metadata such as the managed fields is left out.
+    fn tracked_fields(&self, object: &DynamicObject) -> Map<String, Value> {
+        let mut fields = Map::new();
+        let mut metadata = Map::new();
+        if let Some(labels) = &object.metadata.labels {
+            metadata.insert("labels".to_string(), json_value(labels));
+        }
+        if let Some(annotations) = &object.metadata.annotations {
+            let annotations = annotations
+                .iter()
+                .filter(|(k, _)| !UNTRACKED_ANNOTATIONS.contains(&k.as_str()))
+                .collect::<BTreeMap<_, _>>();
+            metadata.insert("annotations".to_string(), json_value(&annotations));
+        }
+        fields.insert("metadata".to_string(), Value::Object(metadata));
+
+        if !self.rule.metadata_only {
+            if let Value::Object(data) = &object.data {
+                let untracked = ["apiVersion", "kind", "metadata"];
+                for (k, v) in data
+                    .iter()
+                    .filter(|(k, _)| !untracked.contains(&k.as_str()))
+                {
+                    fields.insert(k.clone(), v.clone());
+                }
+            }
+        }
+        fields
+    }
+
+    fn change(
+        &self,
+        action: Action,
+        object: &DynamicObject,
+        diff: Option<Map<String, Value>>,
+    ) -> ResourceChange {
+        ResourceChange {
+            action,
+            name: object.metadata.name.clone().unwrap_or_default(),
+            namespace: object.metadata.namespace.clone(),
+            resource_version: object.metadata.resource_version.clone(),
+            diff,
+        }
+    }
+
+    fn apply(&mut self, object: &DynamicObject) -> Option<ResourceChange> {
+        let key = object_key(object);
+        let fields = self.tracked_fields(object);
+        let change = match self.objects.get(&key) {
+            None => Some(self.change(Action::Added, object, None)),
+            Some(previous) => {
+                let mut diff = Map::new();
+                diff_fields(
+                    "",
+                    &Value::Object(previous.clone()),
+                    &Value::Object(fields.clone()),
+                    &self.rule.ignore_fields,
+                    &mut diff,
+                );
+                // Updates only touching untracked fields, e.g. the managed fields, are skipped
+                if diff.is_empty() {
+                    None
+                } else {
+                    Some(self.change(Action::Updated, object, Some(diff)))
+                }
+            }
+        };
+        self.objects.insert(key, fields);
+        change
+    }
+
+    fn handle(&mut self, event: WatcherEvent<DynamicObject>) -> Vec<ResourceChange> {
+        match event {
+            WatcherEvent::Applied(object) => self.apply(&object).into_iter().collect(),
+            WatcherEvent::Deleted(object) => {
+                self.objects.remove(&object_key(&object));
+                vec![self.change(Action::Deleted, &object, None)]
+            }
+            WatcherEvent::Restarted(objects) => {
+                // The objects present when the watch starts were not changed
+                if !self.listed {
+                    self.listed = true;
+                    for object in objects.iter() {
+                        let fields = self.tracked_fields(object);
+                        self.objects.insert(object_key(object), fields);
+                    }
+                    return Vec::new();
+                }
+
+                // The changes missed while the watch was interrupted
+                let keys = objects.iter().map(object_key).collect::<Vec<_>>();
+                let removed = self
+                    .objects
+                    .keys()
+                    .filter(|k| !keys.contains(k))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let mut changes = objects
+                    .iter()
+                    .filter_map(|o| self.apply(o))
+                    .collect::<Vec<_>>();
+                for (namespace, name) in removed {
+                    self.objects.remove(&(namespace.clone(), name.clone()));
+                    changes.push(ResourceChange {
+                        action: Action::Deleted,
+                        name,
+                        namespace,
+                        resource_version: None,
+                        diff: None,
+                    });
+                }
+                changes
+            }
+        }
+    }
+
+    fn to_line(&self, change: ResourceChange) -> Result<LineBuilder, K8sEventStreamError> {
+        let full_name = match &change.namespace {
+            Some(namespace) => format!("{}/{}", namespace, change.name),
+            None => change.name.clone(),
+        };
+        let mut message = format!(
+            "{} {} {}",
+            self.rule.kind,
+            full_name,
+            change.action.as_str()
+        );
+        if let Some(diff) = &change.diff {
+            message = format!(
+                "{}: {}",
+                message,
+                diff.keys().cloned().collect::<Vec<_>>().join(", ")
+            );
+        }
+        let host = format!("{}/{}", self.rule.kind, change.name);
+        let line = ResourceLogLine {
+            message,
+            kube: ResourceLogLineInner {
+                type_: "resource",
+                action: change.action,
+                api_version: self.rule.api_version.clone(),
+                resource: self.rule.kind.clone(),
+                name: change.name,
+                namespace: change.namespace,
+                resource_version: change.resource_version,
+                diff: change.diff,
+            },
+        };
+        let line = serde_json::to_string(&line)?;
+        debug!("logging resource change: {}", line);
+        Ok(LineBuilder::new().line(line).host(host))
+    }
+}
+
+fn json_value<T: Serialize>(value: &T) -> Value {
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+fn object_key(object: &DynamicObject) -> (Option<String>, String) {
+    (
+        object.metadata.namespace.clone(),
+        object.metadata.name.clone().unwrap_or_default(),
+    )
+}
+
+/// Adds the paths that changed between the values to the diff, with their old and new
+/// values. Objects are compared field by field, other values as a whole.
+fn diff_fields(
+    path: &str,
+    old: &Value,
+    new: &Value,
+    ignore: &[String],
+    diff: &mut Map<String, Value>,
+) {
+    if ignore.iter().any(|i| i == path) {
+        return;
+    }
+    // Compare the fields of the objects that are added or removed
+    let empty = Value::Object(Map::new());
+    let (old, new) = match (old, new) {
+        (Value::Null, Value::Object(_)) => (&empty, new),
+        (Value::Object(_), Value::Null) => (old, &empty),
+        _ => (old, new),
+    };
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let mut keys = old.keys().chain(new.keys()).collect::<Vec<_>>();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let nested = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                diff_fields(
+                    &nested,
+                    old.get(key).unwrap_or(&Value::Null),
+                    new.get(key).unwrap_or(&Value::Null),
+                    ignore,
+                    diff,
+                );
+            }
+        }
+        (old, new) if old != new => {
+            let mut change = Map::new();
+            change.insert("old".to_string(), old.clone());
+            change.insert("new".to_string(), new.clone());
+            diff.insert(path.to_string(), Value::Object(change));
+        }
+        _ => {}
+    }
+}
+
+fn api_resource(rule: &K8sWatchRule) -> ApiResource {
+    let (group, version) = rule
+        .api_version
+        .split_once('/')
+        .unwrap_or(("", &rule.api_version));
+    let gvk = GroupVersionKind::gvk(group, version, &rule.kind);
+    match &rule.plural {
+        Some(plural) => ApiResource::from_gvk_with_plural(&gvk, plural),
+        None => ApiResource::from_gvk(&gvk),
+    }
+}
+
+fn list_params(rule: &K8sWatchRule) -> ListParams {
+    let mut params = ListParams::default();
+    if let Some(fields) = &rule.field_selector {
+        params = params.fields(fields);
+    }
+    if let Some(labels) = &rule.label_selector {
+        params = params.labels(labels);
+    }
+    params
+}
+
+/// Watches the resources of a rule, returning a line for each change.
+fn watch_rule(
+    client: Client,
+    rule: K8sWatchRule,
+) -> impl Stream<Item = Result<LineBuilder, K8sEventStreamError>> + Send {
+    let resource = api_resource(&rule);
+    let api: Api<DynamicObject> = match &rule.namespace {
+        Some(namespace) => Api::namespaced_with(client, namespace, &resource),
+        None => Api::all_with(client, &resource),
+    };
+    let watched = StreamBackoff::new(
+        watcher(api, list_params(&rule)),
+        ExponentialBackoff::default(),
+    );
+    let mut tracker = ResourceTracker::new(rule);
+
+    watched
+        .filter_map(|event| async move {
+            match event {
+                Ok(event) => Some(event),
+                Err(e) => {
+                    warn!("k8s resource watch stream error: {}", e);
+                    None
+                }
+            }
+        })
+        .flat_map(move |event| {
+            let changes = tracker.handle(event);
+            futures::stream::iter(tracker_lines(&tracker, changes))
+        })
+}
+
+fn tracker_lines(
+    tracker: &ResourceTracker,
+    changes: Vec<ResourceChange>,
+) -> Vec<Result<LineBuilder, K8sEventStreamError>> {
+    changes
+        .into_iter()
+        .map(|c| {
+            tracker.to_line(c).map(|l| {
+                Metrics::k8s().increment_lines();
+                l
+            })
+        })
+        .collect()
+}
+
+/// Logs the changes of the resources selected by the watch rules.
+pub struct K8sResourceStream {
+    client: Client,
+    pod_name: String,
+    namespace: String,
+    rules: Vec<K8sWatchRule>,
+}
+
+impl K8sResourceStream {
+    pub fn new(
+        client: Client,
+        pod_name: String,
+        namespace: String,
+        rules: Vec<K8sWatchRule>,
+    ) -> Self {
+        Self {
+            client,
+            pod_name,
+            namespace,
+            rules,
+        }
+    }
+
+    async fn create_stream(
+        election: Arc<LeaderElection>,
+        client: Client,
+        rules: Vec<K8sWatchRule>,
+    ) -> impl Stream<Item = Result<LineBuilder, K8sEventStreamError>> {
+        // Only the leader collects the changes, like the k8s events
+        futures::stream::once({
+            let election = election.clone();
+            async move { election.acquire().await }
+        })
+        .map(move |_| {
+            info!("begin logging k8s resource changes");
+            let election = election.clone();
+            let lost = async move { election.hold(Default::default()).await };
+            futures::stream::select_all(
+                rules
+                    .iter()
+                    .map(|rule| watch_rule(client.clone(), rule.clone()).boxed()),
+            )
+            .take_until(lost)
+            .chain(futures::stream::once(async {
+                Err(K8sEventStreamError::LeadershipLost)
+            }))
+        })
+        .flatten()
+    }
+
+    pub async fn resource_stream(self) -> impl Stream<Item = LineBuilder> + Send {
+        let election = Arc::new(LeaderElection::new(
+            self.client.clone(),
+            &self.namespace,
+            K8S_RESOURCES_LEASE_NAME,
+            &self.pod_name,
+        ));
+        let client = self.client;
+        let rules = self.rules;
+
+        let start_stream = move || {
+            K8sResourceStream::create_stream(election.clone(), client.clone(), rules.clone())
+        };
+
+        let restarting_stream = RestartingStream::new(start_stream, |e| match e {
+            Err(K8sEventStreamError::LeadershipLost) => RequiresRestart::Yes,
+            Err(e) => {
+                warn!("unable to log k8s resource change: {}", e);
+                RequiresRestart::No
+            }
+            _ => RequiresRestart::No,
+        });
+
+        restarting_stream.await.filter_map(|e| async { e.ok() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use serde_json::json;
+
+    fn object(name: &str, version: &str, labels: &[(&str, &str)], data: Value) -> DynamicObject {
+        DynamicObject {
+            types: None,
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                namespace: Some("default".to_string()),
+                resource_version: Some(version.to_string()),
+                labels: Some(
+                    labels
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                ),
+                ..Default::default()
+            },
+            data,
+        }
+    }
+
+    fn tracker(metadata_only: bool, ignore_fields: &[&str]) -> ResourceTracker {
+        ResourceTracker::new(K8sWatchRule {
+            api_version: "apps/v1".to_string(),
+            kind: "Deployment".to_string(),
+            metadata_only,
+            ignore_fields: ignore_fields.iter().map(|f| f.to_string()).collect(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_tracks_changes() {
+        let mut tracker = tracker(false, &["status.observedGeneration"]);
+        let api = object("api", "1", &[], json!({"spec": {"replicas": 2}}));
+        let web = object("web", "2", &[], json!({"spec": {"replicas": 1}}));
+
+        // The objects listed at start are not logged
+        let changes = tracker.handle(WatcherEvent::Restarted(vec![api.clone()]));
+        assert!(changes.is_empty());
+
+        let changes = tracker.handle(WatcherEvent::Applied(web.clone()));
+        assert_eq!(changes[0].action, Action::Added);
+
+        let scaled = object(
+            "api",
+            "3",
+            &[("team", "a")],
+            json!({"spec": {"replicas": 3}, "status": {"observedGeneration": 2}}),
+        );
+        let changes = tracker.handle(WatcherEvent::Applied(scaled.clone()));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].action, Action::Updated);
+        assert_eq!(
+            Value::Object(changes[0].diff.clone().unwrap()),
+            json!({
+                "metadata.labels.team": {"old": null, "new": "a"},
+                "spec.replicas": {"old": 2, "new": 3}
+            })
+        );
+
+        // Versions without tracked changes are skipped
+        let mut touched = scaled.clone();
+        touched.metadata.resource_version = Some("4".to_string());
+        assert!(tracker.handle(WatcherEvent::Applied(touched)).is_empty());
+
+        let changes = tracker.handle(WatcherEvent::Deleted(web));
+        assert_eq!(changes[0].action, Action::Deleted);
+
+        // The changes missed while the watch was interrupted are logged on relist
+        let changes = tracker.handle(WatcherEvent::Restarted(vec![]));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].action, Action::Deleted);
+        assert_eq!(changes[0].name, "api");
+    }
+
+    #[test]
+    fn test_metadata_only() {
+        let mut tracker = tracker(true, &[]);
+        tracker.handle(WatcherEvent::Restarted(vec![object(
+            "config",
+            "1",
+            &[],
+            json!({"data": {"password": "a"}}),
+        )]));
+        let changed = object("config", "2", &[], json!({"data": {"password": "b"}}));
+        assert!(tracker.handle(WatcherEvent::Applied(changed)).is_empty());
+
+        // The data is also in the last applied configuration of the objects applied by kubectl
+        let mut applied = object("config", "3", &[], json!({"data": {"password": "c"}}));
+        applied.metadata.annotations = Some(
+            vec![(
+                UNTRACKED_ANNOTATIONS[0].to_string(),
+                r#"{"data":{"password":"c"}}"#.to_string(),
+            )]
+            .into_iter()
+            .collect(),
+        );
+        applied.metadata.managed_fields = Some(Vec::new());
+        assert!(tracker.handle(WatcherEvent::Applied(applied)).is_empty());
+
+        let labeled = object("config", "4", &[("env", "prod")], json!({}));
+        let changes = tracker.handle(WatcherEvent::Applied(labeled));
+        let line = tracker
+            .to_line(changes.into_iter().next().unwrap())
+            .unwrap();
+        let value: Value = serde_json::from_str(line.line.as_deref().unwrap()).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "message": "Deployment default/config updated: metadata.labels.env",
+                "kube": {
+                    "type": "resource",
+                    "action": "updated",
+                    "api_version": "apps/v1",
+                    "resource": "Deployment",
+                    "name": "config",
+                    "namespace": "default",
+                    "resource_version": "4",
+                    "diff": {"metadata.labels.env": {"old": null, "new": "prod"}}
+                }
+            })
+        );
+        assert_eq!(line.host.as_deref(), Some("Deployment/config"));
+    }
+}
@@ -4,8 +4,8 @@ use log::{info, warn};
 use num::traits::FromPrimitive;
 use prometheus::{
     exponential_buckets, register_histogram, register_histogram_vec, register_int_counter,
-    register_int_counter_vec, register_int_gauge, Histogram, HistogramVec, IntCounter,
-    IntCounterVec, IntGauge,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec, Histogram, HistogramVec,
+    IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
 };
 use std::time::{Duration, Instant};
 use tikv_jemalloc_ctl::stats::{
@@ -28,6 +28,38 @@ lazy_static! {
         register_int_gauge!("logdna_agent_fs_files", "Number of open files").unwrap();
     static ref FS_BYTES: IntCounter =
         register_int_counter!("logdna_agent_fs_bytes", "Number of bytes read by the Filesystem module").unwrap();
+    static ref FS_LONG_LINES: IntCounter = register_int_counter!(
+        "logdna_agent_fs_long_lines",
+        "Lines longer than the maximum line length"
+    )
+    .unwrap();
+    static ref FS_INOTIFY_LIMITS: IntGaugeVec = register_int_gauge_vec!(
+        "logdna_agent_fs_inotify_limit",
+        "Inotify limits of the host, read at startup",
+        &["limit"]
+    )
+    .unwrap();
+    static ref FS_WATCHES: IntGaugeVec = register_int_gauge_vec!(
+        "logdna_agent_fs_watches",
+        "Number of paths watched by inotify or by polling",
+        &["kind"]
+    )
+    .unwrap();
+    static ref FS_WATCH_FALLBACKS: IntCounter = register_int_counter!(
+        "logdna_agent_fs_watch_fallbacks",
+        "Paths polled because of the inotify limits"
+    )
+    .unwrap();
+    static ref FS_WATCH_OVERFLOWS: IntCounter = register_int_counter!(
+        "logdna_agent_fs_watch_overflows",
+        "Number of times the inotify event queue overflowed"
+    )
+    .unwrap();
+    static ref FS_WATCH_DEGRADED: IntGauge = register_int_gauge!(
+        "logdna_agent_fs_watch_degraded",
+        "Set to 1 while paths are polled because of the inotify limits"
+    )
+    .unwrap();
     static ref INGEST_RETRIES: IntCounter = register_int_counter!(
         "logdna_agent_ingest_retries",
         "Retry attempts made to the http ingestion service"
@@ -76,6 +108,18 @@ lazy_static! {
     .unwrap();
     static ref K8S_LINES: IntCounter =
         register_int_counter!("logdna_agent_k8s_lines", "Kubernetes event lines read").unwrap();
+    static ref K8S_UNENRICHED_LINES: IntCounter = register_int_counter!(
+        "logdna_agent_k8s_unenriched_lines",
+        "Container lines sent without the metadata of their pod"
+    )
+    .unwrap();
+    static ref K8S_STARTUP_LEASE_WAIT_SECONDS: Histogram = register_histogram!(
+        "logdna_agent_k8s_startup_lease_wait_seconds",
+        "Time spent waiting for a Kubernetes startup lease, in seconds",
+        // Buckets ranging from 100ms to 27 minutes
+        exponential_buckets(0.1, 3.0, 10).unwrap()
+    )
+    .unwrap();
     static ref JOURNAL_RECORDS: Histogram = register_histogram!(
         "logdna_agent_journald_records",
         "Size of the Journald log entries read"
@@ -92,6 +136,8 @@ mod labels {
     pub const SUCCESS: &str = "success";
     pub const FAILURE: &str = "failure";
     pub const TIMEOUT: &str = "timeout";
+    pub const INOTIFY: &str = "inotify";
+    pub const POLL: &str = "poll";
 }
 
 pub struct Metrics {
@@ -166,7 +212,13 @@ impl Metrics {
                 "writes" => fs_write,
                 "lines" => FS_LINES.get(),
                 "bytes" => FS_BYTES.get(),
+                "long_lines" => FS_LONG_LINES.get(),
                 "files_tracked" => FS_FILES.get(),
+                "watches_inotify" => FS_WATCHES.with_label_values(&[labels::INOTIFY]).get(),
+                "watches_polled" => FS_WATCHES.with_label_values(&[labels::POLL]).get(),
+                "watch_fallbacks" => FS_WATCH_FALLBACKS.get(),
+                "watch_overflows" => FS_WATCH_OVERFLOWS.get(),
+                "watch_health" => if FS_WATCH_DEGRADED.get() == 0 { "ok" } else { "degraded" },
             },
             // CPU and memory metrics are exported to Prometheus by default only on linux.
             // We still rely on jemalloc stats for this periodic printing the memory metrics
@@ -195,6 +247,8 @@ impl Metrics {
                 "creates" => k8s_create,
                 "deletes" => k8s_delete,
                 "events" => k8s_create + k8s_delete,
+                "unenriched_lines" => K8S_UNENRICHED_LINES.get(),
+                "startup_lease_wait" => K8S_STARTUP_LEASE_WAIT_SECONDS.get_sample_sum(),
             },
             "journald" => object!{
                 "lines" => JOURNAL_RECORDS.get_sample_count(),
@@ -245,6 +299,37 @@ impl Fs {
     pub fn add_bytes(&self, num: u64) {
         FS_BYTES.inc_by(num);
     }
+
+    pub fn increment_long_lines(&self) {
+        FS_LONG_LINES.inc();
+    }
+
+    pub fn set_inotify_limit(&self, limit: &str, value: u64) {
+        FS_INOTIFY_LIMITS
+            .with_label_values(&[limit])
+            .set(value.min(i64::MAX as u64) as i64);
+    }
+
+    pub fn set_watches(&self, inotify: usize, polled: usize) {
+        FS_WATCHES
+            .with_label_values(&[labels::INOTIFY])
+            .set(inotify as i64);
+        FS_WATCHES
+            .with_label_values(&[labels::POLL])
+            .set(polled as i64);
+    }
+
+    pub fn increment_watch_fallbacks(&self) {
+        FS_WATCH_FALLBACKS.inc();
+    }
+
+    pub fn increment_watch_overflows(&self) {
+        FS_WATCH_OVERFLOWS.inc();
+    }
+
+    pub fn set_watch_degraded(&self, degraded: bool) {
+        FS_WATCH_DEGRADED.set(degraded as i64);
+    }
 }
 
 pub struct Memory {
@@ -364,6 +449,14 @@ impl K8s {
     pub fn increment_deletes(&self) {
         K8S_EVENTS.with_label_values(&[labels::DELETE]).inc();
     }
+
+    pub fn increment_unenriched_lines(&self) {
+        K8S_UNENRICHED_LINES.inc();
+    }
+
+    pub fn add_startup_lease_wait(&self, start: Instant) {
+        K8S_STARTUP_LEASE_WAIT_SECONDS.observe(elapsed_seconds(&start.elapsed()));
+    }
 }
 
 #[derive(Default)]
@@ -426,6 +519,11 @@ mod tests {
         METRICS.fs.increment_writes();
         METRICS.fs.increment_lines();
         METRICS.fs.add_bytes(123);
+        METRICS.fs.set_inotify_limit("max_user_watches", 8192);
+        METRICS.fs.set_watches(12, 3);
+        METRICS.fs.increment_watch_fallbacks();
+        METRICS.fs.increment_watch_overflows();
+        METRICS.fs.set_watch_degraded(true);
         METRICS.http.add_request_size(12);
         METRICS
             .http
@@ -444,6 +542,10 @@ mod tests {
         METRICS.k8s.increment_lines();
         METRICS.k8s.increment_deletes();
         METRICS.k8s.increment_creates();
+        METRICS.k8s.increment_unenriched_lines();
+        METRICS
+            .k8s
+            .add_startup_lease_wait(Instant::now().sub(Duration::from_millis(1500)));
         METRICS.retry.inc_pending();
         METRICS.retry.inc_pending();
         METRICS.retry.dec_pending();
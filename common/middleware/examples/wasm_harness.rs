@@ -0,0 +1,50 @@
+//! Runs a WebAssembly plugin against sample lines read from stdin.
+//!
+//! Usage: cargo run -p middleware --example wasm_harness -- <plugin.wasm> [fuel] [max_memory] < sample.log
+use http::types::body::LineBuilder;
+use middleware::wasm_plugin::WasmPlugin;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let path = match args.next() {
+        Some(p) => PathBuf::from(p),
+        None => {
+            eprintln!("usage: wasm_harness <plugin.wasm> [fuel] [max_memory] < sample.log");
+            std::process::exit(1);
+        }
+    };
+    let fuel = args.next().map_or(1_000_000, |f| {
+        f.parse().expect("fuel must be a positive integer")
+    });
+    let max_memory = args.next().map_or(64 * 1024 * 1024, |m| {
+        m.parse().expect("max_memory must be a positive integer")
+    });
+
+    let plugin = match WasmPlugin::new(&path, fuel, max_memory) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let lines = io::stdin()
+        .lock()
+        .lines()
+        .map(|l| LineBuilder::new().line(l.expect("failed to read stdin")))
+        .collect::<Vec<_>>();
+
+    for result in plugin.run_samples(lines) {
+        match result {
+            Some(line) => println!(
+                "KEEP level={:?} app={:?} line={}",
+                line.level,
+                line.app,
+                line.line.unwrap_or_default()
+            ),
+            None => println!("DROP"),
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use crate::{Middleware, Status};
+use config::DirMeta;
+use http::types::body::LineBufferMut;
+use serde_json::{Map, Value};
+use std::path::Path;
+
+/// Overrides the app and env of the lines of the files under the configured directories, and
+/// sets their tags in the `tags` field of the line meta.
+pub struct DirMetaRules {
+    // The most specific directories first
+    dirs: Vec<DirMeta>,
+}
+
+impl DirMetaRules {
+    pub fn new(dirs: &[DirMeta]) -> DirMetaRules {
+        let mut dirs = dirs.to_vec();
+        dirs.sort_by_key(|dir| std::cmp::Reverse(dir.dir.components().count()));
+        DirMetaRules { dirs }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dirs.is_empty()
+    }
+
+    fn process_line<'a>(
+        &self,
+        line: &'a mut dyn LineBufferMut,
+    ) -> Status<&'a mut dyn LineBufferMut> {
+        let dir = match line.get_file().and_then(|file| {
+            self.dirs
+                .iter()
+                .find(|dir| Path::new(file).starts_with(&dir.dir))
+        }) {
+            Some(dir) => dir,
+            None => return Status::Ok(line),
+        };
+
+        if let Some(app) = &dir.app {
+            if line.set_app(app.clone()).is_err() {}
+        }
+        if let Some(env) = &dir.env {
+            if line.set_env(env.clone()).is_err() {}
+        }
+        if !dir.tags.is_empty() {
+            let meta = match line.get_meta() {
+                None => Some(Map::new()),
+                Some(Value::Object(existing)) => Some(existing.clone()),
+                // Meta that is not an object can't hold the tags
+                Some(_) => None,
+            };
+            if let Some(mut meta) = meta {
+                let tags = dir.tags.iter().cloned().map(Value::String).collect();
+                meta.insert("tags".to_string(), Value::Array(tags));
+                if line.set_meta(Value::Object(meta)).is_err() {}
+            }
+        }
+        Status::Ok(line)
+    }
+}
+
+impl Middleware for DirMetaRules {
+    fn run(&self) {}
+
+    fn process<'a>(&self, line: &'a mut dyn LineBufferMut) -> Status<&'a mut dyn LineBufferMut> {
+        if self.dirs.is_empty() {
+            return Status::Ok(line);
+        }
+
+        self.process_line(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::types::body::LineBuilder;
+    use std::path::PathBuf;
+
+    #[test]
+    fn should_override_by_most_specific_dir() {
+        let p = DirMetaRules::new(&[
+            DirMeta {
+                dir: PathBuf::from("/srv"),
+                app: Some("srv".to_string()),
+                env: Some("production".to_string()),
+                tags: Vec::new(),
+            },
+            DirMeta {
+                dir: PathBuf::from("/srv/app/logs"),
+                app: Some("app".to_string()),
+                env: None,
+                tags: vec!["app".to_string(), "logs".to_string()],
+            },
+        ]);
+
+        let mut line = LineBuilder::new()
+            .line("GET /")
+            .file("/srv/app/logs/access.log")
+            .app("access.log");
+        assert!(matches!(p.process(&mut line), Status::Ok(_)));
+        assert_eq!(line.app.as_deref(), Some("app"));
+        assert_eq!(line.env, None);
+        assert_eq!(
+            line.meta,
+            Some(serde_json::json!({"tags": ["app", "logs"]}))
+        );
+
+        let mut line = LineBuilder::new().line("GET /").file("/srv/other.log");
+        assert!(matches!(p.process(&mut line), Status::Ok(_)));
+        assert_eq!(line.app.as_deref(), Some("srv"));
+        assert_eq!(line.env.as_deref(), Some("production"));
+        assert_eq!(line.meta, None);
+
+        // The directories are matched by path components
+        let mut line = LineBuilder::new()
+            .line("GET /")
+            .file("/srv2/other.log")
+            .app("other.log");
+        assert!(matches!(p.process(&mut line), Status::Ok(_)));
+        assert_eq!(line.app.as_deref(), Some("other.log"));
+    }
+}
@@ -0,0 +1,291 @@
+use crate::{Middleware, Status};
+use config::{ExtractRule, ExtractTarget, ExtractType};
+use globber::Pattern;
+use http::types::body::{KeyValueMap, LineBufferMut};
+use regex::bytes::Regex;
+use serde_json::{Map, Number, Value};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ExtractRulesError {
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+    #[error("invalid path glob {0}: {1}")]
+    Glob(String, globber::Error),
+}
+
+struct CompiledRule {
+    regex: Regex,
+    target: ExtractTarget,
+    types: BTreeMap<String, ExtractType>,
+    path: Option<Pattern>,
+    app: Option<String>,
+}
+
+/// Adds the values of the named capture groups to the line meta or labels.
+pub struct ExtractRules {
+    rules: Vec<CompiledRule>,
+}
+
+impl ExtractRules {
+    pub fn new(rules: &[ExtractRule]) -> Result<ExtractRules, ExtractRulesError> {
+        let mut compiled = Vec::with_capacity(rules.len());
+        for rule in rules.iter() {
+            compiled.push(CompiledRule {
+                regex: Regex::new(&rule.regex)?,
+                target: rule.target,
+                types: rule.types.clone(),
+                path: match &rule.path {
+                    Some(p) => {
+                        Some(Pattern::new(p).map_err(|e| ExtractRulesError::Glob(p.clone(), e))?)
+                    }
+                    None => None,
+                },
+                app: rule.app.clone(),
+            });
+        }
+        Ok(ExtractRules { rules: compiled })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn process_line<'a>(
+        &self,
+        line: &'a mut dyn LineBufferMut,
+    ) -> Status<&'a mut dyn LineBufferMut> {
+        let mut meta_fields = Map::new();
+        let mut label_fields = Vec::new();
+
+        for rule in self.rules.iter() {
+            if let Some(path) = &rule.path {
+                if !line.get_file().map_or(false, |f| path.matches(f)) {
+                    continue;
+                }
+            }
+
+            if let Some(app) = &rule.app {
+                if line.get_app() != Some(app.as_str()) {
+                    continue;
+                }
+            }
+
+            let value = match line.get_line_buffer() {
+                Some(v) => v,
+                None => return Status::Skip,
+            };
+
+            let captures = match rule.regex.captures(value) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            for name in rule.regex.capture_names().flatten() {
+                let captured = match captures
+                    .name(name)
+                    .and_then(|m| std::str::from_utf8(m.as_bytes()).ok())
+                {
+                    Some(v) => v,
+                    None => continue,
+                };
+
+                match rule.target {
+                    ExtractTarget::Meta => {
+                        let value_type =
+                            rule.types.get(name).copied().unwrap_or(ExtractType::String);
+                        // Values that can't be converted are omitted to keep the field type
+                        // consistent across lines
+                        if let Some(v) = coerce(captured, value_type) {
+                            meta_fields.insert(name.to_string(), v);
+                        }
+                    }
+                    ExtractTarget::Labels => {
+                        label_fields.push((name.to_string(), captured.to_string()));
+                    }
+                }
+            }
+        }
+
+        if !meta_fields.is_empty() {
+            let meta = match line.get_meta() {
+                None => Some(Map::new()),
+                Some(Value::Object(existing)) => Some(existing.clone()),
+                // Meta that is not an object can't hold the fields
+                Some(_) => None,
+            };
+
+            if let Some(mut meta) = meta {
+                meta.extend(meta_fields);
+                if line.set_meta(Value::Object(meta)).is_err() {
+                    return Status::Skip;
+                }
+            }
+        }
+
+        if !label_fields.is_empty() {
+            let mut labels = KeyValueMap::new();
+            if let Some(existing) = line.get_labels() {
+                for (k, v) in existing.iter() {
+                    labels.insert(k.clone(), v.clone());
+                }
+            }
+            for (k, v) in label_fields {
+                labels.insert(k, v);
+            }
+            if line.set_labels(labels).is_err() {
+                return Status::Skip;
+            }
+        }
+
+        Status::Ok(line)
+    }
+}
+
+fn coerce(value: &str, value_type: ExtractType) -> Option<Value> {
+    match value_type {
+        ExtractType::String => Some(Value::String(value.to_string())),
+        ExtractType::Int => value.trim().parse::<i64>().ok().map(Value::from),
+        ExtractType::Float => value
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number),
+        ExtractType::Bool => match value.trim().to_lowercase().as_str() {
+            "true" | "yes" | "1" => Some(Value::Bool(true)),
+            "false" | "no" | "0" => Some(Value::Bool(false)),
+            _ => None,
+        },
+    }
+}
+
+impl Middleware for ExtractRules {
+    fn run(&self) {}
+
+    fn process<'a>(&self, line: &'a mut dyn LineBufferMut) -> Status<&'a mut dyn LineBufferMut> {
+        if self.rules.is_empty() {
+            return Status::Ok(line);
+        }
+
+        self.process_line(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::types::body::LineBuilder;
+    use serde_json::json;
+
+    fn rule(regex: &str) -> ExtractRule {
+        ExtractRule {
+            regex: regex.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn process(p: &ExtractRules, builder: LineBuilder) -> LineBuilder {
+        let mut builder = builder;
+        match p.process(&mut builder) {
+            Status::Ok(_) => {}
+            Status::Skip => panic!("should not have been skipped"),
+        }
+        builder
+    }
+
+    #[test]
+    fn should_extract_into_meta() {
+        let p =
+            ExtractRules::new(&[rule(r"user=(?P<user_id>\w+) req=(?P<request_id>\S+)")]).unwrap();
+        let line = process(&p, LineBuilder::new().line("GET / user=abc req=a-1"));
+        assert_eq!(
+            line.meta,
+            Some(json!({"user_id": "abc", "request_id": "a-1"}))
+        );
+        assert_eq!(line.line.as_deref(), Some("GET / user=abc req=a-1"));
+
+        // Not matching lines are left untouched
+        let line = process(&p, LineBuilder::new().line("GET /"));
+        assert_eq!(line.meta, None);
+    }
+
+    #[test]
+    fn should_merge_with_existing_meta() {
+        let p = ExtractRules::new(&[rule(r"status=(?P<status>\d+)")]).unwrap();
+        let line = process(
+            &p,
+            LineBuilder::new()
+                .line("status=200")
+                .meta(json!({"existing": true})),
+        );
+        assert_eq!(line.meta, Some(json!({"existing": true, "status": "200"})));
+    }
+
+    #[test]
+    fn should_coerce_types() {
+        let mut r = rule(r"status=(?P<status>\w+) took=(?P<took>\S+) cached=(?P<cached>\w+)");
+        r.types.insert("status".into(), ExtractType::Int);
+        r.types.insert("took".into(), ExtractType::Float);
+        r.types.insert("cached".into(), ExtractType::Bool);
+        let p = ExtractRules::new(&[r]).unwrap();
+
+        let line = process(
+            &p,
+            LineBuilder::new().line("status=500 took=1.5 cached=false"),
+        );
+        assert_eq!(
+            line.meta,
+            Some(json!({"status": 500, "took": 1.5, "cached": false}))
+        );
+
+        // Values that can't be converted are omitted
+        let line = process(
+            &p,
+            LineBuilder::new().line("status=abc took=1.5 cached=true"),
+        );
+        assert_eq!(line.meta, Some(json!({"took": 1.5, "cached": true})));
+    }
+
+    #[test]
+    fn should_extract_into_labels() {
+        let mut r = rule(r"team=(?P<team>\w+)");
+        r.target = ExtractTarget::Labels;
+        let p = ExtractRules::new(&[r]).unwrap();
+
+        let line = process(&p, LineBuilder::new().line("team=core"));
+        assert_eq!(
+            line.labels.unwrap().get("team").map(|v| v.as_str()),
+            Some("core")
+        );
+        assert_eq!(line.meta, None);
+    }
+
+    #[test]
+    fn should_scope_rules_by_path_and_app() {
+        let mut by_path = rule(r"path_id=(?P<path_id>\d+)");
+        by_path.path = Some("/var/log/nginx/*.log".to_string());
+        let mut by_app = rule(r"app_id=(?P<app_id>\d+)");
+        by_app.app = Some("api".to_string());
+        let p = ExtractRules::new(&[by_path, by_app]).unwrap();
+
+        let line = process(
+            &p,
+            LineBuilder::new()
+                .line("path_id=1 app_id=2")
+                .file("/var/log/nginx/access.log")
+                .app("web"),
+        );
+        assert_eq!(line.meta, Some(json!({"path_id": "1"})));
+
+        let line = process(
+            &p,
+            LineBuilder::new()
+                .line("path_id=1 app_id=2")
+                .file("/var/log/other.log")
+                .app("api"),
+        );
+        assert_eq!(line.meta, Some(json!({"app_id": "2"})));
+    }
+}
@@ -0,0 +1,339 @@
+use crate::redact::{RedactError, Redactor};
+use crate::{Middleware, Status};
+use config::{FieldAction, FieldRule, FieldTarget};
+use globber::Pattern;
+use http::types::body::{KeyValueMap, LineBufferMut};
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+/// Used when a rule doesn't define a detector nor a regex
+static WHOLE_VALUE_REGEX: &str = r"(?s)^.+$";
+
+#[derive(Debug, Error)]
+pub enum FieldRulesError {
+    #[error("invalid key glob {0}: {1}")]
+    Glob(String, globber::Error),
+    #[error(transparent)]
+    Redact(#[from] RedactError),
+}
+
+enum Action {
+    Redact(Redactor),
+    Drop,
+}
+
+struct CompiledRule {
+    target: FieldTarget,
+    key: Pattern,
+    action: Action,
+}
+
+/// Redacts or drops the annotations, labels and meta fields of the lines by key.
+pub struct FieldRules {
+    rules: Vec<CompiledRule>,
+}
+
+impl FieldRules {
+    pub fn new(rules: &[FieldRule], hash_key: Option<&str>) -> Result<FieldRules, FieldRulesError> {
+        let mut compiled = Vec::with_capacity(rules.len());
+        for rule in rules.iter() {
+            let action = match rule.action {
+                FieldAction::Drop => Action::Drop,
+                FieldAction::Redact => {
+                    let mut redact = rule.redact.clone();
+                    if redact.detector.is_none() && redact.regex.is_none() {
+                        redact.regex = Some(WHOLE_VALUE_REGEX.to_string());
+                    }
+                    Action::Redact(Redactor::new(&redact, hash_key)?)
+                }
+            };
+            compiled.push(CompiledRule {
+                target: rule.target,
+                key: Pattern::new(&rule.key)
+                    .map_err(|e| FieldRulesError::Glob(rule.key.clone(), e))?,
+                action,
+            });
+        }
+        Ok(FieldRules { rules: compiled })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn has_target(&self, target: FieldTarget) -> bool {
+        self.rules.iter().any(|r| r.target == target)
+    }
+
+    /// Applies the rules of `target` to a key-value map, returns `None` when unchanged.
+    fn apply_map(&self, target: FieldTarget, map: &KeyValueMap) -> Option<KeyValueMap> {
+        let mut changed = false;
+        let mut result = KeyValueMap::new();
+        'entries: for (key, value) in map.iter() {
+            let mut value = value.clone();
+            for rule in self.rules.iter() {
+                if rule.target != target || !rule.key.matches(key) {
+                    continue;
+                }
+                match &rule.action {
+                    Action::Drop => {
+                        changed = true;
+                        continue 'entries;
+                    }
+                    Action::Redact(redactor) => {
+                        if let Some(redacted) = redactor.redact(value.as_bytes()) {
+                            changed = true;
+                            value = String::from_utf8_lossy(&redacted).into_owned();
+                        }
+                    }
+                }
+            }
+            result.insert(key.clone(), value);
+        }
+
+        if changed {
+            Some(result)
+        } else {
+            None
+        }
+    }
+
+    /// Applies the meta rules to the fields of an object, the nested fields are matched by
+    /// their dot separated path. Returns true when a field was modified.
+    fn apply_object(&self, prefix: &str, object: &mut Map<String, Value>) -> bool {
+        let mut changed = false;
+        let keys = object.keys().cloned().collect::<Vec<_>>();
+        for key in keys {
+            let path = if prefix.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", prefix, key)
+            };
+
+            let mut matched = false;
+            for rule in self.rules.iter() {
+                if rule.target != FieldTarget::Meta || !rule.key.matches(&path) {
+                    continue;
+                }
+                matched = true;
+                match &rule.action {
+                    Action::Drop => {
+                        object.remove(&key);
+                        changed = true;
+                        break;
+                    }
+                    Action::Redact(redactor) => {
+                        if let Some(value) = object.get_mut(&key) {
+                            changed |= redact_value(redactor, value);
+                        }
+                    }
+                }
+            }
+
+            if !matched {
+                if let Some(Value::Object(nested)) = object.get_mut(&key) {
+                    changed |= self.apply_object(&path, nested);
+                }
+            }
+        }
+        changed
+    }
+}
+
+/// Redacts a value and, for objects and arrays, all the values they contain.
+fn redact_value(redactor: &Redactor, value: &mut Value) -> bool {
+    match value {
+        Value::String(s) => match redactor.redact(s.as_bytes()) {
+            Some(redacted) => {
+                *s = String::from_utf8_lossy(&redacted).into_owned();
+                true
+            }
+            None => false,
+        },
+        Value::Number(_) | Value::Bool(_) => {
+            let text = value.to_string();
+            match redactor.redact(text.as_bytes()) {
+                Some(redacted) => {
+                    *value = Value::String(String::from_utf8_lossy(&redacted).into_owned());
+                    true
+                }
+                None => false,
+            }
+        }
+        Value::Array(values) => values
+            .iter_mut()
+            .fold(false, |changed, v| redact_value(redactor, v) || changed),
+        Value::Object(values) => values
+            .values_mut()
+            .fold(false, |changed, v| redact_value(redactor, v) || changed),
+        Value::Null => false,
+    }
+}
+
+impl Middleware for FieldRules {
+    fn run(&self) {}
+
+    fn process<'a>(&self, line: &'a mut dyn LineBufferMut) -> Status<&'a mut dyn LineBufferMut> {
+        if self.rules.is_empty() {
+            return Status::Ok(line);
+        }
+
+        if self.has_target(FieldTarget::Annotations) {
+            if let Some(annotations) = line
+                .get_annotations()
+                .and_then(|a| self.apply_map(FieldTarget::Annotations, a))
+            {
+                if line.set_annotations(annotations).is_err() {
+                    return Status::Skip;
+                }
+            }
+        }
+
+        if self.has_target(FieldTarget::Labels) {
+            if let Some(labels) = line
+                .get_labels()
+                .and_then(|l| self.apply_map(FieldTarget::Labels, l))
+            {
+                if line.set_labels(labels).is_err() {
+                    return Status::Skip;
+                }
+            }
+        }
+
+        if self.has_target(FieldTarget::Meta) {
+            if let Some(Value::Object(meta)) = line.get_meta() {
+                let mut meta = meta.clone();
+                if self.apply_object("", &mut meta) && line.set_meta(Value::Object(meta)).is_err() {
+                    return Status::Skip;
+                }
+            }
+        }
+
+        Status::Ok(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{RedactDetector, RedactRule, RedactStrategy};
+    use http::types::body::LineBuilder;
+    use serde_json::json;
+
+    fn rule(target: FieldTarget, key: &str, action: FieldAction) -> FieldRule {
+        FieldRule {
+            target,
+            key: key.to_string(),
+            action,
+            redact: RedactRule::default(),
+        }
+    }
+
+    fn process(p: &FieldRules, builder: LineBuilder) -> LineBuilder {
+        let mut builder = builder;
+        match p.process(&mut builder) {
+            Status::Ok(_) => {}
+            Status::Skip => panic!("should not have been skipped"),
+        }
+        builder
+    }
+
+    #[test]
+    fn should_redact_and_drop_annotations_and_labels() {
+        let p = FieldRules::new(
+            &[
+                rule(
+                    FieldTarget::Annotations,
+                    "kubectl.kubernetes.io/*",
+                    FieldAction::Drop,
+                ),
+                rule(FieldTarget::Labels, "*token*", FieldAction::Redact),
+            ],
+            None,
+        )
+        .unwrap();
+
+        let line = process(
+            &p,
+            LineBuilder::new()
+                .line("abc")
+                .annotations(
+                    KeyValueMap::new()
+                        .add(
+                            "kubectl.kubernetes.io/last-applied-configuration",
+                            "{\"secret\": \"value\"}",
+                        )
+                        .add("owner", "team-a"),
+                )
+                .labels(
+                    KeyValueMap::new()
+                        .add("api-token", "abc123")
+                        .add("app", "api"),
+                ),
+        );
+        assert_eq!(
+            line.annotations.unwrap(),
+            KeyValueMap::new().add("owner", "team-a")
+        );
+        assert_eq!(
+            line.labels.unwrap(),
+            KeyValueMap::new()
+                .add("api-token", "[REDACTED]")
+                .add("app", "api")
+        );
+    }
+
+    #[test]
+    fn should_redact_nested_meta() {
+        let mut email = rule(FieldTarget::Meta, "*.email", FieldAction::Redact);
+        email.redact = RedactRule {
+            detector: Some(RedactDetector::Email),
+            strategy: RedactStrategy::Partial,
+            keep_last: Some(3),
+            ..Default::default()
+        };
+        let p = FieldRules::new(
+            &[
+                email,
+                rule(FieldTarget::Meta, "password", FieldAction::Drop),
+                rule(FieldTarget::Meta, "card", FieldAction::Redact),
+            ],
+            None,
+        )
+        .unwrap();
+
+        let line = process(
+            &p,
+            LineBuilder::new().line("abc").meta(json!({
+                "user": {"email": "contact: a@b.com", "name": "a"},
+                "password": "hunter2",
+                "card": {"number": 4111111111111111u64, "cvv": "123"},
+                "other": 1
+            })),
+        );
+        assert_eq!(
+            line.meta,
+            Some(json!({
+                "user": {"email": "contact: *@*.com", "name": "a"},
+                "card": {"number": "[REDACTED]", "cvv": "[REDACTED]"},
+                "other": 1
+            }))
+        );
+    }
+
+    #[test]
+    fn should_leave_lines_without_matching_fields() {
+        let p = FieldRules::new(
+            &[rule(FieldTarget::Meta, "password", FieldAction::Drop)],
+            None,
+        )
+        .unwrap();
+        let line = process(
+            &p,
+            LineBuilder::new()
+                .line("abc")
+                .meta(json!({"user": {"password": "nested"}})),
+        );
+        assert_eq!(line.meta, Some(json!({"user": {"password": "nested"}})));
+    }
+}
@@ -0,0 +1,275 @@
+use crate::{Middleware, Status};
+use config::{LevelConfig, LogLevel};
+use http::types::body::LineBufferMut;
+use lazy_static::lazy_static;
+use regex::bytes::Regex;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::str;
+
+/// Only the start of the lines is searched for the text patterns
+const TEXT_PREFIX_LEN: usize = 512;
+
+/// JSON keys holding the level, in order of preference
+const JSON_LEVEL_KEYS: &[&str] = &["level", "severity", "lvl", "loglevel", "log.level"];
+
+lazy_static! {
+    static ref REGEX_CRI_PREFIX: Regex =
+        Regex::new(r"^[0-9]{4}-[0-9]{2}-[0-9]{2}T\S+ (?P<stream>stdout|stderr) [PF] ").unwrap();
+    static ref REGEX_GLOG: Regex =
+        Regex::new(r"^(?P<level>[IWEF])[0-9]{4} [0-9]{2}:[0-9]{2}:[0-9]{2}").unwrap();
+    static ref REGEX_KEY_VALUE: Regex =
+        Regex::new(r#"(?i)\b(?:level|lvl|severity)\s*[=:]\s*"?(?P<level>[a-z]+)"#).unwrap();
+    static ref REGEX_BRACKETED: Regex = Regex::new(
+        r"(?i)[\[<(]\s*(?P<level>trace|debug|info|notice|warn(?:ing)?|err(?:or)?|crit(?:ical)?|fatal|alert|emerg(?:ency)?|panic)\s*[\]>)]"
+    )
+    .unwrap();
+    static ref REGEX_UPPERCASE: Regex = Regex::new(
+        r"\b(?P<level>TRACE|DEBUG|INFO|NOTICE|WARN(?:ING)?|ERROR|CRIT(?:ICAL)?|FATAL|ALERT|EMERG(?:ENCY)?|PANIC)\b"
+    )
+    .unwrap();
+}
+
+/// Sets the level of the lines that don't have one from common patterns in their content
+/// and drops the lines below the minimum level of their app.
+pub struct LevelDetection {
+    detection: bool,
+    stderr_as_error: bool,
+    min_level: Option<LogLevel>,
+    min_level_by_app: HashMap<String, LogLevel>,
+}
+
+impl LevelDetection {
+    pub fn new(config: &LevelConfig) -> LevelDetection {
+        LevelDetection {
+            detection: config.detection,
+            stderr_as_error: config.stderr_as_error,
+            min_level: config.min_level,
+            min_level_by_app: config.min_level_by_app.clone(),
+        }
+    }
+
+    /// Returns false when the middleware would leave every line untouched.
+    pub fn is_active(&self) -> bool {
+        self.detection || self.min_level.is_some() || !self.min_level_by_app.is_empty()
+    }
+
+    fn min_level_for(&self, app: Option<&str>) -> Option<LogLevel> {
+        app.and_then(|app| self.min_level_by_app.get(app))
+            .copied()
+            .or(self.min_level)
+    }
+}
+
+/// Infers the level from the content of a line, container runtime prefixes are skipped.
+pub fn detect_level(line: &[u8], stderr_as_error: bool) -> Option<LogLevel> {
+    let (mut message, mut stream) = match REGEX_CRI_PREFIX.captures(line) {
+        Some(c) => (
+            &line[c.get(0).unwrap().end()..],
+            c.name("stream").map(|s| s.as_bytes()),
+        ),
+        None => (line, None),
+    };
+
+    let mut json = parse_json(message);
+
+    // Docker json-file lines wrap the message of the container
+    let docker = json.as_ref().and_then(|v| match (&v["log"], &v["stream"]) {
+        (Value::String(log), Value::String(s)) => Some((log.clone(), s.clone())),
+        _ => None,
+    });
+    if let Some((log, s)) = &docker {
+        message = log.as_bytes();
+        stream = Some(s.as_bytes());
+        json = parse_json(message);
+    }
+
+    let level = match json {
+        Some(v) => json_level(&v),
+        None => text_level(&message[..message.len().min(TEXT_PREFIX_LEN)]),
+    };
+
+    level.or_else(|| {
+        if stderr_as_error && stream == Some(&b"stderr"[..]) {
+            Some(LogLevel::Error)
+        } else {
+            None
+        }
+    })
+}
+
+fn parse_json(message: &[u8]) -> Option<Value> {
+    if message.first() == Some(&b'{') {
+        serde_json::from_slice(message).ok()
+    } else {
+        None
+    }
+}
+
+fn json_level(value: &Value) -> Option<LogLevel> {
+    JSON_LEVEL_KEYS.iter().find_map(|key| match &value[*key] {
+        Value::String(s) => s.parse().ok(),
+        // Numeric levels used by bunyan and pino
+        Value::Number(n) => match n.as_u64()? {
+            0..=10 => Some(LogLevel::Trace),
+            11..=20 => Some(LogLevel::Debug),
+            21..=30 => Some(LogLevel::Info),
+            31..=40 => Some(LogLevel::Warn),
+            41..=50 => Some(LogLevel::Error),
+            _ => Some(LogLevel::Critical),
+        },
+        _ => None,
+    })
+}
+
+fn text_level(text: &[u8]) -> Option<LogLevel> {
+    if let Some(c) = REGEX_GLOG.captures(text) {
+        return match c.name("level").map(|l| l.as_bytes()) {
+            Some(b"I") => Some(LogLevel::Info),
+            Some(b"W") => Some(LogLevel::Warn),
+            Some(b"E") => Some(LogLevel::Error),
+            Some(b"F") => Some(LogLevel::Critical),
+            _ => None,
+        };
+    }
+
+    [&*REGEX_KEY_VALUE, &*REGEX_BRACKETED, &*REGEX_UPPERCASE]
+        .iter()
+        .find_map(|regex| {
+            regex
+                .captures(text)
+                .and_then(|c| c.name("level"))
+                .and_then(|l| str::from_utf8(l.as_bytes()).ok())
+                .and_then(|l| l.parse().ok())
+        })
+}
+
+impl Middleware for LevelDetection {
+    fn run(&self) {}
+
+    fn process<'a>(&self, line: &'a mut dyn LineBufferMut) -> Status<&'a mut dyn LineBufferMut> {
+        let mut level = line.get_level().and_then(|l| l.parse::<LogLevel>().ok());
+
+        if line.get_level().is_none() && self.detection {
+            level = line
+                .get_line_buffer()
+                .and_then(|b| detect_level(b, self.stderr_as_error));
+            if let Some(level) = level {
+                if line.set_level(level.to_string()).is_err() {
+                    return Status::Skip;
+                }
+            }
+        }
+
+        // Lines without a known level are always sent
+        match (level, self.min_level_for(line.get_app())) {
+            (Some(level), Some(min_level)) if level < min_level => Status::Skip,
+            _ => Status::Ok(line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::types::body::LineBuilder;
+
+    fn detect(line: &str) -> Option<LogLevel> {
+        detect_level(line.as_bytes(), false)
+    }
+
+    #[test]
+    fn should_detect_text_levels() {
+        assert_eq!(detect("2022-01-02 ERROR failed"), Some(LogLevel::Error));
+        assert_eq!(detect("[warn] disk is almost full"), Some(LogLevel::Warn));
+        assert_eq!(detect("ts=1 level=info msg=ok"), Some(LogLevel::Info));
+        assert_eq!(detect("<Debug> connecting"), Some(LogLevel::Debug));
+        assert_eq!(
+            detect("E0102 15:04:05.123456   1 main.go:10] failed"),
+            Some(LogLevel::Error)
+        );
+        assert_eq!(
+            detect("W0102 15:04:05.123456   1 main.go:10] slow"),
+            Some(LogLevel::Warn)
+        );
+        assert_eq!(detect("nothing to see here"), None);
+        // Lowercase words are too common to be considered
+        assert_eq!(detect("no error found"), None);
+    }
+
+    #[test]
+    fn should_detect_json_levels() {
+        assert_eq!(
+            detect(r#"{"severity":"WARNING","message":"slow"}"#),
+            Some(LogLevel::Warn)
+        );
+        assert_eq!(
+            detect(r#"{"level":50,"msg":"failed"}"#),
+            Some(LogLevel::Error)
+        );
+        // The content of the other fields is ignored
+        assert_eq!(detect(r#"{"message":"ERROR in the text"}"#), None);
+    }
+
+    #[test]
+    fn should_detect_container_levels() {
+        let cri = "2022-01-02T15:04:05.123456789Z stderr F [error] failed";
+        assert_eq!(detect(cri), Some(LogLevel::Error));
+
+        let cri = "2022-01-02T15:04:05.123456789Z stderr F failed";
+        assert_eq!(detect_level(cri.as_bytes(), false), None);
+        assert_eq!(detect_level(cri.as_bytes(), true), Some(LogLevel::Error));
+
+        let cri = "2022-01-02T15:04:05.123456789Z stdout F INFO started";
+        assert_eq!(detect_level(cri.as_bytes(), true), Some(LogLevel::Info));
+
+        let docker = r#"{"log":"{\"level\":\"debug\"}\n","stream":"stdout","time":"2022-01-02"}"#;
+        assert_eq!(detect(docker), Some(LogLevel::Debug));
+
+        let docker = r#"{"log":"failed\n","stream":"stderr","time":"2022-01-02"}"#;
+        assert_eq!(detect_level(docker.as_bytes(), true), Some(LogLevel::Error));
+    }
+
+    #[test]
+    fn should_set_detected_level() {
+        let p = LevelDetection::new(&LevelConfig {
+            detection: true,
+            ..Default::default()
+        });
+
+        let mut line = LineBuilder::new().line("ERROR failed");
+        assert!(matches!(p.process(&mut line), Status::Ok(_)));
+        assert_eq!(line.level.as_deref(), Some("ERROR"));
+
+        // The level set by the source is kept
+        let mut line = LineBuilder::new().line("ERROR failed").level("INFO");
+        assert!(matches!(p.process(&mut line), Status::Ok(_)));
+        assert_eq!(line.level.as_deref(), Some("INFO"));
+    }
+
+    #[test]
+    fn should_drop_lines_below_min_level() {
+        let mut config = LevelConfig {
+            detection: true,
+            min_level: Some(LogLevel::Info),
+            ..Default::default()
+        };
+        config
+            .min_level_by_app
+            .insert("nginx".to_string(), LogLevel::Warn);
+        let p = LevelDetection::new(&config);
+
+        let sent = |line: &str, app: &str| {
+            matches!(
+                p.process(&mut LineBuilder::new().line(line).app(app)),
+                Status::Ok(_)
+            )
+        };
+        assert!(!sent("DEBUG connecting", "api"));
+        assert!(sent("INFO connected", "api"));
+        assert!(!sent("INFO connected", "nginx"));
+        assert!(sent("WARN slow", "nginx"));
+        // Lines without level are sent
+        assert!(sent("connected", "nginx"));
+    }
+}
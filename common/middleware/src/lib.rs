@@ -3,8 +3,16 @@ use std::sync::Arc;
 use http::types::body::LineBufferMut;
 use std::thread::spawn;
 
+pub mod dir_meta;
+pub mod extract_rules;
+pub mod field_rules;
+pub mod level_detection;
 pub mod line_rules;
+pub mod line_script;
 pub mod meta_rules;
+pub mod metric_rules;
+pub mod redact;
+pub mod wasm_plugin;
 
 pub enum Status<T> {
     Ok(T),
@@ -1,21 +1,23 @@
+use crate::redact::{RedactError, Redactor};
 use crate::{Middleware, Status};
+use config::RedactRule;
 use http::types::body::LineBufferMut;
 use regex::bytes::{Regex, RegexSet};
 use std::cmp;
 use thiserror::Error;
 
-static REDACT_BYTES: &[u8] = b"[REDACTED]";
-
 pub struct LineRules {
     exclusion: RegexSet,
     inclusion: RegexSet,
-    redact: Vec<Regex>,
+    redact: Vec<Redactor>,
 }
 
 #[derive(Clone, Debug, Error)]
 pub enum LineRulesError {
     #[error(transparent)]
     RegexError(regex::Error),
+    #[error(transparent)]
+    RedactError(RedactError),
 }
 
 impl LineRules {
@@ -27,7 +29,9 @@ impl LineRules {
         // Use a normal foreach to bubble up parsing errors
         let mut redact_vec = Vec::with_capacity(redact.len());
         for s in redact.iter() {
-            redact_vec.push(Regex::new(s).map_err(LineRulesError::RegexError)?);
+            redact_vec.push(Redactor::constant(
+                Regex::new(s).map_err(LineRulesError::RegexError)?,
+            ));
         }
 
         Ok(LineRules {
@@ -37,6 +41,20 @@ impl LineRules {
         })
     }
 
+    /// Adds redaction rules using built-in detectors and replacement strategies, they are
+    /// applied after the regex patterns.
+    pub fn with_redact_rules(
+        mut self,
+        rules: &[RedactRule],
+        hash_key: Option<&str>,
+    ) -> Result<LineRules, LineRulesError> {
+        for rule in rules.iter() {
+            self.redact
+                .push(Redactor::new(rule, hash_key).map_err(LineRulesError::RedactError)?);
+        }
+        Ok(self)
+    }
+
     /// Applies inclusion and exclusion rules and replaces the redacted values.
     fn process_line<'a>(
         &self,
@@ -66,30 +84,40 @@ impl LineRules {
         value: Vec<u8>,
         line: &'a mut dyn LineBufferMut,
     ) -> Status<&'a mut dyn LineBufferMut> {
-        let mut matches: Vec<(usize, usize)> = vec![];
-        for r in self.redact.iter() {
-            for m in r.find_iter(&value) {
+        // The ranges to redact along with the index of the redactor used for the replacement
+        // and the length of the widest match merged into the range, which picks that redactor
+        let mut matches: Vec<(usize, usize, usize, usize)> = vec![];
+        for (r_index, r) in self.redact.iter().enumerate() {
+            for m in r.find(&value) {
                 let mut overlapping_match = None;
                 let mut insert_index = None;
                 for (i, existing) in matches.iter().enumerate() {
                     let overlaps =
                         // Overlaps when it starts between an existing match
-                        (m.start() >= existing.0 && m.start() <= existing.1)
+                        (m.0 >= existing.0 && m.0 <= existing.1)
                         // or it starts before an existing match
                         // and ends after the existing match end
-                        || (m.start() <= existing.0 && m.end() >= existing.0);
+                        || (m.0 <= existing.0 && m.1 >= existing.0);
 
                     if overlaps {
+                        // The replacement of the redactor with the widest match is kept
+                        let (r_index, width) = if m.1 - m.0 > existing.3 {
+                            (r_index, m.1 - m.0)
+                        } else {
+                            (existing.2, existing.3)
+                        };
                         overlapping_match = Some((
                             i,
-                            cmp::min(existing.0, m.start()),
-                            cmp::max(existing.1, m.end()),
+                            cmp::min(existing.0, m.0),
+                            cmp::max(existing.1, m.1),
+                            r_index,
+                            width,
                         ));
                         // Order is guaranteed so there's no need to continue processing
                         break;
                     }
 
-                    if m.start() < existing.0 {
+                    if m.0 < existing.0 {
                         insert_index = Some(i);
                         // Order is guaranteed so there's no need to continue processing
                         break;
@@ -98,13 +126,13 @@ impl LineRules {
 
                 if let Some(item) = overlapping_match {
                     // Replace existing
-                    matches[item.0] = (item.1, item.2);
+                    matches[item.0] = (item.1, item.2, item.3, item.4);
                 } else if let Some(index) = insert_index {
                     // Insert at position and shift all elements after it to the right
-                    matches.insert(index, (m.start(), m.end()));
+                    matches.insert(index, (m.0, m.1, r_index, m.1 - m.0));
                 } else {
                     // Append
-                    matches.push((m.start(), m.end()));
+                    matches.push((m.0, m.1, r_index, m.1 - m.0));
                 }
             }
         }
@@ -117,7 +145,7 @@ impl LineRules {
         let mut index = 0;
         for item in matches {
             redacted.extend_from_slice(&value[index..item.0]);
-            redacted.extend_from_slice(REDACT_BYTES);
+            redacted.extend_from_slice(&self.redact[item.2].replace(&value[item.0..item.1]));
             index = item.1;
         }
 
@@ -328,6 +356,71 @@ mod tests {
         );
     }
 
+    #[test]
+    fn should_redact_with_detectors_and_strategies() {
+        use config::{RedactDetector, RedactStrategy};
+
+        let rules = vec![
+            RedactRule {
+                detector: Some(RedactDetector::Email),
+                strategy: RedactStrategy::Hash,
+                ..Default::default()
+            },
+            RedactRule {
+                detector: Some(RedactDetector::CreditCard),
+                strategy: RedactStrategy::Partial,
+                ..Default::default()
+            },
+        ];
+        let p = LineRules::new(&[], &[], &[s!("(?i:SENSITIVE)")])
+            .unwrap()
+            .with_redact_rules(&rules, Some("key"))
+            .unwrap();
+
+        let redacted = match p.process(
+            &mut LineBuilder::new()
+                .line("SENSITIVE a@example.com paid with 4111 1111 1111 1111, a@example.com"),
+        ) {
+            Status::Ok(l) => String::from_utf8(l.get_line_buffer().unwrap().to_vec()).unwrap(),
+            Status::Skip => panic!("should not have been skipped"),
+        };
+        let parts = redacted.split(' ').collect::<Vec<_>>();
+        assert_eq!(parts[0], "[REDACTED]");
+        assert!(parts[1].starts_with("[REDACTED:"));
+        assert_eq!(parts[4..8].join(" "), "**** **** **** 1111,");
+        // The same value is always replaced by the same token
+        assert_eq!(parts[1], parts[8]);
+
+        assert!(matches!(
+            LineRules::new(&[], &[], &[])
+                .unwrap()
+                .with_redact_rules(&rules, None),
+            Err(LineRulesError::RedactError(_))
+        ));
+    }
+
+    #[test]
+    fn should_redact_overlaps_with_the_widest_match_strategy() {
+        use config::{RedactDetector, RedactStrategy};
+
+        let rules = vec![RedactRule {
+            detector: Some(RedactDetector::CreditCard),
+            strategy: RedactStrategy::Partial,
+            ..Default::default()
+        }];
+        let p = LineRules::new(&[], &[], &[s!("1111 1111")])
+            .unwrap()
+            .with_redact_rules(&rules, None)
+            .unwrap();
+
+        let redacted =
+            match p.process(&mut LineBuilder::new().line("paid with 4111 1111 1111 1111")) {
+                Status::Ok(l) => String::from_utf8(l.get_line_buffer().unwrap().to_vec()).unwrap(),
+                Status::Skip => panic!("should not have been skipped"),
+            };
+        assert_eq!(redacted, "paid with **** **** **** 1111");
+    }
+
     #[test]
     fn should_apply_rules_and_redact_lines() {
         let redact = &vec![s!("(?i:SENSITIVE)")];
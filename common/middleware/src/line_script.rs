@@ -0,0 +1,381 @@
+use crate::{Middleware, Status};
+use config::ScriptConfig;
+use http::types::body::{KeyValueMap, LineBufferMut};
+use log::{debug, error, info, warn};
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_ARRAY_SIZE: usize = 10_000;
+const MAX_MAP_SIZE: usize = 10_000;
+
+#[derive(Debug, Error)]
+pub enum LineScriptError {
+    #[error("failed to read script {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("failed to compile script: {0}")]
+    Compile(String),
+}
+
+struct CompiledScript {
+    modified: Option<SystemTime>,
+    ast: Arc<AST>,
+}
+
+/// Runs a sandboxed [Rhai](https://rhai.rs) script for each line.
+///
+/// The script scope exposes the `line`, `app`, `host`, `env`, `file`, `level`, `meta`,
+/// `labels` and `annotations` variables, any change made to them is applied to the line.
+/// The line is skipped when the script evaluates to `false`.
+pub struct LineScript {
+    engine: Engine,
+    path: Option<PathBuf>,
+    script: RwLock<CompiledScript>,
+}
+
+impl LineScript {
+    /// Loads the script defined in the config, the file is watched for changes in `run`.
+    pub fn new(config: &ScriptConfig) -> Result<LineScript, LineScriptError> {
+        let engine = create_engine(config);
+        let modified = modified_time(&config.path);
+        let source = std::fs::read_to_string(&config.path)
+            .map_err(|e| LineScriptError::Io(config.path.clone(), e))?;
+        let ast = compile(&engine, &source)?;
+        Ok(LineScript {
+            engine,
+            path: Some(config.path.clone()),
+            script: RwLock::new(CompiledScript {
+                modified,
+                ast: Arc::new(ast),
+            }),
+        })
+    }
+
+    /// Creates an instance from the script source, without reloading.
+    pub fn from_source(source: &str, config: &ScriptConfig) -> Result<LineScript, LineScriptError> {
+        let engine = create_engine(config);
+        let ast = compile(&engine, source)?;
+        Ok(LineScript {
+            engine,
+            path: None,
+            script: RwLock::new(CompiledScript {
+                modified: None,
+                ast: Arc::new(ast),
+            }),
+        })
+    }
+
+    /// Recompiles the script when the file was modified since it was last loaded.
+    /// When the new version fails to compile, the previous version is kept.
+    fn reload(&self) {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return,
+        };
+
+        let modified = modified_time(path);
+        if modified.is_none() || modified == self.script.read().unwrap().modified {
+            return;
+        }
+
+        let result = std::fs::read_to_string(path)
+            .map_err(|e| LineScriptError::Io(path.clone(), e))
+            .and_then(|source| compile(&self.engine, &source));
+
+        let mut script = self.script.write().unwrap();
+        // Avoid retrying on every interval when the file is invalid
+        script.modified = modified;
+        match result {
+            Ok(ast) => {
+                script.ast = Arc::new(ast);
+                info!("reloaded line script {}", path.display());
+            }
+            Err(e) => error!("line script was not reloaded: {}", e),
+        }
+    }
+
+    /// Evaluates the script and applies its changes, returns false when the line should be
+    /// skipped.
+    fn process_line(&self, line: &mut dyn LineBufferMut) -> Result<bool, Box<EvalAltResult>> {
+        let ast = self.script.read().unwrap().ast.clone();
+
+        let value = match line.get_line_buffer() {
+            Some(v) => String::from_utf8_lossy(v).into_owned(),
+            // Nothing for the script to work on, the line is kept as is
+            None => return Ok(true),
+        };
+        let app = line.get_app().map(String::from);
+        let host = line.get_host().map(String::from);
+        let env = line.get_env().map(String::from);
+        let file = line.get_file().map(String::from);
+        let level = line.get_level().map(String::from);
+        let labels = line.get_labels().map(to_pairs).unwrap_or_default();
+        let annotations = line.get_annotations().map(to_pairs).unwrap_or_default();
+        let meta = match line.get_meta() {
+            Some(v) => rhai::serde::to_dynamic(v)?,
+            None => Dynamic::UNIT,
+        };
+
+        let mut scope = Scope::new();
+        scope.push("line", value.clone());
+        scope.push("app", to_dynamic(&app));
+        scope.push("host", to_dynamic(&host));
+        scope.push("env", to_dynamic(&env));
+        scope.push("file", to_dynamic(&file));
+        scope.push("level", to_dynamic(&level));
+        scope.push("labels", to_map(&labels));
+        scope.push("annotations", to_map(&annotations));
+        scope.push("meta", meta);
+
+        let result = self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, &ast)?;
+        if let Ok(false) = result.as_bool() {
+            return Ok(false);
+        }
+
+        // Convert the meta before applying any change to avoid partially modified lines
+        let new_meta = match scope.get_value::<Dynamic>("meta") {
+            Some(v) if !v.is::<()>() => Some(rhai::serde::from_dynamic::<serde_json::Value>(&v)?),
+            _ => None,
+        };
+
+        // Only apply the fields that were changed by the script
+        if let Some(new_value) = scope.get_value::<String>("line") {
+            if new_value != value && line.set_line_buffer(new_value.into_bytes()).is_err() {
+                return Ok(false);
+            }
+        }
+
+        macro_rules! apply_field {
+            ($name: expr, $original: expr, $setter: ident) => {
+                if let Some(new_value) = scope.get_value::<String>($name) {
+                    if Some(&new_value) != $original.as_ref() && line.$setter(new_value).is_err() {
+                        warn!("line script failed to set {}", $name);
+                    }
+                }
+            };
+        }
+
+        apply_field!("app", app, set_app);
+        apply_field!("host", host, set_host);
+        apply_field!("env", env, set_env);
+        apply_field!("file", file, set_file);
+        apply_field!("level", level, set_level);
+
+        if let Some(new_labels) = scope.get_value::<Map>("labels").map(|m| map_to_pairs(&m)) {
+            if new_labels != labels && line.set_labels(to_key_value_map(new_labels)).is_err() {
+                warn!("line script failed to set labels");
+            }
+        }
+
+        if let Some(new_annotations) = scope
+            .get_value::<Map>("annotations")
+            .map(|m| map_to_pairs(&m))
+        {
+            if new_annotations != annotations
+                && line
+                    .set_annotations(to_key_value_map(new_annotations))
+                    .is_err()
+            {
+                warn!("line script failed to set annotations");
+            }
+        }
+
+        if let Some(new_meta) = new_meta {
+            if line.get_meta() != Some(&new_meta) && line.set_meta(new_meta).is_err() {
+                warn!("line script failed to set meta");
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl Middleware for LineScript {
+    fn run(&self) {
+        if self.path.is_none() {
+            return;
+        }
+
+        loop {
+            sleep(RELOAD_INTERVAL);
+            self.reload();
+        }
+    }
+
+    fn process<'a>(&self, line: &'a mut dyn LineBufferMut) -> Status<&'a mut dyn LineBufferMut> {
+        match self.process_line(line) {
+            Ok(true) => Status::Ok(line),
+            Ok(false) => Status::Skip,
+            Err(e) => {
+                warn!("line script failed, forwarding the line: {}", e);
+                Status::Ok(line)
+            }
+        }
+    }
+}
+
+fn create_engine(config: &ScriptConfig) -> Engine {
+    let mut engine = Engine::new();
+    // Sandbox: limit the work and memory per call and disable the loading of other files
+    engine.set_max_operations(config.max_operations);
+    engine.set_max_string_size(config.max_string_size);
+    engine.set_max_array_size(MAX_ARRAY_SIZE);
+    engine.set_max_map_size(MAX_MAP_SIZE);
+    engine.set_module_resolver(DummyModuleResolver::new());
+    engine.on_print(|s| debug!("line script: {}", s));
+    engine.on_debug(|s, _, _| debug!("line script: {}", s));
+    engine
+}
+
+fn compile(engine: &Engine, source: &str) -> Result<AST, LineScriptError> {
+    engine
+        .compile(source)
+        .map_err(|e| LineScriptError::Compile(e.to_string()))
+}
+
+fn modified_time(path: &std::path::Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn to_dynamic(value: &Option<String>) -> Dynamic {
+    match value {
+        Some(v) => Dynamic::from(v.clone()),
+        None => Dynamic::UNIT,
+    }
+}
+
+fn to_pairs(kvm: &KeyValueMap) -> BTreeMap<String, String> {
+    kvm.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+}
+
+fn to_map(pairs: &BTreeMap<String, String>) -> Map {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.as_str().into(), Dynamic::from(v.clone())))
+        .collect()
+}
+
+fn map_to_pairs(map: &Map) -> BTreeMap<String, String> {
+    map.iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+fn to_key_value_map(pairs: BTreeMap<String, String>) -> KeyValueMap {
+    let mut kvm = KeyValueMap::new();
+    for (k, v) in pairs.into_iter() {
+        kvm.insert(k, v);
+    }
+    kvm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::types::body::LineBuilder;
+    use tempfile::tempdir;
+
+    fn config(path: PathBuf) -> ScriptConfig {
+        ScriptConfig {
+            path,
+            max_operations: 10_000,
+            max_string_size: 1024,
+        }
+    }
+
+    fn script(source: &str) -> LineScript {
+        LineScript::from_source(source, &config(PathBuf::new())).unwrap()
+    }
+
+    #[test]
+    fn should_skip_lines() {
+        let p = script(r#"!line.contains("DEBUG")"#);
+        assert!(matches!(
+            p.process(&mut LineBuilder::new().line("INFO something")),
+            Status::Ok(_)
+        ));
+        assert!(matches!(
+            p.process(&mut LineBuilder::new().line("DEBUG something")),
+            Status::Skip
+        ));
+    }
+
+    #[test]
+    fn should_modify_line_and_meta() {
+        let p = script(
+            r#"
+            if line.starts_with("E ") {
+                level = "ERROR";
+                line = line.sub_string(2);
+            }
+            labels["team"] = "core";
+            "#,
+        );
+        let mut builder = LineBuilder::new().line("E failed").app("api");
+        match p.process(&mut builder) {
+            Status::Ok(_) => {}
+            Status::Skip => panic!("should not have been skipped"),
+        }
+        assert_eq!(builder.line.as_deref(), Some("failed"));
+        assert_eq!(builder.level.as_deref(), Some("ERROR"));
+        assert_eq!(builder.app.as_deref(), Some("api"));
+        assert_eq!(
+            builder.labels.unwrap().get("team").map(|v| v.as_str()),
+            Some("core")
+        );
+    }
+
+    #[test]
+    fn should_forward_unmodified_lines_when_limits_are_reached() {
+        let p = script("loop { line += \"a\"; }");
+        let mut builder = LineBuilder::new().line("original");
+        assert!(matches!(p.process(&mut builder), Status::Ok(_)));
+        assert_eq!(builder.line.as_deref(), Some("original"));
+    }
+
+    #[test]
+    fn should_not_import_modules() {
+        let p = script(r#"import "other" as other; line = "modified";"#);
+        let mut builder = LineBuilder::new().line("original");
+        assert!(matches!(p.process(&mut builder), Status::Ok(_)));
+        assert_eq!(builder.line.as_deref(), Some("original"));
+    }
+
+    #[test]
+    fn should_reload_modified_script() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("script.rhai");
+        std::fs::write(&path, r#"line = "first";"#).unwrap();
+        let p = LineScript::new(&config(path.clone())).unwrap();
+
+        let mut builder = LineBuilder::new().line("original");
+        p.process(&mut builder);
+        assert_eq!(builder.line.as_deref(), Some("first"));
+
+        std::fs::write(&path, r#"line = "second";"#).unwrap();
+        // Make sure the change is detected regardless of the timestamp granularity
+        p.script.write().unwrap().modified = None;
+        p.reload();
+
+        let mut builder = LineBuilder::new().line("original");
+        p.process(&mut builder);
+        assert_eq!(builder.line.as_deref(), Some("second"));
+
+        // An invalid script keeps the previous version
+        std::fs::write(&path, "line = ").unwrap();
+        p.script.write().unwrap().modified = None;
+        p.reload();
+
+        let mut builder = LineBuilder::new().line("original");
+        p.process(&mut builder);
+        assert_eq!(builder.line.as_deref(), Some("second"));
+    }
+}
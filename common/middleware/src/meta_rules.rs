@@ -139,16 +139,16 @@ impl MetaRules {
         //
         let mut meta_map = HashMap::new();
         for (k, v) in self.env_map.iter() {
-            meta_map.insert(MAP_PREFIX_ENV.to_string() + k, v.clone());
+            meta_map.insert(MAP_PREFIX_ENV.to_string() + k.as_str(), v.clone());
         }
         if let Some(annotations) = line.get_annotations() {
             for (k, v) in annotations.iter() {
-                meta_map.insert(MAP_PREFIX_ANN.to_string() + k, v.clone());
+                meta_map.insert(MAP_PREFIX_ANN.to_string() + k.as_str(), v.clone());
             }
         }
         if let Some(labels) = line.get_labels() {
             for (k, v) in labels.iter() {
-                meta_map.insert(MAP_PREFIX_LAB.to_string() + k, v.clone());
+                meta_map.insert(MAP_PREFIX_LAB.to_string() + k.as_str(), v.clone());
             }
         }
         line.get_app()
@@ -179,7 +179,7 @@ impl MetaRules {
             });
             for (k, v) in over_annotations.iter() {
                 let v = substitute(v, &meta_map);
-                meta_map.insert(MAP_PREFIX_ANN.to_string() + k, v.clone()); // insert "with override"
+                meta_map.insert(MAP_PREFIX_ANN.to_string() + k.as_str(), v.clone()); // insert "with override"
                 if v.is_empty() {
                     new_annotations = new_annotations.remove(&k.clone());
                 } else {
@@ -199,7 +199,7 @@ impl MetaRules {
             });
             for (k, v) in over_labels.iter() {
                 let v = substitute(v, &meta_map);
-                meta_map.insert(MAP_PREFIX_LAB.to_string() + k, v.clone());
+                meta_map.insert(MAP_PREFIX_LAB.to_string() + k.as_str(), v.clone());
                 if v.is_empty() {
                     new_labels = new_labels.remove(&k.clone());
                 } else {
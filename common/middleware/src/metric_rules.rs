@@ -0,0 +1,383 @@
+use crate::{Middleware, Status};
+use config::{MetricRule, MetricRuleType};
+use http::types::body::LineBufferMut;
+use log::warn;
+use prometheus::{CounterVec, HistogramOpts, HistogramVec, Opts, Registry};
+use regex::bytes::Regex;
+use std::collections::HashSet;
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use thiserror::Error;
+
+/// Default maximum number of label value combinations tracked per rule
+pub const DEFAULT_MAX_CARDINALITY: usize = 1000;
+
+#[derive(Debug, Error)]
+pub enum MetricRulesError {
+    #[error("invalid regex for metric {0}: {1}")]
+    Regex(String, regex::Error),
+    #[error("metric {0} uses label {1} which is not a capture group nor a line field")]
+    UnknownLabel(String, String),
+    #[error("metric {0} value {1} is not a capture group")]
+    UnknownValue(String, String),
+    #[error("histogram metric {0} requires a value capture group")]
+    MissingValue(String),
+    #[error(transparent)]
+    Prometheus(#[from] prometheus::Error),
+}
+
+enum Collector {
+    Counter(CounterVec),
+    Histogram(HistogramVec),
+}
+
+enum LabelSource {
+    Capture(String),
+    App,
+    Host,
+    Env,
+    File,
+    Level,
+}
+
+struct CompiledRule {
+    name: String,
+    regex: Regex,
+    labels: Vec<LabelSource>,
+    value: Option<String>,
+    collector: Collector,
+    max_cardinality: usize,
+    label_values: Mutex<HashSet<Vec<String>>>,
+    overflow_reported: AtomicBool,
+}
+
+/// Derives counters and histograms from the content of the lines, the lines themselves
+/// are never modified nor skipped.
+pub struct MetricRules {
+    rules: Vec<CompiledRule>,
+}
+
+impl MetricRules {
+    /// Builds the rules and registers the resulting metrics in `registry`, usually
+    /// `prometheus::default_registry()` so that they are exposed on the metrics endpoint.
+    pub fn new(rules: &[MetricRule], registry: &Registry) -> Result<MetricRules, MetricRulesError> {
+        let mut compiled = Vec::with_capacity(rules.len());
+        for rule in rules.iter() {
+            compiled.push(CompiledRule::new(rule, registry)?);
+        }
+        Ok(MetricRules { rules: compiled })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+impl CompiledRule {
+    fn new(rule: &MetricRule, registry: &Registry) -> Result<CompiledRule, MetricRulesError> {
+        let regex =
+            Regex::new(&rule.regex).map_err(|e| MetricRulesError::Regex(rule.name.clone(), e))?;
+        let capture_names = regex.capture_names().flatten().collect::<HashSet<_>>();
+
+        let mut labels = Vec::with_capacity(rule.labels.len());
+        for label in rule.labels.iter() {
+            labels.push(if capture_names.contains(label.as_str()) {
+                LabelSource::Capture(label.clone())
+            } else {
+                match label.as_str() {
+                    "app" => LabelSource::App,
+                    "host" => LabelSource::Host,
+                    "env" => LabelSource::Env,
+                    "file" => LabelSource::File,
+                    "level" => LabelSource::Level,
+                    _ => {
+                        return Err(MetricRulesError::UnknownLabel(
+                            rule.name.clone(),
+                            label.clone(),
+                        ))
+                    }
+                }
+            });
+        }
+
+        if let Some(value) = &rule.value {
+            if !capture_names.contains(value.as_str()) {
+                return Err(MetricRulesError::UnknownValue(
+                    rule.name.clone(),
+                    value.clone(),
+                ));
+            }
+        }
+
+        let help = rule
+            .help
+            .clone()
+            .unwrap_or_else(|| format!("Derived from lines matching {}", rule.regex));
+        let label_names = rule.labels.iter().map(|l| l.as_str()).collect::<Vec<_>>();
+        let collector = match rule.metric_type {
+            MetricRuleType::Counter => {
+                let counter = CounterVec::new(Opts::new(rule.name.clone(), help), &label_names)?;
+                registry.register(Box::new(counter.clone()))?;
+                Collector::Counter(counter)
+            }
+            MetricRuleType::Histogram => {
+                if rule.value.is_none() {
+                    return Err(MetricRulesError::MissingValue(rule.name.clone()));
+                }
+                let mut opts = HistogramOpts::new(rule.name.clone(), help);
+                if let Some(buckets) = &rule.buckets {
+                    opts = opts.buckets(buckets.clone());
+                }
+                let histogram = HistogramVec::new(opts, &label_names)?;
+                registry.register(Box::new(histogram.clone()))?;
+                Collector::Histogram(histogram)
+            }
+        };
+
+        Ok(CompiledRule {
+            name: rule.name.clone(),
+            regex,
+            labels,
+            value: rule.value.clone(),
+            collector,
+            max_cardinality: rule.max_cardinality.unwrap_or(DEFAULT_MAX_CARDINALITY),
+            label_values: Mutex::new(HashSet::new()),
+            overflow_reported: AtomicBool::new(false),
+        })
+    }
+
+    fn observe(&self, line: &mut dyn LineBufferMut) {
+        let (label_values, value) = {
+            let buffer = match line.get_line_buffer() {
+                Some(b) => b,
+                None => return,
+            };
+            let captures = match self.regex.captures(buffer) {
+                Some(c) => c,
+                None => return,
+            };
+
+            let value = match &self.value {
+                Some(name) => match captures
+                    .name(name)
+                    .and_then(|m| str::from_utf8(m.as_bytes()).ok())
+                    .and_then(|v| v.trim().parse::<f64>().ok())
+                {
+                    Some(v) if v.is_finite() => Some(v),
+                    // The line matched but the value can not be used
+                    _ => return,
+                },
+                None => None,
+            };
+
+            let label_values = self
+                .labels
+                .iter()
+                .map(|source| match source {
+                    LabelSource::Capture(name) => captures
+                        .name(name)
+                        .map(|m| String::from_utf8_lossy(m.as_bytes()).into_owned())
+                        .unwrap_or_default(),
+                    _ => String::new(),
+                })
+                .collect::<Vec<_>>();
+            (label_values, value)
+        };
+
+        // Line fields are read once the line buffer borrow is released
+        let label_values = self
+            .labels
+            .iter()
+            .zip(label_values)
+            .map(|(source, captured)| {
+                match source {
+                    LabelSource::Capture(_) => Some(captured),
+                    LabelSource::App => line.get_app().map(String::from),
+                    LabelSource::Host => line.get_host().map(String::from),
+                    LabelSource::Env => line.get_env().map(String::from),
+                    LabelSource::File => line.get_file().map(String::from),
+                    LabelSource::Level => line.get_level().map(String::from),
+                }
+                .unwrap_or_default()
+            })
+            .collect::<Vec<_>>();
+
+        if !self.track_label_values(&label_values) {
+            return;
+        }
+
+        let label_values = label_values.iter().map(|v| v.as_str()).collect::<Vec<_>>();
+        match &self.collector {
+            Collector::Counter(counter) => {
+                let value = value.unwrap_or(1.0);
+                // Counters can only go up
+                if value >= 0.0 {
+                    counter.with_label_values(&label_values).inc_by(value);
+                }
+            }
+            Collector::Histogram(histogram) => {
+                if let Some(value) = value {
+                    histogram.with_label_values(&label_values).observe(value);
+                }
+            }
+        }
+    }
+
+    /// Returns false when the label values would exceed the cardinality cap of the rule.
+    fn track_label_values(&self, label_values: &[String]) -> bool {
+        if label_values.is_empty() {
+            return true;
+        }
+
+        let mut known = self.label_values.lock().unwrap();
+        if known.contains(label_values) {
+            return true;
+        }
+
+        if known.len() >= self.max_cardinality {
+            if !self.overflow_reported.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "metric {} reached its maximum cardinality of {}, new label values will be ignored",
+                    self.name, self.max_cardinality
+                );
+            }
+            return false;
+        }
+
+        known.insert(label_values.to_vec());
+        true
+    }
+}
+
+impl Middleware for MetricRules {
+    fn run(&self) {}
+
+    fn process<'a>(&self, line: &'a mut dyn LineBufferMut) -> Status<&'a mut dyn LineBufferMut> {
+        for rule in self.rules.iter() {
+            rule.observe(line);
+        }
+        Status::Ok(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::types::body::LineBuilder;
+
+    fn rule(name: &str, regex: &str) -> MetricRule {
+        MetricRule {
+            name: name.to_string(),
+            regex: regex.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn sample_count(registry: &Registry, name: &str) -> Vec<(Vec<String>, f64)> {
+        registry
+            .gather()
+            .into_iter()
+            .filter(|f| f.get_name() == name)
+            .flat_map(|f| f.get_metric().to_vec())
+            .map(|m| {
+                let labels = m
+                    .get_label()
+                    .iter()
+                    .map(|l| l.get_value().to_string())
+                    .collect();
+                let value = if m.has_histogram() {
+                    m.get_histogram().get_sample_count() as f64
+                } else {
+                    m.get_counter().get_value()
+                };
+                (labels, value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_count_matching_lines_without_modifying_them() {
+        let registry = Registry::new();
+        let mut r = rule("server_errors", r"status=(?P<status>5\d\d)");
+        r.labels = vec!["app".to_string(), "status".to_string()];
+        let p = MetricRules::new(&[r], &registry).unwrap();
+
+        for line in &["status=500", "status=200", "status=503", "status=500"] {
+            let mut builder = LineBuilder::new().line(*line).app("api");
+            match p.process(&mut builder) {
+                Status::Ok(l) => assert_eq!(l.get_line_buffer().unwrap(), line.as_bytes()),
+                Status::Skip => panic!("should not have been skipped"),
+            }
+        }
+
+        let mut samples = sample_count(&registry, "server_errors");
+        samples.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            samples,
+            vec![
+                (vec!["api".to_string(), "500".to_string()], 2.0),
+                (vec!["api".to_string(), "503".to_string()], 1.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_observe_captured_values() {
+        let registry = Registry::new();
+        let mut r = rule("duration_ms", r"duration_ms=(?P<duration>\d+)");
+        r.metric_type = MetricRuleType::Histogram;
+        r.value = Some("duration".to_string());
+        r.buckets = Some(vec![10.0, 100.0]);
+        let p = MetricRules::new(&[r], &registry).unwrap();
+
+        for line in &["duration_ms=5", "duration_ms=50", "no duration"] {
+            p.process(&mut LineBuilder::new().line(*line));
+        }
+
+        assert_eq!(sample_count(&registry, "duration_ms"), vec![(vec![], 2.0)]);
+    }
+
+    #[test]
+    fn should_cap_label_cardinality() {
+        let registry = Registry::new();
+        let mut r = rule("user_requests", r"user=(?P<user>\w+)");
+        r.labels = vec!["user".to_string()];
+        r.max_cardinality = Some(2);
+        let p = MetricRules::new(&[r], &registry).unwrap();
+
+        for line in &["user=a", "user=b", "user=c", "user=a"] {
+            p.process(&mut LineBuilder::new().line(*line));
+        }
+
+        let mut samples = sample_count(&registry, "user_requests");
+        samples.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            samples,
+            vec![(vec!["a".to_string()], 2.0), (vec!["b".to_string()], 1.0)]
+        );
+    }
+
+    #[test]
+    fn should_validate_rules() {
+        let registry = Registry::new();
+        let mut r = rule("invalid_label", r"status=(?P<status>\d+)");
+        r.labels = vec!["unknown".to_string()];
+        assert!(matches!(
+            MetricRules::new(&[r], &registry),
+            Err(MetricRulesError::UnknownLabel(_, _))
+        ));
+
+        let mut r = rule("missing_value", r"status=(?P<status>\d+)");
+        r.metric_type = MetricRuleType::Histogram;
+        assert!(matches!(
+            MetricRules::new(&[r], &registry),
+            Err(MetricRulesError::MissingValue(_))
+        ));
+
+        assert!(matches!(
+            MetricRules::new(&[rule("invalid_regex", r"(")], &registry),
+            Err(MetricRulesError::Regex(_, _))
+        ));
+    }
+}
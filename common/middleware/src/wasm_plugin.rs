@@ -0,0 +1,536 @@
+//! WebAssembly plugin middleware.
+//!
+//! A plugin is a WebAssembly module that exports:
+//! - `memory`: the linear memory of the module.
+//! - `alloc(len: i32) -> i32`: returns a pointer to `len` bytes where the agent writes the line.
+//! - `process(ptr: i32, len: i32) -> i32`: processes the line and returns [`KEEP`], [`DROP`]
+//!   or [`MODIFIED`].
+//! - `dealloc(ptr: i32, len: i32)` (optional): releases the memory returned by `alloc`.
+//!
+//! The agent provides the following functions in the `logdna` import module. Getters copy up to
+//! `out_cap` bytes of the value to `out_ptr` and return the length of the value, or -1 when not
+//! set. Setters return 0 on success and -1 on error, their changes are only applied when
+//! `process` returns [`MODIFIED`].
+//! - `get_field(name_ptr, name_len, out_ptr, out_cap) -> i32`: reads the `app`, `host`, `env`,
+//!   `file` or `level` of the line.
+//! - `get_label(key_ptr, key_len, out_ptr, out_cap) -> i32`: reads a k8s label of the line.
+//! - `get_annotation(key_ptr, key_len, out_ptr, out_cap) -> i32`: reads a k8s annotation.
+//! - `set_line(ptr, len) -> i32`: replaces the line content.
+//! - `set_field(name_ptr, name_len, value_ptr, value_len) -> i32`: sets one of the fields
+//!   available in `get_field`.
+use crate::{Middleware, Status};
+use http::types::body::{LineBufferMut, LineBuilder};
+use log::warn;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use thiserror::Error;
+use wasmtime::{
+    Caller, Config, Engine, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    TypedFunc,
+};
+
+pub const KEEP: i32 = 0;
+pub const DROP: i32 = 1;
+pub const MODIFIED: i32 = 2;
+
+const IMPORT_MODULE: &str = "logdna";
+const FIELDS: [&str; 5] = ["app", "host", "env", "file", "level"];
+
+#[derive(Debug, Error)]
+pub enum WasmPluginError {
+    #[error("failed to load wasm plugin {0}: {1}")]
+    Load(String, anyhow::Error),
+    #[error("failed to instantiate wasm plugin: {0}")]
+    Instantiate(anyhow::Error),
+}
+
+/// Line values exposed to the plugin and the changes it requested.
+#[derive(Default)]
+struct HostState {
+    fields: HashMap<&'static str, String>,
+    labels: HashMap<String, String>,
+    annotations: HashMap<String, String>,
+    new_line: Option<Vec<u8>>,
+    new_fields: Vec<(&'static str, String)>,
+}
+
+/// Data of the store of an instance, the limits are kept across lines.
+struct StoreState {
+    line: HostState,
+    limits: StoreLimits,
+}
+
+struct PluginInstance {
+    store: Store<StoreState>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    process: TypedFunc<(i32, i32), i32>,
+    dealloc: Option<TypedFunc<(i32, i32), ()>>,
+}
+
+pub struct WasmPlugin {
+    name: String,
+    engine: Engine,
+    module: Module,
+    linker: Linker<StoreState>,
+    fuel: u64,
+    max_memory: usize,
+    // Instances are not thread safe, it's recreated when a call traps
+    instance: Mutex<Option<PluginInstance>>,
+}
+
+impl WasmPlugin {
+    /// Loads the module from a `.wasm` (or `.wat`) file, each line can consume up to `fuel`
+    /// units of fuel and the linear memory can't grow past `max_memory` bytes.
+    pub fn new(path: &Path, fuel: u64, max_memory: usize) -> Result<WasmPlugin, WasmPluginError> {
+        let name = path.display().to_string();
+        let engine = create_engine().map_err(|e| WasmPluginError::Load(name.clone(), e))?;
+        let module =
+            Module::from_file(&engine, path).map_err(|e| WasmPluginError::Load(name.clone(), e))?;
+        WasmPlugin::with_module(name, engine, module, fuel, max_memory)
+    }
+
+    /// Creates a plugin from the module binary or text representation.
+    pub fn from_bytes(
+        name: &str,
+        bytes: impl AsRef<[u8]>,
+        fuel: u64,
+        max_memory: usize,
+    ) -> Result<WasmPlugin, WasmPluginError> {
+        let engine = create_engine().map_err(|e| WasmPluginError::Load(name.into(), e))?;
+        let module =
+            Module::new(&engine, bytes).map_err(|e| WasmPluginError::Load(name.into(), e))?;
+        WasmPlugin::with_module(name.into(), engine, module, fuel, max_memory)
+    }
+
+    fn with_module(
+        name: String,
+        engine: Engine,
+        module: Module,
+        fuel: u64,
+        max_memory: usize,
+    ) -> Result<WasmPlugin, WasmPluginError> {
+        let linker = create_linker(&engine).map_err(|e| WasmPluginError::Load(name.clone(), e))?;
+        let plugin = WasmPlugin {
+            name,
+            engine,
+            module,
+            linker,
+            fuel,
+            max_memory,
+            instance: Mutex::new(None),
+        };
+        // Validate the exports on startup rather than on the first line
+        let instance = plugin.instantiate()?;
+        *plugin.instance.lock().unwrap() = Some(instance);
+        Ok(plugin)
+    }
+
+    fn instantiate(&self) -> Result<PluginInstance, WasmPluginError> {
+        let mut store = Store::new(
+            &self.engine,
+            StoreState {
+                line: HostState::default(),
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(self.max_memory)
+                    .build(),
+            },
+        );
+        // Growing the memory past the limit fails, which usually makes the plugin trap
+        store.limiter(|state| &mut state.limits);
+        let instance = self
+            .linker
+            .instantiate(&mut store, &self.module)
+            .map_err(WasmPluginError::Instantiate)?;
+        let memory = instance.get_memory(&mut store, "memory").ok_or_else(|| {
+            WasmPluginError::Instantiate(anyhow::anyhow!("missing memory export"))
+        })?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&mut store, "alloc")
+            .map_err(WasmPluginError::Instantiate)?;
+        let process = instance
+            .get_typed_func::<(i32, i32), i32>(&mut store, "process")
+            .map_err(WasmPluginError::Instantiate)?;
+        let dealloc = instance
+            .get_typed_func::<(i32, i32), ()>(&mut store, "dealloc")
+            .ok();
+        Ok(PluginInstance {
+            store,
+            memory,
+            alloc,
+            process,
+            dealloc,
+        })
+    }
+
+    /// Runs the plugin, returning the result code and the state with the requested changes.
+    fn call(&self, value: &[u8], state: HostState) -> anyhow::Result<(i32, HostState)> {
+        let mut guard = self.instance.lock().unwrap();
+        if guard.is_none() {
+            *guard = Some(self.instantiate()?);
+        }
+        let instance = guard.as_mut().unwrap();
+        instance.store.data_mut().line = state;
+
+        let result = call_instance(instance, value, self.fuel);
+
+        match result {
+            Ok(code) => Ok((code, std::mem::take(&mut instance.store.data_mut().line))),
+            Err(e) => {
+                // The module state can't be trusted after a trap
+                *guard = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// Runs the plugin and applies its changes, returns false when the line should be skipped.
+    fn process_line(&self, line: &mut dyn LineBufferMut) -> anyhow::Result<bool> {
+        let value = match line.get_line_buffer() {
+            Some(v) => v.to_vec(),
+            // The plugin only sees lines with a buffer, the others are kept
+            None => return Ok(true),
+        };
+
+        let mut state = HostState::default();
+        for (name, value) in FIELDS.iter().zip(
+            [
+                line.get_app(),
+                line.get_host(),
+                line.get_env(),
+                line.get_file(),
+                line.get_level(),
+            ]
+            .iter(),
+        ) {
+            if let Some(value) = value {
+                state.fields.insert(*name, value.to_string());
+            }
+        }
+        if let Some(labels) = line.get_labels() {
+            state.labels = labels.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        }
+        if let Some(annotations) = line.get_annotations() {
+            state.annotations = annotations
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+        }
+
+        let (code, state) = self.call(&value, state)?;
+        match code {
+            KEEP => Ok(true),
+            DROP => Ok(false),
+            MODIFIED => {
+                if let Some(new_line) = state.new_line {
+                    if line.set_line_buffer(new_line).is_err() {
+                        return Ok(false);
+                    }
+                }
+                for (name, value) in state.new_fields {
+                    let result = match name {
+                        "app" => line.set_app(value),
+                        "host" => line.set_host(value),
+                        "env" => line.set_env(value),
+                        "file" => line.set_file(value),
+                        _ => line.set_level(value),
+                    };
+                    if result.is_err() {
+                        warn!("wasm plugin {} failed to set {}", self.name, name);
+                    }
+                }
+                Ok(true)
+            }
+            code => Err(anyhow::anyhow!("unknown result code {}", code)),
+        }
+    }
+
+    /// Runs the plugin against sample lines, returning the processed lines or `None` for the
+    /// lines that were dropped. Used to test plugins outside of the agent.
+    pub fn run_samples(&self, lines: Vec<LineBuilder>) -> Vec<Option<LineBuilder>> {
+        lines
+            .into_iter()
+            .map(|mut line| {
+                let keep = matches!(self.process(&mut line), Status::Ok(_));
+                if keep {
+                    Some(line)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+impl Middleware for WasmPlugin {
+    fn run(&self) {}
+
+    fn process<'a>(&self, line: &'a mut dyn LineBufferMut) -> Status<&'a mut dyn LineBufferMut> {
+        match self.process_line(line) {
+            Ok(true) => Status::Ok(line),
+            Ok(false) => Status::Skip,
+            Err(e) => {
+                warn!(
+                    "wasm plugin {} failed, forwarding the line: {}",
+                    self.name, e
+                );
+                Status::Ok(line)
+            }
+        }
+    }
+}
+
+fn create_engine() -> anyhow::Result<Engine> {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    Engine::new(&config)
+}
+
+fn call_instance(instance: &mut PluginInstance, value: &[u8], fuel: u64) -> anyhow::Result<i32> {
+    // Fuel is not carried over from the previous line
+    instance.store.set_fuel(fuel)?;
+    let len = value.len() as i32;
+    let ptr = instance.alloc.call(&mut instance.store, len)?;
+    instance
+        .memory
+        .write(&mut instance.store, ptr as usize, value)?;
+    let code = instance.process.call(&mut instance.store, (ptr, len))?;
+    if let Some(dealloc) = &instance.dealloc {
+        dealloc.call(&mut instance.store, (ptr, len))?;
+    }
+    Ok(code)
+}
+
+fn create_linker(engine: &Engine) -> anyhow::Result<Linker<StoreState>> {
+    let mut linker = Linker::new(engine);
+    linker.func_wrap(
+        IMPORT_MODULE,
+        "get_field",
+        |mut caller: Caller<'_, StoreState>, ptr: i32, len: i32, out_ptr: i32, out_cap: i32| {
+            let value = read_string(&mut caller, ptr, len)
+                .and_then(|name| caller.data().line.fields.get(name.as_str()).cloned());
+            write_value(&mut caller, value, out_ptr, out_cap)
+        },
+    )?;
+    linker.func_wrap(
+        IMPORT_MODULE,
+        "get_label",
+        |mut caller: Caller<'_, StoreState>, ptr: i32, len: i32, out_ptr: i32, out_cap: i32| {
+            let value = read_string(&mut caller, ptr, len)
+                .and_then(|key| caller.data().line.labels.get(&key).cloned());
+            write_value(&mut caller, value, out_ptr, out_cap)
+        },
+    )?;
+    linker.func_wrap(
+        IMPORT_MODULE,
+        "get_annotation",
+        |mut caller: Caller<'_, StoreState>, ptr: i32, len: i32, out_ptr: i32, out_cap: i32| {
+            let value = read_string(&mut caller, ptr, len)
+                .and_then(|key| caller.data().line.annotations.get(&key).cloned());
+            write_value(&mut caller, value, out_ptr, out_cap)
+        },
+    )?;
+    linker.func_wrap(
+        IMPORT_MODULE,
+        "set_line",
+        |mut caller: Caller<'_, StoreState>, ptr: i32, len: i32| match read_bytes(
+            &mut caller,
+            ptr,
+            len,
+        ) {
+            Some(value) => {
+                caller.data_mut().line.new_line = Some(value);
+                0
+            }
+            None => -1,
+        },
+    )?;
+    linker.func_wrap(
+        IMPORT_MODULE,
+        "set_field",
+        |mut caller: Caller<'_, StoreState>, ptr: i32, len: i32, value_ptr: i32, value_len: i32| {
+            let name = read_string(&mut caller, ptr, len)
+                .and_then(|name| FIELDS.iter().find(|f| **f == name).copied());
+            match (name, read_string(&mut caller, value_ptr, value_len)) {
+                (Some(name), Some(value)) => {
+                    caller.data_mut().line.new_fields.push((name, value));
+                    0
+                }
+                _ => -1,
+            }
+        },
+    )?;
+    Ok(linker)
+}
+
+fn read_bytes(caller: &mut Caller<'_, StoreState>, ptr: i32, len: i32) -> Option<Vec<u8>> {
+    if ptr < 0 || len < 0 {
+        return None;
+    }
+    let memory = caller.get_export("memory")?.into_memory()?;
+    let mut buf = vec![0; len as usize];
+    memory.read(&caller, ptr as usize, &mut buf).ok()?;
+    Some(buf)
+}
+
+fn read_string(caller: &mut Caller<'_, StoreState>, ptr: i32, len: i32) -> Option<String> {
+    read_bytes(caller, ptr, len).and_then(|b| String::from_utf8(b).ok())
+}
+
+fn write_value(
+    caller: &mut Caller<'_, StoreState>,
+    value: Option<String>,
+    out_ptr: i32,
+    out_cap: i32,
+) -> i32 {
+    let value = match value {
+        Some(v) => v,
+        None => return -1,
+    };
+    if out_ptr < 0 || out_cap < 0 {
+        return -1;
+    }
+    let memory = match caller.get_export("memory").and_then(|e| e.into_memory()) {
+        Some(m) => m,
+        None => return -1,
+    };
+    let len = std::cmp::min(value.len(), out_cap as usize);
+    if memory
+        .write(caller, out_ptr as usize, &value.as_bytes()[..len])
+        .is_err()
+    {
+        return -1;
+    }
+    value.len() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::types::body::KeyValueMap;
+
+    // Drops lines starting with "D", replaces the lines starting with "M" and sets the level,
+    // replaces the lines starting with "L" with the "team" label and loops forever on "X"
+    static TEST_PLUGIN: &str = r#"
+(module
+  (import "logdna" "get_label" (func $get_label (param i32 i32 i32 i32) (result i32)))
+  (import "logdna" "set_line" (func $set_line (param i32 i32) (result i32)))
+  (import "logdna" "set_field" (func $set_field (param i32 i32 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "team")
+  (data (i32.const 16) "level")
+  (data (i32.const 32) "WARN")
+  (func (export "alloc") (param i32) (result i32)
+    i32.const 1024)
+  (func (export "process") (param $ptr i32) (param $len i32) (result i32)
+    (local $first i32)
+    (if (i32.eqz (local.get $len)) (then (return (i32.const 0))))
+    (local.set $first (i32.load8_u (local.get $ptr)))
+    (if (i32.eq (local.get $first) (i32.const 68)) (then (return (i32.const 1))))
+    (if (i32.eq (local.get $first) (i32.const 77))
+      (then
+        (drop (call $set_field (i32.const 16) (i32.const 5) (i32.const 32) (i32.const 4)))
+        (drop (call $set_line
+          (i32.add (local.get $ptr) (i32.const 1))
+          (i32.sub (local.get $len) (i32.const 1))))
+        (return (i32.const 2))))
+    (if (i32.eq (local.get $first) (i32.const 76))
+      (then
+        (drop (call $set_line
+          (i32.const 512)
+          (call $get_label (i32.const 0) (i32.const 4) (i32.const 512) (i32.const 64))))
+        (return (i32.const 2))))
+    (if (i32.eq (local.get $first) (i32.const 88))
+      (then (loop $forever (br $forever))))
+    (i32.const 0))
+)
+"#;
+
+    // Grows the memory by 16 pages (1MiB) and drops the line, traps when it can't grow
+    static GROWING_PLUGIN: &str = r#"
+(module
+  (memory (export "memory") 1)
+  (func (export "alloc") (param i32) (result i32)
+    i32.const 1024)
+  (func (export "process") (param $ptr i32) (param $len i32) (result i32)
+    (if (i32.eq (memory.grow (i32.const 16)) (i32.const -1)) (then unreachable))
+    (i32.const 1))
+)
+"#;
+
+    const MAX_MEMORY: usize = 4 * 1024 * 1024;
+
+    fn plugin() -> WasmPlugin {
+        WasmPlugin::from_bytes("test", TEST_PLUGIN, 10_000, MAX_MEMORY).unwrap()
+    }
+
+    #[test]
+    fn should_keep_and_drop_lines() {
+        let results = plugin().run_samples(vec![
+            LineBuilder::new().line("INFO something"),
+            LineBuilder::new().line("DEBUG something"),
+        ]);
+        assert_eq!(results.len(), 2);
+        assert_eq!(
+            results[0].as_ref().unwrap().line.as_deref(),
+            Some("INFO something")
+        );
+        assert!(results[1].is_none());
+    }
+
+    #[test]
+    fn should_modify_lines() {
+        let results = plugin().run_samples(vec![LineBuilder::new().line("Mmodified")]);
+        let line = results[0].as_ref().unwrap();
+        assert_eq!(line.line.as_deref(), Some("modified"));
+        assert_eq!(line.level.as_deref(), Some("WARN"));
+    }
+
+    #[test]
+    fn should_read_k8s_metadata() {
+        let labels = KeyValueMap::new().add("team".to_string(), "core".to_string());
+        let results = plugin().run_samples(vec![LineBuilder::new().line("L").labels(labels)]);
+        assert_eq!(results[0].as_ref().unwrap().line.as_deref(), Some("core"));
+    }
+
+    #[test]
+    fn should_limit_fuel_and_recover() {
+        let results = plugin().run_samples(vec![
+            LineBuilder::new().line("X loops forever"),
+            LineBuilder::new().line("Mrecovered"),
+        ]);
+        // The line is forwarded when the plugin runs out of fuel
+        assert_eq!(
+            results[0].as_ref().unwrap().line.as_deref(),
+            Some("X loops forever")
+        );
+        assert_eq!(
+            results[1].as_ref().unwrap().line.as_deref(),
+            Some("recovered")
+        );
+    }
+
+    #[test]
+    fn should_limit_memory() {
+        let plugin = WasmPlugin::from_bytes("growing", GROWING_PLUGIN, 10_000, MAX_MEMORY).unwrap();
+        let results = plugin.run_samples(
+            (0..5)
+                .map(|i| LineBuilder::new().line(format!("line {}", i)))
+                .collect(),
+        );
+        // 1 + 3 * 16 pages fit in 4MiB, the plugin is stopped on the 4th line
+        assert!(results[..3].iter().all(Option::is_none));
+        assert_eq!(results[3].as_ref().unwrap().line.as_deref(), Some("line 3"));
+        // The instance is recreated with its initial memory after the trap
+        assert!(results[4].is_none());
+    }
+
+    #[test]
+    fn should_validate_exports() {
+        assert!(matches!(
+            WasmPlugin::from_bytes("invalid", "(module)", 10_000, MAX_MEMORY),
+            Err(WasmPluginError::Instantiate(_))
+        ));
+    }
+}
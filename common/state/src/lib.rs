@@ -492,6 +492,10 @@ fn handle_file_offset_event(
             FileOffsetUpdate::Delete(key) => {
                 let mut wb = wb.unwrap_or_default();
                 state.remove(&key);
+                // The staged spans predate the deletion, they would be written back on flush
+                for (_, offsets) in pending.iter_mut() {
+                    offsets.remove(&key);
+                }
                 wb.delete_cf(cf_handle, u64::to_be_bytes(key.0));
                 Ok(EventAction::Nop((
                     Some(wb),
@@ -602,6 +606,54 @@ mod test {
         _test(&data_dir, 2);
     }
 
+    #[test]
+    fn delete_drops_staged_offsets() {
+        let data_dir = tempdir().expect("Could not create temp dir").into_path();
+        let agent_state = AgentState::new(&data_dir).unwrap();
+        let offset_state = agent_state.get_offset_state();
+
+        let wh = offset_state.write_handle();
+        let fh = offset_state.flush_handle();
+        let sh = offset_state.shutdown_handle().unwrap();
+
+        tokio_test::block_on(async {
+            let _ = tokio::join!(
+                async {
+                    // Staged before the file is truncated
+                    let mut updates = OffsetMap::default();
+                    updates.insert(1, (0, 100)).unwrap();
+                    updates.insert(2, (0, 50)).unwrap();
+                    let stale = wh.update(updates).await.unwrap();
+
+                    wh.delete(1).await.unwrap();
+                    let mut updates = OffsetMap::default();
+                    updates.insert(1, (0, 10)).unwrap();
+                    let truncated = wh.update(updates).await.unwrap();
+
+                    fh.flush(Some(stale)).await.unwrap();
+                    fh.flush(Some(truncated)).await.unwrap();
+                    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                    sh.shutdown();
+                },
+                offset_state.run().unwrap()
+            );
+        });
+
+        let mut offsets = offset_state
+            .offsets()
+            .unwrap()
+            .into_iter()
+            .map(|fo| {
+                (
+                    fo.key.ffi(),
+                    fo.offsets.iter().map(|s| (s.start, s.end)).collect(),
+                )
+            })
+            .collect::<Vec<(u64, Vec<(u64, u64)>)>>();
+        offsets.sort();
+        assert_eq!(offsets, vec![(1, vec![(0, 10)]), (2, vec![(0, 50)])]);
+    }
+
     #[test]
     fn load_agent_state_dir_missing() {
         // build a path with multiple levels of missing directories to ensure they're all created
@@ -45,6 +45,11 @@ impl OffsetMap {
         Ok(())
     }
 
+    /// Drops the spans of a file, the map is copied when it's shared.
+    pub fn remove(&mut self, key: &FileId) {
+        Arc::make_mut(&mut self.inner).retain(|(file_id, _)| file_id != key);
+    }
+
     pub fn items_as_ref(&self) -> &[(FileId, SpanVec)] {
         self.inner.as_ref().as_ref()
     }